@@ -0,0 +1,19 @@
+#!/bin/bash
+# runs the backend scenarios with canbench and prints the results of each
+# scenario for all backends next to each other
+
+BACKENDS="mounted_file memory_vfs stable_fs_v1 stable_fs_v2 heap"
+SCENARIOS="add_10000_persons insert_1_person_after_10000 query_person_by_id_after_10000 query_1_person_by_name_after_10000 update_person_by_id_after_10000 delete_person_by_id_after_10000"
+
+canbench &> report/canbench-backends.log
+
+for SCENARIO in $SCENARIOS
+do
+  echo "--- ${SCENARIO} ---"
+  for BACKEND in $BACKENDS
+  do
+    # the instructions line follows the "Benchmark: <name>" header
+    INSTRUCTIONS=`grep -A3 "Benchmark: ${BACKEND}_${SCENARIO}\$" report/canbench-backends.log | grep instructions | sed 's/.*instructions: //'`
+    printf "%-14s %s\n" "$BACKEND" "$INSTRUCTIONS"
+  done
+done
@@ -0,0 +1,19 @@
+#!/bin/bash
+export backend=ic_rusqlite_bench_backend
+export target_path="./target/wasm32-wasi/release"
+
+set -e
+
+./build.sh
+
+./deploy.sh
+
+dfx canister call $backend bench1_insert_person "(0, 10000)"
+dfx canister call $backend count '("person")'
+
+echo "Upgrade canister"
+dfx canister install --mode upgrade --yes --wasm $target_path/$backend.wasm.gz $backend
+
+# the same count is expected after the upgrade
+dfx canister call $backend count '("person")'
+dfx canister call $backend bench1_insert_person_one "(10000)"
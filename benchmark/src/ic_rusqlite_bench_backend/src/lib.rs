@@ -1,13 +1,19 @@
 use ic_stable_structures::memory_manager::MemoryId;
 use ic_stable_structures::Memory;
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
 use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+use ic_stable_structures::{StableCell, Storable};
 
-use candid::CandidType;
+use candid::{CandidType, Decode, Encode};
 use ic_cdk::api::call::RejectionCode;
 use rusqlite::types::Type;
 use rusqlite::Connection;
+use rusqlite::OpenFlags;
 use rusqlite::ToSql;
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::rc::Rc;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -15,17 +21,30 @@ use serde::Serialize;
 const WASI_MEMORY_ID: u8 = 50;
 const MOUNTED_MEMORY_ID: u8 = 20;
 
+const VFS_MEMORY_ID: u8 = 30;
+
+const BACKEND_MEMORY_ID: MemoryId = MemoryId::new(10);
+
 const PROFILING: MemoryId = MemoryId::new(100);
 
 const DB_FILE_NAME: &str = "db.db3";
 const FAST_FILE_NAME: &str = "db.db3";
-const DB_JOURNAL_FILE_NAME: &str = "db.db3-journal1";
+const DB_JOURNAL_FILE_NAME: &str = "db.db3-journal";
 
 thread_local! {
     static DB: RefCell<Option<Connection>> = RefCell::new(None);
 
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // the backend selected with `use_backend`, restored by `post_upgrade`
+    static BACKEND: RefCell<StableCell<Backend, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BACKEND_MEMORY_ID)),
+            Backend::MountedFile,
+        )
+        .unwrap(),
+    );
 }
 
 #[ic_cdk::update]
@@ -146,6 +165,166 @@ fn create_index() -> Result {
     })
 }
 
+#[ic_cdk::update]
+fn create_person2_table() -> Result {
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        let db = db.as_mut().unwrap();
+
+        match db.execute(
+            "CREATE TABLE IF NOT EXISTS person2 (
+                    id INTEGER not null primary key,
+                    name VARCHAR(20),
+                    age INTEGER not null,
+                    gender INTEGER not null,
+                    data TEXT)",
+            (),
+        ) {
+            Ok(_) => Ok(format!(
+                "create_person2_table: {:?}",
+                ic_cdk::api::performance_counter(0)
+            )),
+            Err(err) => Err(Error::CanisterError {
+                message: format!("create_person2_table error: {:?}", err),
+            }),
+        }
+    })
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum ChunkType {
+    V1,
+    V2,
+}
+
+/// Storage of the database and journal files, selected with `use_backend`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    // stable memories mounted as files, the default set up by `init`
+    MountedFile,
+    // stable memories read and written by SQLite through the memory_vfs VFS
+    MemoryVfs,
+    // regular files of the stable-fs file system
+    StableFsFile(ChunkType),
+    // files on the heap only
+    Heap,
+}
+
+impl Storable for Backend {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// mounts the files of a backend, the heap backend replaces the file system instead
+fn mount_backend(backend: Backend) {
+    use ic_wasi_polyfill::{FileSystem, TransientStorage};
+
+    MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+
+        match backend {
+            Backend::MountedFile => {
+                let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID));
+                ic_wasi_polyfill::mount_memory_file(FAST_FILE_NAME, Box::new(memory));
+                let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID + 1));
+                ic_wasi_polyfill::mount_memory_file(DB_JOURNAL_FILE_NAME, Box::new(memory));
+            }
+            Backend::MemoryVfs => {
+                // reads and writes go to stable memory directly instead of through the WASI file system
+                let sizes = Rc::new(m.get(MemoryId::new(VFS_MEMORY_ID + 2)));
+                let memory = m.get(MemoryId::new(VFS_MEMORY_ID));
+                memory_vfs::mount(DB_FILE_NAME, Box::new(memory), sizes.clone(), 0);
+                let memory = m.get(MemoryId::new(VFS_MEMORY_ID + 1));
+                memory_vfs::mount(DB_JOURNAL_FILE_NAME, Box::new(memory), sizes, 1);
+            }
+            Backend::StableFsFile(chunk_type) => {
+                ic_wasi_polyfill::FS.with(|fs| {
+                    fs.borrow_mut().storage.set_chunk_type(match chunk_type {
+                        ChunkType::V1 => ic_wasi_polyfill::ChunkType::V1,
+                        ChunkType::V2 => ic_wasi_polyfill::ChunkType::V2,
+                    });
+                });
+            }
+            Backend::Heap => {
+                ic_wasi_polyfill::FS.with(|fs| {
+                    *fs.borrow_mut() =
+                        FileSystem::new(Box::new(TransientStorage::new())).unwrap();
+                });
+            }
+        }
+    });
+
+    // temporary files of SQLite, the directory already exists after an upgrade
+    std::fs::create_dir_all("/tmp").unwrap();
+}
+
+// reopens an empty database on another backend, call it before any data is inserted
+#[ic_cdk::update]
+fn use_backend(backend: Backend) -> Result {
+    DB.with(|db| *db.borrow_mut() = None);
+
+    // the mounted memories are left behind, the new backend starts with empty files
+    ic_wasi_polyfill::unmount_memory_file(FAST_FILE_NAME);
+    ic_wasi_polyfill::unmount_memory_file(DB_JOURNAL_FILE_NAME);
+    let _ = std::fs::remove_file(FAST_FILE_NAME);
+    let _ = std::fs::remove_file(DB_JOURNAL_FILE_NAME);
+
+    // back to the stable file system with the default chunk type, also when leaving the heap backend
+    init_file_system();
+
+    mount_backend(backend);
+    BACKEND.with(|b| b.borrow_mut().set(backend).unwrap());
+
+    open_database();
+    set_pragmas();
+    create_tables();
+
+    Ok(format!(
+        "use_backend performance_counter: {:?}",
+        ic_cdk::api::performance_counter(0)
+    ))
+}
+
+#[ic_cdk::update]
+fn set_page_size(page_size: usize) -> Result {
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        let db = db.as_mut().unwrap();
+
+        // the page size of an existing database only changes when it is rebuilt
+        match db.execute_batch(&format!("PRAGMA page_size = {}; VACUUM;", page_size)) {
+            Ok(_) => {}
+            Err(err) => {
+                return Err(Error::CanisterError {
+                    message: format!("set_page_size error: {:?}", err),
+                })
+            }
+        }
+
+        let effective: usize = db
+            .pragma_query_value(None, "page_size", |row| row.get(0))
+            .unwrap();
+
+        if effective != page_size {
+            return Err(Error::CanisterError {
+                message: format!("set_page_size: page size is still {}", effective),
+            });
+        }
+
+        Ok(format!(
+            "set_page_size performance_counter: {:?}",
+            ic_cdk::api::performance_counter(0)
+        ))
+    })
+}
+
 #[ic_cdk::update]
 fn bench1_insert_person(offset: usize, count: usize) -> Result {
     DB.with(|db| {
@@ -744,23 +923,32 @@ struct Person2 {
 }
 
 fn open_database() {
+    let backend = BACKEND.with(|b| *b.borrow().get());
+
     DB.with(|db| {
         let mut db = db.borrow_mut();
-        *db = Some(Connection::open(DB_FILE_NAME).unwrap());
+        *db = Some(match backend {
+            Backend::MemoryVfs => Connection::open_with_flags_and_vfs(
+                DB_FILE_NAME,
+                OpenFlags::default(),
+                memory_vfs::VFS_NAME,
+            )
+            .unwrap(),
+            _ => Connection::open(DB_FILE_NAME).unwrap(),
+        });
     });
 }
 
 pub fn profiling_init() {
     let memory = MEMORY_MANAGER.with(|m| m.borrow().get(PROFILING));
-    memory.grow(4096);
-}
 
-#[ic_cdk::init]
-pub fn init() {
-    use ic_wasi_polyfill::ChunkType;
-
-    profiling_init();
+    // the profiling memory is kept across upgrades
+    if memory.size() == 0 {
+        memory.grow(4096);
+    }
+}
 
+fn init_file_system() {
     MEMORY_MANAGER.with(|m| {
         let m = m.borrow();
 
@@ -771,23 +959,34 @@ pub fn init() {
             &m,
             WASI_MEMORY_ID..WASI_MEMORY_ID + 10,
         );
+    });
 
-        ic_wasi_polyfill::FS.with(|fs| {
-            let mut fs = fs.borrow_mut();
-            fs.storage.set_chunk_type(ChunkType::V2);
-        });
+    set_chunk_type_v2();
+}
 
-        let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID));
-        ic_wasi_polyfill::mount_memory_file(FAST_FILE_NAME, Box::new(memory));
-        
-        let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID + 1));
-        ic_wasi_polyfill::mount_memory_file(DB_JOURNAL_FILE_NAME, Box::new(memory));
+fn set_chunk_type_v2() {
+    ic_wasi_polyfill::FS.with(|fs| {
+        let mut fs = fs.borrow_mut();
+        fs.storage.set_chunk_type(ic_wasi_polyfill::ChunkType::V2);
     });
+}
 
-    std::fs::create_dir("/tmp").unwrap();
+#[ic_cdk::init]
+pub fn init() {
+    profiling_init();
+
+    init_file_system();
+
+    mount_backend(Backend::MountedFile);
 
     open_database();
 
+    set_pragmas();
+
+    create_tables();
+}
+
+fn set_pragmas() {
     // set pragmas
     DB.with(|db| {
         let mut db = db.borrow_mut();
@@ -799,8 +998,8 @@ pub fn init() {
         // reduce synchronizations
         db.pragma_update(None, "synchronous", &0 as &dyn ToSql).unwrap();
         
-        // use fewer writes to disk with larger memory chunks
-        // Note: values above 16384 cause I/O errors for some reason
+        // use fewer writes to disk with larger memory chunks,
+        // the page size benchmarks switch to other sizes with `set_page_size`
         db.pragma_update(None, "page_size", &16384 as &dyn ToSql).unwrap();
 
         // reduce locks and unlocks
@@ -814,8 +1013,9 @@ pub fn init() {
         //db.pragma_update(None, "cache_size", &1000000 as &dyn ToSql).unwrap();
 
     });
+}
 
-    // config DB, create tables
+fn create_tables() {
     DB.with(|db| {
         let mut db = db.borrow_mut();
         let db = db.as_mut().unwrap();
@@ -835,24 +1035,48 @@ pub fn init() {
     });
 }
 
-/*
+#[ic_cdk::pre_upgrade]
+pub fn pre_upgrade() {
+    // every committed transaction is already in the files, closing the
+    // connection just releases the exclusive lock and the page cache
+    DB.with(|db| *db.borrow_mut() = None);
+}
+
 #[ic_cdk::post_upgrade]
 pub fn post_upgrade() {
     profiling_init();
 
-    MEMORY_MANAGER.with(|m| {
-        let m = m.borrow();
-        ic_wasi_polyfill::init_with_memory_manager(&[0u8; 32], &[], &m, WASI_MEMORY_ID..WASI_MEMORY_ID + 10);
+    init_file_system();
 
-        let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID));
-        ic_wasi_polyfill::mount_memory_file(FAST_FILE_NAME, Box::new(memory));
-        let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID + 1));
-        ic_wasi_polyfill::mount_memory_file(DB_JOURNAL_FILE_NAME, Box::new(memory));
-    });
+    // remounts the memories or restores the chunk type of the selected backend
+    let backend = BACKEND.with(|b| *b.borrow().get());
+    mount_backend(backend);
 
     open_database();
+
+    set_pragmas();
+
+    // heap files do not survive an upgrade, their tables are created again
+    create_tables();
+
+    verify_database();
+}
+
+// traps if the database came back damaged, which rolls the upgrade back
+fn verify_database() {
+    DB.with(|db| {
+        let db = db.borrow();
+        let db = db.as_ref().unwrap();
+
+        let check: String = db
+            .pragma_query_value(None, "quick_check", |row| row.get(0))
+            .unwrap_or_else(|err| format!("{:?}", err));
+
+        if check != "ok" {
+            ic_cdk::trap(&format!("the database is damaged after the upgrade: {}", check));
+        }
+    });
 }
-*/
 
 #[derive(CandidType, Deserialize, Debug)]
 enum Error {
@@ -1278,4 +1502,409 @@ mod benches {
     }
     
     /////////////////
+
+    // page size comparison, person2 rows carry ~4 KB of data each
+
+    fn add_persons2(page_size: usize, count: usize) {
+        create_person2_table().unwrap();
+        set_page_size(page_size).unwrap();
+        bench2_insert_person2(0, count).unwrap();
+    }
+
+    #[bench(raw)]
+    fn insert_1000_persons2_page_size_4k() -> BenchResult {
+        create_person2_table().unwrap();
+        set_page_size(4096).unwrap();
+
+        bench_fn(|| {
+            bench2_insert_person2(0, 1000).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_id_page_size_4k() -> BenchResult {
+        add_persons2(4096, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_limit_offset_page_size_4k() -> BenchResult {
+        add_persons2(4096, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_limit_offset(10, 500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn update_person2_by_id_page_size_4k() -> BenchResult {
+        add_persons2(4096, 1000);
+
+        bench_fn(|| {
+            bench2_update_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn insert_1000_persons2_page_size_16k() -> BenchResult {
+        create_person2_table().unwrap();
+        set_page_size(16384).unwrap();
+
+        bench_fn(|| {
+            bench2_insert_person2(0, 1000).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_id_page_size_16k() -> BenchResult {
+        add_persons2(16384, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_limit_offset_page_size_16k() -> BenchResult {
+        add_persons2(16384, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_limit_offset(10, 500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn update_person2_by_id_page_size_16k() -> BenchResult {
+        add_persons2(16384, 1000);
+
+        bench_fn(|| {
+            bench2_update_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn insert_1000_persons2_page_size_32k() -> BenchResult {
+        create_person2_table().unwrap();
+        set_page_size(32768).unwrap();
+
+        bench_fn(|| {
+            bench2_insert_person2(0, 1000).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_id_page_size_32k() -> BenchResult {
+        add_persons2(32768, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_limit_offset_page_size_32k() -> BenchResult {
+        add_persons2(32768, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_limit_offset(10, 500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn update_person2_by_id_page_size_32k() -> BenchResult {
+        add_persons2(32768, 1000);
+
+        bench_fn(|| {
+            bench2_update_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn insert_1000_persons2_page_size_64k() -> BenchResult {
+        create_person2_table().unwrap();
+        set_page_size(65536).unwrap();
+
+        bench_fn(|| {
+            bench2_insert_person2(0, 1000).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_id_page_size_64k() -> BenchResult {
+        add_persons2(65536, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_id(500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn query_person2_by_limit_offset_page_size_64k() -> BenchResult {
+        add_persons2(65536, 1000);
+
+        bench_fn(|| {
+            bench2_query_person2_by_limit_offset(10, 500).unwrap();
+        })
+    }
+
+    #[bench(raw)]
+    fn update_person2_by_id_page_size_64k() -> BenchResult {
+        add_persons2(65536, 1000);
+
+        bench_fn(|| {
+            bench2_update_person2_by_id(500).unwrap();
+        })
+    }
+
+    ///////////////// upgrades, the cost of pre_upgrade and post_upgrade with data
+
+    fn upgrade_after(count: usize) -> BenchResult {
+        add_persons(count);
+        create_index().unwrap();
+
+        let result = bench_fn(|| {
+            pre_upgrade();
+            post_upgrade();
+        });
+
+        // the data is still there after the upgrade
+        count_persons(count);
+
+        result
+    }
+
+    fn count_persons(expected: usize) {
+        DB.with(|db| {
+            let db = db.borrow();
+            let count: usize = db
+                .as_ref()
+                .unwrap()
+                .query_row("select count(*) from person", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, expected);
+        });
+    }
+
+    #[bench(raw)]
+    fn upgrade_after_10000_persons() -> BenchResult {
+        upgrade_after(10000)
+    }
+
+    #[bench(raw)]
+    fn upgrade_after_100000_persons() -> BenchResult {
+        upgrade_after(100000)
+    }
+
+    #[bench(raw)]
+    fn upgrade_after_500000_persons() -> BenchResult {
+        upgrade_after(500000)
+    }
+
+    #[bench(raw)]
+    fn upgrade_after_1000000_persons() -> BenchResult {
+        upgrade_after(1000000)
+    }
+
+    #[bench(raw)]
+    fn upgrade_after_100000_persons_on_memory_vfs() -> BenchResult {
+        use_backend(Backend::MemoryVfs).unwrap();
+        upgrade_after(100000)
+    }
+
+    #[bench(raw)]
+    fn upgrade_after_100000_persons_on_stable_fs_v2() -> BenchResult {
+        use_backend(Backend::StableFsFile(ChunkType::V2)).unwrap();
+        upgrade_after(100000)
+    }
+
+    ///////////////// the same scenarios on every backend
+
+    fn on_backend(backend: Backend, scenario: fn()) -> BenchResult {
+        use_backend(backend).unwrap();
+        add_persons(10000);
+        create_index().unwrap();
+
+        bench_fn(scenario)
+    }
+
+    fn insert_1_person() {
+        bench1_insert_person_one(10000).unwrap();
+    }
+
+    fn query_person_by_id() {
+        bench1_query_person_by_id(10000).unwrap();
+    }
+
+    fn query_1_person_by_name() {
+        bench1_query_person_by_name(10000).unwrap();
+    }
+
+    fn update_person_by_id() {
+        bench1_update_person_by_id(10000).unwrap();
+    }
+
+    fn delete_person_by_id() {
+        bench1_delete_person_by_id(10000).unwrap();
+    }
+
+    fn add_10000_persons_on(backend: Backend) -> BenchResult {
+        use_backend(backend).unwrap();
+
+        bench_fn(|| {
+            add_persons(10000);
+        })
+    }
+
+    #[bench(raw)]
+    fn mounted_file_add_10000_persons() -> BenchResult {
+        add_10000_persons_on(Backend::MountedFile)
+    }
+
+    #[bench(raw)]
+    fn memory_vfs_add_10000_persons() -> BenchResult {
+        add_10000_persons_on(Backend::MemoryVfs)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v1_add_10000_persons() -> BenchResult {
+        add_10000_persons_on(Backend::StableFsFile(ChunkType::V1))
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v2_add_10000_persons() -> BenchResult {
+        add_10000_persons_on(Backend::StableFsFile(ChunkType::V2))
+    }
+
+    #[bench(raw)]
+    fn heap_add_10000_persons() -> BenchResult {
+        add_10000_persons_on(Backend::Heap)
+    }
+
+    #[bench(raw)]
+    fn mounted_file_insert_1_person_after_10000() -> BenchResult {
+        on_backend(Backend::MountedFile, insert_1_person)
+    }
+
+    #[bench(raw)]
+    fn memory_vfs_insert_1_person_after_10000() -> BenchResult {
+        on_backend(Backend::MemoryVfs, insert_1_person)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v1_insert_1_person_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V1), insert_1_person)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v2_insert_1_person_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V2), insert_1_person)
+    }
+
+    #[bench(raw)]
+    fn heap_insert_1_person_after_10000() -> BenchResult {
+        on_backend(Backend::Heap, insert_1_person)
+    }
+
+    #[bench(raw)]
+    fn mounted_file_query_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::MountedFile, query_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn memory_vfs_query_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::MemoryVfs, query_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v1_query_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V1), query_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v2_query_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V2), query_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn heap_query_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::Heap, query_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn mounted_file_query_1_person_by_name_after_10000() -> BenchResult {
+        on_backend(Backend::MountedFile, query_1_person_by_name)
+    }
+
+    #[bench(raw)]
+    fn memory_vfs_query_1_person_by_name_after_10000() -> BenchResult {
+        on_backend(Backend::MemoryVfs, query_1_person_by_name)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v1_query_1_person_by_name_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V1), query_1_person_by_name)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v2_query_1_person_by_name_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V2), query_1_person_by_name)
+    }
+
+    #[bench(raw)]
+    fn heap_query_1_person_by_name_after_10000() -> BenchResult {
+        on_backend(Backend::Heap, query_1_person_by_name)
+    }
+
+    #[bench(raw)]
+    fn mounted_file_update_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::MountedFile, update_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn memory_vfs_update_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::MemoryVfs, update_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v1_update_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V1), update_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v2_update_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V2), update_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn heap_update_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::Heap, update_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn mounted_file_delete_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::MountedFile, delete_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn memory_vfs_delete_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::MemoryVfs, delete_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v1_delete_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V1), delete_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn stable_fs_v2_delete_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::StableFsFile(ChunkType::V2), delete_person_by_id)
+    }
+
+    #[bench(raw)]
+    fn heap_delete_person_by_id_after_10000() -> BenchResult {
+        on_backend(Backend::Heap, delete_person_by_id)
+    }
 }
@@ -6,10 +6,12 @@ export CC_wasm32_wasi="/opt/wasi-sdk/bin/clang --sysroot=/opt/wasi-sdk/share/was
 
 cargo build --release --target "wasm32-wasi"
 
-rm -f ./target/wasm32-wasi/release/no_wasi.wasm.gz
+rm -f ./target/wasm32-wasi/release/no_wasi.wasm.gz ./target/wasm32-wasi/release/router_no_wasi.wasm.gz
 
 wasi2ic ./target/wasm32-wasi/release/demo3_backend.wasm ./target/wasm32-wasi/release/no_wasi.wasm
 
 gzip ./target/wasm32-wasi/release/no_wasi.wasm
 
+wasi2ic ./target/wasm32-wasi/release/demo3_router.wasm ./target/wasm32-wasi/release/router_no_wasi.wasm
 
+gzip ./target/wasm32-wasi/release/router_no_wasi.wasm
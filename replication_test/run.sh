@@ -0,0 +1,12 @@
+#!/bin/bash
+# Builds demo3_backend and runs the replication tests against two canisters in PocketIC.
+# Needs the wasi-sdk used by compile.sh and the PocketIC server binary in POCKET_IC_BIN.
+
+set -e
+
+cd "$(dirname "$0")/.."
+./compile.sh
+
+cd replication_test
+export DEMO3_WASM="../target/wasm32-wasi/release/no_wasi.wasm.gz"
+cargo test -- --nocapture
@@ -0,0 +1,3 @@
+//! Replication tests of `demo3_backend` with a primary and a follower canister in PocketIC.
+//!
+//! The tests live in `tests/`, run them with `run.sh`.
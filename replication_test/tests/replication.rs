@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Principal, Reserved};
+use pocket_ic::{PocketIc, WasmResult};
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+enum Role {
+    Standalone,
+    Primary,
+    Follower { primary: Principal },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct FollowerState {
+    applied_checkpoint: Option<u64>,
+    last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct ReplicationStatus {
+    role: Role,
+    next_checkpoint: u64,
+    applied_checkpoint: Option<u64>,
+    followers: Vec<(Principal, FollowerState)>,
+}
+
+type Rows = Vec<(u64, String, String, u32)>;
+
+fn install(pic: &PocketIc, wasm: &[u8]) -> Principal {
+    let canister = pic.create_canister();
+    pic.add_cycles(canister, 10_000_000_000_000);
+    pic.install_canister(canister, wasm.to_vec(), encode_args(()).unwrap(), None);
+    canister
+}
+
+fn reply(result: Result<WasmResult, pocket_ic::UserError>) -> Vec<u8> {
+    match result.expect("call failed") {
+        WasmResult::Reply(bytes) => bytes,
+        WasmResult::Reject(message) => panic!("rejected: {message}"),
+    }
+}
+
+fn update(pic: &PocketIc, canister: Principal, method: &str, args: Vec<u8>) -> Vec<u8> {
+    reply(pic.update_call(canister, Principal::anonymous(), method, args))
+}
+
+fn query(pic: &PocketIc, canister: Principal, method: &str) -> Vec<u8> {
+    reply(pic.query_call(
+        canister,
+        Principal::anonymous(),
+        method,
+        encode_args(()).unwrap(),
+    ))
+}
+
+fn admin(pic: &PocketIc, canister: Principal, method: &str, args: Vec<u8>) {
+    let result: Result<(), Reserved> = decode_one(&update(pic, canister, method, args)).unwrap();
+    assert!(result.is_ok(), "{method} failed");
+}
+
+fn add(pic: &PocketIc, canister: Principal, name: &str) -> Result<(), Reserved> {
+    let args = encode_args((name, "", 42u32)).unwrap();
+    decode_one(&update(pic, canister, "add", args)).unwrap()
+}
+
+fn list(pic: &PocketIc, canister: Principal) -> Rows {
+    decode_one(&query(pic, canister, "list")).unwrap()
+}
+
+fn status(pic: &PocketIc, canister: Principal) -> ReplicationStatus {
+    decode_one(&query(pic, canister, "get_replication_status")).unwrap()
+}
+
+// lets the push timer of the primary run until the follower caught up
+fn sync(pic: &PocketIc, primary: Principal, follower: Principal) {
+    for _ in 0..50 {
+        pic.advance_time(Duration::from_secs(2));
+        pic.tick();
+
+        let next = status(pic, primary).next_checkpoint;
+        if status(pic, follower).applied_checkpoint == Some(next) {
+            return;
+        }
+    }
+    panic!("the follower did not catch up: {:?}", status(pic, primary));
+}
+
+fn setup() -> (PocketIc, Principal, Principal) {
+    let wasm = std::fs::read(std::env::var("DEMO3_WASM").expect("DEMO3_WASM is not set"))
+        .expect("cannot read the demo3_backend wasm");
+
+    let pic = PocketIc::new();
+    let primary = install(&pic, &wasm);
+    let follower = install(&pic, &wasm);
+
+    admin(
+        &pic,
+        follower,
+        "set_replication_role",
+        encode_args((Role::Follower { primary }, Some(20u64))).unwrap(),
+    );
+    admin(
+        &pic,
+        primary,
+        "set_replication_role",
+        encode_args((Role::Primary, Some(20u64))).unwrap(),
+    );
+    admin(&pic, primary, "add_follower", encode_one(follower).unwrap());
+
+    (pic, primary, follower)
+}
+
+#[test]
+fn follower_replays_the_writes_of_the_primary() {
+    let (pic, primary, follower) = setup();
+
+    for name in ["a", "b", "c"] {
+        add(&pic, primary, name).unwrap();
+    }
+    sync(&pic, primary, follower);
+
+    assert_eq!(list(&pic, primary).len(), 3);
+    assert_eq!(list(&pic, follower), list(&pic, primary));
+
+    // followers are read-only
+    assert!(add(&pic, follower, "d").is_err());
+}
+
+#[test]
+fn lagging_follower_resyncs_from_a_snapshot() {
+    let (pic, primary, follower) = setup();
+
+    add(&pic, primary, "a").unwrap();
+    sync(&pic, primary, follower);
+
+    pic.stop_canister(follower, None).unwrap();
+    for i in 0..30 {
+        add(&pic, primary, &format!("row {i}")).unwrap();
+    }
+    pic.advance_time(Duration::from_secs(10));
+    pic.tick();
+
+    pic.start_canister(follower, None).unwrap();
+    sync(&pic, primary, follower);
+
+    assert_eq!(list(&pic, primary).len(), 31);
+    assert_eq!(list(&pic, follower), list(&pic, primary));
+
+    let status = status(&pic, primary);
+    assert!(status.followers.iter().all(|(_, f)| f.last_error.is_none()));
+}
@@ -0,0 +1,514 @@
+//! Index advisor based on the observed workload.
+//!
+//! `record_workload` and `execute_statement` record the shape of the statement they
+//! run, its SQL with the literals replaced by `?`, and the instructions it took. The
+//! shapes are kept in stable memory, encrypted like the other memories holding SQL
+//! text. A query call drops its changes when it ends, so
+//! the statements of `query` and `query_statement` are not recorded; `record_workload`
+//! runs a query in an update call for that purpose.
+//!
+//! Like the `.expert` command of the SQLite shell, the advisor copies the schema and
+//! the statistics into an empty in-memory database, adds candidate indexes on the
+//! columns a shape mentions and keeps the ones the planner picks. No index is built on
+//! the live tables until a suggestion is applied as a `CreateIndex` job.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use rusqlite::Connection;
+
+use crate::encryption::EncryptedMemory;
+use crate::util::quote;
+use crate::{plan, MEMORY_MANAGER};
+
+pub const WORKLOAD_MEMORY_ID: u8 = 32;
+
+type WorkloadMemory = EncryptedMemory<VirtualMemory<DefaultMemoryImpl>>;
+
+// shapes kept at most, the cheapest one is evicted first
+const MAX_SHAPES: usize = 200;
+
+// columns of a table combined into one candidate index at most
+const MAX_INDEX_COLUMNS: usize = 3;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueryShape {
+    pub sql: String,
+    pub calls: u64,
+    pub total_instructions: u64,
+    pub max_instructions: u64,
+    /// Time in nanoseconds.
+    pub last_seen: u64,
+}
+
+impl Storable for QueryShape {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IndexSuggestion {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    /// The shapes the index is picked for.
+    pub shapes: Vec<String>,
+    /// Instructions those shapes took so far.
+    pub instructions: u64,
+}
+
+thread_local! {
+    static SHAPES: RefCell<StableBTreeMap<String, QueryShape, WorkloadMemory>> =
+        RefCell::new(StableBTreeMap::init(memory()));
+}
+
+fn memory() -> WorkloadMemory {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(WORKLOAD_MEMORY_ID)));
+    EncryptedMemory::new(memory, WORKLOAD_MEMORY_ID)
+}
+
+fn is_identifier(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '$' | '?' | ':' | '@')
+}
+
+/// The shape of a statement, its literals are replaced by `?` and lists of them by a
+/// single one.
+pub fn normalize(sql: &str) -> String {
+    let mut shape = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let previous = shape.chars().last().unwrap_or(' ');
+        match c {
+            '\'' => {
+                // '' is a quote inside the string
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                shape.push('?');
+            }
+            'x' | 'X' if chars.peek() == Some(&'\'') && !is_identifier(previous) => {
+                chars.next();
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                }
+                shape.push('?');
+            }
+            '0'..='9' if !is_identifier(previous) => {
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.')
+                    .is_some()
+                {}
+                shape.push('?');
+            }
+            '"' | '`' | '[' => {
+                let end = if c == '[' { ']' } else { c };
+                shape.push(c);
+                for c in chars.by_ref() {
+                    shape.push(c);
+                    if c == end {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                if previous != ' ' {
+                    shape.push(' ');
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                for c in chars.by_ref() {
+                    if star && c == '/' {
+                        break;
+                    }
+                    star = c == '*';
+                }
+                if previous != ' ' {
+                    shape.push(' ');
+                }
+            }
+            c if c.is_whitespace() => {
+                if previous != ' ' {
+                    shape.push(' ');
+                }
+            }
+            c => shape.push(c),
+        }
+    }
+
+    let mut shape = shape.trim().trim_end_matches(';').trim_end().to_string();
+    for list in ["?, ?", "?,?"] {
+        while shape.contains(list) {
+            shape = shape.replace(list, "?");
+        }
+    }
+    shape
+}
+
+/// Adds a call of `sql` that took `instructions` to the workload.
+pub fn record(sql: &str, instructions: u64, time: u64) {
+    let sql = normalize(sql);
+
+    SHAPES.with(|s| {
+        let mut shapes = s.borrow_mut();
+        if !shapes.contains_key(&sql) && shapes.len() >= MAX_SHAPES as u64 {
+            let cheapest = shapes
+                .iter()
+                .min_by_key(|(_, shape)| shape.total_instructions)
+                .map(|(sql, _)| sql)
+                .unwrap();
+            shapes.remove(&cheapest);
+        }
+
+        let mut shape = shapes.get(&sql).unwrap_or(QueryShape {
+            sql: sql.clone(),
+            calls: 0,
+            total_instructions: 0,
+            max_instructions: 0,
+            last_seen: time,
+        });
+        shape.calls += 1;
+        shape.total_instructions = shape.total_instructions.saturating_add(instructions);
+        shape.max_instructions = shape.max_instructions.max(instructions);
+        shape.last_seen = time;
+        shapes.insert(sql, shape);
+    });
+}
+
+/// The recorded shapes, the most expensive first.
+pub fn workload() -> Vec<QueryShape> {
+    let mut shapes: Vec<_> = SHAPES.with(|s| s.borrow().iter().map(|(_, shape)| shape).collect());
+    shapes.sort_by_key(|shape| std::cmp::Reverse(shape.total_instructions));
+    shapes
+}
+
+pub fn clear() {
+    SHAPES.with(|s| s.borrow_mut().clear_new());
+}
+
+// an empty database with the schema and the statistics of `db`
+fn scratch_copy(db: &Connection) -> rusqlite::Result<Connection> {
+    let scratch = Connection::open_in_memory()?;
+
+    let mut stmt = db.prepare(
+        "SELECT sql FROM sqlite_schema
+        WHERE sql IS NOT NULL AND type IN ('table', 'index', 'view')
+            AND name NOT LIKE 'sqlite_%'
+        ORDER BY type = 'view', type = 'index'",
+    )?;
+    let schema: Vec<String> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for sql in schema {
+        scratch.execute_batch(&sql)?;
+    }
+
+    let analyzed: bool = db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_schema WHERE name = 'sqlite_stat1')",
+        [],
+        |row| row.get(0),
+    )?;
+    if analyzed {
+        // creates an empty sqlite_stat1
+        scratch.execute_batch("ANALYZE sqlite_schema")?;
+        let mut stmt = db.prepare("SELECT tbl, idx, stat FROM sqlite_stat1")?;
+        let stats: Vec<(String, Option<String>, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for stat in stats {
+            scratch.execute("INSERT INTO sqlite_stat1 VALUES (?1, ?2, ?3)", stat)?;
+        }
+        // the planner reads the statistics when the schema is loaded
+        scratch.execute_batch("ANALYZE sqlite_schema")?;
+    }
+
+    Ok(scratch)
+}
+
+// the columns of `table` named in `sql`, in the order they first appear
+fn mentioned_columns(db: &Connection, table: &str, sql: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare("SELECT name FROM pragma_table_info(?1)")?;
+    let columns: Vec<String> = stmt
+        .query_map([table], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut mentioned = Vec::new();
+    for word in sql.split(|c: char| !(c.is_alphanumeric() || c == '_')) {
+        if let Some(column) = columns.iter().find(|c| c.eq_ignore_ascii_case(word)) {
+            if !mentioned.contains(column) {
+                mentioned.push(column.clone());
+            }
+        }
+    }
+    Ok(mentioned)
+}
+
+// the leading columns of the indexes `table` has already
+fn existing_indexes(db: &Connection, table: &str) -> rusqlite::Result<Vec<Vec<String>>> {
+    let mut stmt = db.prepare(
+        "SELECT l.name, i.name FROM pragma_index_list(?1) AS l, pragma_index_info(l.name) AS i
+        ORDER BY l.name, i.seqno",
+    )?;
+    let rows: Vec<(String, Option<String>)> = stmt
+        .query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut indexes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (index, column) in rows {
+        indexes
+            .entry(index)
+            .or_default()
+            .push(column.unwrap_or_default());
+    }
+    Ok(indexes.into_values().collect())
+}
+
+fn candidates(db: &Connection, table: &str, sql: &str) -> rusqlite::Result<Vec<Vec<String>>> {
+    let columns = mentioned_columns(db, table, sql)?;
+    let existing = existing_indexes(db, table)?;
+
+    let mut candidates: Vec<Vec<String>> = columns.iter().map(|c| vec![c.clone()]).collect();
+    if columns.len() > 1 {
+        candidates.push(columns.into_iter().take(MAX_INDEX_COLUMNS).collect());
+    }
+    candidates.retain(|candidate| {
+        !existing
+            .iter()
+            .any(|index| index.starts_with(candidate.as_slice()))
+    });
+    Ok(candidates)
+}
+
+// the candidate indexes the planner picks for one shape
+fn picked_indexes(scratch: &Connection, sql: &str) -> rusqlite::Result<Vec<(String, Vec<String>)>> {
+    let Ok(before) = plan::explain(scratch, sql) else {
+        // e.g. a statement on a temporary table
+        return Ok(Vec::new());
+    };
+    if before.full_scans.is_empty() && before.temp_btrees.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut tables: Vec<String> = before.full_scans.into_iter().map(|s| s.table).collect();
+    // a sort may be avoided with an index on any table the statement reads
+    if !before.temp_btrees.is_empty() {
+        let mut stmt = scratch.prepare("SELECT name FROM sqlite_schema WHERE type = 'table'")?;
+        for table in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            let table = table?;
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+    }
+
+    let mut created = Vec::new();
+    for table in tables {
+        for columns in candidates(scratch, &table, sql)? {
+            let name = format!("advisor_{}", created.len());
+            scratch.execute_batch(&format!(
+                "CREATE INDEX {} ON {} ({})",
+                name,
+                quote(&table),
+                columns
+                    .iter()
+                    .map(|c| quote(c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))?;
+            created.push((name, table.clone(), columns));
+        }
+    }
+
+    let details: Vec<String> = {
+        let mut stmt = scratch.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+        let details = stmt.raw_query().mapped(|row| row.get(3));
+        details.collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut picked = Vec::new();
+    for (name, table, columns) in created {
+        let used = format!("INDEX {}", name);
+        if details
+            .iter()
+            .any(|d| d.ends_with(&used) || d.contains(&format!("{} ", used)))
+        {
+            picked.push((table, columns));
+        }
+        scratch.execute_batch(&format!("DROP INDEX {}", name))?;
+    }
+    Ok(picked)
+}
+
+/// Suggests indexes for the recorded workload, the most beneficial first.
+pub fn suggest(db: &Connection, shapes: &[QueryShape]) -> rusqlite::Result<Vec<IndexSuggestion>> {
+    let scratch = scratch_copy(db)?;
+
+    let mut suggestions: Vec<IndexSuggestion> = Vec::new();
+    for shape in shapes {
+        for (table, columns) in picked_indexes(&scratch, &shape.sql)? {
+            let position = suggestions
+                .iter()
+                .position(|s| s.table == table && s.columns == columns);
+            let suggestion = match position {
+                Some(position) => &mut suggestions[position],
+                None => {
+                    suggestions.push(IndexSuggestion {
+                        name: format!("{}_{}_idx", table, columns.join("_")),
+                        table,
+                        columns,
+                        shapes: Vec::new(),
+                        instructions: 0,
+                    });
+                    suggestions.last_mut().unwrap()
+                }
+            };
+            suggestion.shapes.push(shape.sql.clone());
+            suggestion.instructions = suggestion
+                .instructions
+                .saturating_add(shape.total_instructions);
+        }
+    }
+
+    suggestions.sort_by_key(|s| std::cmp::Reverse(s.instructions));
+    Ok(suggestions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_literals_and_whitespace() {
+        let cases = [
+            (
+                "SELECT * FROM person  WHERE name = 'O''Brien' AND age > 42;",
+                "SELECT * FROM person WHERE name = ? AND age > ?",
+            ),
+            (
+                "select id from person where id in (1, 2,3) -- comment\n and data = x'00ff'",
+                "select id from person where id in (?) and data = ?",
+            ),
+            (
+                "SELECT \"col 1\", t2.c3 FROM t2 /* note */ WHERE c3 = ?1 AND c4 < 1.5e3",
+                "SELECT \"col 1\", t2.c3 FROM t2 WHERE c3 = ?1 AND c4 < ?",
+            ),
+        ];
+        for (sql, shape) in cases {
+            assert_eq!(normalize(sql), shape);
+        }
+    }
+
+    #[test]
+    fn records_and_evicts_shapes() {
+        clear();
+        record("SELECT * FROM person WHERE id = 1", 100, 1);
+        record("SELECT * FROM person WHERE id = 2", 300, 2);
+        record("SELECT count(*) FROM person", 50, 3);
+
+        let shapes = workload();
+        assert_eq!(shapes.len(), 2);
+        assert_eq!(
+            shapes[0],
+            QueryShape {
+                sql: "SELECT * FROM person WHERE id = ?".to_string(),
+                calls: 2,
+                total_instructions: 400,
+                max_instructions: 300,
+                last_seen: 2,
+            }
+        );
+
+        for i in 0..MAX_SHAPES {
+            record(&format!("SELECT c{} FROM person", i), 1000, 4);
+        }
+        let shapes = workload();
+        assert_eq!(shapes.len(), MAX_SHAPES);
+        assert!(!shapes
+            .iter()
+            .any(|s| s.sql == "SELECT count(*) FROM person"));
+        clear();
+    }
+
+    #[test]
+    fn suggests_indexes_the_planner_picks() {
+        let db = crate::util::database();
+        db.execute_batch(
+            "CREATE INDEX person_name ON person (name);
+            CREATE TABLE pet (id INTEGER PRIMARY KEY, owner INTEGER, kind TEXT);",
+        )
+        .unwrap();
+
+        let shape = |sql: &str, total_instructions| QueryShape {
+            sql: normalize(sql),
+            calls: 1,
+            total_instructions,
+            max_instructions: total_instructions,
+            last_seen: 0,
+        };
+        let shapes = [
+            shape("SELECT * FROM person WHERE age = 30", 1000),
+            shape("SELECT name FROM person WHERE age > 30 ORDER BY age", 500),
+            shape("SELECT * FROM pet AS p WHERE p.owner = 7", 2000),
+            // served by an existing index already
+            shape("SELECT * FROM person WHERE name = 'amy'", 9000),
+            shape("SELECT * FROM person WHERE id = 3", 9000),
+        ];
+
+        let suggestions = suggest(&db, &shapes).unwrap();
+        assert_eq!(
+            suggestions,
+            [
+                IndexSuggestion {
+                    name: "pet_owner_idx".to_string(),
+                    table: "pet".to_string(),
+                    columns: vec!["owner".to_string()],
+                    shapes: vec!["SELECT * FROM pet AS p WHERE p.owner = ?".to_string()],
+                    instructions: 2000,
+                },
+                IndexSuggestion {
+                    name: "person_age_idx".to_string(),
+                    table: "person".to_string(),
+                    columns: vec!["age".to_string()],
+                    shapes: vec![
+                        "SELECT * FROM person WHERE age = ?".to_string(),
+                        "SELECT name FROM person WHERE age > ? ORDER BY age".to_string(),
+                    ],
+                    instructions: 1500,
+                },
+            ]
+        );
+
+        // the live database is not changed
+        let indexes: i64 = db
+            .query_row(
+                "SELECT count(*) FROM sqlite_schema WHERE type = 'index'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 1);
+    }
+}
@@ -0,0 +1,189 @@
+//! Instruction budgets for statements run by the query endpoints.
+//!
+//! A progress handler checks the instruction counter of the message every
+//! `CHECK_INTERVAL` virtual machine steps and interrupts the running statement once the
+//! budget of the endpoint is used up. The call then returns `BudgetExceeded` instead of
+//! trapping at the instruction limit of the message.
+
+use std::cell::RefCell;
+use std::ffi::c_int;
+
+use candid::{CandidType, Deserialize};
+use rusqlite::Connection;
+
+/// Budget of the endpoints without a configured one, below the 5B instruction limit
+/// of a query.
+pub const DEFAULT_BUDGET: u64 = 4_000_000_000;
+
+// virtual machine steps between two checks of the instruction counter
+const CHECK_INTERVAL: c_int = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallerRole {
+    /// A controller of the canister.
+    Admin,
+    User,
+}
+
+/// Instructions a call may use, for one endpoint and/or role or for all of them.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Budget {
+    pub endpoint: Option<String>,
+    pub role: Option<CallerRole>,
+    pub instructions: u64,
+}
+
+/// A budget that ran out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Exceeded {
+    pub budget: u64,
+    pub used: u64,
+}
+
+struct Guard {
+    budget: u64,
+    counter: fn() -> u64,
+    exceeded: bool,
+}
+
+thread_local! {
+    static GUARD: RefCell<Option<Guard>> = const { RefCell::new(None) };
+}
+
+pub fn caller_role() -> CallerRole {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        CallerRole::Admin
+    } else {
+        CallerRole::User
+    }
+}
+
+/// Installs the progress handler on a connection to the live database.
+pub fn install_handler(db: &Connection) {
+    db.progress_handler(
+        CHECK_INTERVAL,
+        Some(|| {
+            GUARD.with(|guard| match guard.borrow_mut().as_mut() {
+                Some(guard) if (guard.counter)() > guard.budget => {
+                    guard.exceeded = true;
+                    // interrupts the statement with SQLITE_INTERRUPT
+                    true
+                }
+                _ => false,
+            })
+        }),
+    );
+}
+
+/// The most specific budget for a call, an endpoint match takes precedence over a role
+/// match and the later of two equally specific budgets wins.
+pub fn budget_for(budgets: &[Budget], endpoint: &str, role: CallerRole) -> u64 {
+    budgets
+        .iter()
+        .filter(|b| b.endpoint.as_deref().is_none_or(|e| e == endpoint))
+        .filter(|b| b.role.is_none_or(|r| r == role))
+        .max_by_key(|b| (b.endpoint.is_some(), b.role.is_some()))
+        .map_or(DEFAULT_BUDGET, |b| b.instructions)
+}
+
+/// Runs the statements of `f` within `budget` instructions of the message, as counted
+/// by `counter`.
+pub fn run<T>(budget: u64, counter: fn() -> u64, f: impl FnOnce() -> T) -> (T, Option<Exceeded>) {
+    GUARD.with(|g| {
+        *g.borrow_mut() = Some(Guard {
+            budget,
+            counter,
+            exceeded: false,
+        })
+    });
+
+    let result = f();
+
+    let guard = GUARD.with(|g| g.borrow_mut().take()).unwrap();
+    let exceeded = guard.exceeded.then(|| Exceeded {
+        budget,
+        used: counter(),
+    });
+
+    (result, exceeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    thread_local! {
+        static CALLS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    // pretends every check costs 1000 instructions
+    fn counter() -> u64 {
+        CALLS.with(|c| {
+            c.set(c.get() + 1);
+            c.get() * 1000
+        })
+    }
+
+    fn count_rows(db: &Connection, rows: &mut u64) -> rusqlite::Result<()> {
+        let mut stmt = db.prepare(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100000)
+            SELECT i FROM n",
+        )?;
+        let mut result = stmt.query([])?;
+        while result.next()?.is_some() {
+            *rows += 1;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn interrupts_statements_over_the_budget() {
+        let db = Connection::open_in_memory().unwrap();
+        install_handler(&db);
+
+        let mut rows = 0;
+        let (result, exceeded) = run(50_000, counter, || count_rows(&db, &mut rows));
+        let exceeded = exceeded.unwrap();
+        assert_eq!(exceeded.budget, 50_000);
+        assert!(exceeded.used > 50_000);
+        assert!(matches!(
+            result,
+            Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == rusqlite::ErrorCode::OperationInterrupted
+        ));
+        assert!(rows > 0 && rows < 100_000);
+
+        // the handler does nothing outside of `run`
+        let mut rows = 0;
+        count_rows(&db, &mut rows).unwrap();
+        assert_eq!(rows, 100_000);
+
+        CALLS.with(|c| c.set(0));
+        let mut rows = 0;
+        let (result, exceeded) = run(u64::MAX, counter, || count_rows(&db, &mut rows));
+        assert!(result.is_ok() && exceeded.is_none());
+        assert_eq!(rows, 100_000);
+    }
+
+    #[test]
+    fn picks_the_most_specific_budget() {
+        let budget = |endpoint: Option<&str>, role, instructions| Budget {
+            endpoint: endpoint.map(str::to_string),
+            role,
+            instructions,
+        };
+        let budgets = [
+            budget(None, None, 1),
+            budget(None, Some(CallerRole::Admin), 2),
+            budget(Some("query"), None, 3),
+            budget(Some("query"), Some(CallerRole::User), 4),
+        ];
+
+        assert_eq!(budget_for(&[], "query", CallerRole::User), DEFAULT_BUDGET);
+        assert_eq!(budget_for(&budgets, "list_people", CallerRole::User), 1);
+        assert_eq!(budget_for(&budgets, "list_people", CallerRole::Admin), 2);
+        assert_eq!(budget_for(&budgets, "query", CallerRole::Admin), 3);
+        assert_eq!(budget_for(&budgets, "query", CallerRole::User), 4);
+    }
+}
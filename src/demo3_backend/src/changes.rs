@@ -0,0 +1,344 @@
+//! Change data capture feed of row-level changes.
+//!
+//! The SQLite update hook collects the rows a transaction inserts, updates or deletes,
+//! the commit hook appends them to a change log in stable memory under the next commit
+//! sequence number and the rollback hook discards them. Only the most recent changes
+//! are retained; a consumer that asks for changes older than the retained window is
+//! told about the gap and has to reload the tables it follows. A database rebuilt from
+//! the replay log replaces the tables without changes, `reset` then drops them all.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use rusqlite::hooks::Action;
+use rusqlite::Connection;
+
+use crate::{config, Error, MEMORY_MANAGER};
+
+pub const CHANGE_LOG_MEMORY_ID: u8 = 25;
+
+pub const DEFAULT_RETENTION: u64 = 10_000;
+
+// changes returned by one `changes_since` call at most
+const MAX_PAGE_SIZE: u64 = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// Commit sequence number, shared by all changes of one transaction.
+    pub commit: u64,
+    pub table: String,
+    pub rowid: i64,
+    pub operation: Operation,
+}
+
+impl Storable for Change {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ChangesPage {
+    /// Changes ordered by their sequence number.
+    pub changes: Vec<(u64, Change)>,
+    /// Sequence number to ask for in the next call.
+    pub next_seq: u64,
+    /// Oldest change still retained, `next_seq` if the log is empty.
+    pub first_retained_seq: u64,
+    /// Changes between the requested sequence number and `first_retained_seq` are lost.
+    pub gap: bool,
+}
+
+thread_local! {
+    static LOG: RefCell<StableBTreeMap<u64, Change, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(CHANGE_LOG_MEMORY_ID))),
+        ));
+
+    // changes of the open transaction, without a commit sequence number yet; a statement
+    // that fails inside an explicit transaction leaves its rows here, the endpoints only
+    // run single statements in autocommit mode
+    static PENDING: RefCell<Vec<(String, i64, Operation)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn retention() -> u64 {
+    config::get()
+        .change_log_retention
+        .unwrap_or(DEFAULT_RETENTION)
+}
+
+/// Installs the hooks on a connection to the live database. The rows of the shadow
+/// tables of the jobs and of the tables of SQLite are not changes of the feed.
+pub fn install_hooks(db: &Connection) {
+    db.update_hook(Some(
+        |action: Action, _db: &str, table: &str, rowid: i64| {
+            if crate::jobs::is_internal_table(table) {
+                return;
+            }
+            let operation = match action {
+                Action::SQLITE_INSERT => Operation::Insert,
+                Action::SQLITE_UPDATE => Operation::Update,
+                Action::SQLITE_DELETE => Operation::Delete,
+                _ => return,
+            };
+            PENDING.with(|p| p.borrow_mut().push((table.to_string(), rowid, operation)));
+        },
+    ));
+
+    db.commit_hook(Some(|| {
+        append_pending();
+        // returning true would turn the commit into a rollback
+        false
+    }));
+
+    db.rollback_hook(Some(|| PENDING.with(|p| p.borrow_mut().clear())));
+}
+
+fn append_pending() {
+    let pending = PENDING.with(|p| std::mem::take(&mut *p.borrow_mut()));
+    if pending.is_empty() {
+        return;
+    }
+
+    crate::maintenance::mark_changed(pending.iter().map(|(table, _, _)| table.as_str()));
+
+    let retention = retention();
+
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+
+        let (mut seq, commit) = next(&log);
+
+        for (table, rowid, operation) in pending {
+            log.insert(
+                seq,
+                Change {
+                    commit,
+                    table,
+                    rowid,
+                    operation,
+                },
+            );
+            seq += 1;
+        }
+
+        trim(&mut log, retention);
+    });
+}
+
+// the sequence and commit numbers continue after the last retained change
+fn next(log: &StableBTreeMap<u64, Change, VirtualMemory<DefaultMemoryImpl>>) -> (u64, u64) {
+    match log.last_key_value() {
+        Some((seq, change)) => (seq + 1, change.commit + 1),
+        None => config::get().change_log_next.unwrap_or((0, 0)),
+    }
+}
+
+fn trim(log: &mut StableBTreeMap<u64, Change, VirtualMemory<DefaultMemoryImpl>>, retention: u64) {
+    while log.len() > retention.max(1) {
+        let (seq, _) = log.first_key_value().unwrap();
+        log.remove(&seq);
+    }
+}
+
+/// Returns up to `limit` changes starting at `seq`.
+pub fn changes_since(seq: u64, limit: u64) -> ChangesPage {
+    LOG.with(|log| {
+        let log = log.borrow();
+
+        let (next, _) = next(&log);
+        let first_retained_seq = log.first_key_value().map_or(next, |(seq, _)| seq);
+
+        let changes: Vec<(u64, Change)> = log
+            .range(seq..)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .collect();
+
+        ChangesPage {
+            next_seq: changes
+                .last()
+                .map_or(seq.max(first_retained_seq), |(seq, _)| seq + 1),
+            first_retained_seq,
+            gap: seq < first_retained_seq,
+            changes,
+        }
+    })
+}
+
+/// Sets how many changes are retained and drops the older ones at once.
+pub fn set_retention(retention: u64) -> Result<(), Error> {
+    if retention == 0 {
+        return Err(Error::InvalidArgument {
+            message: "at least one change must be retained".to_string(),
+        });
+    }
+
+    config::update(|c| c.change_log_retention = Some(retention));
+    LOG.with(|log| trim(&mut log.borrow_mut(), retention));
+
+    Ok(())
+}
+
+/// Drops every change and skips a sequence number, so that every consumer sees a gap.
+pub fn reset() {
+    let (seq, commit) = LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let next = next(&log);
+        while let Some((seq, _)) = log.first_key_value() {
+            log.remove(&seq);
+        }
+        next
+    });
+
+    config::update(|c| c.change_log_next = Some((seq + 1, commit + 1)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let db = crate::util::database();
+        install_hooks(&db);
+        db
+    }
+
+    fn insert(db: &Connection, name: &str) {
+        db.execute(
+            "INSERT INTO person (name, data, age) VALUES (?1, '', 42)",
+            [name],
+        )
+        .unwrap();
+    }
+
+    fn summary(page: &ChangesPage) -> Vec<(u64, u64, i64, Operation)> {
+        page.changes
+            .iter()
+            .map(|(seq, c)| (*seq, c.commit, c.rowid, c.operation))
+            .collect()
+    }
+
+    #[test]
+    fn records_committed_changes_per_transaction() {
+        let db = database();
+        insert(&db, "a");
+        db.execute_batch(
+            "BEGIN;
+            INSERT INTO person (name) VALUES ('b');
+            UPDATE person SET age = 1 WHERE id = 1;
+            COMMIT;",
+        )
+        .unwrap();
+        db.execute("DELETE FROM person WHERE id = 2", []).unwrap();
+
+        let page = changes_since(0, 10);
+        assert_eq!(
+            summary(&page),
+            [
+                (0, 0, 1, Operation::Insert),
+                (1, 1, 2, Operation::Insert),
+                (2, 1, 1, Operation::Update),
+                (3, 2, 2, Operation::Delete),
+            ]
+        );
+        assert!(page.changes.iter().all(|(_, c)| c.table == "person"));
+        assert_eq!(page.next_seq, 4);
+        assert!(!page.gap);
+
+        let page = changes_since(4, 10);
+        assert!(page.changes.is_empty());
+        assert_eq!(page.next_seq, 4);
+    }
+
+    #[test]
+    fn internal_tables_are_not_recorded() {
+        let db = database();
+        db.execute_batch(
+            "CREATE TABLE _job1_new_person AS SELECT * FROM person;
+            INSERT INTO _job1_new_person (name) VALUES ('a');
+            CREATE INDEX person_age ON person (age);
+            ANALYZE;",
+        )
+        .unwrap();
+        insert(&db, "b");
+
+        let page = changes_since(0, 10);
+        let tables: Vec<&str> = page.changes.iter().map(|(_, c)| c.table.as_str()).collect();
+        assert_eq!(tables, ["person"]);
+    }
+
+    #[test]
+    fn rolled_back_changes_are_not_recorded() {
+        let db = database();
+        db.execute_batch("BEGIN; INSERT INTO person (name) VALUES ('a'); ROLLBACK;")
+            .unwrap();
+        // fails on the NOT NULL constraint after the first row was inserted
+        assert!(db
+            .execute_batch("INSERT INTO person (name) VALUES ('b'), (NULL);")
+            .is_err());
+        insert(&db, "c");
+
+        assert_eq!(
+            summary(&changes_since(0, 10)),
+            [(0, 0, 1, Operation::Insert)]
+        );
+    }
+
+    #[test]
+    fn reports_a_gap_behind_the_retained_window() {
+        let db = database();
+        set_retention(3).unwrap();
+        for name in ["a", "b", "c", "d", "e"] {
+            insert(&db, name);
+        }
+
+        let page = changes_since(1, 2);
+        assert!(page.gap);
+        assert_eq!(page.first_retained_seq, 2);
+        assert_eq!(
+            summary(&page),
+            [(2, 2, 3, Operation::Insert), (3, 3, 4, Operation::Insert)]
+        );
+        assert_eq!(page.next_seq, 4);
+
+        assert!(!changes_since(page.next_seq, 10).gap);
+
+        set_retention(1).unwrap();
+        assert_eq!(changes_since(0, 10).first_retained_seq, 4);
+        assert!(set_retention(0).is_err());
+    }
+
+    #[test]
+    fn a_reset_is_a_gap_for_every_consumer() {
+        let db = database();
+        insert(&db, "a");
+        insert(&db, "b");
+
+        reset();
+        let page = changes_since(2, 10);
+        assert!(page.gap && page.changes.is_empty());
+        assert_eq!((page.first_retained_seq, page.next_seq), (3, 3));
+
+        insert(&db, "c");
+        let page = changes_since(3, 10);
+        assert!(!page.gap);
+        assert_eq!(summary(&page), [(3, 3, 3, Operation::Insert)]);
+    }
+}
@@ -0,0 +1,625 @@
+//! Session extension changesets for syncing with an off-chain replica.
+//!
+//! While the capture is enabled, every write endpoint runs with a session attached
+//! to all tables and stores the changeset of its changes under the next checkpoint
+//! number. `changeset_since` combines the stored changesets from a checkpoint on into
+//! one, which a replica applies with `sqlite3changeset_apply`. The patchset of the
+//! same session is stored alongside and combined instead when asked for. Only the last `retention` changesets are kept, a replica
+//! further behind has to resync.
+//! Local edits travel the other way through `apply`, with the conflicts resolved by a
+//! policy and reported one by one. The stored changesets hold row values, so their
+//! memory is encrypted like the database files.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use rusqlite::{ffi, Connection};
+
+use crate::changes::Operation;
+use crate::config;
+use crate::encryption::EncryptedMemory;
+use crate::replay::SqlValue;
+use crate::session::{self, Session};
+use crate::util::to_error;
+use crate::{Error, MEMORY_MANAGER};
+
+pub const CHANGESETS_MEMORY_ID: u8 = 26;
+
+/// Changesets kept if no retention is set, a follower further behind than
+/// `replication::DEFAULT_MAX_LAG` is resynced from a snapshot anyway.
+pub const DEFAULT_RETENTION: u64 = 10_000;
+
+// stored changesets combined by one `changeset_since` call at most, in bytes
+const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+type ChangesetMemory = EncryptedMemory<VirtualMemory<DefaultMemoryImpl>>;
+
+/// Changes of one write call.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Captured {
+    changeset: Vec<u8>,
+    patchset: Vec<u8>,
+}
+
+impl Storable for Captured {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Changeset capture settings kept in the config.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ChangesetConfig {
+    pub enabled: bool,
+    /// Oldest checkpoint a changeset can still be produced from while none is kept,
+    /// see `first_checkpoint`.
+    pub first_checkpoint: u64,
+    /// Changesets kept at most, `DEFAULT_RETENTION` if not set.
+    pub retention: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ChangesetPage {
+    /// Changeset or patchset of the writes from the requested checkpoint on.
+    pub changeset: Vec<u8>,
+    /// Checkpoint to ask for in the next call.
+    pub next_checkpoint: u64,
+    /// False if more changes are left after `next_checkpoint`.
+    pub complete: bool,
+}
+
+/// How `apply` resolves a conflict between an uploaded change and the database.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Rolls back the whole changeset at the first conflict.
+    Abort,
+    /// Overwrites the row, conflicts that cannot be resolved that way are omitted.
+    Replace,
+    /// Skips the conflicting change.
+    Omit,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// The row exists but its values differ from the expected old values.
+    Data,
+    /// The row to update or delete does not exist.
+    NotFound,
+    /// A row with the primary key to insert already exists.
+    Conflict,
+    /// The change violates a constraint.
+    Constraint,
+    ForeignKey,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Aborted,
+    Replaced,
+    Omitted,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub table: String,
+    pub operation: Operation,
+    pub kind: ConflictKind,
+    /// Primary key values of the conflicting row.
+    pub primary_key: Vec<SqlValue>,
+    pub resolution: Resolution,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ApplyReport {
+    /// False if the changeset was rolled back by the `Abort` policy.
+    pub applied: bool,
+    pub conflicts: Vec<Conflict>,
+    /// Checkpoint the applied changes were captured under, if the capture is enabled.
+    pub checkpoint: Option<u64>,
+}
+
+fn memory() -> ChangesetMemory {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(CHANGESETS_MEMORY_ID)));
+    EncryptedMemory::new(memory, CHANGESETS_MEMORY_ID)
+}
+
+thread_local! {
+    static CHANGESETS: RefCell<StableBTreeMap<u64, Captured, ChangesetMemory>> =
+        RefCell::new(StableBTreeMap::init(memory()));
+}
+
+/// Checkpoint the next captured write will be stored under.
+pub fn next_checkpoint() -> u64 {
+    CHANGESETS.with(|c| {
+        c.borrow()
+            .last_key_value()
+            .map(|(checkpoint, _)| checkpoint + 1)
+            .unwrap_or_else(|| config::get().changesets.first_checkpoint)
+    })
+}
+
+/// Oldest checkpoint a changeset can still be produced from.
+pub fn first_checkpoint() -> u64 {
+    CHANGESETS.with(|c| {
+        c.borrow()
+            .first_key_value()
+            .map(|(checkpoint, _)| checkpoint)
+            .unwrap_or_else(|| config::get().changesets.first_checkpoint)
+    })
+}
+
+// drops the oldest changesets beyond the retention, the checkpoints stay contiguous
+fn trim(changesets: &mut StableBTreeMap<u64, Captured, ChangesetMemory>, retention: u64) {
+    while changesets.len() > retention.max(1) {
+        let Some((checkpoint, _)) = changesets.first_key_value() else {
+            break;
+        };
+        changesets.remove(&checkpoint);
+    }
+}
+
+/// Runs a write with a session attached and stores its changes if the capture is enabled.
+///
+/// Returns the result of the write and the checkpoint its changes were stored under.
+pub fn capture<T>(
+    db: &Connection,
+    write: impl FnOnce() -> Result<T, Error>,
+) -> Result<(T, Option<u64>), Error> {
+    if !config::get().changesets.enabled {
+        return write().map(|result| (result, None));
+    }
+
+    let session = Session::new(db).map_err(to_error)?;
+
+    let result = write()?;
+
+    if session.is_empty() {
+        return Ok((result, None));
+    }
+
+    let captured = Captured {
+        changeset: session.changeset().map_err(to_error)?,
+        patchset: session.patchset().map_err(to_error)?,
+    };
+    drop(session);
+
+    let checkpoint = next_checkpoint();
+    let retention = config::get()
+        .changesets
+        .retention
+        .unwrap_or(DEFAULT_RETENTION);
+    CHANGESETS.with(|c| {
+        let mut changesets = c.borrow_mut();
+        changesets.insert(checkpoint, captured);
+        trim(&mut changesets, retention);
+    });
+
+    Ok((result, Some(checkpoint)))
+}
+
+pub fn set_enabled(enabled: bool) {
+    if !enabled {
+        // changes made while disabled are not captured, older checkpoints would miss them
+        truncate(next_checkpoint());
+    }
+    config::update(|c| c.changesets.enabled = enabled);
+}
+
+/// Drops the changes before `up_to`, which no replica needs anymore.
+pub fn truncate(up_to: u64) {
+    let up_to = up_to.min(next_checkpoint());
+
+    CHANGESETS.with(|c| {
+        let mut changesets = c.borrow_mut();
+        while let Some((checkpoint, _)) = changesets.first_key_value() {
+            if checkpoint >= up_to {
+                break;
+            }
+            changesets.remove(&checkpoint);
+        }
+    });
+
+    config::update(|c| c.changesets.first_checkpoint = c.changesets.first_checkpoint.max(up_to));
+}
+
+/// Drops every changeset and skips a checkpoint, so that every replica has to resync.
+///
+/// Called once a database rebuilt from the replay log replaced the live one, which
+/// changes the tables without a changeset.
+pub fn reset() {
+    let next = next_checkpoint();
+    truncate(next);
+    config::update(|c| c.changesets.first_checkpoint = next + 1);
+}
+
+pub fn set_retention(retention: u64) -> Result<(), Error> {
+    if retention == 0 {
+        return Err(Error::InvalidArgument {
+            message: "at least one changeset must be retained".to_string(),
+        });
+    }
+
+    config::update(|c| c.changesets.retention = Some(retention));
+    CHANGESETS.with(|c| trim(&mut c.borrow_mut(), retention));
+
+    Ok(())
+}
+
+/// Combines the changes from `checkpoint` on into one changeset, or patchset.
+pub fn changeset_since(checkpoint: u64, patchset: bool) -> Result<ChangesetPage, Error> {
+    let first_checkpoint = first_checkpoint();
+    if checkpoint < first_checkpoint {
+        return Err(Error::InvalidArgument {
+            message: format!(
+                "the changes before checkpoint {} are no longer kept, resync the replica",
+                first_checkpoint
+            ),
+        });
+    }
+
+    let mut next_checkpoint = checkpoint;
+    let mut size = 0;
+    let mut complete = true;
+
+    let changesets: Vec<Vec<u8>> = CHANGESETS.with(|c| {
+        let mut changesets = Vec::new();
+        for (key, captured) in c.borrow().range(checkpoint..) {
+            let bytes = if patchset {
+                captured.patchset
+            } else {
+                captured.changeset
+            };
+
+            // always make progress, even with one oversized changeset
+            if size > 0 && size + bytes.len() > MAX_PAGE_BYTES {
+                complete = false;
+                break;
+            }
+            size += bytes.len();
+
+            changesets.push(bytes);
+            next_checkpoint = key + 1;
+        }
+        changesets
+    });
+
+    let changeset = session::combine(changesets.iter().map(Vec::as_slice)).map_err(to_error)?;
+
+    Ok(ChangesetPage {
+        changeset,
+        next_checkpoint,
+        complete,
+    })
+}
+
+/// Applies an uploaded changeset or patchset in one transaction.
+pub fn apply(
+    db: &Connection,
+    changeset: &[u8],
+    policy: ConflictPolicy,
+) -> Result<ApplyReport, Error> {
+    let ((applied, conflicts), checkpoint) =
+        capture(db, || apply_uncaptured(db, changeset, policy))?;
+
+    Ok(ApplyReport {
+        applied,
+        conflicts,
+        checkpoint,
+    })
+}
+
+/// Applies a changeset without capturing its changes, returns whether it was applied.
+pub fn apply_uncaptured(
+    db: &Connection,
+    changeset: &[u8],
+    policy: ConflictPolicy,
+) -> Result<(bool, Vec<Conflict>), Error> {
+    let mut conflicts = Vec::new();
+
+    let on_conflict = |conflict, change: &session::ConflictingChange| {
+        let kind = match conflict {
+            ffi::SQLITE_CHANGESET_DATA => ConflictKind::Data,
+            ffi::SQLITE_CHANGESET_NOTFOUND => ConflictKind::NotFound,
+            ffi::SQLITE_CHANGESET_CONFLICT => ConflictKind::Conflict,
+            ffi::SQLITE_CHANGESET_FOREIGN_KEY => ConflictKind::ForeignKey,
+            _ => ConflictKind::Constraint,
+        };
+
+        // only rows that exist with other values can be replaced
+        let replaceable = matches!(kind, ConflictKind::Data | ConflictKind::Conflict);
+        let (action, resolution) = match policy {
+            ConflictPolicy::Abort => (ffi::SQLITE_CHANGESET_ABORT, Resolution::Aborted),
+            ConflictPolicy::Replace if replaceable => {
+                (ffi::SQLITE_CHANGESET_REPLACE, Resolution::Replaced)
+            }
+            _ => (ffi::SQLITE_CHANGESET_OMIT, Resolution::Omitted),
+        };
+
+        // a foreign key conflict is reported once for the whole changeset, without a row
+        let (table, operation, primary_key) = if kind == ConflictKind::ForeignKey {
+            (String::new(), Operation::Update, Vec::new())
+        } else {
+            let (table, op) = change.operation();
+            let operation = match op {
+                ffi::SQLITE_INSERT => Operation::Insert,
+                ffi::SQLITE_DELETE => Operation::Delete,
+                _ => Operation::Update,
+            };
+            (table, operation, change.primary_key())
+        };
+
+        conflicts.push(Conflict {
+            table,
+            operation,
+            kind,
+            primary_key,
+            resolution,
+        });
+
+        action
+    };
+
+    let applied = match session::apply(db, changeset, on_conflict) {
+        Ok(()) => true,
+        Err(rusqlite::Error::SqliteFailure(err, _))
+            if err.code == rusqlite::ErrorCode::OperationAborted =>
+        {
+            false
+        }
+        Err(err) => return Err(crate::quota::map_write_error(err)),
+    };
+
+    Ok((applied, conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::database;
+
+    fn write(db: &Connection, sql: &str) -> Option<u64> {
+        let (_, checkpoint) = capture(db, || {
+            db.execute_batch(sql).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        checkpoint
+    }
+
+    fn rows(db: &Connection) -> Vec<(i64, String, i64)> {
+        let mut stmt = db
+            .prepare("SELECT id, name, age FROM person ORDER BY id")
+            .unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replica_catches_up_from_a_checkpoint() {
+        let db = database();
+        assert_eq!(
+            write(&db, "INSERT INTO person (name, age) VALUES ('a', 1)"),
+            None
+        );
+
+        set_enabled(true);
+        let checkpoint = write(&db, "INSERT INTO person (name, age) VALUES ('b', 2)").unwrap();
+        write(
+            &db,
+            "UPDATE person SET age = 10 WHERE id = 1; DELETE FROM person WHERE id = 2;",
+        );
+        write(&db, "INSERT INTO person (name, age) VALUES ('c', 3)");
+
+        // applying them below captures more changes
+        let pages = [false, true].map(|patchset| changeset_since(checkpoint, patchset).unwrap());
+
+        for page in pages {
+            assert!(page.complete);
+            assert_eq!(page.next_checkpoint, checkpoint + 3);
+
+            // the replica was copied before the capture started
+            let replica = database();
+            replica
+                .execute_batch("INSERT INTO person (name, age) VALUES ('a', 1)")
+                .unwrap();
+
+            let report = apply(&replica, &page.changeset, ConflictPolicy::Abort).unwrap();
+            assert!(report.applied);
+            assert!(report.conflicts.is_empty());
+            assert_eq!(rows(&replica), rows(&db));
+        }
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_the_policy() {
+        let local = database();
+        local
+            .execute_batch(
+                "INSERT INTO person (name, age) VALUES ('a', 1), ('b', 2);
+                UPDATE person SET age = 5 WHERE id = 1;",
+            )
+            .unwrap();
+
+        // local edits: update row 1, insert row 3, delete row 2
+        let changeset = {
+            let session = Session::new(&local).unwrap();
+            local
+                .execute_batch(
+                    "UPDATE person SET age = 50 WHERE id = 1;
+                    INSERT INTO person (id, name, age) VALUES (3, 'local', 3);
+                    DELETE FROM person WHERE id = 2;",
+                )
+                .unwrap();
+            session.changeset().unwrap()
+        };
+
+        let canister = || {
+            let db = database();
+            // row 1 changed meanwhile, row 3 was taken, row 2 is already gone
+            db.execute_batch(
+                "INSERT INTO person (name, age) VALUES ('a', 7), ('b', 2);
+                INSERT INTO person (id, name, age) VALUES (3, 'canister', 3);
+                DELETE FROM person WHERE id = 2;",
+            )
+            .unwrap();
+            db
+        };
+
+        let db = canister();
+        let report = apply(&db, &changeset, ConflictPolicy::Abort).unwrap();
+        assert!(!report.applied);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].resolution, Resolution::Aborted);
+        assert_eq!(rows(&db), rows(&canister()));
+
+        let db = canister();
+        let report = apply(&db, &changeset, ConflictPolicy::Omit).unwrap();
+        assert!(report.applied);
+        let mut kinds: Vec<_> = report
+            .conflicts
+            .iter()
+            .map(|c| (c.kind, c.operation, c.primary_key.clone()))
+            .collect();
+        kinds.sort_by_key(|(_, _, pk)| format!("{:?}", pk));
+        assert_eq!(
+            kinds,
+            [
+                (
+                    ConflictKind::Data,
+                    Operation::Update,
+                    vec![SqlValue::Integer(1)]
+                ),
+                (
+                    ConflictKind::NotFound,
+                    Operation::Delete,
+                    vec![SqlValue::Integer(2)]
+                ),
+                (
+                    ConflictKind::Conflict,
+                    Operation::Insert,
+                    vec![SqlValue::Integer(3)]
+                ),
+            ]
+        );
+        assert_eq!(rows(&db), rows(&canister()));
+
+        let db = canister();
+        let report = apply(&db, &changeset, ConflictPolicy::Replace).unwrap();
+        assert!(report.applied);
+        assert_eq!(
+            report
+                .conflicts
+                .iter()
+                .filter(|c| c.resolution == Resolution::Replaced)
+                .count(),
+            2
+        );
+        assert_eq!(
+            rows(&db),
+            [(1, "a".to_string(), 50), (3, "local".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn patchsets_are_captured_with_changesets() {
+        let setup =
+            "CREATE TABLE pairs (a TEXT, b INTEGER, value BLOB, score REAL, PRIMARY KEY (b, a));
+            INSERT INTO person (name, data, age) VALUES ('a', 'x', 1), ('b', NULL, 2);
+            INSERT INTO pairs VALUES ('k', 1, x'00ff', 0.5), ('l', 2, NULL, 1.5);";
+        let changes = format!(
+            "UPDATE person SET age = 10, data = NULL WHERE id = 1;
+            DELETE FROM person WHERE id = 2;
+            INSERT INTO person (name, data, age) VALUES ('{}', NULL, 300);
+            UPDATE pairs SET score = -2.25 WHERE a = 'k';
+            DELETE FROM pairs WHERE a = 'l';
+            INSERT INTO pairs VALUES ('m', 3, x'0102', NULL);",
+            "c".repeat(200)
+        );
+
+        let db = database();
+        db.execute_batch(setup).unwrap();
+        set_enabled(true);
+        let checkpoint = write(&db, &changes).unwrap();
+
+        // the same writes on a copy, with the session output checked directly
+        let copy = database();
+        copy.execute_batch(setup).unwrap();
+        let session = Session::new(&copy).unwrap();
+        copy.execute_batch(&changes).unwrap();
+        let expected = [session.changeset().unwrap(), session.patchset().unwrap()]
+            .map(|bytes| session::combine([bytes.as_slice()]).unwrap());
+        drop(session);
+
+        let pages = [false, true].map(|patchset| changeset_since(checkpoint, patchset).unwrap());
+        assert_eq!(
+            pages.each_ref().map(|page| page.changeset.clone()),
+            expected
+        );
+        assert!(expected[1].len() < expected[0].len());
+
+        let replica = database();
+        replica.execute_batch(setup).unwrap();
+        let report = apply(&replica, &pages[1].changeset, ConflictPolicy::Abort).unwrap();
+        assert!(report.applied);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(rows(&replica), rows(&db));
+    }
+
+    #[test]
+    fn only_the_retained_changesets_are_kept() {
+        let db = database();
+        set_enabled(true);
+        set_retention(2).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            write(
+                &db,
+                &format!("INSERT INTO person (name, age) VALUES ('{}', 1)", name),
+            );
+        }
+
+        assert_eq!(first_checkpoint(), 2);
+        assert!(changeset_since(1, false).is_err());
+        assert_eq!(changeset_since(2, true).unwrap().next_checkpoint, 4);
+
+        set_retention(1).unwrap();
+        assert_eq!(first_checkpoint(), 3);
+        assert!(set_retention(0).is_err());
+    }
+
+    #[test]
+    fn truncated_checkpoints_need_a_resync() {
+        let db = database();
+        set_enabled(true);
+        for name in ["a", "b", "c"] {
+            write(
+                &db,
+                &format!("INSERT INTO person (name, age) VALUES ('{}', 1)", name),
+            );
+        }
+
+        truncate(2);
+        assert!(changeset_since(1, false).is_err());
+        assert!(changeset_since(2, false).is_ok());
+
+        set_enabled(false);
+        assert!(changeset_since(2, false).is_err());
+        assert_eq!(changeset_since(3, false).unwrap().next_checkpoint, 3);
+
+        // a replica that was up to date has to resync as well
+        reset();
+        assert!(changeset_since(3, false).is_err());
+        assert_eq!(next_checkpoint(), 4);
+    }
+}
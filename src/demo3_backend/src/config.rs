@@ -0,0 +1,177 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableCell, Storable};
+
+use crate::MEMORY_MANAGER;
+
+pub const CONFIG_MEMORY_ID: u8 = 1;
+
+/// Chunk layout of files stored in the stable-fs file system.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkType {
+    /// Fixed 4 KiB chunks, each one addressed through the chunk index.
+    V1,
+    /// Larger chunks allocated from a separate memory, faster for big files.
+    V2,
+}
+
+impl From<ChunkType> for ic_wasi_polyfill::ChunkType {
+    fn from(chunk_type: ChunkType) -> Self {
+        match chunk_type {
+            ChunkType::V1 => ic_wasi_polyfill::ChunkType::V1,
+            ChunkType::V2 => ic_wasi_polyfill::ChunkType::V2,
+        }
+    }
+}
+
+/// Where the database and journal files are stored.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Memories mounted as files with `ic_wasi_polyfill::mount_memory_file`.
+    #[default]
+    MountedFile,
+    /// Memories accessed by SQLite directly through the `memory_vfs` VFS.
+    MemoryVfs,
+    /// Regular files of the stable-fs file system.
+    StableFsFile(ChunkType),
+    /// Files kept on the heap only, the database is lost on every upgrade.
+    Heap,
+}
+
+impl Backend {
+    /// Only the memories mounted by the first two backends are encrypted.
+    pub fn supports_encryption(&self) -> bool {
+        matches!(self, Backend::MountedFile | Backend::MemoryVfs)
+    }
+}
+
+/// Canister settings that must survive upgrades.
+///
+/// New fields should be added as `Option` or with `#[serde(default)]`, so that
+/// the value stored by an older canister version still decodes.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Config {
+    /// Pragma overrides set through `set_pragma`, reapplied on every connection open.
+    #[serde(default)]
+    pub pragmas: BTreeMap<String, i64>,
+
+    /// Journal mode selected with `set_journal_mode`, `journal::DEFAULT_MODE` if not set.
+    #[serde(default)]
+    pub journal_mode: Option<String>,
+
+    /// Storage quota set with `set_storage_quota`, no limit if not set.
+    #[serde(default)]
+    pub storage_quota: Option<crate::quota::StorageQuota>,
+
+    /// Fingerprint of the encryption key and the state of a running key rotation.
+    #[serde(default)]
+    pub encryption: crate::encryption::EncryptionConfig,
+
+    /// Storage backend chosen on install, changed afterwards only by a migration.
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// Migration to another backend started with `migrate_backend`, if any.
+    #[serde(default)]
+    pub migration: Option<crate::migrate::Migration>,
+
+    /// Written by `pre_upgrade` and consumed by `post_upgrade`.
+    #[serde(default)]
+    pub shutdown: Option<crate::lifecycle::ShutdownMarker>,
+
+    /// How the last upgrade went, reported by `get_upgrade_status`.
+    #[serde(default)]
+    pub last_upgrade: Option<crate::lifecycle::UpgradeStatus>,
+
+    /// Replay log switch, truncation point and rebuild progress.
+    #[serde(default)]
+    pub replay_log: crate::replay::ReplayLogConfig,
+
+    /// Number of changes kept in the change log, `changes::DEFAULT_RETENTION` if not set.
+    #[serde(default)]
+    pub change_log_retention: Option<u64>,
+
+    /// Sequence and commit number the change log continues from after `changes::reset`.
+    #[serde(default)]
+    pub change_log_next: Option<(u64, u64)>,
+
+    /// Changeset capture switch and the oldest checkpoint still kept.
+    #[serde(default)]
+    pub changesets: crate::changesets::ChangesetConfig,
+
+    /// Primary or follower role, the followers of a primary and their progress.
+    #[serde(default)]
+    pub replication: crate::replication::ReplicationConfig,
+
+    /// Instruction budgets set with `set_instruction_budgets`, `budget::DEFAULT_BUDGET` if
+    /// none matches.
+    #[serde(default)]
+    pub instruction_budgets: Vec<crate::budget::Budget>,
+
+    /// Maintenance tasks and their schedule, set with `set_maintenance`.
+    #[serde(default)]
+    pub maintenance: crate::maintenance::MaintenanceConfig,
+
+    /// The last run of every maintenance task.
+    #[serde(default)]
+    pub maintenance_runs: Vec<crate::maintenance::TaskRun>,
+    /// Statements registered with `register_statement`, by name.
+    #[serde(default)]
+    pub statements: BTreeMap<String, crate::statements::NamedStatement>,
+
+    /// Allowlist mode, only the registered statements run.
+    #[serde(default)]
+    pub statements_only: bool,
+    /// Full-scan limit of the `query` calls of non-admins, set with `set_full_scan_policy`.
+    #[serde(default)]
+    pub full_scan_policy: Option<crate::plan::FullScanPolicy>,
+
+    /// Reports the cost of every call next to its result, set with `set_call_metrics`.
+    #[serde(default)]
+    pub call_metrics: bool,
+
+    /// Threshold and capacity of the slow-query log, set with `set_slow_query_log`.
+    #[serde(default)]
+    pub slow_query_log: crate::trace::SlowQueryLogConfig,
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<Config, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(CONFIG_MEMORY_ID))),
+            Config::default(),
+        )
+        .expect("failed to initialize the config cell"),
+    );
+}
+
+pub fn get() -> Config {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn update<R>(f: impl FnOnce(&mut Config) -> R) -> R {
+    CONFIG.with(|c| {
+        let mut cell = c.borrow_mut();
+        let mut config = cell.get().clone();
+        let res = f(&mut config);
+        cell.set(config).expect("failed to store the config");
+        res
+    })
+}
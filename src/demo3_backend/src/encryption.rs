@@ -0,0 +1,696 @@
+//! Page encryption at rest for the mounted database and journal files, the replay log,
+//! the captured changesets, the slow-query log and the recorded workload.
+//!
+//! The memories behind the mounted files are wrapped in an [`EncryptedMemory`],
+//! which encrypts every 4 KiB sector with AES-256-XTS, the sector index being the
+//! tweak. Keys are derived with HKDF from key material passed in the init or upgrade
+//! arguments and only live in the canister heap, stable memory just keeps a
+//! fingerprint to detect a wrong key.
+//!
+//! A key rotation re-encrypts the sectors in the background: sectors below the
+//! rotation cursor use the next key, the others the current one. A file that is
+//! completely re-encrypted uses the next key only, also for the sectors it grows
+//! afterwards, while the other files are still rotating. Rotating from or to "no key"
+//! enables or disables the encryption of an existing database.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::time::Duration;
+
+use aes::cipher::KeyInit;
+use aes::Aes256;
+use candid::{CandidType, Deserialize};
+use hkdf::Hkdf;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory;
+use sha2::Sha256;
+use xts_mode::{get_tweak_default, Xts128};
+
+use crate::advisor::WORKLOAD_MEMORY_ID;
+use crate::changesets::CHANGESETS_MEMORY_ID;
+use crate::replay::{
+    REPLAY_LOG_DATA_MEMORY_ID, REPLAY_LOG_INDEX_MEMORY_ID, SECOND_LOG_DATA_MEMORY_ID,
+    SECOND_LOG_INDEX_MEMORY_ID, SNAPSHOT_FILE_NAMES, SNAPSHOT_MEMORY_IDS,
+};
+use crate::trace::SLOW_QUERY_LOG_MEMORY_ID;
+use crate::{
+    config, Error, DB_FILE_NAME, DB_JOURNAL_FILE_NAME, JOURNAL_MEMORY_ID, MEMORY_MANAGER,
+    MOUNTED_MEMORY_ID,
+};
+
+pub const SECTOR_SIZE: u64 = 4096;
+
+const MIN_KEY_MATERIAL_LEN: usize = 32;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// sectors re-encrypted by one timer call, 4 MiB per file
+const ROTATION_STEP_SECTORS: u64 = 1024;
+
+/// Encrypted memories and the file names their keys are derived for.
+const ENCRYPTED_FILES: &[(u8, &str)] = &[
+    (MOUNTED_MEMORY_ID, DB_FILE_NAME),
+    (JOURNAL_MEMORY_ID, DB_JOURNAL_FILE_NAME),
+    (REPLAY_LOG_INDEX_MEMORY_ID, "replay log index"),
+    (REPLAY_LOG_DATA_MEMORY_ID, "replay log"),
+    (SECOND_LOG_INDEX_MEMORY_ID, "second replay log index"),
+    (SECOND_LOG_DATA_MEMORY_ID, "second replay log"),
+    (SNAPSHOT_MEMORY_IDS[0], SNAPSHOT_FILE_NAMES[0]),
+    (SNAPSHOT_MEMORY_IDS[1], SNAPSHOT_FILE_NAMES[1]),
+    (CHANGESETS_MEMORY_ID, "changesets"),
+    (SLOW_QUERY_LOG_MEMORY_ID, "slow-query log"),
+    (WORKLOAD_MEMORY_ID, "workload"),
+];
+
+/// Encryption settings kept in the config, the keys themselves are never stored.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EncryptionConfig {
+    /// Fingerprint of the key the files are encrypted with, `None` for plaintext.
+    pub key_fingerprint: Option<String>,
+    pub rotation: Option<RotationConfig>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RotationConfig {
+    pub next_key_fingerprint: Option<String>,
+    /// Re-encrypted sectors per memory id, for the files still rotating.
+    pub cursors: BTreeMap<u8, u64>,
+    /// Memory ids of the files completely re-encrypted with the next key.
+    #[serde(default)]
+    pub done: BTreeSet<u8>,
+}
+
+/// Key material, at least 32 bytes, e.g. the output of a vetKD key derivation.
+pub struct KeyMaterial(Vec<u8>);
+
+impl KeyMaterial {
+    pub fn from_hex(hex_key: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(hex_key.trim()).map_err(|err| Error::InvalidArgument {
+            message: format!("the encryption key is not valid hex: {}", err),
+        })?;
+
+        if bytes.len() < MIN_KEY_MATERIAL_LEN {
+            return Err(Error::InvalidArgument {
+                message: format!(
+                    "the encryption key must have at least {} bytes",
+                    MIN_KEY_MATERIAL_LEN
+                ),
+            });
+        }
+
+        Ok(Self(bytes))
+    }
+
+    fn expand(&self, info: &str, out: &mut [u8]) {
+        Hkdf::<Sha256>::new(Some(b"demo3 page encryption"), &self.0)
+            .expand(info.as_bytes(), out)
+            .expect("HKDF output length is valid");
+    }
+
+    pub fn fingerprint(&self) -> String {
+        let mut out = [0u8; 8];
+        self.expand("fingerprint", &mut out);
+        hex::encode(out)
+    }
+
+    /// Derives the cipher of a single file, every file gets its own keys.
+    fn cipher(&self, file_name: &str) -> Cipher {
+        let mut key = [0u8; 64];
+        self.expand(file_name, &mut key);
+
+        Rc::new(Xts128::new(
+            Aes256::new_from_slice(&key[..32]).unwrap(),
+            Aes256::new_from_slice(&key[32..]).unwrap(),
+        ))
+    }
+}
+
+type Cipher = Rc<Xts128<Aes256>>;
+
+#[derive(Clone, Default)]
+struct FileKeys {
+    current: Option<Cipher>,
+    rotation: Option<Rotation>,
+}
+
+#[derive(Clone)]
+struct Rotation {
+    next: Option<Cipher>,
+    cursor: u64,
+}
+
+impl FileKeys {
+    fn is_plaintext(&self) -> bool {
+        self.current.is_none() && self.rotation.is_none()
+    }
+
+    fn cipher(&self, sector: u64) -> Option<&Cipher> {
+        match &self.rotation {
+            Some(rotation) if sector < rotation.cursor => rotation.next.as_ref(),
+            _ => self.current.as_ref(),
+        }
+    }
+}
+
+thread_local! {
+    static KEYS: RefCell<BTreeMap<u8, FileKeys>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn file_keys(id: u8) -> FileKeys {
+    KEYS.with(|k| k.borrow().get(&id).cloned().unwrap_or_default())
+}
+
+/// Sets the key a memory is encrypted with, `None` keeps it in plaintext.
+pub fn install_key(id: u8, file_name: &str, key: Option<&KeyMaterial>) {
+    let keys = FileKeys {
+        current: key.map(|k| k.cipher(file_name)),
+        rotation: None,
+    };
+    KEYS.with(|k| k.borrow_mut().insert(id, keys));
+}
+
+/// Starts (or resumes at `cursor`) re-encrypting a memory with the `next` key.
+pub fn start_rotation(id: u8, file_name: &str, next: Option<&KeyMaterial>, cursor: u64) {
+    KEYS.with(|k| {
+        let mut keys = k.borrow_mut();
+        let keys = keys.entry(id).or_default();
+        keys.rotation = Some(Rotation {
+            next: next.map(|k| k.cipher(file_name)),
+            cursor,
+        });
+    });
+}
+
+/// Re-encrypts up to `max_sectors` sectors of the memory with the next key.
+///
+/// Returns the new cursor and whether the whole memory is re-encrypted, in
+/// which case the next key becomes the current one.
+pub fn rotate<M: Memory>(id: u8, memory: &M, max_sectors: u64) -> (u64, bool) {
+    let keys = file_keys(id);
+    let rotation = keys.rotation.as_ref().expect("no key rotation in progress");
+
+    let sectors = memory.size() * WASM_PAGE_SIZE / SECTOR_SIZE;
+    let end = sectors.min(rotation.cursor.saturating_add(max_sectors));
+
+    let mut buf = vec![0u8; SECTOR_SIZE as usize];
+    for sector in rotation.cursor..end {
+        read_sector(memory, keys.current.as_ref(), sector, &mut buf);
+        write_sector(memory, rotation.next.as_ref(), sector, &mut buf);
+    }
+
+    let done = end >= sectors;
+
+    KEYS.with(|k| {
+        let mut k = k.borrow_mut();
+        let keys = k.get_mut(&id).unwrap();
+        if done {
+            keys.current = keys.rotation.take().unwrap().next;
+        } else {
+            keys.rotation.as_mut().unwrap().cursor = end;
+        }
+    });
+
+    (end, done)
+}
+
+fn read_sector<M: Memory>(memory: &M, cipher: Option<&Cipher>, sector: u64, buf: &mut [u8]) {
+    memory.read(sector * SECTOR_SIZE, buf);
+
+    // sectors that were never written read as zeros, like in a plaintext file
+    if let Some(cipher) = cipher {
+        if buf.iter().any(|b| *b != 0) {
+            cipher.decrypt_sector(buf, get_tweak_default(sector as u128));
+        }
+    }
+}
+
+// encrypts `buf` in place before writing it
+fn write_sector<M: Memory>(memory: &M, cipher: Option<&Cipher>, sector: u64, buf: &mut [u8]) {
+    if let Some(cipher) = cipher {
+        if buf.iter().any(|b| *b != 0) {
+            cipher.encrypt_sector(buf, get_tweak_default(sector as u128));
+        }
+    }
+
+    memory.write(sector * SECTOR_SIZE, buf);
+}
+
+/// A memory that encrypts its content sector by sector with the keys installed for `id`.
+pub struct EncryptedMemory<M: Memory> {
+    inner: M,
+    id: u8,
+}
+
+impl<M: Memory> EncryptedMemory<M> {
+    pub fn new(inner: M, id: u8) -> Self {
+        Self { inner, id }
+    }
+}
+
+impl<M: Memory> Memory for EncryptedMemory<M> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        self.inner.grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let keys = file_keys(self.id);
+        if keys.is_plaintext() {
+            return self.inner.read(offset, dst);
+        }
+
+        let mut buf = vec![0u8; SECTOR_SIZE as usize];
+        let mut pos = 0;
+        while pos < dst.len() {
+            let address = offset + pos as u64;
+            let sector = address / SECTOR_SIZE;
+            let start = (address % SECTOR_SIZE) as usize;
+            let len = (SECTOR_SIZE as usize - start).min(dst.len() - pos);
+
+            read_sector(&self.inner, keys.cipher(sector), sector, &mut buf);
+            dst[pos..pos + len].copy_from_slice(&buf[start..start + len]);
+
+            pos += len;
+        }
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let keys = file_keys(self.id);
+        if keys.is_plaintext() {
+            return self.inner.write(offset, src);
+        }
+
+        let mut buf = vec![0u8; SECTOR_SIZE as usize];
+        let mut pos = 0;
+        while pos < src.len() {
+            let address = offset + pos as u64;
+            let sector = address / SECTOR_SIZE;
+            let start = (address % SECTOR_SIZE) as usize;
+            let len = (SECTOR_SIZE as usize - start).min(src.len() - pos);
+            let cipher = keys.cipher(sector);
+
+            // partially written sectors keep the rest of their content
+            if len < SECTOR_SIZE as usize {
+                read_sector(&self.inner, cipher, sector, &mut buf);
+            }
+            buf[start..start + len].copy_from_slice(&src[pos..pos + len]);
+            write_sector(&self.inner, cipher, sector, &mut buf);
+
+            pos += len;
+        }
+    }
+}
+
+fn parse_key(
+    hex_key: Option<&str>,
+    expected: Option<&String>,
+    name: &str,
+) -> Result<Option<KeyMaterial>, Error> {
+    let key = hex_key.map(KeyMaterial::from_hex).transpose()?;
+
+    match (key, expected) {
+        (None, None) => Ok(None),
+        (Some(key), Some(expected)) if &key.fingerprint() == expected => Ok(Some(key)),
+        (Some(_), Some(_)) => Err(Error::InvalidArgument {
+            message: format!(
+                "{} does not match the key the database is encrypted with",
+                name
+            ),
+        }),
+        (None, Some(_)) => Err(Error::InvalidArgument {
+            message: format!("the database is encrypted, {} is required", name),
+        }),
+        (Some(_), None) => Err(Error::InvalidArgument {
+            message: format!(
+                "{} is not used by the database, use rotate_encryption_key to encrypt it",
+                name
+            ),
+        }),
+    }
+}
+
+/// Installs the keys on init, a new database is encrypted right away when a key is given.
+pub fn init(key: Option<&str>) -> Result<(), Error> {
+    let key = key.map(KeyMaterial::from_hex).transpose()?;
+
+    config::update(|c| {
+        c.encryption = EncryptionConfig {
+            key_fingerprint: key.as_ref().map(|k| k.fingerprint()),
+            rotation: None,
+        }
+    });
+
+    for (id, file_name) in ENCRYPTED_FILES {
+        install_key(*id, file_name, key.as_ref());
+    }
+
+    Ok(())
+}
+
+/// Installs the keys after an upgrade and resumes an interrupted key rotation.
+pub fn post_upgrade(key: Option<&str>, next_key: Option<&str>) -> Result<(), Error> {
+    if install_keys(key, next_key)? {
+        schedule_rotation_step();
+    }
+    Ok(())
+}
+
+// installs the keys from the config, returns whether a rotation is to be resumed
+fn install_keys(key: Option<&str>, next_key: Option<&str>) -> Result<bool, Error> {
+    let encryption = config::get().encryption;
+
+    let key = parse_key(key, encryption.key_fingerprint.as_ref(), "encryption_key")?;
+    for (id, file_name) in ENCRYPTED_FILES {
+        install_key(*id, file_name, key.as_ref());
+    }
+
+    let Some(rotation) = encryption.rotation else {
+        return Ok(false);
+    };
+    let next = parse_key(
+        next_key,
+        rotation.next_key_fingerprint.as_ref(),
+        "next_encryption_key",
+    )?;
+    for (id, file_name) in ENCRYPTED_FILES {
+        if rotation.done.contains(id) {
+            install_key(*id, file_name, next.as_ref());
+        } else {
+            let cursor = rotation.cursors.get(id).copied().unwrap_or_default();
+            start_rotation(*id, file_name, next.as_ref(), cursor);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Starts re-encrypting the files with a new key, `None` decrypts them.
+pub fn rotate_key(next_key: Option<&str>) -> Result<Option<String>, Error> {
+    let fingerprint = start_key_rotation(next_key)?;
+    schedule_rotation_step();
+    Ok(fingerprint)
+}
+
+fn start_key_rotation(next_key: Option<&str>) -> Result<Option<String>, Error> {
+    if config::get().encryption.rotation.is_some() {
+        return Err(Error::InvalidArgument {
+            message: "a key rotation is already in progress".to_string(),
+        });
+    }
+
+    let next = next_key.map(KeyMaterial::from_hex).transpose()?;
+    let fingerprint = next.as_ref().map(|k| k.fingerprint());
+
+    for (id, file_name) in ENCRYPTED_FILES {
+        start_rotation(*id, file_name, next.as_ref(), 0);
+    }
+
+    config::update(|c| {
+        c.encryption.rotation = Some(RotationConfig {
+            next_key_fingerprint: fingerprint.clone(),
+            cursors: BTreeMap::new(),
+            done: BTreeSet::new(),
+        })
+    });
+
+    Ok(fingerprint)
+}
+
+fn schedule_rotation_step() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        if !rotation_step() {
+            schedule_rotation_step();
+        }
+    });
+}
+
+// re-encrypts the next sectors of every rotating file, returns whether all files are done
+fn rotation_step() -> bool {
+    let mut cursors = BTreeMap::new();
+    let mut done = BTreeSet::new();
+
+    for (id, _) in ENCRYPTED_FILES {
+        if file_keys(*id).rotation.is_none() {
+            continue;
+        }
+
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(*id)));
+        let (cursor, finished) = rotate(*id, &memory, ROTATION_STEP_SECTORS);
+        if finished {
+            done.insert(*id);
+        } else {
+            cursors.insert(*id, cursor);
+        }
+    }
+
+    // the progress is stored in the same message as the re-encrypted sectors, a file
+    // that is done keeps the next key after an upgrade
+    config::update(|c| {
+        let rotation = c.encryption.rotation.as_mut().unwrap();
+        for id in &done {
+            rotation.cursors.remove(id);
+        }
+        rotation.cursors.extend(cursors);
+        rotation.done.extend(done);
+
+        let all_done = ENCRYPTED_FILES
+            .iter()
+            .all(|(id, _)| rotation.done.contains(id));
+        if all_done {
+            c.encryption.key_fingerprint = rotation.next_key_fingerprint.clone();
+            c.encryption.rotation = None;
+        }
+        all_done
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+    use rusqlite::Connection;
+
+    const ID: u8 = 1;
+    const MARKER: &str = "plaintext-marker-";
+
+    fn key(seed: u8) -> KeyMaterial {
+        KeyMaterial::from_hex(&hex::encode([seed; 32])).unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|w| w == needle.as_bytes())
+    }
+
+    fn raw_bytes(memory: &VectorMemory) -> Vec<u8> {
+        memory.borrow().clone()
+    }
+
+    /// Builds a database file with rows containing `MARKER` and returns its bytes.
+    fn database_image() -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.db3");
+        let db = Connection::open(&path).unwrap();
+        db.execute_batch(&format!(
+            "PRAGMA page_size = 16384;
+            CREATE TABLE person (id INTEGER PRIMARY KEY, name TEXT NOT NULL, data TEXT);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+            INSERT INTO person (name, data) SELECT 'person' || i, '{MARKER}' || i FROM n;"
+        ))
+        .unwrap();
+        drop(db);
+        std::fs::read(path).unwrap()
+    }
+
+    /// Writes the image page by page the way the file system writes a mounted file.
+    fn store<M: Memory>(memory: &EncryptedMemory<M>, image: &[u8]) {
+        let pages = (image.len() as u64).div_ceil(WASM_PAGE_SIZE);
+        if memory.size() < pages {
+            memory.grow(pages - memory.size());
+        }
+        for (i, page) in image.chunks(16384).enumerate() {
+            memory.write(i as u64 * 16384, page);
+        }
+    }
+
+    fn load<M: Memory>(memory: &EncryptedMemory<M>, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        memory.read(0, &mut buf);
+        buf
+    }
+
+    fn rows(image: &[u8]) -> i64 {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.db3");
+        std::fs::write(&path, image).unwrap();
+        Connection::open(path)
+            .unwrap()
+            .query_row(
+                &format!("SELECT count(*) FROM person WHERE data LIKE '{MARKER}%'"),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn unaligned_reads_and_writes() {
+        install_key(ID, "test", Some(&key(1)));
+        let raw = VectorMemory::default();
+        let memory = EncryptedMemory::new(raw.clone(), ID);
+        memory.grow(2);
+
+        let mut model = vec![0u8; 2 * WASM_PAGE_SIZE as usize];
+        let mut seed = 7u64;
+        for round in 0..200 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let offset = (seed >> 33) as usize % (model.len() - 10000);
+            let len = 1 + (seed >> 17) as usize % 10000;
+            let data: Vec<u8> = (0..len).map(|i| (i + round) as u8 | 1).collect();
+
+            memory.write(offset as u64, &data);
+            model[offset..offset + len].copy_from_slice(&data);
+        }
+
+        assert_eq!(load(&memory, model.len()), model);
+        assert_ne!(raw_bytes(&raw)[..model.len()], model[..]);
+    }
+
+    #[test]
+    fn raw_memory_never_contains_plaintext_rows() {
+        let image = database_image();
+        assert!(contains(&image, MARKER));
+
+        install_key(ID, DB_FILE_NAME, Some(&key(1)));
+        let raw = VectorMemory::default();
+        let memory = EncryptedMemory::new(raw.clone(), ID);
+        store(&memory, &image);
+
+        assert!(!contains(&raw_bytes(&raw), MARKER));
+        assert!(!contains(&raw_bytes(&raw), "SQLite format 3"));
+
+        let decrypted = load(&memory, image.len());
+        assert_eq!(decrypted, image);
+        assert_eq!(rows(&decrypted), 500);
+    }
+
+    #[test]
+    fn key_rotation_in_chunks() {
+        let image = database_image();
+
+        install_key(ID, DB_FILE_NAME, Some(&key(1)));
+        let raw = VectorMemory::default();
+        let memory = EncryptedMemory::new(raw.clone(), ID);
+        store(&memory, &image);
+        let before = raw_bytes(&raw);
+
+        start_rotation(ID, DB_FILE_NAME, Some(&key(2)), 0);
+        let (cursor, done) = rotate(ID, &raw, 10);
+        assert_eq!((cursor, done), (10, false));
+
+        // the file stays readable and writable while it is half re-encrypted
+        assert_eq!(load(&memory, image.len()), image);
+        store(&memory, &image);
+        assert!(!contains(&raw_bytes(&raw), MARKER));
+
+        while !rotate(ID, &raw, 10).1 {}
+
+        let after = raw_bytes(&raw);
+        assert_ne!(before, after);
+        assert!(!contains(&after, MARKER));
+
+        // only the new key decrypts the file now
+        install_key(ID, DB_FILE_NAME, Some(&key(2)));
+        assert_eq!(load(&memory, image.len()), image);
+        install_key(ID, DB_FILE_NAME, Some(&key(1)));
+        assert_ne!(load(&memory, image.len()), image);
+    }
+
+    #[test]
+    fn rotation_to_plaintext_decrypts_the_file() {
+        let image = database_image();
+
+        install_key(ID, DB_FILE_NAME, Some(&key(1)));
+        let raw = VectorMemory::default();
+        let memory = EncryptedMemory::new(raw.clone(), ID);
+        store(&memory, &image);
+
+        start_rotation(ID, DB_FILE_NAME, None, 0);
+        while !rotate(ID, &raw, 100).1 {}
+
+        assert_eq!(raw_bytes(&raw)[..image.len()], image[..]);
+    }
+
+    #[test]
+    fn upgrade_after_one_file_finished_rotating() {
+        let (key1, key2) = (hex::encode([1u8; 32]), hex::encode([2u8; 32]));
+        init(Some(&key1)).unwrap();
+
+        let memory = |id| {
+            let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+            EncryptedMemory::new(memory, id)
+        };
+        let database = memory(MOUNTED_MEMORY_ID);
+        let journal = memory(JOURNAL_MEMORY_ID);
+
+        // the database takes two rotation steps, the journal one
+        let image: Vec<u8> = (0..(ROTATION_STEP_SECTORS + 16) * SECTOR_SIZE)
+            .map(|i| (i % 251) as u8 | 1)
+            .collect();
+        store(&database, &image);
+        store(&journal, &image[..50_000]);
+
+        start_key_rotation(Some(&key2)).unwrap();
+        assert!(!rotation_step());
+        let rotation = config::get().encryption.rotation.unwrap();
+        assert!(rotation.done.contains(&JOURNAL_MEMORY_ID));
+        assert!(!rotation.cursors.contains_key(&JOURNAL_MEMORY_ID));
+        assert_eq!(
+            rotation.cursors.get(&MOUNTED_MEMORY_ID),
+            Some(&ROTATION_STEP_SECTORS)
+        );
+
+        // the journal grows past its rotated sectors with the next key
+        let journal_image: Vec<u8> = (0..200_000).map(|i| (i % 13) as u8 | 1).collect();
+        store(&journal, &journal_image);
+
+        // the keys only live in the heap, an upgrade installs them again
+        KEYS.with(|k| k.borrow_mut().clear());
+        assert!(install_keys(Some(&key1), Some(&key2)).unwrap());
+        assert_eq!(load(&journal, journal_image.len()), journal_image);
+        assert_eq!(load(&database, image.len()), image);
+
+        while !rotation_step() {}
+        let encryption = config::get().encryption;
+        assert!(encryption.rotation.is_none());
+        assert_eq!(encryption.key_fingerprint, Some(key(2).fingerprint()));
+
+        KEYS.with(|k| k.borrow_mut().clear());
+        assert!(!install_keys(Some(&key2), None).unwrap());
+        assert_eq!(load(&journal, journal_image.len()), journal_image);
+        assert_eq!(load(&database, image.len()), image);
+    }
+
+    #[test]
+    fn rejects_short_or_mismatching_keys() {
+        assert!(KeyMaterial::from_hex("abcd").is_err());
+        assert!(KeyMaterial::from_hex("not hex").is_err());
+
+        let expected = key(1).fingerprint();
+        let good = hex::encode([1u8; 32]);
+        let bad = hex::encode([2u8; 32]);
+
+        assert!(parse_key(Some(&good), Some(&expected), "key").is_ok());
+        assert!(parse_key(Some(&bad), Some(&expected), "key").is_err());
+        assert!(parse_key(None, Some(&expected), "key").is_err());
+        assert!(parse_key(Some(&good), None, "key").is_err());
+        assert!(parse_key(None, None, "key").unwrap().is_none());
+    }
+}
@@ -0,0 +1,1169 @@
+//! Maintenance jobs too heavy for one message.
+//!
+//! A job walks a table in chunks of rowids and an interval timer runs the chunks in
+//! separate messages, so other calls are served in between. The progress is stored in
+//! the `_jobs` table in the same transaction as the chunk. A chunk that uses more than
+//! `CHUNK_BUDGET` instructions is interrupted and rolled back, and the job goes on with
+//! chunks half as large. A trap rolls back the message in flight but not the interval,
+//! whose next tick runs the chunk again. A failed chunk pauses the job until it is
+//! retried.
+//!
+//! `Update` and `Delete` jobs run their statement on one rowid range per chunk.
+//! SQLite cannot build an index piecewise, so `CreateIndex` copies the table into a
+//! shadow table that has the index already, triggers mirror the writes made meanwhile
+//! and the shadow replaces the table at the end. The shadow gets copies of the existing
+//! indexes under temporary names, the table keeps its own until the swap. The swap
+//! renames the tables, moves the index names over in `sqlite_schema` and recreates the
+//! triggers of the table on the shadow, which are not there during the copy so that it
+//! does not fire them. The replaced table is then emptied in chunks and dropped, as is
+//! the shadow table of a cancelled job.
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_timers::TimerId;
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::Serialize;
+
+use crate::replay::{self, SqlValue};
+use crate::util::{exists, invalid, literal, quote, to_error};
+use crate::{budget, changesets, quota, Error, WriteKind, DB};
+
+/// Tables whose name starts with this prefix belong to the jobs.
+pub const INTERNAL_TABLE_PREFIX: &str = "_job";
+
+/// Whether `table` belongs to the jobs or to SQLite itself.
+pub fn is_internal_table(table: &str) -> bool {
+    table.starts_with(INTERNAL_TABLE_PREFIX) || table.starts_with("sqlite_")
+}
+
+pub const DEFAULT_CHUNK_ROWS: u64 = 10_000;
+
+// instructions a timer message spends on chunks before it yields to other calls
+const STEP_BUDGET: u64 = 5_000_000_000;
+
+// instructions one chunk may use, a step stays below 15B of the 40B of a message
+const CHUNK_BUDGET: u64 = 10_000_000_000;
+
+// time between two steps, also while writes are refused, e.g. during a migration
+const STEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    /// `UPDATE table SET {set} WHERE {filter}`.
+    Update {
+        table: String,
+        set: String,
+        filter: Option<String>,
+    },
+    /// `DELETE FROM table WHERE {filter}`.
+    Delete {
+        table: String,
+        filter: Option<String>,
+    },
+    /// `CREATE [UNIQUE] INDEX name ON table ({columns})`, a column may carry `COLLATE`
+    /// or `DESC`.
+    CreateIndex {
+        name: String,
+        table: String,
+        columns: Vec<String>,
+        unique: bool,
+    },
+}
+
+impl JobKind {
+    /// A delete is accepted past the soft limit of the quota, it frees space.
+    pub fn write_kind(&self) -> WriteKind {
+        match self {
+            JobKind::Delete { .. } => WriteKind::Shrinks,
+            _ => WriteKind::Rows,
+        }
+    }
+
+    fn table(&self) -> &str {
+        match self {
+            JobKind::Update { table, .. }
+            | JobKind::Delete { table, .. }
+            | JobKind::CreateIndex { table, .. } => table,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Running,
+    /// A cancelled `CreateIndex` emptying its shadow table.
+    Cancelling,
+    Completed,
+    Cancelled,
+    /// Paused by the error of a chunk until retried.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// First rowid of the next chunk.
+    pub next_rowid: i64,
+    /// Rows per chunk, halved whenever a chunk uses more than its instruction budget.
+    pub chunk_rows: u64,
+    pub chunks: u64,
+    /// Rows changed, or copied by a `CreateIndex`, so far.
+    pub rows: u64,
+    /// Times in nanoseconds.
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    // the interval timer running the steps while a job is active
+    static TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+// the name of a table or index standing in for `name` during a job, `role` is "new" for
+// the shadow table and its indexes and "old" for the replaced ones
+fn job_name(job: &Job, role: &str, name: &str) -> String {
+    format!("{}{}_{}_{}", INTERNAL_TABLE_PREFIX, job.id, role, name)
+}
+
+fn shadow_table(job: &Job) -> String {
+    job_name(job, "new", job.kind.table())
+}
+
+fn trigger_name(job: &Job, operation: &str) -> String {
+    format!("{}{}_{}", INTERNAL_TABLE_PREFIX, job.id, operation)
+}
+
+pub fn create_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS _jobs (
+            id          INTEGER PRIMARY KEY,
+            kind        TEXT NOT NULL,
+            status      TEXT NOT NULL,
+            error       TEXT,
+            next_rowid  INTEGER NOT NULL,
+            chunk_rows  INTEGER NOT NULL,
+            chunks      INTEGER NOT NULL DEFAULT 0,
+            rows        INTEGER NOT NULL DEFAULT 0,
+            created_at  INTEGER NOT NULL,
+            updated_at  INTEGER NOT NULL
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn job_from_row(row: &Row) -> rusqlite::Result<Job> {
+    let kind: String = row.get(1)?;
+    let status: String = row.get(2)?;
+    Ok(Job {
+        id: row.get(0)?,
+        kind: serde_json::from_str(&kind).unwrap(),
+        status: match status.as_str() {
+            "Running" => JobStatus::Running,
+            "Cancelling" => JobStatus::Cancelling,
+            "Completed" => JobStatus::Completed,
+            "Cancelled" => JobStatus::Cancelled,
+            _ => JobStatus::Failed {
+                error: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            },
+        },
+        next_rowid: row.get(4)?,
+        chunk_rows: row.get(5)?,
+        chunks: row.get(6)?,
+        rows: row.get(7)?,
+        created_at: row.get(8)?,
+        updated_at: row.get(9)?,
+    })
+}
+
+const JOB_COLUMNS: &str =
+    "id, kind, status, error, next_rowid, chunk_rows, chunks, rows, created_at, updated_at";
+
+pub fn get(db: &Connection, id: u64) -> Result<Job, Error> {
+    db.query_row(
+        &format!("SELECT {JOB_COLUMNS} FROM _jobs WHERE id = ?1"),
+        [id],
+        job_from_row,
+    )
+    .optional()
+    .map_err(to_error)?
+    .ok_or_else(|| invalid(format!("there is no job {}", id)))
+}
+
+pub fn list(db: &Connection) -> Result<Vec<Job>, Error> {
+    let mut stmt = db
+        .prepare(&format!("SELECT {JOB_COLUMNS} FROM _jobs ORDER BY id"))
+        .map_err(to_error)?;
+    let jobs = stmt.query_map([], job_from_row).map_err(to_error)?;
+    jobs.collect::<rusqlite::Result<_>>().map_err(to_error)
+}
+
+// the oldest job with chunks left to run
+fn next_active(db: &Connection) -> rusqlite::Result<Option<Job>> {
+    db.query_row(
+        &format!(
+            "SELECT {JOB_COLUMNS} FROM _jobs
+            WHERE status IN ('Running', 'Cancelling') ORDER BY id LIMIT 1"
+        ),
+        [],
+        job_from_row,
+    )
+    .optional()
+}
+
+fn set_status(db: &Connection, id: u64, status: &JobStatus, time: u64) -> rusqlite::Result<()> {
+    let (status, error) = match status {
+        JobStatus::Running => ("Running", None),
+        JobStatus::Cancelling => ("Cancelling", None),
+        JobStatus::Completed => ("Completed", None),
+        JobStatus::Cancelled => ("Cancelled", None),
+        JobStatus::Failed { error } => ("Failed", Some(error.as_str())),
+    };
+    db.execute(
+        "UPDATE _jobs SET status = ?2, error = ?3, updated_at = ?4 WHERE id = ?1",
+        (id, status, error, time),
+    )?;
+    Ok(())
+}
+
+// columns copied to the shadow table, `rowid` unless a column is an alias of it
+fn copied_columns(db: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare("SELECT name, type, pk FROM pragma_table_info(?1) ORDER BY cid")?;
+    let columns: Vec<(String, String, i64)> = stmt
+        .query_map([table], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let primary_keys: Vec<_> = columns.iter().filter(|(_, _, pk)| *pk > 0).collect();
+    let has_alias = matches!(
+        primary_keys.as_slice(),
+        [(_, column_type, _)] if column_type.eq_ignore_ascii_case("INTEGER")
+    );
+
+    let mut names: Vec<String> = columns.iter().map(|(name, _, _)| quote(name)).collect();
+    if !has_alias {
+        names.insert(0, "rowid".to_string());
+    }
+    Ok(names)
+}
+
+// the statements of one chunk, bound to the first and the end rowid of the range
+fn chunk_statements(db: &Connection, job: &Job) -> rusqlite::Result<Vec<String>> {
+    let table = quote(job.kind.table());
+    let range = "rowid >= ?1 AND rowid < ?2";
+
+    Ok(match &job.kind {
+        JobKind::Update { set, filter, .. } => vec![format!(
+            "UPDATE {table} SET {set} WHERE {range} AND ({})",
+            filter.as_deref().unwrap_or("1")
+        )],
+        JobKind::Delete { filter, .. } => vec![format!(
+            "DELETE FROM {table} WHERE {range} AND ({})",
+            filter.as_deref().unwrap_or("1")
+        )],
+        JobKind::CreateIndex { .. } => {
+            let shadow = quote(&shadow_table(job));
+            let columns = copied_columns(db, job.kind.table())?.join(", ");
+            vec![
+                format!("DELETE FROM {shadow} WHERE {range}"),
+                format!(
+                    "INSERT INTO {shadow} ({columns}) SELECT {columns} FROM {table} WHERE {range}"
+                ),
+            ]
+        }
+    })
+}
+
+// replaces the first `name` that follows one of `keywords` in a statement
+fn replace_name(sql: &str, name: &str, replacement: &str, keywords: &[&str]) -> Option<String> {
+    let lower = sql.to_lowercase();
+    let names = [
+        name.to_lowercase(),
+        quote(name).to_lowercase(),
+        format!("[{}]", name.to_lowercase()),
+        format!("`{}`", name.to_lowercase()),
+    ];
+
+    let mut search = 0;
+    while let Some(found) = lower[search..].find(|c: char| c.is_whitespace()) {
+        let start = search + found + lower[search + found..].len()
+            - lower[search + found..].trim_start().len();
+        let rest = &lower[start..];
+        for name in &names {
+            if rest.starts_with(name.as_str())
+                && rest[name.len()..].starts_with(|c: char| c.is_whitespace() || c == '(')
+            {
+                let preceding = lower[..start].trim_end();
+                if keywords.iter().any(|k| preceding.ends_with(k)) {
+                    return Some(format!(
+                        "{}{}{}",
+                        &sql[..start],
+                        quote(replacement),
+                        &sql[start + name.len()..]
+                    ));
+                }
+            }
+        }
+        search = start.max(search + found + 1);
+    }
+    None
+}
+
+// replaces the table name of a `CREATE TABLE` or `CREATE INDEX` statement
+fn retarget(sql: &str, table: &str, replacement: &str) -> Result<String, Error> {
+    replace_name(sql, table, replacement, &[" on", "table"])
+        .ok_or_else(|| invalid(format!("cannot find the table name in {}", sql)))
+}
+
+// replaces the index name of a `CREATE INDEX` statement
+fn rename_index(sql: &str, index: &str, replacement: &str) -> Result<String, Error> {
+    replace_name(sql, index, replacement, &["index", "exists"])
+        .ok_or_else(|| invalid(format!("cannot find the index name in {}", sql)))
+}
+
+// the indexes of a table that have a `CREATE INDEX` statement, by name
+fn indexes(db: &Connection, table: &str) -> Result<Vec<(String, String)>, Error> {
+    db.prepare(
+        "SELECT name, sql FROM sqlite_schema
+        WHERE type = 'index' AND tbl_name = ?1 AND sql IS NOT NULL ORDER BY name",
+    )
+    .and_then(|mut stmt| {
+        stmt.query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    })
+    .map_err(to_error)
+}
+
+// creates a shadow table with copies of the indexes and mirrors the writes to the table
+// into it
+fn create_shadow(
+    db: &Connection,
+    job: &Job,
+    index: String,
+    log: &mut Vec<String>,
+) -> Result<(), Error> {
+    let table = job.kind.table();
+    let shadow = shadow_table(job);
+
+    let table_sql: String = db
+        .query_row(
+            "SELECT sql FROM sqlite_schema WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get(0),
+        )
+        .map_err(to_error)?;
+
+    log.push(retarget(&table_sql, table, &shadow)?);
+    for (name, sql) in indexes(db, table)? {
+        let sql = retarget(&sql, table, &shadow)?;
+        log.push(rename_index(&sql, &name, &job_name(job, "new", &name))?);
+    }
+    log.push(index);
+
+    let columns = copied_columns(db, table).map_err(to_error)?;
+    let values = |row: &str| {
+        columns
+            .iter()
+            .map(|c| format!("{row}.{c}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let (table, shadow_name, columns) = (quote(table), quote(&shadow), columns.join(", "));
+    log.push(format!(
+        "CREATE TRIGGER {} AFTER INSERT ON {table} BEGIN
+            DELETE FROM {shadow_name} WHERE rowid = NEW.rowid;
+            INSERT INTO {shadow_name} ({columns}) VALUES ({});
+        END",
+        quote(&trigger_name(job, "insert")),
+        values("NEW")
+    ));
+    log.push(format!(
+        "CREATE TRIGGER {} AFTER UPDATE ON {table} BEGIN
+            DELETE FROM {shadow_name} WHERE rowid IN (OLD.rowid, NEW.rowid);
+            INSERT INTO {shadow_name} ({columns}) VALUES ({});
+        END",
+        quote(&trigger_name(job, "update")),
+        values("NEW")
+    ));
+    log.push(format!(
+        "CREATE TRIGGER {} AFTER DELETE ON {table} BEGIN
+            DELETE FROM {shadow_name} WHERE rowid = OLD.rowid;
+        END",
+        quote(&trigger_name(job, "delete")),
+    ));
+
+    Ok(())
+}
+
+/// Starts a job, its chunks run in later messages.
+pub fn start(
+    db: &Connection,
+    kind: JobKind,
+    chunk_rows: Option<u64>,
+    caller: Principal,
+    time: u64,
+) -> Result<Job, Error> {
+    let table = kind.table();
+    if is_internal_table(table) {
+        return Err(invalid(format!("{} is an internal table", table)));
+    }
+    let chunk_rows = chunk_rows.unwrap_or(DEFAULT_CHUNK_ROWS);
+    if chunk_rows == 0 {
+        return Err(invalid("a chunk must have at least one row".to_string()));
+    }
+
+    // also refuses tables without rowid
+    let first_rowid: Option<i64> = db
+        .query_row(
+            &format!("SELECT min(rowid) FROM {}", quote(table)),
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|err| invalid(format!("cannot walk the rowids of {}: {}", table, err)))?;
+
+    if let JobKind::CreateIndex { name, .. } = &kind {
+        if exists(db, name)? {
+            return Err(invalid(format!("{} already exists", name)));
+        }
+    }
+
+    let tx = db.unchecked_transaction().map_err(to_error)?;
+    tx.execute(
+        "INSERT INTO _jobs (kind, status, next_rowid, chunk_rows, created_at, updated_at)
+        VALUES (?1, 'Running', ?2, ?3, ?4, ?4)",
+        (
+            serde_json::to_string(&kind).unwrap(),
+            first_rowid.unwrap_or(0),
+            chunk_rows,
+            time,
+        ),
+    )
+    .map_err(to_error)?;
+    let job = get(&tx, tx.last_insert_rowid() as u64)?;
+
+    let mut log = Vec::new();
+    if let JobKind::CreateIndex {
+        name,
+        columns,
+        unique,
+        ..
+    } = &job.kind
+    {
+        let index = format!(
+            "CREATE {}INDEX {} ON {} ({})",
+            if *unique { "UNIQUE " } else { "" },
+            quote(name),
+            quote(&shadow_table(&job)),
+            columns.join(", ")
+        );
+        create_shadow(&tx, &job, index, &mut log)?;
+    }
+    for sql in &log {
+        tx.execute_batch(sql)
+            .map_err(|err| invalid(format!("{}: {}", sql, err)))?;
+    }
+
+    // checks the statements of the chunks before the first one runs
+    for sql in chunk_statements(&tx, &job).map_err(to_error)? {
+        tx.prepare(&sql)
+            .map_err(|err| invalid(format!("{}: {}", sql, err)))?;
+    }
+
+    tx.commit().map_err(to_error)?;
+
+    for sql in &log {
+        replay::record(sql, &[], caller, time);
+    }
+
+    Ok(job)
+}
+
+// halves the chunks of a job after one ran out of instructions
+fn shrink(db: &Connection, job: &Job, time: u64) -> Result<(), Error> {
+    if job.chunk_rows == 1 {
+        return Err(invalid(format!(
+            "a chunk of one row uses more than {} instructions",
+            CHUNK_BUDGET
+        )));
+    }
+    db.execute(
+        "UPDATE _jobs SET chunk_rows = ?2, updated_at = ?3 WHERE id = ?1",
+        (job.id, job.chunk_rows / 2, time),
+    )
+    .map_err(to_error)?;
+    Ok(())
+}
+
+// runs `write` within `chunk_budget` instructions, returns false if it ran out of them
+// and the chunks of the job were halved instead
+fn bounded(
+    db: &Connection,
+    job: &Job,
+    time: u64,
+    counter: fn() -> u64,
+    chunk_budget: u64,
+    write: impl FnOnce() -> Result<(), Error>,
+) -> Result<bool, Error> {
+    let limit = counter().saturating_add(chunk_budget);
+    let (written, exceeded) = budget::run(limit, counter, write);
+    if exceeded.is_some() {
+        // the transaction of the chunk was rolled back
+        shrink(db, job, time)?;
+        return Ok(false);
+    }
+    written.map(|()| true)
+}
+
+// runs the next chunk of a job within `chunk_budget` instructions, or finishes it
+fn step(
+    db: &Connection,
+    job: &Job,
+    caller: Principal,
+    time: u64,
+    counter: fn() -> u64,
+    chunk_budget: u64,
+) -> Result<(), Error> {
+    if let Some(table) = emptied_table(db, job)? {
+        return empty(db, job, &table, caller, time, counter, chunk_budget);
+    }
+
+    let table = quote(job.kind.table());
+    let last_rowid: Option<i64> = db
+        .query_row(&format!("SELECT max(rowid) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .map_err(to_error)?;
+
+    if last_rowid.is_none_or(|last| job.next_rowid > last) {
+        return finish(db, job, caller, time);
+    }
+
+    let end = job.next_rowid.saturating_add(job.chunk_rows as i64);
+    let statements = chunk_statements(db, job).map_err(to_error)?;
+    let params = [SqlValue::Integer(job.next_rowid), SqlValue::Integer(end)];
+
+    let write = || {
+        let tx = db.unchecked_transaction().map_err(quota::map_write_error)?;
+        let mut rows = 0;
+        for sql in &statements {
+            rows = tx
+                .execute(sql, rusqlite::params_from_iter(&params))
+                .map_err(quota::map_write_error)?;
+        }
+        tx.execute(
+            "UPDATE _jobs SET next_rowid = ?2, chunks = chunks + 1, rows = rows + ?3,
+                updated_at = ?4
+            WHERE id = ?1",
+            (job.id, end, rows, time),
+        )
+        .map_err(to_error)?;
+        tx.commit().map_err(quota::map_write_error)
+    };
+
+    // followers receive the changed rows, the copy to a shadow table stays local
+    let written = bounded(db, job, time, counter, chunk_budget, || match job.kind {
+        JobKind::CreateIndex { .. } => write(),
+        _ => changesets::capture(db, write).map(|_| ()),
+    })?;
+
+    if written {
+        for sql in &statements {
+            replay::record(sql, &params, caller, time);
+        }
+    }
+
+    Ok(())
+}
+
+// the table a `CreateIndex` empties before dropping it: the shadow table once the job is
+// cancelled, the replaced table once the shadow took its place
+fn emptied_table(db: &Connection, job: &Job) -> Result<Option<String>, Error> {
+    if !matches!(job.kind, JobKind::CreateIndex { .. }) {
+        return Ok(None);
+    }
+    if job.status == JobStatus::Cancelling {
+        return Ok(Some(shadow_table(job)));
+    }
+    let replaced = job_name(job, "old", job.kind.table());
+    Ok(exists(db, &replaced)?.then_some(replaced))
+}
+
+// deletes the next chunk of rows of `table`, or drops it once it is empty
+fn empty(
+    db: &Connection,
+    job: &Job,
+    table: &str,
+    caller: Principal,
+    time: u64,
+    counter: fn() -> u64,
+    chunk_budget: u64,
+) -> Result<(), Error> {
+    let table = quote(table);
+    let is_empty: bool = db
+        .query_row(
+            &format!("SELECT NOT EXISTS (SELECT 1 FROM {table})"),
+            [],
+            |row| row.get(0),
+        )
+        .map_err(to_error)?;
+
+    if is_empty {
+        let status = match job.status {
+            JobStatus::Cancelling => JobStatus::Cancelled,
+            _ => JobStatus::Completed,
+        };
+        let sql = format!("DROP TABLE {table}");
+        let tx = db.unchecked_transaction().map_err(to_error)?;
+        tx.execute_batch(&sql).map_err(to_error)?;
+        set_status(&tx, job.id, &status, time).map_err(to_error)?;
+        tx.commit().map_err(to_error)?;
+        replay::record(&sql, &[], caller, time);
+        return Ok(());
+    }
+
+    let sql = format!(
+        "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} ORDER BY rowid LIMIT ?1)"
+    );
+    let params = [SqlValue::Integer(job.chunk_rows as i64)];
+    let write = || {
+        let tx = db.unchecked_transaction().map_err(quota::map_write_error)?;
+        tx.execute(&sql, rusqlite::params_from_iter(&params))
+            .map_err(quota::map_write_error)?;
+        tx.execute(
+            "UPDATE _jobs SET chunks = chunks + 1, updated_at = ?2 WHERE id = ?1",
+            (job.id, time),
+        )
+        .map_err(to_error)?;
+        tx.commit().map_err(quota::map_write_error)
+    };
+
+    if bounded(db, job, time, counter, chunk_budget, write)? {
+        replay::record(&sql, &params, caller, time);
+    }
+
+    Ok(())
+}
+
+fn drop_mirror_triggers(job: &Job, log: &mut Vec<String>) {
+    for operation in ["insert", "update", "delete"] {
+        log.push(format!(
+            "DROP TRIGGER {}",
+            quote(&trigger_name(job, operation))
+        ));
+    }
+}
+
+// replaces the table by its shadow, all statements only touch the schema
+fn swap(db: &Connection, job: &Job, index: &str, log: &mut Vec<String>) -> Result<(), Error> {
+    let table = job.kind.table();
+    let shadow = shadow_table(job);
+    let replaced = job_name(job, "old", table);
+
+    let originals = indexes(db, table)?;
+    let copies = indexes(db, &shadow)?;
+    for (name, _) in &originals {
+        if !copies
+            .iter()
+            .any(|(copy, _)| *copy == job_name(job, "new", name))
+        {
+            return Err(invalid(format!(
+                "the index {} was created while the job ran, drop it or cancel the job",
+                name
+            )));
+        }
+    }
+    let triggers: Vec<(String, String)> = db
+        .prepare(
+            "SELECT name, sql FROM sqlite_schema
+            WHERE type = 'trigger' AND tbl_name = ?1 AND name NOT LIKE ?2 ESCAPE '\\'",
+        )
+        .and_then(|mut stmt| {
+            let internal = format!("\\{}%", INTERNAL_TABLE_PREFIX);
+            stmt.query_map((table, internal), |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .map_err(to_error)?;
+
+    let rename_in_schema = |from: &str, to: &str, sql: &str| {
+        format!(
+            "UPDATE sqlite_schema SET name = {}, sql = {} WHERE type = 'index' AND name = {}",
+            literal(to),
+            literal(sql),
+            literal(from)
+        )
+    };
+
+    drop_mirror_triggers(job, log);
+    for (name, _) in &triggers {
+        log.push(format!("DROP TRIGGER {}", quote(name)));
+    }
+    // keeps the views and triggers that name the table pointing to it
+    log.push("PRAGMA legacy_alter_table = ON".to_string());
+    log.push(format!(
+        "ALTER TABLE {} RENAME TO {}",
+        quote(table),
+        quote(&replaced)
+    ));
+    log.push("PRAGMA writable_schema = ON".to_string());
+    for (name, sql) in &originals {
+        let old = job_name(job, "old", name);
+        let sql = rename_index(&retarget(sql, table, &replaced)?, name, &old)?;
+        log.push(rename_in_schema(name, &old, &sql));
+    }
+    log.push("PRAGMA writable_schema = OFF".to_string());
+    log.push(format!(
+        "ALTER TABLE {} RENAME TO {}",
+        quote(&shadow),
+        quote(table)
+    ));
+    log.push("PRAGMA writable_schema = ON".to_string());
+    for (copy, _) in &copies {
+        if copy == index {
+            continue;
+        }
+        match originals
+            .iter()
+            .find(|(name, _)| *copy == job_name(job, "new", name))
+        {
+            Some((name, sql)) => log.push(rename_in_schema(copy, name, sql)),
+            // the original was dropped while the job ran
+            None => log.push(format!("DROP INDEX {}", quote(copy))),
+        }
+    }
+    log.push("PRAGMA writable_schema = RESET".to_string());
+    log.push("PRAGMA legacy_alter_table = OFF".to_string());
+    log.extend(triggers.into_iter().map(|(_, sql)| sql));
+
+    Ok(())
+}
+
+// ends a job once its chunks walked the whole table, a `CreateIndex` then swaps the
+// tables and empties the replaced one
+fn finish(db: &Connection, job: &Job, caller: Principal, time: u64) -> Result<(), Error> {
+    let mut log = Vec::new();
+    let status = match &job.kind {
+        JobKind::CreateIndex { name, .. } => {
+            swap(db, job, name, &mut log)?;
+            JobStatus::Running
+        }
+        _ => JobStatus::Completed,
+    };
+
+    let tx = db.unchecked_transaction().map_err(to_error)?;
+    for sql in &log {
+        tx.execute_batch(sql).map_err(to_error)?;
+    }
+    set_status(&tx, job.id, &status, time).map_err(to_error)?;
+    tx.commit().map_err(to_error)?;
+
+    for sql in &log {
+        replay::record(sql, &[], caller, time);
+    }
+
+    Ok(())
+}
+
+/// Runs chunks of at most `chunk_budget` instructions until `budget` instructions, as
+/// counted by `counter`, are used or no job is left; returns whether any is left.
+pub fn run(
+    db: &Connection,
+    caller: Principal,
+    time: u64,
+    counter: fn() -> u64,
+    budget: u64,
+    chunk_budget: u64,
+) -> bool {
+    loop {
+        let job = match next_active(db) {
+            Ok(Some(job)) => job,
+            Ok(None) => return false,
+            Err(err) => {
+                ic_cdk::eprintln!("cannot read the jobs: {:?}", err);
+                return false;
+            }
+        };
+
+        if let Err(err) = step(db, &job, caller, time, counter, chunk_budget) {
+            let status = JobStatus::Failed {
+                error: format!("{:?}", err),
+            };
+            if let Err(err) = set_status(db, job.id, &status, time) {
+                ic_cdk::eprintln!("cannot pause the job {}: {:?}", job.id, err);
+                return false;
+            }
+        }
+
+        if counter() >= budget {
+            return matches!(next_active(db), Ok(Some(_)));
+        }
+    }
+}
+
+/// Stops a job; a `CreateIndex` stops mirroring the table and empties its shadow table
+/// in later chunks. A `CreateIndex` that replaced its table already cannot be cancelled.
+pub fn cancel(db: &Connection, id: u64, caller: Principal, time: u64) -> Result<Job, Error> {
+    let job = get(db, id)?;
+    if !matches!(job.status, JobStatus::Running | JobStatus::Failed { .. }) {
+        return Err(invalid(format!("the job {} is not running", id)));
+    }
+
+    match &job.kind {
+        JobKind::CreateIndex { .. } => {
+            if emptied_table(db, &job)?.is_some() {
+                return Err(invalid(format!(
+                    "the job {} replaced its table already",
+                    id
+                )));
+            }
+            let mut log = Vec::new();
+            drop_mirror_triggers(&job, &mut log);
+            let tx = db.unchecked_transaction().map_err(to_error)?;
+            for sql in &log {
+                tx.execute_batch(sql).map_err(to_error)?;
+            }
+            set_status(&tx, id, &JobStatus::Cancelling, time).map_err(to_error)?;
+            tx.commit().map_err(to_error)?;
+            for sql in &log {
+                replay::record(sql, &[], caller, time);
+            }
+        }
+        _ => set_status(db, id, &JobStatus::Cancelled, time).map_err(to_error)?,
+    }
+
+    get(db, id)
+}
+
+/// Resumes a failed job from the chunk that failed.
+pub fn retry(db: &Connection, id: u64, time: u64) -> Result<Job, Error> {
+    let job = get(db, id)?;
+    if !matches!(job.status, JobStatus::Failed { .. }) {
+        return Err(invalid(format!("the job {} has not failed", id)));
+    }
+
+    set_status(db, id, &JobStatus::Running, time).map_err(to_error)?;
+    get(db, id)
+}
+
+/// Runs the active jobs in timer messages until none is left.
+pub fn schedule() {
+    if TIMER.with(Cell::get).is_none() {
+        let timer = ic_cdk_timers::set_timer_interval(STEP_INTERVAL, run_step);
+        TIMER.with(|t| t.set(Some(timer)));
+    }
+}
+
+fn stop() {
+    if let Some(timer) = TIMER.with(Cell::take) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
+
+fn run_step() {
+    // the live database has no connection while a rebuilt one is copied over it
+    if replay::activating() {
+        return;
+    }
+
+    let active = DB.with(|db| next_active(db.borrow().as_ref().unwrap()));
+    let Ok(Some(job)) = active else {
+        stop();
+        return;
+    };
+
+    // a follower only changes through replication, the next ticks run the jobs once it
+    // is promoted or writes are allowed again
+    if crate::check_writable(job.kind.write_kind()).is_err() {
+        return;
+    }
+
+    let more = DB.with(|db| {
+        let db = db.borrow();
+        run(
+            db.as_ref().unwrap(),
+            ic_cdk::api::id(),
+            ic_cdk::api::time(),
+            ic_cdk::api::instruction_counter,
+            STEP_BUDGET,
+            CHUNK_BUDGET,
+        )
+    });
+    if !more {
+        stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every call pretends a message is used up, so each `run` is one chunk
+    fn exhausted() -> u64 {
+        u64::MAX
+    }
+
+    fn database(rows: i64) -> Connection {
+        let db = crate::util::database();
+        create_table(&db).unwrap();
+        for i in 0..rows {
+            db.execute(
+                "INSERT INTO person (name, data, age) VALUES (?1, '', ?2)",
+                (format!("name {}", i), i % 10),
+            )
+            .unwrap();
+        }
+        db
+    }
+
+    fn start_job(db: &Connection, kind: JobKind, chunk_rows: u64) -> Job {
+        start(db, kind, Some(chunk_rows), Principal::anonymous(), 0).unwrap()
+    }
+
+    fn run_chunk(db: &Connection) -> bool {
+        run(db, Principal::anonymous(), 1, exhausted, 0, u64::MAX)
+    }
+
+    fn count(db: &Connection, sql: &str) -> i64 {
+        db.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn updates_and_deletes_in_chunks() {
+        let db = database(100);
+        let job = start_job(
+            &db,
+            JobKind::Update {
+                table: "person".to_string(),
+                set: "data = 'x'".to_string(),
+                filter: Some("age < 5".to_string()),
+            },
+            30,
+        );
+        assert_eq!((job.next_rowid, job.status), (1, JobStatus::Running));
+
+        assert!(run_chunk(&db));
+        let job = get(&db, job.id).unwrap();
+        assert_eq!((job.next_rowid, job.chunks, job.rows), (31, 1, 15));
+
+        // writes between the chunks are picked up by the later ones
+        db.execute("INSERT INTO person (name, age) VALUES ('late', 1)", [])
+            .unwrap();
+        while run_chunk(&db) {}
+
+        let job = get(&db, job.id).unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!((job.chunks, job.rows), (4, 51));
+        assert_eq!(
+            count(&db, "SELECT count(*) FROM person WHERE data = 'x'"),
+            51
+        );
+
+        let job = start_job(
+            &db,
+            JobKind::Delete {
+                table: "person".to_string(),
+                filter: Some("data = 'x'".to_string()),
+            },
+            1000,
+        );
+        while run_chunk(&db) {}
+        assert_eq!(get(&db, job.id).unwrap().status, JobStatus::Completed);
+        assert_eq!(count(&db, "SELECT count(*) FROM person"), 50);
+    }
+
+    thread_local! {
+        static CALLS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    // pretends every check of the counter costs 1000 instructions
+    fn ticking() -> u64 {
+        CALLS.with(|c| {
+            c.set(c.get() + 1);
+            c.get() * 1000
+        })
+    }
+
+    #[test]
+    fn halves_chunks_over_their_budget() {
+        let db = database(1000);
+        crate::budget::install_handler(&db);
+        let job = start_job(
+            &db,
+            JobKind::Update {
+                table: "person".to_string(),
+                set: "data = 'x'".to_string(),
+                filter: None,
+            },
+            1000,
+        );
+
+        while run(&db, Principal::anonymous(), 1, ticking, 0, 3000) {}
+
+        let job = get(&db, job.id).unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert!(job.chunk_rows < 1000, "{job:?}");
+        assert_eq!(job.rows, 1000);
+        assert_eq!(
+            count(&db, "SELECT count(*) FROM person WHERE data = 'x'"),
+            1000
+        );
+    }
+
+    #[test]
+    fn creates_an_index_through_a_shadow_table() {
+        let db = database(100);
+        db.execute_batch(
+            "CREATE INDEX person_age ON person (age);
+            CREATE TABLE audit (id INTEGER);
+            CREATE TRIGGER person_audit AFTER DELETE ON person BEGIN
+                INSERT INTO audit VALUES (OLD.id);
+            END;",
+        )
+        .unwrap();
+        let plan = |db: &Connection| -> String {
+            db.query_row(
+                "EXPLAIN QUERY PLAN SELECT name FROM person WHERE age = 3",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap()
+        };
+
+        let job = start_job(
+            &db,
+            JobKind::CreateIndex {
+                name: "person_name".to_string(),
+                table: "person".to_string(),
+                columns: vec!["name".to_string()],
+                unique: true,
+            },
+            40,
+        );
+        assert!(run_chunk(&db));
+        // the table keeps its indexes while it is copied
+        assert!(plan(&db).contains("person_age"), "{}", plan(&db));
+
+        // writes while the table is copied reach the shadow table as well
+        db.execute_batch(
+            "UPDATE person SET age = 99 WHERE id = 2;
+            UPDATE person SET age = 98 WHERE id = 90;
+            DELETE FROM person WHERE id IN (3, 91);
+            INSERT INTO person (name, age) VALUES ('late', 1);",
+        )
+        .unwrap();
+        // the unique index is enforced before it replaces the table
+        assert!(db
+            .execute("INSERT INTO person (name) VALUES ('name 5')", [])
+            .is_err());
+
+        // 3 chunks copy the table, 3 more empty the replaced one before it is dropped
+        while run_chunk(&db) {}
+        let job = get(&db, job.id).unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!(job.chunks, 6);
+
+        assert_eq!(count(&db, "SELECT count(*) FROM person"), 99);
+        assert_eq!(count(&db, "SELECT age FROM person WHERE id = 2"), 99);
+        assert_eq!(count(&db, "SELECT age FROM person WHERE id = 90"), 98);
+        assert_eq!(count(&db, "SELECT id FROM person WHERE name = 'late'"), 101);
+
+        assert!(plan(&db).contains("person_age"), "{}", plan(&db));
+        // the trigger fired for the deletes above only, not for the copy
+        assert_eq!(count(&db, "SELECT count(*) FROM audit"), 2);
+        db.execute("DELETE FROM person WHERE id = 4", []).unwrap();
+        assert_eq!(count(&db, "SELECT count(*) FROM audit"), 3);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT count(*) FROM pragma_integrity_check WHERE integrity_check != 'ok'"
+            ),
+            0
+        );
+
+        let schema: Vec<(String, String)> = db
+            .prepare("SELECT type, name FROM sqlite_schema WHERE tbl_name = 'person' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            schema,
+            [
+                ("table".to_string(), "person".to_string()),
+                ("index".to_string(), "person_age".to_string()),
+                ("trigger".to_string(), "person_audit".to_string()),
+                ("index".to_string(), "person_name".to_string()),
+            ]
+        );
+        assert_eq!(
+            count(
+                &db,
+                "SELECT count(*) FROM sqlite_schema
+                WHERE name LIKE '\\_job%' ESCAPE '\\' AND name != '_jobs'"
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn pauses_failed_jobs_until_retried_or_cancelled() {
+        let db = database(20);
+        db.execute("UPDATE person SET name = 'twin' WHERE id IN (5, 6)", [])
+            .unwrap();
+
+        let job = start_job(
+            &db,
+            JobKind::CreateIndex {
+                name: "person_name".to_string(),
+                table: "person".to_string(),
+                columns: vec!["name".to_string()],
+                unique: true,
+            },
+            10,
+        );
+        assert!(!run_chunk(&db));
+        let failed = get(&db, job.id).unwrap();
+        assert!(matches!(failed.status, JobStatus::Failed { .. }));
+        assert_eq!(failed.next_rowid, 1);
+
+        db.execute("UPDATE person SET name = 'single' WHERE id = 6", [])
+            .unwrap();
+        assert_eq!(retry(&db, job.id, 2).unwrap().status, JobStatus::Running);
+        assert!(run_chunk(&db));
+
+        let cancelled = cancel(&db, job.id, Principal::anonymous(), 3).unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelling);
+        while run_chunk(&db) {}
+        // the copy stopped, the rows copied so far were deleted in one chunk before the drop
+        let cancelled = get(&db, job.id).unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert_eq!((cancelled.rows, cancelled.chunks), (10, 2));
+        assert_eq!(count(&db, "SELECT count(*) FROM person"), 20);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT count(*) FROM sqlite_schema WHERE type = 'index'"
+            ),
+            0
+        );
+
+        assert!(retry(&db, job.id, 4).is_err());
+        assert!(cancel(&db, job.id, Principal::anonymous(), 4).is_err());
+        assert!(start(
+            &db,
+            JobKind::Delete {
+                table: "_jobs".to_string(),
+                filter: None
+            },
+            None,
+            Principal::anonymous(),
+            5
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use rusqlite::Connection;
+
+use crate::Error;
+
+/// Journal mode used when none was selected with `set_journal_mode`.
+pub const DEFAULT_MODE: &str = "TRUNCATE";
+
+/// Journal modes that can be selected for the database.
+///
+/// DELETE is not listed: the journal file is a mounted memory file, and mounted
+/// files cannot be deleted. OFF is not listed either: without a journal, a statement
+/// that fails halfway, e.g. on a constraint or on the instruction budget, leaves its
+/// partial changes in the database, and so does a rolled back transaction. The other
+/// modes roll back on both the mounted files and `memory_vfs`, see the tests.
+/// WAL is not available: neither the wasi VFS nor `memory_vfs` implements the
+/// shared-memory methods (`xShmMap`) the WAL index needs, and the `-wal` file would
+/// not be one of the mounted memories.
+pub const SUPPORTED: &[&str] = &["TRUNCATE", "PERSIST", "MEMORY"];
+
+/// Checks that `mode` is one of the supported journal modes and returns it in canonical form.
+pub fn validate(mode: &str) -> Result<String, Error> {
+    let mode = mode.trim().to_ascii_uppercase();
+
+    if SUPPORTED.contains(&mode.as_str()) {
+        Ok(mode)
+    } else {
+        Err(Error::InvalidArgument {
+            message: format!(
+                "unsupported journal mode '{}', supported modes: {}",
+                mode,
+                SUPPORTED.join(", ")
+            ),
+        })
+    }
+}
+
+/// Switches the database into the given journal mode.
+pub fn apply(db: &Connection, mode: &str) -> Result<String, Error> {
+    let effective: String = db
+        .pragma_update_and_check(None, "journal_mode", mode, |row| row.get(0))
+        .map_err(|err| Error::CanisterError {
+            message: format!("{:?}", err),
+        })?;
+
+    // SQLite reports the old mode when the switch is not possible (e.g. inside a transaction)
+    if !effective.eq_ignore_ascii_case(mode) {
+        return Err(Error::CanisterError {
+            message: format!(
+                "failed to switch journal mode to {}, the current mode is {}",
+                mode, effective
+            ),
+        });
+    }
+
+    Ok(effective.to_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+    use ic_wasi_polyfill::{FileSystem, StableStorage};
+    use rusqlite::OpenFlags;
+
+    use crate::config::Backend;
+    use crate::{
+        wasi_vfs, DB_FILE_NAME, DB_JOURNAL_FILE_NAME, JOURNAL_MEMORY_ID, MOUNTED_MEMORY_ID,
+    };
+
+    const ROWS: i64 = 500;
+
+    // the database and the journal on memories, as mounted by `mount_memory_files`
+    fn mount(backend: Backend) {
+        match backend {
+            Backend::MemoryVfs => {
+                let sizes = Rc::new(DefaultMemoryImpl::default());
+                memory_vfs::mount(
+                    DB_FILE_NAME,
+                    Box::new(DefaultMemoryImpl::default()),
+                    sizes.clone(),
+                    0,
+                );
+                memory_vfs::mount(
+                    DB_JOURNAL_FILE_NAME,
+                    Box::new(DefaultMemoryImpl::default()),
+                    sizes,
+                    1,
+                );
+            }
+            Backend::MountedFile => {
+                let m = MemoryManager::init(DefaultMemoryImpl::default());
+                ic_wasi_polyfill::FS.with(|fs| {
+                    *fs.borrow_mut() =
+                        FileSystem::new(Box::new(StableStorage::new_with_memory_manager(
+                            &m,
+                            crate::WASI_MEMORY_ID..crate::WASI_MEMORY_ID + 10,
+                        )))
+                        .unwrap();
+                });
+                ic_wasi_polyfill::mount_memory_file(
+                    DB_FILE_NAME,
+                    Box::new(m.get(MemoryId::new(MOUNTED_MEMORY_ID))),
+                );
+                ic_wasi_polyfill::mount_memory_file(
+                    DB_JOURNAL_FILE_NAME,
+                    Box::new(m.get(MemoryId::new(JOURNAL_MEMORY_ID))),
+                );
+            }
+            _ => unreachable!("{backend:?} has no mounted files"),
+        }
+    }
+
+    fn open(backend: Backend, mode: &str) -> Connection {
+        let vfs = match backend {
+            Backend::MemoryVfs => memory_vfs::VFS_NAME,
+            _ => {
+                wasi_vfs::register();
+                wasi_vfs::VFS_NAME
+            }
+        };
+        let db =
+            Connection::open_with_flags_and_vfs(DB_FILE_NAME, OpenFlags::default(), vfs).unwrap();
+        db.pragma_update(None, "locking_mode", "EXCLUSIVE").unwrap();
+        db.pragma_update(None, "synchronous", 0).unwrap();
+        apply(&db, mode).unwrap();
+        // a tiny cache forces modified pages to be written to the database file
+        // before the failure, so the rollback really has to come from the journal
+        db.pragma_update(None, "cache_size", 2).unwrap();
+        db
+    }
+
+    fn setup(backend: Backend, mode: &str) -> Connection {
+        mount(backend);
+        let db = open(backend, mode);
+        db.execute_batch(&format!(
+            "CREATE TABLE person (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                data TEXT,
+                age INTEGER
+            );
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {ROWS})
+            INSERT INTO person (name, data, age)
+                SELECT 'person' || i, hex(randomblob(500)), 18 + i % 10 FROM n;"
+        ))
+        .unwrap();
+        db
+    }
+
+    fn checksum(db: &Connection) -> (i64, i64, String) {
+        db.query_row(
+            "SELECT count(*), sum(age), group_concat(name || data) FROM person",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    // copies the table row by row, the statement fails with NOT NULL halfway through
+    const FAILING_INSERT: &str = "INSERT INTO person (name, data, age)
+        SELECT CASE WHEN id > 250 THEN NULL ELSE name END, data, age FROM person";
+
+    /// Returns whether a failing statement and a failing transaction left the data untouched.
+    fn rollback_is_atomic(backend: Backend, mode: &str) -> bool {
+        let mut db = setup(backend, mode);
+        let before = checksum(&db);
+
+        assert!(db.execute(FAILING_INSERT, []).is_err());
+        let statement_ok = checksum(&db) == before;
+
+        {
+            let tx = db.transaction().unwrap();
+            tx.execute("UPDATE person SET age = age + 1, data = 'updated'", [])
+                .unwrap();
+            assert!(tx.execute(FAILING_INSERT, []).is_err());
+            // dropping the transaction rolls it back
+        }
+        let transaction_ok = checksum(&db) == before;
+
+        // what is left in the journal must not be replayed when the database is reopened
+        let after = checksum(&db);
+        drop(db);
+        let db = open(backend, mode);
+        assert_eq!(
+            checksum(&db),
+            after,
+            "{backend:?} {mode}: data changed after reopening"
+        );
+        let integrity: String = db
+            .pragma_query_value(None, "integrity_check", |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+
+        statement_ok && transaction_ok
+    }
+
+    #[test]
+    fn rejects_unsupported_modes() {
+        assert_eq!(validate(" persist ").unwrap(), "PERSIST");
+        assert!(validate("DELETE").is_err());
+        assert!(validate("OFF").is_err());
+        assert!(validate("WAL").is_err());
+        assert!(validate("something").is_err());
+    }
+
+    #[test]
+    fn rollback_in_truncate_mode() {
+        assert!(rollback_is_atomic(Backend::MemoryVfs, "TRUNCATE"));
+        assert!(rollback_is_atomic(Backend::MountedFile, "TRUNCATE"));
+    }
+
+    #[test]
+    fn rollback_in_persist_mode() {
+        assert!(rollback_is_atomic(Backend::MemoryVfs, "PERSIST"));
+        assert!(rollback_is_atomic(Backend::MountedFile, "PERSIST"));
+    }
+
+    #[test]
+    fn rollback_in_memory_mode() {
+        assert!(rollback_is_atomic(Backend::MemoryVfs, "MEMORY"));
+        assert!(rollback_is_atomic(Backend::MountedFile, "MEMORY"));
+    }
+
+    // the reason OFF cannot be selected
+    #[test]
+    fn no_rollback_in_off_mode() {
+        assert!(!rollback_is_atomic(Backend::MemoryVfs, "OFF"));
+        assert!(!rollback_is_atomic(Backend::MountedFile, "OFF"));
+    }
+}
@@ -1,140 +1,753 @@
 use std::cell::RefCell;
+use std::rc::Rc;
+
+mod advisor;
+mod budget;
+mod changes;
+mod changesets;
+mod config;
+mod encryption;
+mod jobs;
+mod journal;
+mod lifecycle;
+mod maintenance;
+mod metrics;
+mod migrate;
+mod plan;
+mod pragmas;
+mod prometheus;
+mod quota;
+mod replay;
+mod replication;
+mod session;
+mod shard;
+mod statements;
+mod storage;
+mod trace;
+mod util;
+#[cfg(test)]
+mod wasi_vfs;
 
 use candid::CandidType;
 use candid::Deserialize;
 use rusqlite::types::Type;
 use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::ToSql;
 
+use config::Backend;
+use encryption::EncryptedMemory;
 use ic_stable_structures::memory_manager::MemoryId;
 use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+use ic_wasi_polyfill::{FileSystem, TransientStorage};
+use replay::SqlValue;
 
 thread_local! {
-    static DB: RefCell<Option<Connection>> = RefCell::new(None);
+    static DB: RefCell<Option<Connection>> = const { RefCell::new(None) };
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 }
 
+const WASI_MEMORY_ID: u8 = 200;
 const MOUNTED_MEMORY_ID: u8 = 20;
+const JOURNAL_MEMORY_ID: u8 = MOUNTED_MEMORY_ID + 1;
+const VFS_SIZES_MEMORY_ID: u8 = MOUNTED_MEMORY_ID + 2;
 const DB_FILE_NAME: &str = "db.db3";
-
+const DB_JOURNAL_FILE_NAME: &str = "db.db3-journal";
+// page size of a new database, the largest one SQLite supports
+const PAGE_SIZE: i64 = 65536;
 
 #[ic_cdk::update]
-fn add(name: String, data: String, age: u32) {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        let db = db.as_mut().unwrap();
-        db.execute(
-            "INSERT INTO person (name, data, age) VALUES (?1, ?2, ?3)",
-            (&name, &data, age),
-        )
-        .unwrap();
-    });
+fn add(name: String, data: String, age: u32) -> Metered<Result> {
+    metered("add", || {
+        check_writable(WriteKind::Rows)?;
+
+        let sql = "INSERT INTO person (name, data, age) VALUES (?1, ?2, ?3)";
+        let params = [
+            SqlValue::Text(name),
+            SqlValue::Text(data),
+            SqlValue::Integer(age as i64),
+        ];
+
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let db = db.as_mut().unwrap();
+            changesets::capture(db, || {
+                db.execute(sql, rusqlite::params_from_iter(&params))
+                    .map_err(quota::map_write_error)
+            })?;
+
+            replay::record(sql, &params, ic_cdk::caller(), ic_cdk::api::time());
+
+            Ok(())
+        })
+    })
 }
 
 #[ic_cdk::query]
-fn list() -> Vec<(u64, String, String, u32)> {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        let db = db.as_mut().unwrap();
-        let mut stmt = db
-            .prepare("SELECT id, name, data, age FROM person")
-            .unwrap();
-        let rows = stmt
-            .query_map([], |row| {
-                Ok((
-                    row.get(0).unwrap(),
-                    row.get(1).unwrap(),
-                    row.get(2).unwrap(),
-                    row.get(3).unwrap(),
-                ))
-            })
-            .unwrap();
-        let mut result = vec![];
-        for person in rows {
-            result.push(person.unwrap());
+fn list() -> Metered<Vec<(u64, String, String, u32)>> {
+    metered("list", || {
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let db = db.as_mut().unwrap();
+            let mut stmt = db
+                .prepare("SELECT id, name, data, age FROM person")
+                .unwrap();
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get(0).unwrap(),
+                        row.get(1).unwrap(),
+                        row.get(2).unwrap(),
+                        row.get(3).unwrap(),
+                    ))
+                })
+                .unwrap();
+            let mut result = vec![];
+            for person in rows {
+                result.push(person.unwrap());
+            }
+            result
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn query(sql: String, allow_partial: Option<bool>) -> Metered<QueryResult> {
+    metered("query", || run_query(&sql, allow_partial))
+}
+
+#[ic_cdk::update]
+fn record_workload(sql: String, allow_partial: Option<bool>) -> Metered<QueryResult> {
+    metered("record_workload", || {
+        check_admin()?;
+
+        // an update call keeps what the statement writes
+        let readonly = DB.with(|db| {
+            let db = db.borrow();
+            let stmt = db.as_ref().unwrap().prepare(&sql);
+            stmt.map(|stmt| stmt.readonly())
+                .map_err(|err| Error::InvalidArgument {
+                    message: err.to_string(),
+                })
+        })?;
+        if !readonly {
+            return Err(Error::InvalidArgument {
+                message: "only read-only statements are recorded".to_string(),
+            });
+        }
+
+        let result = run_query(&sql, allow_partial);
+        if matches!(result, Ok(_) | Err(Error::BudgetExceeded { .. })) {
+            advisor::record(
+                &sql,
+                ic_cdk::api::instruction_counter(),
+                ic_cdk::api::time(),
+            );
         }
         result
     })
 }
 
-#[ic_cdk::query]
-fn query(sql: String) -> QueryResult {
+fn run_query(sql: &str, allow_partial: Option<bool>) -> QueryResult {
+    statements::check_arbitrary_sql()?;
+    let budget = call_budget("query");
+
     DB.with(|db| {
         let mut db = db.borrow_mut();
         let db = db.as_mut().unwrap();
 
-        let mut stmt = db.prepare(&sql).unwrap();
-        let cnt = stmt.column_count();
-        let mut rows = stmt.query([]).unwrap();
         let mut res: Vec<Vec<String>> = Vec::new();
 
-        loop {
-            match rows.next() {
-                Ok(row) => match row {
-                    Some(row) => {
-                        let mut vec: Vec<String> = Vec::new();
-                        for idx in 0..cnt {
-                            let v = row.get_ref_unwrap(idx);
-                            match v.data_type() {
-                                Type::Null => vec.push(String::from("")),
-                                Type::Integer => vec.push(v.as_i64().unwrap().to_string()),
-                                Type::Real => vec.push(v.as_f64().unwrap().to_string()),
-                                Type::Text => vec.push(v.as_str().unwrap().parse().unwrap()),
-                                Type::Blob => vec.push(hex::encode(v.as_blob().unwrap())),
-                            }
-                        }
-                        res.push(vec)
+        // preparing reads the schema, it counts against the budget as well
+        let (result, exceeded) = budget::run(budget, ic_cdk::api::instruction_counter, || {
+            let mut stmt = db.prepare(sql).map_err(|err| Error::InvalidArgument {
+                message: err.to_string(),
+            })?;
+            let cnt = stmt.column_count();
+
+            if let Some(policy) = config::get().full_scan_policy {
+                if budget::caller_role() != budget::CallerRole::Admin {
+                    plan::check_policy(db, sql, &policy)?;
+                }
+            }
+
+            let mut rows = stmt.query([]).map_err(util::to_error)?;
+            while let Some(row) = rows.next().map_err(util::to_error)? {
+                let mut vec: Vec<String> = Vec::new();
+                for idx in 0..cnt {
+                    let v = row.get_ref_unwrap(idx);
+                    match v.data_type() {
+                        Type::Null => vec.push(String::from("")),
+                        Type::Integer => vec.push(v.as_i64().unwrap().to_string()),
+                        Type::Real => vec.push(v.as_f64().unwrap().to_string()),
+                        Type::Text => vec.push(v.as_str().unwrap().parse().unwrap()),
+                        Type::Blob => vec.push(hex::encode(v.as_blob().unwrap())),
                     }
-                    None => break,
-                },
-                Err(err) => {
-                    return Err(Error::CanisterError {
-                        message: format!("{:?}", err),
-                    })
                 }
+                res.push(vec)
             }
+            Ok(())
+        });
+
+        if let Some(exceeded) = exceeded {
+            return Err(Error::BudgetExceeded {
+                budget: exceeded.budget,
+                used: exceeded.used,
+                partial_rows: allow_partial.unwrap_or(false).then_some(res),
+            });
         }
+        result?;
+
         Ok(res)
     })
 }
 
+// the instruction budget of an endpoint for the caller
+fn call_budget(endpoint: &str) -> u64 {
+    budget::budget_for(
+        &config::get().instruction_budgets,
+        endpoint,
+        budget::caller_role(),
+    )
+}
+
+// runs a statement of an endpoint within its instruction budget
+fn run_with_budget<T>(endpoint: &str, f: impl FnOnce() -> rusqlite::Result<T>) -> Result<T> {
+    let budget = call_budget(endpoint);
+
+    match budget::run(budget, ic_cdk::api::instruction_counter, f) {
+        (_, Some(exceeded)) => Err(Error::BudgetExceeded {
+            budget: exceeded.budget,
+            used: exceeded.used,
+            partial_rows: None,
+        }),
+        (result, None) => result.map_err(util::to_error),
+    }
+}
+
+// runs an endpoint, with the cost of the call if the call metrics are enabled; the
+// statements of the call are traced with its caller and the call is counted in the
+// Prometheus metrics
+fn metered<T: CallOutcome>(endpoint: &'static str, f: impl FnOnce() -> T) -> Metered<T> {
+    // the live database has no connection while a rebuilt one is copied over it
+    if replay::activating() && !ACTIVATION_ENDPOINTS.contains(&endpoint) {
+        ic_cdk::trap("the rebuilt database is being activated, try again later");
+    }
+
+    trace::set_caller(Some(ic_cdk::caller()));
+
+    let result = if config::get().call_metrics && !replay::activating() {
+        let snapshot = || {
+            DB.with(|db| {
+                metrics::Snapshot::take(
+                    db.borrow().as_ref().unwrap(),
+                    ic_cdk::api::stable::stable_size(),
+                )
+            })
+        };
+
+        let start = snapshot();
+        let result = f();
+        let metrics = start.until(&snapshot(), ic_cdk::api::instruction_counter());
+        (result, Some(metrics))
+    } else {
+        (f(), None)
+    };
+
+    trace::set_caller(None);
+    prometheus::record_call(
+        endpoint,
+        ic_cdk::api::instruction_counter(),
+        result.0.error().map(Error::code),
+    );
+    result
+}
+
+// the endpoints that do not use the database, so they also work during an activation
+const ACTIVATION_ENDPOINTS: [&str; 2] = ["activate_rebuilt_database", "get_upgrade_status"];
+
+// the result of an endpoint, seen by `metered`
+trait CallOutcome {
+    fn error(&self) -> Option<&Error> {
+        None
+    }
+}
+
+impl<T> CallOutcome for Result<T> {
+    fn error(&self) -> Option<&Error> {
+        self.as_ref().err()
+    }
+}
+
+impl CallOutcome for Vec<(u64, String, String, u32)> {}
+impl CallOutcome for changes::ChangesPage {}
+impl CallOutcome for replication::ReplicationStatus {}
+
+#[ic_cdk::update]
+fn set_call_metrics(enabled: bool) -> Metered<Result> {
+    metered("set_call_metrics", || {
+        check_admin()?;
+
+        config::update(|c| c.call_metrics = enabled);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_statement_trace() -> Metered<Result<Vec<trace::TracedStatement>>> {
+    metered("get_statement_trace", || {
+        check_admin()?;
+
+        Ok(trace::recent())
+    })
+}
+
+#[ic_cdk::update]
+fn set_slow_query_log(slow_query_log: trace::SlowQueryLogConfig) -> Metered<Result> {
+    metered("set_slow_query_log", || {
+        check_admin()?;
+
+        trace::set_slow_query_log(slow_query_log)
+    })
+}
+
+#[ic_cdk::query]
+fn get_slow_queries(start: u64, limit: u64) -> Metered<Result<trace::SlowQueryPage>> {
+    metered("get_slow_queries", || {
+        check_admin()?;
+
+        Ok(trace::slow_queries(start, limit))
+    })
+}
+
+#[ic_cdk::update]
+fn reset_slow_queries() -> Metered<Result<u64>> {
+    metered("reset_slow_queries", || {
+        check_admin()?;
+
+        Ok(trace::reset_slow_queries())
+    })
+}
+
+#[ic_cdk::update]
+fn set_instruction_budgets(budgets: Vec<budget::Budget>) -> Metered<Result> {
+    metered("set_instruction_budgets", || {
+        check_admin()?;
+
+        if budgets.iter().any(|b| b.instructions == 0) {
+            return Err(Error::InvalidArgument {
+                message: "a budget must allow some instructions".to_string(),
+            });
+        }
+        config::update(|c| c.instruction_budgets = budgets);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_instruction_budgets() -> Metered<Result<Vec<budget::Budget>>> {
+    metered("get_instruction_budgets", || {
+        check_admin()?;
+
+        Ok(config::get().instruction_budgets)
+    })
+}
+
+#[ic_cdk::update]
+fn start_job(kind: jobs::JobKind, chunk_rows: Option<u64>) -> Metered<Result<jobs::Job>> {
+    metered("start_job", || {
+        check_admin()?;
+
+        create_job(kind, chunk_rows)
+    })
+}
+
+fn create_job(kind: jobs::JobKind, chunk_rows: Option<u64>) -> Result<jobs::Job> {
+    check_writable(kind.write_kind())?;
+
+    let job = DB.with(|db| {
+        let db = db.borrow();
+        jobs::start(
+            db.as_ref().unwrap(),
+            kind,
+            chunk_rows,
+            ic_cdk::caller(),
+            ic_cdk::api::time(),
+        )
+    })?;
+    jobs::schedule();
+
+    Ok(job)
+}
+
+#[ic_cdk::query]
+fn get_job(id: u64) -> Metered<Result<jobs::Job>> {
+    metered("get_job", || {
+        check_admin()?;
+
+        DB.with(|db| jobs::get(db.borrow().as_ref().unwrap(), id))
+    })
+}
+
+#[ic_cdk::query]
+fn list_jobs() -> Metered<Result<Vec<jobs::Job>>> {
+    metered("list_jobs", || {
+        check_admin()?;
+
+        DB.with(|db| jobs::list(db.borrow().as_ref().unwrap()))
+    })
+}
+
+#[ic_cdk::update]
+fn cancel_job(id: u64) -> Metered<Result<jobs::Job>> {
+    metered("cancel_job", || {
+        check_admin()?;
+        check_writable(WriteKind::Shrinks)?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            jobs::cancel(
+                db.as_ref().unwrap(),
+                id,
+                ic_cdk::caller(),
+                ic_cdk::api::time(),
+            )
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn retry_job(id: u64) -> Metered<Result<jobs::Job>> {
+    metered("retry_job", || {
+        check_admin()?;
+        let kind = DB
+            .with(|db| jobs::get(db.borrow().as_ref().unwrap(), id))?
+            .kind;
+        check_writable(kind.write_kind())?;
+
+        let job =
+            DB.with(|db| jobs::retry(db.borrow().as_ref().unwrap(), id, ic_cdk::api::time()))?;
+        jobs::schedule();
+
+        Ok(job)
+    })
+}
+
+#[ic_cdk::update]
+fn register_statement(statement: statements::NamedStatement) -> Metered<Result> {
+    metered("register_statement", || {
+        check_admin()?;
+
+        DB.with(|db| statements::validate(db.borrow().as_ref().unwrap(), &statement))?;
+        config::update(|c| c.statements.insert(statement.name.clone(), statement));
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn remove_statement(name: String) -> Metered<Result<bool>> {
+    metered("remove_statement", || {
+        check_admin()?;
+
+        Ok(config::update(|c| c.statements.remove(&name)).is_some())
+    })
+}
+
+#[ic_cdk::query]
+fn list_statements() -> Metered<Result<Vec<statements::NamedStatement>>> {
+    metered("list_statements", || {
+        check_admin()?;
+
+        Ok(config::get().statements.into_values().collect())
+    })
+}
+
+#[ic_cdk::update]
+fn set_statements_only(enabled: bool) -> Metered<Result> {
+    metered("set_statements_only", || {
+        check_admin()?;
+
+        config::update(|c| c.statements_only = enabled);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn query_statement(name: String, args: Vec<SqlValue>) -> Metered<Result<Vec<Vec<SqlValue>>>> {
+    metered("query_statement", || {
+        let statement = statements::get(&name, statements::Access::Read)?;
+        statements::check_args(&statement, &args)?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+            run_with_budget("query_statement", || {
+                statements::query(db, &statement, &args)
+            })
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn execute_statement(name: String, args: Vec<SqlValue>) -> Metered<Result<u64>> {
+    metered("execute_statement", || {
+        let statement = statements::get(&name, statements::Access::Write)?;
+        statements::check_args(&statement, &args)?;
+        check_writable(WriteKind::of_sql(&statement.sql))?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+            let (changed, _) = changesets::capture(db, || {
+                statements::execute(db, &statement, &args).map_err(quota::map_write_error)
+            })?;
+
+            replay::record(&statement.sql, &args, ic_cdk::caller(), ic_cdk::api::time());
+            advisor::record(
+                &statement.sql,
+                ic_cdk::api::instruction_counter(),
+                ic_cdk::api::time(),
+            );
+
+            Ok(changed as u64)
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn explain_query_plan(sql: String) -> Metered<Result<plan::QueryPlan>> {
+    metered("explain_query_plan", || {
+        if budget::caller_role() != budget::CallerRole::Admin {
+            statements::check_arbitrary_sql()?;
+        }
+
+        DB.with(|db| {
+            plan::explain(db.borrow().as_ref().unwrap(), &sql).map_err(|err| {
+                Error::InvalidArgument {
+                    message: err.to_string(),
+                }
+            })
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn set_full_scan_policy(policy: Option<plan::FullScanPolicy>) -> Metered<Result> {
+    metered("set_full_scan_policy", || {
+        check_admin()?;
+
+        config::update(|c| c.full_scan_policy = policy);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_workload() -> Metered<Result<Vec<advisor::QueryShape>>> {
+    metered("get_workload", || {
+        check_admin()?;
+
+        Ok(advisor::workload())
+    })
+}
+
+#[ic_cdk::update]
+fn clear_workload() -> Metered<Result> {
+    metered("clear_workload", || {
+        check_admin()?;
+
+        advisor::clear();
+
+        Ok(())
+    })
+}
+
+fn index_suggestions() -> Result<Vec<advisor::IndexSuggestion>> {
+    DB.with(|db| {
+        advisor::suggest(db.borrow().as_ref().unwrap(), &advisor::workload()).map_err(|err| {
+            Error::CanisterError {
+                message: format!("{:?}", err),
+            }
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn suggest_indexes() -> Metered<Result<Vec<advisor::IndexSuggestion>>> {
+    metered("suggest_indexes", || {
+        check_admin()?;
+
+        index_suggestions()
+    })
+}
+
+#[ic_cdk::update]
+fn apply_index_suggestion(name: String, chunk_rows: Option<u64>) -> Metered<Result<jobs::Job>> {
+    metered("apply_index_suggestion", || {
+        check_admin()?;
+
+        let suggestion = index_suggestions()?
+            .into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| Error::InvalidArgument {
+                message: format!("there is no suggestion {}", name),
+            })?;
+
+        create_job(
+            jobs::JobKind::CreateIndex {
+                name: suggestion.name,
+                table: suggestion.table,
+                columns: suggestion.columns,
+                unique: false,
+            },
+            chunk_rows,
+        )
+    })
+}
+
+#[ic_cdk::update]
+fn set_maintenance(maintenance: maintenance::MaintenanceConfig) -> Metered<Result> {
+    metered("set_maintenance", || {
+        check_admin()?;
+
+        DB.with(|db| maintenance::validate(db.borrow().as_ref().unwrap(), &maintenance))?;
+        config::update(|c| c.maintenance = maintenance);
+        maintenance::start_timer();
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_maintenance_status() -> Metered<Result<maintenance::MaintenanceStatus>> {
+    metered("get_maintenance_status", || {
+        check_admin()?;
+
+        Ok(maintenance::status())
+    })
+}
+
+#[ic_cdk::update]
+fn run_maintenance() -> Metered<Result<Vec<maintenance::TaskRun>>> {
+    metered("run_maintenance", || {
+        check_admin()?;
+
+        maintenance::run_now()
+    })
+}
 
 fn mount_memory_files() {
+    let backend = config::get().backend;
+
+    if backend == Backend::Heap {
+        // nothing is written to stable memory, the tables are recreated after every upgrade
+        ic_wasi_polyfill::FS.with(|fs| {
+            *fs.borrow_mut() = FileSystem::new(Box::new(TransientStorage::new())).unwrap();
+        });
+        ic_wasi_polyfill::init(&[0u8; 32], &[]);
+        return;
+    }
+
     MEMORY_MANAGER.with(|m| {
         let m = m.borrow();
-        ic_wasi_polyfill::init_with_memory_manager(&[0u8; 32], &[], &m, 200..210);
+        ic_wasi_polyfill::init_with_memory_manager(
+            &[0u8; 32],
+            &[],
+            &m,
+            WASI_MEMORY_ID..WASI_MEMORY_ID + 10,
+        );
 
-        // mount virtual memory as file for faster DB operations
+        // the pages are encrypted when an encryption key is installed
         let memory = m.get(MemoryId::new(MOUNTED_MEMORY_ID));
-        ic_wasi_polyfill::mount_memory_file(DB_FILE_NAME, Box::new(memory));
+        let db_memory = EncryptedMemory::new(memory, MOUNTED_MEMORY_ID);
+
+        // the rollback journal is written on every transaction, keep it on its own memory as well
+        let memory = m.get(MemoryId::new(JOURNAL_MEMORY_ID));
+        let journal_memory = EncryptedMemory::new(memory, JOURNAL_MEMORY_ID);
+
+        match backend {
+            Backend::MountedFile => {
+                // mount virtual memory as file for faster DB operations
+                ic_wasi_polyfill::mount_memory_file(DB_FILE_NAME, Box::new(db_memory));
+                ic_wasi_polyfill::mount_memory_file(DB_JOURNAL_FILE_NAME, Box::new(journal_memory));
+            }
+            Backend::MemoryVfs => {
+                // SQLite reads and writes the memories directly, bypassing the WASI layer
+                let sizes = Rc::new(m.get(MemoryId::new(VFS_SIZES_MEMORY_ID)));
+                memory_vfs::mount(DB_FILE_NAME, Box::new(db_memory), sizes.clone(), 0);
+                memory_vfs::mount(DB_JOURNAL_FILE_NAME, Box::new(journal_memory), sizes, 1);
+            }
+            Backend::StableFsFile(chunk_type) => {
+                // the chunk type only applies to files created after it is set
+                ic_wasi_polyfill::FS.with(|fs| {
+                    fs.borrow_mut().storage.set_chunk_type(chunk_type.into());
+                });
+            }
+            Backend::Heap => unreachable!(),
+        }
     });
+
+    migrate::mount_staging_file();
+    replay::mount_snapshot_files();
 }
 
 fn open_database() {
     DB.with(|db| {
         let mut db = db.borrow_mut();
-        *db = Some(Connection::open(DB_FILE_NAME).unwrap());
+        *db = Some(match config::get().backend {
+            Backend::MemoryVfs => Connection::open_with_flags_and_vfs(
+                DB_FILE_NAME,
+                OpenFlags::default(),
+                memory_vfs::VFS_NAME,
+            )
+            .unwrap(),
+            _ => Connection::open(DB_FILE_NAME).unwrap(),
+        });
+        changes::install_hooks(db.as_ref().unwrap());
+        budget::install_handler(db.as_ref().unwrap());
+        trace::install(
+            db.as_ref().unwrap(),
+            ic_cdk::api::instruction_counter,
+            ic_cdk::api::time,
+        );
+        db.as_ref()
+            .unwrap()
+            .set_prepared_statement_cache_capacity(statements::CACHE_CAPACITY);
     });
-
 }
 
 fn create_tables() {
     DB.with(|db| {
-        let mut db = db.borrow_mut();
-        let db = db.as_mut().unwrap();
-        db.execute(
-            "CREATE TABLE person IF NOT EXISTS (
-                id    INTEGER PRIMARY KEY,
-                name  TEXT NOT NULL,
-                data  TEXT,
-                age   INTEGER
-           )",
-            (), // empty list of parameters.
-        )
-        .unwrap();
+        let db = db.borrow();
+        create_tables_in(db.as_ref().unwrap()).unwrap();
     });
 }
 
+// also creates the tables of a database rebuilt from the replay log
+fn create_tables_in(db: &Connection) -> rusqlite::Result<()> {
+    create_schema(db)?;
+    jobs::create_table(db)
+}
+
+fn create_schema(db: &Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS person (
+            id    INTEGER PRIMARY KEY,
+            name  TEXT NOT NULL,
+            data  TEXT,
+            age   INTEGER
+       )",
+        (), // empty list of parameters.
+    )?;
+
+    Ok(())
+}
+
 fn set_pragmas() {
     // set pragmas
     DB.with(|db| {
@@ -142,48 +755,759 @@ fn set_pragmas() {
         let db = db.as_mut().unwrap();
 
         // do not create and destroy the journal file every time, set its size to 0 instead
-        db.pragma_update(None, "journal_mode", &"TRUNCATE" as &dyn ToSql).unwrap();
-        
+        // a mode that is no longer supported (e.g. OFF) falls back to the default
+        let journal_mode = config::get()
+            .journal_mode
+            .and_then(|mode| journal::validate(&mode).ok())
+            .unwrap_or_else(|| journal::DEFAULT_MODE.to_string());
+        journal::apply(db, &journal_mode).unwrap();
+
         // reduce synchronizations
-        db.pragma_update(None, "synchronous", &0 as &dyn ToSql).unwrap();
-        
-        // use fewer writes to disk with larger memory chunks
-        // Note: values above 16384 cause I/O errors for some reason
-        db.pragma_update(None, "page_size", &16384 as &dyn ToSql).unwrap();
+        db.pragma_update(None, "synchronous", &0 as &dyn ToSql)
+            .unwrap();
+
+        // use fewer writes to disk with larger memory chunks, only applies to a new database,
+        // an existing one keeps its page size until `set_pragma` changes it and a VACUUM runs
+        db.pragma_update(None, "page_size", &PAGE_SIZE as &dyn ToSql)
+            .unwrap();
 
         // reduce locks and unlocks
-        db.pragma_update(None, "locking_mode", &"EXCLUSIVE" as &dyn ToSql).unwrap();
-        
-        // temp_store = MEMORY, disables creating temp files, improves performance, 
+        db.pragma_update(None, "locking_mode", &"EXCLUSIVE" as &dyn ToSql)
+            .unwrap();
+
+        // temp_store = MEMORY, disables creating temp files, improves performance,
         // this workaround also avoids sqlite error on complex queries
-        db.pragma_update(None, "temp_store", &2 as &dyn ToSql).unwrap();
+        db.pragma_update(None, "temp_store", &2 as &dyn ToSql)
+            .unwrap();
+
+        // apply the values tuned at runtime with `set_pragma` (e.g. a larger cache_size)
+        for (name, value) in config::get().pragmas {
+            if let Err(err) = pragmas::apply(db, &name, value) {
+                ic_cdk::eprintln!("failed to apply pragma {} = {}: {:?}", name, value, err);
+            }
+        }
+
+        // let SQLite refuse to grow the database past the storage quota
+        if let Err(err) = quota::apply_page_limit(db, config::get().storage_quota.as_ref()) {
+            ic_cdk::eprintln!("failed to apply the storage quota: {:?}", err);
+        }
+    });
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
 
-        // add this option to minimize disk reads and work in canister memory instead
-        //db.pragma_update(None, "cache_size", &1000000 as &dyn ToSql).unwrap();
-    });    
+fn text_response(status_code: u16, content_type: &str, body: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body: body.into_bytes(),
+    }
+}
+
+// the HTTP gateway interface, without the metrics envelope of the other endpoints
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    if path != "/metrics" {
+        return text_response(404, "text/plain", "not found\n".to_string());
+    }
+    if request.method != "GET" {
+        return text_response(405, "text/plain", "method not allowed\n".to_string());
+    }
+
+    if replay::activating() {
+        return text_response(
+            503,
+            "text/plain",
+            "the rebuilt database is being activated\n".to_string(),
+        );
+    }
+
+    let metrics = DB.with(|db| prometheus::render(db.borrow().as_ref().unwrap()));
+    match metrics {
+        Ok(metrics) => text_response(200, "text/plain; version=0.0.4", metrics),
+        Err(err) => text_response(500, "text/plain", format!("{:?}\n", err)),
+    }
+}
+
+/// What a write endpoint does to the database, see `check_writable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WriteKind {
+    /// Writes rows of a caller, which may grow the database.
+    Rows,
+    /// Only deletes rows or stops work, accepted past the soft limit of the quota.
+    Shrinks,
+    /// Applies the changes of the primary on a follower.
+    Replica,
+    /// Vacuum, pragmas and other changes that keep the rows, also run on a follower.
+    Maintenance,
+}
+
+impl WriteKind {
+    fn of_sql(sql: &str) -> WriteKind {
+        match sql.trim_start().get(..6) {
+            Some(keyword) if keyword.eq_ignore_ascii_case("DELETE") => WriteKind::Shrinks,
+            _ => WriteKind::Rows,
+        }
+    }
+}
+
+/// Guard of every endpoint that changes the database: a follower only changes through
+/// `replicate`, nothing changes while the database is migrated, activated or waits for
+/// its recovery, and only deletes and maintenance are accepted past the soft limit.
+fn check_writable(kind: WriteKind) -> Result {
+    if matches!(kind, WriteKind::Rows | WriteKind::Shrinks) {
+        replication::check_writable()?;
+    }
+    migrate::check_writable()?;
+    if matches!(kind, WriteKind::Rows | WriteKind::Replica) {
+        DB.with(|db| quota::check_writable(db.borrow().as_ref().unwrap()))?;
+    }
+    Ok(())
+}
+
+fn check_admin() -> Result {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+#[ic_cdk::query]
+fn get_pragmas() -> Metered<Result<Vec<(String, String)>>> {
+    metered("get_pragmas", || {
+        check_admin()?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            pragmas::read_all(db).map_err(|err| Error::CanisterError {
+                message: format!("{:?}", err),
+            })
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn set_pragma(name: String, value: String) -> Metered<Result<String>> {
+    metered("set_pragma", || {
+        check_admin()?;
+        check_writable(WriteKind::Maintenance)?;
+
+        let (name, value) = pragmas::validate(&name, &value)?;
+
+        let effective = DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            pragmas::apply(db, &name, value).map_err(|err| Error::CanisterError {
+                message: format!("{:?}", err),
+            })
+        })?;
+
+        config::update(|c| c.pragmas.insert(name, value));
+
+        Ok(effective)
+    })
+}
+
+#[ic_cdk::update]
+fn set_journal_mode(mode: String) -> Metered<Result<String>> {
+    metered("set_journal_mode", || {
+        check_admin()?;
+        check_writable(WriteKind::Maintenance)?;
+
+        let mode = journal::validate(&mode)?;
+
+        let effective = DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            journal::apply(db, &mode)
+        })?;
+
+        config::update(|c| c.journal_mode = Some(mode));
+
+        Ok(effective)
+    })
+}
+
+#[ic_cdk::query]
+fn get_storage_stats() -> Metered<Result<storage::StorageStats>> {
+    metered("get_storage_stats", || {
+        check_admin()?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            storage::stats(db).map_err(|err| Error::CanisterError {
+                message: format!("{:?}", err),
+            })
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn vacuum() -> Metered<Result<storage::VacuumProgress>> {
+    metered("vacuum", || {
+        check_admin()?;
+        check_writable(WriteKind::Maintenance)?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            let progress = storage::vacuum(db).map_err(|err| Error::CanisterError {
+                message: format!("{:?}", err),
+            })?;
+
+            // the page limit depends on the page size, which may have been changed by the vacuum
+            quota::apply_page_limit(db, config::get().storage_quota.as_ref()).map_err(|err| {
+                Error::CanisterError {
+                    message: format!("{:?}", err),
+                }
+            })?;
+
+            Ok(progress)
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn incremental_vacuum(max_pages: u64) -> Metered<Result<storage::VacuumProgress>> {
+    metered("incremental_vacuum", || {
+        check_admin()?;
+        check_writable(WriteKind::Maintenance)?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            storage::incremental_vacuum(db, max_pages, ic_cdk::api::instruction_counter)
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn set_storage_quota(storage_quota: Option<quota::StorageQuota>) -> Metered<Result<u64>> {
+    metered("set_storage_quota", || {
+        check_admin()?;
+
+        if let Some(q) = &storage_quota {
+            quota::validate(q)?;
+        }
+
+        let max_page_count = DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            quota::apply_page_limit(db, storage_quota.as_ref()).map_err(|err| {
+                Error::CanisterError {
+                    message: format!("{:?}", err),
+                }
+            })
+        })?;
+
+        config::update(|c| c.storage_quota = storage_quota);
+
+        Ok(max_page_count)
+    })
+}
+
+#[ic_cdk::update]
+fn rotate_encryption_key(next_key: Option<String>) -> Metered<Result<Option<String>>> {
+    metered("rotate_encryption_key", || {
+        check_admin()?;
+        check_writable(WriteKind::Maintenance)?;
+
+        let backend = config::get().backend;
+        if !backend.supports_encryption() {
+            return Err(Error::InvalidArgument {
+                message: format!("the {:?} backend cannot be encrypted", backend),
+            });
+        }
+
+        encryption::rotate_key(next_key.as_deref())
+    })
+}
+
+#[ic_cdk::query]
+fn get_upgrade_status() -> Metered<Result<Option<lifecycle::UpgradeStatus>>> {
+    metered("get_upgrade_status", || {
+        check_admin()?;
+
+        Ok(config::get().last_upgrade)
+    })
+}
+
+#[ic_cdk::update]
+fn resume_writes() -> Metered<Result> {
+    metered("resume_writes", || {
+        check_admin()?;
+
+        lifecycle::resume_writes()
+    })
+}
+
+#[ic_cdk::update]
+fn set_replay_log(enabled: bool) -> Metered<Result> {
+    metered("set_replay_log", || {
+        check_admin()?;
+
+        DB.with(|db| replay::set_enabled(db.borrow().as_ref().unwrap(), enabled))
+    })
+}
+
+#[ic_cdk::query]
+fn get_replay_log(start: u64, limit: u64) -> Metered<Result<replay::LogPage>> {
+    metered("get_replay_log", || {
+        check_admin()?;
+
+        Ok(replay::page(start, limit))
+    })
+}
+
+#[ic_cdk::update]
+fn truncate_replay_log(up_to: u64) -> Metered<Result<replay::TruncateProgress>> {
+    metered("truncate_replay_log", || {
+        check_admin()?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            replay::truncate(db, up_to, ic_cdk::api::instruction_counter)
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn rebuild_from_replay_log() -> Metered<Result<replay::RebuildProgress>> {
+    metered("rebuild_from_replay_log", || {
+        check_admin()?;
+
+        replay::rebuild(ic_cdk::api::instruction_counter)
+    })
+}
+
+#[ic_cdk::update]
+fn activate_rebuilt_database() -> Metered<Result<replay::ActivationProgress>> {
+    metered("activate_rebuilt_database", || {
+        check_admin()?;
+
+        replay::activate_rebuild(ic_cdk::api::instruction_counter)
+    })
+}
+
+#[ic_cdk::query]
+fn changes_since(seq: u64, limit: u64) -> Metered<changes::ChangesPage> {
+    metered("changes_since", || changes::changes_since(seq, limit))
+}
+
+#[ic_cdk::update]
+fn set_change_log_retention(retention: u64) -> Metered<Result> {
+    metered("set_change_log_retention", || {
+        check_admin()?;
+
+        changes::set_retention(retention)
+    })
+}
+
+#[ic_cdk::update]
+fn set_changeset_capture(enabled: bool) -> Metered<Result> {
+    metered("set_changeset_capture", || {
+        check_admin()?;
+
+        changesets::set_enabled(enabled);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn set_changeset_retention(retention: u64) -> Metered<Result> {
+    metered("set_changeset_retention", || {
+        check_admin()?;
+
+        changesets::set_retention(retention)
+    })
+}
+
+#[ic_cdk::query]
+fn changeset_since(checkpoint: u64, patchset: bool) -> Metered<Result<changesets::ChangesetPage>> {
+    metered("changeset_since", || {
+        check_admin()?;
+
+        changesets::changeset_since(checkpoint, patchset)
+    })
+}
+
+#[ic_cdk::update]
+fn truncate_changesets(up_to: u64) -> Metered<Result> {
+    metered("truncate_changesets", || {
+        check_admin()?;
+
+        changesets::truncate(up_to);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn apply_changeset(
+    changeset: Vec<u8>,
+    policy: changesets::ConflictPolicy,
+) -> Metered<Result<changesets::ApplyReport>> {
+    metered("apply_changeset", || {
+        check_admin()?;
+        check_writable(WriteKind::Rows)?;
+
+        if config::get().replay_log.enabled {
+            return Err(Error::InvalidArgument {
+                message:
+                    "the replay log only records SQL statements, disable it to apply changesets"
+                        .to_string(),
+            });
+        }
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            changesets::apply(db, &changeset, policy)
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn set_replication_role(role: replication::Role, max_lag: Option<u64>) -> Metered<Result> {
+    metered("set_replication_role", || {
+        check_admin()?;
+
+        replication::set_role(role, max_lag);
 
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn add_follower(follower: candid::Principal) -> Metered<Result> {
+    metered("add_follower", || {
+        check_admin()?;
+
+        replication::add_follower(follower);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn remove_follower(follower: candid::Principal) -> Metered<Result> {
+    metered("remove_follower", || {
+        check_admin()?;
+
+        replication::remove_follower(follower);
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn replicate(batch: replication::Batch) -> Metered<Result<replication::FollowerStatus>> {
+    metered("replicate", || {
+        check_writable(WriteKind::Replica)?;
+
+        DB.with(|db| {
+            let db = db.borrow();
+            let db = db.as_ref().unwrap();
+
+            replication::replicate(db, ic_cdk::caller(), &batch)
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_replication_status() -> Metered<replication::ReplicationStatus> {
+    metered("get_replication_status", replication::status)
+}
+
+// runs the statements of one write in a transaction, captured and logged like `add`
+fn execute_writes(kind: WriteKind, statements: &[(&str, &[SqlValue])]) -> Result<usize> {
+    check_writable(kind)?;
+
+    DB.with(|db| {
+        let db = db.borrow();
+        let db = db.as_ref().unwrap();
+
+        let (changed, _) = changesets::capture(db, || {
+            let tx = db.unchecked_transaction().map_err(quota::map_write_error)?;
+            let mut changed = 0;
+            for (sql, params) in statements {
+                changed += tx
+                    .execute(sql, rusqlite::params_from_iter(params.iter()))
+                    .map_err(quota::map_write_error)?;
+            }
+            tx.commit().map_err(quota::map_write_error)?;
+            Ok(changed)
+        })?;
+
+        for (sql, params) in statements {
+            replay::record(sql, params, ic_cdk::caller(), ic_cdk::api::time());
+        }
+
+        Ok(changed)
+    })
+}
+
+#[ic_cdk::query]
+fn get_person(id: u64) -> Metered<Result<Option<shard::Person>>> {
+    metered("get_person", || {
+        DB.with(|db| shard::get(db.borrow().as_ref().unwrap(), id).map_err(util::to_error))
+    })
+}
+
+#[ic_cdk::query]
+fn list_people(
+    order: shard::PersonOrder,
+    after: Option<shard::Person>,
+    limit: u32,
+) -> Metered<Result<Vec<shard::Person>>> {
+    metered("list_people", || {
+        DB.with(|db| {
+            run_with_budget("list_people", || {
+                shard::list(db.borrow().as_ref().unwrap(), order, after.as_ref(), limit)
+            })
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_key_range(
+    start: u64,
+    end: u64,
+    after_id: Option<u64>,
+    limit: u32,
+) -> Metered<Result<Vec<shard::Person>>> {
+    metered("get_key_range", || {
+        check_admin()?;
+
+        if start >= end || end > shard::KEY_SPACE {
+            return Err(Error::InvalidArgument {
+                message: format!("invalid key range {}..{}", start, end),
+            });
+        }
+
+        DB.with(|db| {
+            run_with_budget("get_key_range", || {
+                shard::key_range(db.borrow().as_ref().unwrap(), start, end, after_id, limit)
+            })
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn put_people(people: Vec<shard::Person>) -> Metered<Result> {
+    metered("put_people", || {
+        check_admin()?;
+
+        let params: Vec<[SqlValue; 4]> = people.iter().map(shard::Person::params).collect();
+        let statements: Vec<(&str, &[SqlValue])> = params
+            .iter()
+            .map(|params| (shard::PUT_SQL, params.as_slice()))
+            .collect();
+        execute_writes(WriteKind::Rows, &statements)?;
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn delete_person(id: u64) -> Metered<Result<bool>> {
+    metered("delete_person", || {
+        check_admin()?;
+
+        let params = [SqlValue::Integer(id as i64)];
+        Ok(execute_writes(WriteKind::Shrinks, &[(shard::DELETE_SQL, &params)])? > 0)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_key_range(start: u64, end: u64, limit: u32) -> Metered<Result<u64>> {
+    metered("delete_key_range", || {
+        check_admin()?;
+
+        let (sql, params) = shard::delete_key_range_sql(start, end, limit);
+        Ok(execute_writes(WriteKind::Shrinks, &[(&sql, &params)])? as u64)
+    })
+}
+
+#[ic_cdk::update]
+fn migrate_backend(target: Backend) -> Metered<Result<migrate::Migration>> {
+    metered("migrate_backend", || {
+        check_admin()?;
+
+        migrate::step(target, ic_cdk::api::instruction_counter)
+    })
+}
+
+#[ic_cdk::update]
+fn cancel_backend_migration() -> Metered<Result> {
+    metered("cancel_backend_migration", || {
+        check_admin()?;
+
+        migrate::cancel()
+    })
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct InitArgs {
+    /// Hex encoded key material for the database pages, at least 32 bytes.
+    encryption_key: Option<String>,
+    /// The key being rotated to, only needed when upgrading during a key rotation.
+    next_encryption_key: Option<String>,
+    /// Storage backend of a new database, ignored on upgrades.
+    backend: Option<Backend>,
 }
 
 #[ic_cdk::init]
-fn init() {
+fn init(args: Option<InitArgs>) {
+    let args = args.unwrap_or_default();
+    let backend = args.backend.unwrap_or_default();
+    if args.encryption_key.is_some() && !backend.supports_encryption() {
+        ic_cdk::trap(&format!("the {:?} backend cannot be encrypted", backend));
+    }
+
+    encryption::init(args.encryption_key.as_deref())
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("{:?}", err)));
+    config::update(|c| c.backend = backend);
+
     mount_memory_files();
     open_database();
     set_pragmas();
     create_tables();
 }
 
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    replay::abort_snapshot();
+    replay::abort_activation();
+    if let Some(db) = DB.with(|db| db.borrow_mut().take()) {
+        lifecycle::record_shutdown(lifecycle::shutdown(
+            db,
+            ic_cdk::api::time(),
+            ic_cdk::api::instruction_counter,
+        ));
+    }
+}
+
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
+    let args = args.unwrap_or_default();
+    encryption::post_upgrade(
+        args.encryption_key.as_deref(),
+        args.next_encryption_key.as_deref(),
+    )
+    .unwrap_or_else(|err| ic_cdk::trap(&format!("{:?}", err)));
+
     mount_memory_files();
     open_database();
+
+    let marker = lifecycle::take_shutdown_marker();
+    let status = DB.with(|db| {
+        lifecycle::recover(
+            db.borrow().as_ref().unwrap(),
+            marker,
+            ic_cdk::api::instruction_counter,
+        )
+    });
+    config::update(|c| c.last_upgrade = Some(status));
+
     set_pragmas();
-}
+    create_tables();
 
+    replication::start_timer();
+    jobs::schedule();
+    maintenance::start_timer();
+    lifecycle::schedule();
+}
 
-#[derive(CandidType, Deserialize)]
+// variant names are part of the public candid interface
+#[allow(clippy::enum_variant_names)]
+#[derive(CandidType, Deserialize, Debug)]
 enum Error {
     InvalidCanister,
-    CanisterError { message: String },
+    CanisterError {
+        message: String,
+    },
+    Unauthorized,
+    InvalidArgument {
+        message: String,
+    },
+    StorageFull,
+    QuotaExceeded {
+        used_bytes: u64,
+        soft_limit_bytes: u64,
+    },
+    MigrationInProgress {
+        target: Backend,
+    },
+    ReadOnlyReplica {
+        primary: candid::Principal,
+    },
+    DatabaseDamaged {
+        check: String,
+    },
+    BudgetExceeded {
+        budget: u64,
+        used: u64,
+        partial_rows: Option<Vec<Vec<String>>>,
+    },
+    ArbitrarySqlDisabled,
+    FullScanRejected {
+        table: String,
+        estimated_rows: u64,
+        max_rows: u64,
+    },
 }
 
+impl Error {
+    // the name of the variant, the `code` label of the error counters
+    fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidCanister => "InvalidCanister",
+            Error::CanisterError { .. } => "CanisterError",
+            Error::Unauthorized => "Unauthorized",
+            Error::InvalidArgument { .. } => "InvalidArgument",
+            Error::StorageFull => "StorageFull",
+            Error::QuotaExceeded { .. } => "QuotaExceeded",
+            Error::MigrationInProgress { .. } => "MigrationInProgress",
+            Error::ReadOnlyReplica { .. } => "ReadOnlyReplica",
+            Error::DatabaseDamaged { .. } => "DatabaseDamaged",
+            Error::BudgetExceeded { .. } => "BudgetExceeded",
+            Error::ArbitrarySqlDisabled => "ArbitrarySqlDisabled",
+            Error::FullScanRejected { .. } => "FullScanRejected",
+        }
+    }
+}
+
+type Result<T = (), E = Error> = std::result::Result<T, E>;
+
 type QueryResult<T = Vec<Vec<String>>, E = Error> = std::result::Result<T, E>;
+
+type Metered<T> = (T, Option<metrics::CallMetrics>);
@@ -0,0 +1,411 @@
+//! Clean shutdown before an upgrade and recovery after an unclean one.
+//!
+//! `pre_upgrade` rolls back a transaction left open, checks the database, closes the
+//! connection and stores a [`ShutdownMarker`] in the config. `post_upgrade` consumes
+//! the marker: without one (a skipped or trapped `pre_upgrade`, or an upgrade from a
+//! version without this hook) the database is treated like after a crash. Its hot
+//! journal, if any, is rolled back and the database is checked again.
+//!
+//! Both checks run within `CHECK_BUDGET` instructions, so that a large database cannot
+//! make the upgrade trap; a check that runs out is recorded as `CHECK_INTERRUPTED` and
+//! does not count as a failure. A database that fails the check after an unclean
+//! shutdown refuses writes. If the replay log is enabled, a timer rebuilds it from the
+//! log and its snapshot; the rebuilt database only replaces the damaged one once a
+//! controller calls `activate_rebuilt_database`. Writes are accepted again after that,
+//! or once a controller calls `resume_writes`.
+//!
+//! A running key rotation or backend migration keeps its state in the config and
+//! simply continues after the upgrade.
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk_timers::TimerId;
+use rusqlite::Connection;
+
+use crate::{budget, config, replay, Error};
+
+/// Instructions each of the checks of an upgrade may use.
+pub const CHECK_BUDGET: u64 = 20_000_000_000;
+
+/// The result of a check that ran out of instructions.
+pub const CHECK_INTERRUPTED: &str = "interrupted";
+
+const REBUILD_INTERVAL: Duration = Duration::from_secs(1);
+
+thread_local! {
+    static TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShutdownMarker {
+    /// Time of the `pre_upgrade` call in nanoseconds.
+    pub time: u64,
+    /// A transaction was still open and has been rolled back.
+    pub rolled_back: bool,
+    /// Result of `PRAGMA quick_check` before the connection was closed.
+    pub quick_check: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpgradeStatus {
+    pub clean_shutdown: bool,
+    pub shutdown: Option<ShutdownMarker>,
+    /// Result of the check run by the recovery, `None` after a clean shutdown.
+    pub recovery_check: Option<String>,
+    /// Set once the recovery check failed, writes are refused until it is `Rebuilt`.
+    #[serde(default)]
+    pub recovery: Option<Recovery>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// A timer replays the log into the snapshot, a controller then activates it.
+    Rebuilding,
+    /// The rebuilt database replaced the damaged one.
+    Rebuilt,
+    /// The database could not be rebuilt, `resume_writes` accepts it as it is.
+    Failed { message: String },
+}
+
+fn quick_check(db: &Connection, counter: fn() -> u64) -> String {
+    let (check, exceeded) = budget::run(CHECK_BUDGET, counter, || {
+        db.query_row("PRAGMA quick_check(1)", [], |row| row.get(0))
+            .unwrap_or_else(|err| format!("{:?}", err))
+    });
+    match exceeded {
+        Some(_) => CHECK_INTERRUPTED.to_string(),
+        None => check,
+    }
+}
+
+fn passed(check: &str) -> bool {
+    check == "ok" || check == CHECK_INTERRUPTED
+}
+
+/// Closes the connection and returns the marker to store for `post_upgrade`.
+pub fn shutdown(db: Connection, time: u64, counter: fn() -> u64) -> ShutdownMarker {
+    // the journal is empty once no transaction is open
+    let rolled_back = !db.is_autocommit();
+    if rolled_back {
+        if let Err(err) = db.execute_batch("ROLLBACK") {
+            ic_cdk::eprintln!("pre_upgrade: rollback failed: {:?}", err);
+        }
+    }
+
+    let mut marker = ShutdownMarker {
+        time,
+        rolled_back,
+        quick_check: quick_check(&db, counter),
+    };
+
+    if let Err((_, err)) = db.close() {
+        // the marker must not claim a clean shutdown then
+        marker.quick_check = format!("close failed: {:?}", err);
+    }
+
+    marker
+}
+
+/// Stores the marker for the next `post_upgrade`, called from `pre_upgrade`.
+pub fn record_shutdown(marker: ShutdownMarker) {
+    config::update(|c| c.shutdown = Some(marker));
+}
+
+/// Consumes the shutdown marker and runs the recovery if the shutdown was not clean.
+///
+/// A recovery that has not brought back a sound database before the upgrade continues.
+pub fn recover(
+    db: &Connection,
+    marker: Option<ShutdownMarker>,
+    counter: fn() -> u64,
+) -> UpgradeStatus {
+    let clean_shutdown = marker.as_ref().is_some_and(|m| passed(&m.quick_check));
+    let pending = config::get()
+        .last_upgrade
+        .and_then(|status| status.recovery)
+        .filter(|recovery| *recovery != Recovery::Rebuilt);
+
+    if clean_shutdown {
+        return UpgradeStatus {
+            clean_shutdown,
+            shutdown: marker,
+            recovery_check: None,
+            recovery: pending,
+        };
+    }
+
+    // the first read rolls back a hot journal left by an interrupted transaction
+    let check = quick_check(db, counter);
+    let recovery = if passed(&check) {
+        pending
+    } else if config::get().replay_log.enabled {
+        Some(Recovery::Rebuilding)
+    } else {
+        Some(Recovery::Failed {
+            message: "the replay log is disabled, there is nothing to rebuild from".to_string(),
+        })
+    };
+
+    UpgradeStatus {
+        clean_shutdown,
+        shutdown: marker,
+        recovery_check: Some(check),
+        recovery,
+    }
+}
+
+fn recovery() -> Option<Recovery> {
+    config::get()
+        .last_upgrade
+        .and_then(|status| status.recovery)
+}
+
+fn set_recovery(recovery: Recovery) {
+    config::update(|c| {
+        if let Some(status) = c.last_upgrade.as_mut() {
+            status.recovery = Some(recovery);
+        }
+    });
+}
+
+/// Guard for write endpoints, a database that failed its check must be rebuilt first.
+pub fn check_writable() -> Result<(), Error> {
+    match recovery() {
+        Some(Recovery::Rebuilding | Recovery::Failed { .. }) => Err(Error::DatabaseDamaged {
+            check: config::get()
+                .last_upgrade
+                .and_then(|status| status.recovery_check)
+                .unwrap_or_default(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Accepts the database as it is after a failed recovery.
+pub fn resume_writes() -> Result<(), Error> {
+    match recovery() {
+        Some(Recovery::Rebuilding | Recovery::Failed { .. }) => {
+            stop();
+            set_recovery(Recovery::Rebuilt);
+            Ok(())
+        }
+        _ => Err(Error::InvalidArgument {
+            message: "writes are not blocked".to_string(),
+        }),
+    }
+}
+
+/// Marks the recovery as done once a rebuilt database replaced the damaged one.
+pub fn rebuilt() {
+    if matches!(
+        recovery(),
+        Some(Recovery::Rebuilding | Recovery::Failed { .. })
+    ) {
+        stop();
+        set_recovery(Recovery::Rebuilt);
+    }
+}
+
+/// Starts the rebuild timer while a recovery is rebuilding, also called after an upgrade.
+pub fn schedule() {
+    if recovery() == Some(Recovery::Rebuilding)
+        && config::get().replay_log.enabled
+        && TIMER.get().is_none()
+    {
+        TIMER.set(Some(ic_cdk_timers::set_timer_interval(
+            REBUILD_INTERVAL,
+            rebuild_step,
+        )));
+    }
+}
+
+fn stop() {
+    if let Some(timer) = TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
+
+// replays the next entries, the timer stops once the rebuilt database is ready to be
+// activated
+fn rebuild_step() {
+    let failed = |message: String| {
+        stop();
+        set_recovery(Recovery::Failed { message });
+    };
+
+    let progress = match replay::rebuild(ic_cdk::api::instruction_counter) {
+        Ok(progress) => progress,
+        Err(err) => return failed(format!("{:?}", err)),
+    };
+    match progress.quick_check.as_deref() {
+        None => {}
+        // writes stay refused until a controller activates the rebuilt database
+        Some("ok") => stop(),
+        Some(check) => failed(format!("the rebuilt database failed the check: {}", check)),
+    }
+}
+
+/// Takes the marker out of the config, so that the next upgrade needs a new one.
+pub fn take_shutdown_marker() -> Option<ShutdownMarker> {
+    config::update(|c| c.shutdown.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(db: &Connection) -> i64 {
+        db.query_row("SELECT count(*) FROM person", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn setup(dir: &tempfile::TempDir) -> Connection {
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        db.execute_batch(
+            "PRAGMA journal_mode = TRUNCATE;
+            CREATE TABLE person (id INTEGER PRIMARY KEY, data TEXT);
+            INSERT INTO person (data) VALUES ('a'), ('b'), ('c');",
+        )
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn shutdown_rolls_back_an_open_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = setup(&dir);
+        db.execute_batch("BEGIN; INSERT INTO person (data) VALUES ('d');")
+            .unwrap();
+
+        let marker = shutdown(db, 42, || 0);
+        assert!(marker.rolled_back);
+        assert_eq!(marker.quick_check, "ok");
+
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        assert_eq!(count(&db), 3);
+
+        let status = recover(&db, Some(marker), || 0);
+        assert!(status.clean_shutdown);
+        assert!(status.recovery_check.is_none());
+    }
+
+    #[test]
+    fn recovery_rolls_back_a_hot_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = setup(&dir);
+
+        // a small cache makes SQLite write pages of the open transaction to the file
+        db.execute_batch(
+            "PRAGMA cache_size = 1;
+            BEGIN;
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+            INSERT INTO person (data) SELECT hex(randomblob(500)) FROM n;",
+        )
+        .unwrap();
+
+        // the state a trap in the middle of the transaction would leave behind
+        let crashed = tempfile::tempdir().unwrap();
+        for name in ["db.db3", "db.db3-journal"] {
+            std::fs::copy(dir.path().join(name), crashed.path().join(name)).unwrap();
+        }
+        assert!(
+            std::fs::metadata(crashed.path().join("db.db3-journal"))
+                .unwrap()
+                .len()
+                > 0
+        );
+        drop(db);
+
+        let db = Connection::open(crashed.path().join("db.db3")).unwrap();
+        let status = recover(&db, None, || 0);
+        assert!(!status.clean_shutdown);
+        assert_eq!(status.recovery_check.as_deref(), Some("ok"));
+        assert_eq!(count(&db), 3);
+    }
+
+    #[test]
+    fn a_failed_check_is_not_a_clean_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = setup(&dir);
+
+        let marker = ShutdownMarker {
+            time: 0,
+            rolled_back: false,
+            quick_check: "*** in database main ***".to_string(),
+        };
+        let status = recover(&db, Some(marker), || 0);
+        assert!(!status.clean_shutdown);
+        assert_eq!(status.recovery_check.as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn an_interrupted_check_still_is_a_clean_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = setup(&dir);
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+            INSERT INTO person (data) SELECT hex(randomblob(500)) FROM n;",
+        )
+        .unwrap();
+        budget::install_handler(&db);
+
+        let marker = shutdown(db, 42, || u64::MAX);
+        assert_eq!(marker.quick_check, CHECK_INTERRUPTED);
+
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        let status = recover(&db, Some(marker), || 0);
+        assert!(status.clean_shutdown);
+        assert_eq!(status.recovery, None);
+    }
+
+    #[test]
+    fn a_damaged_database_refuses_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = setup(&dir);
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+            INSERT INTO person (data) SELECT hex(randomblob(500)) FROM n;
+            CREATE INDEX person_data ON person (data);",
+        )
+        .unwrap();
+        drop(db);
+
+        // overwrites a page in the middle of the file
+        let path = dir.path().join("db.db3");
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2 / 4096 * 4096;
+        bytes[middle..middle + 4096].fill(0x55);
+        std::fs::write(&path, bytes).unwrap();
+
+        let db = Connection::open(&path).unwrap();
+        let status = recover(&db, None, || 0);
+        assert_ne!(status.recovery_check.as_deref(), Some("ok"));
+        // without a replay log there is nothing to rebuild from
+        assert!(matches!(status.recovery, Some(Recovery::Failed { .. })));
+
+        config::update(|c| c.replay_log.enabled = true);
+        let status = recover(&db, None, || 0);
+        assert_eq!(status.recovery, Some(Recovery::Rebuilding));
+        config::update(|c| c.last_upgrade = Some(status));
+        assert!(matches!(
+            check_writable(),
+            Err(Error::DatabaseDamaged { .. })
+        ));
+
+        // a clean upgrade keeps the recovery going
+        let marker = ShutdownMarker {
+            time: 0,
+            rolled_back: false,
+            quick_check: "ok".to_string(),
+        };
+        let status = recover(&db, Some(marker), || 0);
+        assert_eq!(status.recovery, Some(Recovery::Rebuilding));
+        config::update(|c| c.last_upgrade = Some(status));
+
+        resume_writes().unwrap();
+        assert!(check_writable().is_ok());
+        assert!(resume_writes().is_err());
+    }
+}
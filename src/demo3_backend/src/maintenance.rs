@@ -0,0 +1,664 @@
+//! Periodic database maintenance.
+//!
+//! A timer runs the configured tasks one after the other, each one within its own
+//! instruction budget; a task over its budget is interrupted by the progress handler of
+//! `budget` and its current statement is rolled back. `Analyze` and `QuickCheck` work
+//! table by table and continue after the last finished table on the next run, `Retention`
+//! keeps the batches it deleted. `Optimize` and `IncrementalVacuum` start over, they only
+//! finish within a budget that covers them.
+//!
+//! `Analyze` only visits the tables changed since it last ran, as seen by the commit hook
+//! of `changes`. The set is kept on the heap, after an upgrade `Optimize` covers the tables
+//! changed before it. The position of `QuickCheck` is kept on the heap as well, after an
+//! upgrade it starts again with the first table.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_timers::TimerId;
+use rusqlite::Connection;
+
+use crate::replay::{self, SqlValue};
+use crate::util::{invalid, quote, to_error};
+use crate::{budget, changesets, config, replication, Error, WriteKind, DB};
+
+/// Budget of a task if none is configured.
+pub const DEFAULT_TASK_BUDGET: u64 = 2_000_000_000;
+
+// keeps the five tasks of one run below the 40B instruction limit of a message
+const MAX_TASK_BUDGET: u64 = 5_000_000_000;
+
+// the check of the largest table has to fit, four tasks at MAX_TASK_BUDGET leave 20B
+const MAX_CHECK_BUDGET: u64 = 15_000_000_000;
+
+/// Pages freed by an incremental vacuum if not configured.
+pub const DEFAULT_VACUUM_PAGES: u64 = 1000;
+
+// rows deleted by one retention statement
+const RETENTION_BATCH_ROWS: u64 = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    /// `PRAGMA optimize`.
+    Optimize,
+    /// `ANALYZE` of the tables changed since the last run.
+    Analyze,
+    /// `PRAGMA quick_check` of one table after the other, within `check_budget`.
+    QuickCheck,
+    /// `PRAGMA incremental_vacuum`, frees pages only with `auto_vacuum = INCREMENTAL`.
+    IncrementalVacuum,
+    /// Deletes the rows older than the retention rules allow, on the primary only.
+    Retention,
+}
+
+/// Deletes the rows of `table` whose `time_column`, a time in nanoseconds like
+/// `ic_cdk::api::time`, is older than `max_age_seconds`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub table: String,
+    pub time_column: String,
+    pub max_age_seconds: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceConfig {
+    /// Seconds between two runs, the tasks only run on `run_maintenance` if not set.
+    pub interval_seconds: Option<u64>,
+    /// Tasks of a run, in this order.
+    pub tasks: Vec<Task>,
+    /// Instructions a task may use per run, `DEFAULT_TASK_BUDGET` if not set.
+    pub task_budget: Option<u64>,
+    /// Instructions `QuickCheck` may use per run, `task_budget` if not set. A table whose
+    /// check does not fit is never checked.
+    pub check_budget: Option<u64>,
+    /// Pages an incremental vacuum frees per run, `DEFAULT_VACUUM_PAGES` if not set.
+    pub vacuum_pages: Option<u64>,
+    pub retention: Vec<RetentionRule>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Done {
+        message: String,
+    },
+    /// Stopped at the budget, the next run continues.
+    Interrupted,
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// The last run of a task.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TaskRun {
+    pub task: Task,
+    /// Time in nanoseconds.
+    pub started_at: u64,
+    pub instructions: u64,
+    pub outcome: Outcome,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MaintenanceStatus {
+    pub config: MaintenanceConfig,
+    pub last_runs: Vec<TaskRun>,
+    /// Tables `Analyze` visits on its next run.
+    pub changed_tables: Vec<String>,
+}
+
+thread_local! {
+    static CHANGED_TABLES: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+    // the last table checked by `QuickCheck`, `None` before the first one
+    static CHECKED_UP_TO: RefCell<Option<String>> = const { RefCell::new(None) };
+    static TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+/// Called by the commit hook with the tables of a committed transaction.
+pub fn mark_changed<'a>(tables: impl IntoIterator<Item = &'a str>) {
+    CHANGED_TABLES.with(|c| {
+        let mut changed = c.borrow_mut();
+        for table in tables {
+            if !changed.contains(table) {
+                changed.insert(table.to_string());
+            }
+        }
+    });
+}
+
+fn retention_sql(rule: &RetentionRule) -> String {
+    let table = quote(&rule.table);
+    format!(
+        "DELETE FROM {table} WHERE rowid IN (
+            SELECT rowid FROM {table} WHERE {} < ?1 LIMIT {RETENTION_BATCH_ROWS}
+        )",
+        quote(&rule.time_column)
+    )
+}
+
+pub fn validate(db: &Connection, config: &MaintenanceConfig) -> Result<(), Error> {
+    if config.interval_seconds == Some(0) {
+        return Err(invalid(
+            "the interval must be at least one second".to_string(),
+        ));
+    }
+    if let Some(budget) = config.task_budget {
+        if budget == 0 || budget > MAX_TASK_BUDGET {
+            return Err(invalid(format!(
+                "a task budget must be between 1 and {}",
+                MAX_TASK_BUDGET
+            )));
+        }
+    }
+    if let Some(budget) = config.check_budget {
+        if budget == 0 || budget > MAX_CHECK_BUDGET {
+            return Err(invalid(format!(
+                "a check budget must be between 1 and {}",
+                MAX_CHECK_BUDGET
+            )));
+        }
+    }
+    if config.vacuum_pages == Some(0) {
+        return Err(invalid("a vacuum must free at least one page".to_string()));
+    }
+    for rule in &config.retention {
+        if crate::jobs::is_internal_table(&rule.table) {
+            return Err(invalid(format!("{} is an internal table", rule.table)));
+        }
+        // a quoted column that does not exist would be read as a string
+        let has_column: bool = db
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+                [&rule.table, &rule.time_column],
+                |row| row.get(0),
+            )
+            .map_err(to_error)?;
+        if !has_column {
+            return Err(invalid(format!(
+                "{} has no column {}",
+                rule.table, rule.time_column
+            )));
+        }
+        db.prepare(&retention_sql(rule))
+            .map_err(|err| invalid(format!("retention of {}: {}", rule.table, err)))?;
+    }
+    Ok(())
+}
+
+fn optimize(db: &Connection) -> Result<String, Error> {
+    db.execute_batch("PRAGMA optimize").map_err(to_error)?;
+    Ok("optimized".to_string())
+}
+
+fn analyze(db: &Connection) -> Result<String, Error> {
+    let changed = CHANGED_TABLES.with(|c| std::mem::take(&mut *c.borrow_mut()));
+
+    let mut analyzed = Vec::new();
+    let mut result = Ok(());
+    for table in &changed {
+        // the table may have been dropped since it changed
+        let exists: bool = db
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ?1)",
+                [table],
+                |row| row.get(0),
+            )
+            .map_err(to_error)?;
+        if !exists {
+            continue;
+        }
+
+        result = db.execute_batch(&format!("ANALYZE {}", quote(table)));
+        if result.is_err() {
+            break;
+        }
+        analyzed.push(table.as_str());
+    }
+
+    // the tables not analyzed yet wait for the next run
+    mark_changed(
+        changed
+            .iter()
+            .map(String::as_str)
+            .filter(|t| result.is_err() && !analyzed.contains(t)),
+    );
+    result.map_err(to_error)?;
+
+    Ok(if analyzed.is_empty() {
+        "no changed tables".to_string()
+    } else {
+        format!("analyzed {}", analyzed.join(", "))
+    })
+}
+
+fn quick_check(db: &Connection) -> Result<String, Error> {
+    let after = CHECKED_UP_TO
+        .with(|c| c.borrow().clone())
+        .unwrap_or_default();
+    let mut stmt = db
+        .prepare(
+            "SELECT name FROM sqlite_schema
+            WHERE type = 'table' AND sql NOT LIKE 'CREATE VIRTUAL%' AND name > ?1
+            ORDER BY name",
+        )
+        .map_err(to_error)?;
+    let tables: Vec<String> = stmt
+        .query_map([after], |row| row.get(0))
+        .map_err(to_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(to_error)?;
+
+    for table in &tables {
+        let mut stmt = db
+            .prepare(&format!("PRAGMA quick_check({})", quote(table)))
+            .map_err(to_error)?;
+        let problems: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(to_error)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(to_error)?;
+
+        // a damaged table is reported once, the next run goes on with the table after it
+        CHECKED_UP_TO.with(|c| *c.borrow_mut() = Some(table.clone()));
+        if problems != ["ok"] {
+            return Err(Error::CanisterError {
+                message: format!("{}: {}", table, problems.join("; ")),
+            });
+        }
+    }
+
+    CHECKED_UP_TO.with(|c| *c.borrow_mut() = None);
+    Ok(format!("ok, checked {}", tables.join(", ")))
+}
+
+fn incremental_vacuum(db: &Connection, pages: u64) -> Result<String, Error> {
+    let free_pages = || -> Result<u64, Error> {
+        db.query_row("PRAGMA freelist_count", [], |row| row.get(0))
+            .map_err(to_error)
+    };
+
+    let before = free_pages()?;
+    // every step frees one page
+    let mut stmt = db
+        .prepare(&format!("PRAGMA incremental_vacuum({})", pages))
+        .map_err(to_error)?;
+    let mut rows = stmt.query([]).map_err(to_error)?;
+    while rows.next().map_err(to_error)?.is_some() {}
+    let after = free_pages()?;
+
+    Ok(format!(
+        "freed {} pages, {} free pages left",
+        before - after,
+        after
+    ))
+}
+
+fn retention(
+    db: &Connection,
+    rules: &[RetentionRule],
+    caller: Principal,
+    time: u64,
+) -> Result<String, Error> {
+    let mut deleted = 0;
+    for rule in rules {
+        let sql = retention_sql(rule);
+        let cutoff = time.saturating_sub(rule.max_age_seconds.saturating_mul(1_000_000_000));
+        let params = [SqlValue::Integer(cutoff.min(i64::MAX as u64) as i64)];
+
+        loop {
+            let (rows, _) = changesets::capture(db, || {
+                db.execute(&sql, rusqlite::params_from_iter(&params))
+                    .map_err(to_error)
+            })?;
+            if rows == 0 {
+                break;
+            }
+            replay::record(&sql, &params, caller, time);
+            deleted += rows;
+        }
+    }
+    Ok(format!("deleted {} rows", deleted))
+}
+
+/// Runs the tasks of `config`, with the instructions counted by `counter`; the
+/// `Retention` task is skipped unless `primary`.
+pub fn run(
+    db: &Connection,
+    config: &MaintenanceConfig,
+    primary: bool,
+    caller: Principal,
+    time: u64,
+    counter: fn() -> u64,
+) -> Vec<TaskRun> {
+    let task_budget = config.task_budget.unwrap_or(DEFAULT_TASK_BUDGET);
+
+    config
+        .tasks
+        .iter()
+        .map(|&task| {
+            let budget = match task {
+                Task::QuickCheck => config.check_budget.unwrap_or(task_budget),
+                _ => task_budget,
+            };
+            let start = counter();
+            let (result, exceeded) =
+                budget::run(start.saturating_add(budget), counter, || match task {
+                    Task::Optimize => optimize(db).map(Some),
+                    Task::Analyze => analyze(db).map(Some),
+                    Task::QuickCheck => quick_check(db).map(Some),
+                    Task::IncrementalVacuum => {
+                        incremental_vacuum(db, config.vacuum_pages.unwrap_or(DEFAULT_VACUUM_PAGES))
+                            .map(Some)
+                    }
+                    Task::Retention if !primary => Ok(None),
+                    Task::Retention => retention(db, &config.retention, caller, time).map(Some),
+                });
+
+            let outcome = match (result, exceeded) {
+                (_, Some(_)) => Outcome::Interrupted,
+                (Ok(Some(message)), None) => Outcome::Done { message },
+                (Ok(None), None) => Outcome::Skipped {
+                    reason: "a follower receives the deletes of its primary".to_string(),
+                },
+                (Err(err), None) => Outcome::Failed {
+                    error: format!("{:?}", err),
+                },
+            };
+
+            TaskRun {
+                task,
+                started_at: time,
+                instructions: counter().saturating_sub(start),
+                outcome,
+            }
+        })
+        .collect()
+}
+
+/// Runs the configured tasks on the live database and stores their outcome.
+pub fn run_now() -> Result<Vec<TaskRun>, Error> {
+    crate::check_writable(WriteKind::Maintenance)?;
+
+    let config = config::get().maintenance;
+    let runs = DB.with(|db| {
+        let db = db.borrow();
+        run(
+            db.as_ref().unwrap(),
+            &config,
+            replication::check_writable().is_ok(),
+            ic_cdk::api::id(),
+            ic_cdk::api::time(),
+            ic_cdk::api::instruction_counter,
+        )
+    });
+
+    config::update(|c| {
+        c.maintenance_runs
+            .retain(|last| runs.iter().all(|run| run.task != last.task));
+        c.maintenance_runs.extend(runs.iter().cloned());
+    });
+
+    Ok(runs)
+}
+
+pub fn status() -> MaintenanceStatus {
+    let config = config::get();
+    MaintenanceStatus {
+        config: config.maintenance,
+        last_runs: config.maintenance_runs,
+        changed_tables: CHANGED_TABLES.with(|c| c.borrow().iter().cloned().collect()),
+    }
+}
+
+/// Starts or restarts the timer with the configured interval.
+pub fn start_timer() {
+    TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some(id) = timer.take() {
+            ic_cdk_timers::clear_timer(id);
+        }
+
+        if let Some(seconds) = config::get().maintenance.interval_seconds {
+            *timer = Some(ic_cdk_timers::set_timer_interval(
+                Duration::from_secs(seconds),
+                || {
+                    // a migration moves the database, the next run catches up
+                    if let Err(err) = run_now() {
+                        ic_cdk::eprintln!("maintenance skipped: {:?}", err);
+                    }
+                },
+            ));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        static COUNTER: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+
+    // pretends every check costs 1000 instructions
+    fn counter() -> u64 {
+        COUNTER.with(|c| {
+            c.set(c.get() + 1000);
+            c.get()
+        })
+    }
+
+    fn database() -> Connection {
+        let db = crate::util::database();
+        budget::install_handler(&db);
+        // auto_vacuum only changes with a VACUUM once the database has tables
+        db.execute_batch(
+            "PRAGMA auto_vacuum = INCREMENTAL;
+            VACUUM;
+            CREATE TABLE events (id INTEGER PRIMARY KEY, created_at INTEGER);
+            CREATE INDEX events_created_at ON events (created_at);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2500)
+            INSERT INTO events (created_at) SELECT i * 1000000000 FROM n;",
+        )
+        .unwrap();
+        db
+    }
+
+    fn config(tasks: Vec<Task>) -> MaintenanceConfig {
+        MaintenanceConfig {
+            tasks,
+            retention: vec![RetentionRule {
+                table: "events".to_string(),
+                time_column: "created_at".to_string(),
+                max_age_seconds: 500,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn status_tables() -> Vec<String> {
+        CHANGED_TABLES.with(|c| c.borrow().iter().cloned().collect())
+    }
+
+    fn outcomes(runs: Vec<TaskRun>) -> Vec<(Task, Outcome)> {
+        runs.into_iter().map(|r| (r.task, r.outcome)).collect()
+    }
+
+    fn done(message: &str) -> Outcome {
+        Outcome::Done {
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn runs_the_configured_tasks() {
+        let db = database();
+        mark_changed(["person", "events", "dropped"]);
+
+        let config = config(vec![
+            Task::Retention,
+            Task::Analyze,
+            Task::QuickCheck,
+            Task::IncrementalVacuum,
+            Task::Optimize,
+        ]);
+        validate(&db, &config).unwrap();
+        let runs = run(
+            &db,
+            &config,
+            true,
+            Principal::anonymous(),
+            3000 * 1_000_000_000,
+            counter,
+        );
+        assert!(runs.iter().all(|r| r.instructions > 0 && r.started_at > 0));
+        assert_eq!(
+            outcomes(runs),
+            [
+                (Task::Retention, done("deleted 2499 rows")),
+                (Task::Analyze, done("analyzed events, person")),
+                (
+                    Task::QuickCheck,
+                    done("ok, checked events, person, sqlite_stat1, sqlite_stat4")
+                ),
+                (
+                    Task::IncrementalVacuum,
+                    done("freed 17 pages, 0 free pages left")
+                ),
+                (Task::Optimize, done("optimized")),
+            ]
+        );
+        assert_eq!(
+            db.query_row("SELECT count(*) FROM events", [], |row| row
+                .get::<_, i64>(0))
+                .unwrap(),
+            1
+        );
+
+        // nothing changed since
+        let runs = run(&db, &config, false, Principal::anonymous(), 1, counter);
+        assert_eq!(
+            outcomes(runs)[..2],
+            [
+                (
+                    Task::Retention,
+                    Outcome::Skipped {
+                        reason: "a follower receives the deletes of its primary".to_string()
+                    }
+                ),
+                (Task::Analyze, done("no changed tables")),
+            ]
+        );
+    }
+
+    #[test]
+    fn interrupts_tasks_over_their_budget() {
+        let db = database();
+        mark_changed(["events"]);
+        let config = MaintenanceConfig {
+            task_budget: Some(1),
+            ..config(vec![Task::Retention, Task::Analyze])
+        };
+
+        let runs = run(
+            &db,
+            &config,
+            true,
+            Principal::anonymous(),
+            3000 * 1_000_000_000,
+            counter,
+        );
+        assert_eq!(
+            outcomes(runs),
+            [
+                (Task::Retention, Outcome::Interrupted),
+                (Task::Analyze, Outcome::Interrupted),
+            ]
+        );
+        // the interrupted statements are rolled back and retried on the next run
+        assert_eq!(
+            db.query_row("SELECT count(*) FROM events", [], |row| row
+                .get::<_, i64>(0))
+                .unwrap(),
+            2500
+        );
+        assert_eq!(status_tables(), ["events"]);
+    }
+
+    #[test]
+    fn quick_check_continues_after_the_last_checked_table() {
+        let db = database();
+        let config = config(vec![Task::QuickCheck]);
+
+        let interrupted = MaintenanceConfig {
+            check_budget: Some(1),
+            ..config.clone()
+        };
+        let runs = run(&db, &interrupted, true, Principal::anonymous(), 1, counter);
+        assert_eq!(outcomes(runs), [(Task::QuickCheck, Outcome::Interrupted)]);
+        assert_eq!(CHECKED_UP_TO.with(|c| c.borrow().clone()), None);
+
+        // "events" was checked by an earlier run
+        CHECKED_UP_TO.with(|c| *c.borrow_mut() = Some("events".to_string()));
+        let runs = run(&db, &config, true, Principal::anonymous(), 1, counter);
+        assert_eq!(
+            outcomes(runs),
+            [(Task::QuickCheck, done("ok, checked person"))]
+        );
+
+        // the next run starts again with the first table
+        let runs = run(&db, &config, true, Principal::anonymous(), 1, counter);
+        assert_eq!(
+            outcomes(runs),
+            [(Task::QuickCheck, done("ok, checked events, person"))]
+        );
+    }
+
+    #[test]
+    fn validates_the_config() {
+        let db = database();
+        assert!(validate(&db, &config(vec![])).is_ok());
+
+        let invalid_configs = [
+            MaintenanceConfig {
+                interval_seconds: Some(0),
+                ..Default::default()
+            },
+            MaintenanceConfig {
+                task_budget: Some(MAX_TASK_BUDGET + 1),
+                ..Default::default()
+            },
+            MaintenanceConfig {
+                check_budget: Some(MAX_CHECK_BUDGET + 1),
+                ..Default::default()
+            },
+            MaintenanceConfig {
+                vacuum_pages: Some(0),
+                ..Default::default()
+            },
+            MaintenanceConfig {
+                retention: vec![RetentionRule {
+                    table: "events".to_string(),
+                    time_column: "missing".to_string(),
+                    max_age_seconds: 1,
+                }],
+                ..Default::default()
+            },
+            MaintenanceConfig {
+                retention: vec![RetentionRule {
+                    table: "_jobs".to_string(),
+                    time_column: "created_at".to_string(),
+                    max_age_seconds: 1,
+                }],
+                ..Default::default()
+            },
+        ];
+        for config in invalid_configs {
+            assert!(validate(&db, &config).is_err(), "{config:?}");
+        }
+    }
+}
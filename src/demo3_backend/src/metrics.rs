@@ -0,0 +1,143 @@
+//! Cost of a call, reported next to the result of every endpoint once enabled with
+//! `set_call_metrics`.
+//!
+//! The counters of SQLite are cumulative per connection, a call takes a snapshot when it
+//! starts and reports the difference when it ends. Rows read are the rows returned by the
+//! statements of the call, counted by the trace callback; rows written are the rows
+//! inserted, updated or deleted. Pages read are the misses of the page cache, pages
+//! written are the pages the pager wrote to the database file.
+
+use std::cell::Cell;
+use std::ffi::c_int;
+
+use candid::{CandidType, Deserialize};
+use rusqlite::{ffi, Connection};
+
+/// Bytes of a page of the stable memory.
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallMetrics {
+    /// Instructions of the message up to the end of the call.
+    pub instructions: u64,
+    pub rows_read: u64,
+    pub rows_written: u64,
+    pub pages_read: u64,
+    pub pages_written: u64,
+    pub stable_memory_growth_bytes: u64,
+}
+
+thread_local! {
+    static ROWS_READ: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Counts a row returned by a statement, called by the trace callback.
+pub fn count_row() {
+    ROWS_READ.with(|rows| rows.set(rows.get() + 1));
+}
+
+/// Rows returned by the statements of the live connection so far.
+pub fn rows_read() -> u64 {
+    ROWS_READ.with(Cell::get)
+}
+
+fn db_status(db: &Connection, op: c_int) -> u64 {
+    let (mut current, mut highwater) = (0, 0);
+    unsafe { ffi::sqlite3_db_status(db.handle(), op, &mut current, &mut highwater, 0) };
+    current.max(0) as u64
+}
+
+/// The counters at one point of a call.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Snapshot {
+    rows_read: u64,
+    rows_written: u64,
+    pages_read: u64,
+    pages_written: u64,
+    stable_pages: u64,
+}
+
+impl Snapshot {
+    pub fn take(db: &Connection, stable_pages: u64) -> Self {
+        Snapshot {
+            rows_read: rows_read(),
+            rows_written: unsafe { ffi::sqlite3_total_changes64(db.handle()) }.max(0) as u64,
+            pages_read: db_status(db, ffi::SQLITE_DBSTATUS_CACHE_MISS),
+            pages_written: db_status(db, ffi::SQLITE_DBSTATUS_CACHE_WRITE),
+            stable_pages,
+        }
+    }
+
+    /// The cost of the call between `self` and `end`. A counter that went back, because
+    /// the call reopened the connection, reports nothing.
+    pub fn until(&self, end: &Snapshot, instructions: u64) -> CallMetrics {
+        CallMetrics {
+            instructions,
+            rows_read: end.rows_read.saturating_sub(self.rows_read),
+            rows_written: end.rows_written.saturating_sub(self.rows_written),
+            pages_read: end.pages_read.saturating_sub(self.pages_read),
+            pages_written: end.pages_written.saturating_sub(self.pages_written),
+            stable_memory_growth_bytes: end.stable_pages.saturating_sub(self.stable_pages)
+                * WASM_PAGE_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_rows_and_pages_of_a_call() {
+        let path = std::env::temp_dir().join(format!("metrics-{}.db3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let db = Connection::open(&path).unwrap();
+        crate::trace::install(&db, || 0, || 0);
+        crate::create_schema(&db).unwrap();
+
+        let start = Snapshot::take(&db, 10);
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+            INSERT INTO person (name, data, age) SELECT 'name ' || i, hex(randomblob(100)), i FROM n;
+            UPDATE person SET age = 0 WHERE age <= 20;",
+        )
+        .unwrap();
+        let names: Vec<String> = db
+            .prepare("SELECT name FROM person WHERE age = 0")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let metrics = start.until(&Snapshot::take(&db, 12), 1000);
+
+        assert_eq!(names.len(), 20);
+        assert_eq!(metrics.instructions, 1000);
+        assert_eq!(metrics.rows_read, 20);
+        assert_eq!(metrics.rows_written, 320);
+        assert!(metrics.pages_written > 0, "{metrics:?}");
+        assert_eq!(metrics.stable_memory_growth_bytes, 2 * WASM_PAGE_SIZE);
+
+        // a new connection reads the pages from the file
+        drop(db);
+        let db = Connection::open(&path).unwrap();
+        crate::trace::install(&db, || 0, || 0);
+        // the schema is read on the first statement
+        db.execute_batch("SELECT 1 FROM person LIMIT 0").unwrap();
+        let start = Snapshot::take(&db, 12);
+        let count: i64 = db
+            .query_row("SELECT count(*) FROM person", [], |row| row.get(0))
+            .unwrap();
+        let metrics = start.until(&Snapshot::take(&db, 12), 0);
+
+        assert_eq!(count, 300);
+        assert_eq!(metrics.rows_read, 1);
+        assert_eq!(metrics.rows_written, 0);
+        assert!(metrics.pages_read > 0, "{metrics:?}");
+        assert_eq!(metrics.stable_memory_growth_bytes, 0);
+
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+}
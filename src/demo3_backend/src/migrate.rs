@@ -0,0 +1,508 @@
+//! Moves the live database to another storage backend without a reinstall.
+//!
+//! The database file is copied into a staging file in chunks, over as many calls as
+//! needed, and then compared with the original chunk by chunk. Writes are refused
+//! while a migration runs, so the copy cannot fall behind. The last call swaps the
+//! files, reopens the database and runs `PRAGMA quick_check`; a failed check traps,
+//! which rolls the whole switch back and leaves the original database in place.
+//!
+//! The mounted file and the memory VFS backends keep the database in the same
+//! memories, a migration between them rewrites the bytes in place.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory;
+
+use crate::config::{self, Backend};
+use crate::encryption::EncryptedMemory;
+use crate::{
+    lifecycle, quota, replay, Error, DB, DB_FILE_NAME, DB_JOURNAL_FILE_NAME, JOURNAL_MEMORY_ID,
+    MEMORY_MANAGER, MOUNTED_MEMORY_ID, VFS_SIZES_MEMORY_ID,
+};
+
+const STAGING_FILE_NAME: &str = "db.db3.migrate";
+
+// instructions one call may spend on copying or comparing before it returns
+const MIGRATION_INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+// bytes read and written at once, a multiple of every page size
+const CHUNK_SIZE: usize = 65536;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Copy,
+    Verify,
+    /// The database runs on the target backend.
+    Done,
+}
+
+/// A running migration, kept in the config so that it continues after an upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Migration {
+    pub target: Backend,
+    pub phase: Phase,
+    /// Bytes copied or verified so far.
+    pub offset: u64,
+    /// Size of the database file, it does not change while writes are refused.
+    pub size: u64,
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Reads a file that lives directly in a memory.
+struct MemoryReader<M: Memory> {
+    memory: M,
+    size: u64,
+    position: u64,
+}
+
+impl<M: Memory> Read for MemoryReader<M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (self.size.saturating_sub(self.position) as usize).min(buf.len());
+        self.memory.read(self.position, &mut buf[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<M: Memory> Seek for MemoryReader<M> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(delta) => self.size.saturating_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.saturating_add_signed(delta),
+        };
+        Ok(self.position)
+    }
+}
+
+fn io_error(err: io::Error) -> Error {
+    Error::CanisterError {
+        message: format!("migration: {:?}", err),
+    }
+}
+
+fn memory(id: u8) -> impl Memory {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+    EncryptedMemory::new(memory, id)
+}
+
+fn stores_in_memories(backend: Backend) -> bool {
+    matches!(backend, Backend::MountedFile | Backend::MemoryVfs)
+}
+
+fn open_source(backend: Backend) -> Result<Box<dyn ReadSeek>, Error> {
+    match backend {
+        Backend::MemoryVfs => Ok(Box::new(MemoryReader {
+            memory: memory(MOUNTED_MEMORY_ID),
+            size: memory_vfs::file_size(DB_FILE_NAME).unwrap_or_default(),
+            position: 0,
+        })),
+        _ => Ok(Box::new(
+            std::fs::File::open(DB_FILE_NAME).map_err(io_error)?,
+        )),
+    }
+}
+
+fn open_staging() -> Result<std::fs::File, Error> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(STAGING_FILE_NAME)
+        .map_err(io_error)
+}
+
+/// Mounts the staging file of a running migration again after an upgrade.
+pub fn mount_staging_file() {
+    if let Some(migration) = config::get().migration {
+        if stores_in_memories(migration.target) {
+            ic_wasi_polyfill::mount_memory_file(
+                STAGING_FILE_NAME,
+                Box::new(memory(MOUNTED_MEMORY_ID)),
+            );
+        }
+    }
+}
+
+/// Guard for write endpoints, the database must not change while it is copied or
+/// while it waits for its recovery.
+pub fn check_writable() -> Result<(), Error> {
+    check_idle()?;
+    lifecycle::check_writable()
+}
+
+/// Refuses to replace the database while it is copied.
+pub fn check_idle() -> Result<(), Error> {
+    replay::check_idle()?;
+    match config::get().migration {
+        Some(migration) => Err(Error::MigrationInProgress {
+            target: migration.target,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn start(target: Backend) -> Result<Migration, Error> {
+    let config = config::get();
+
+    if target == config.backend {
+        return Err(Error::InvalidArgument {
+            message: format!("the database already uses the {:?} backend", target),
+        });
+    }
+
+    if config.backend == Backend::Heap || target == Backend::Heap {
+        return Err(Error::InvalidArgument {
+            message: "the Heap backend cannot be migrated from or to".to_string(),
+        });
+    }
+
+    if config.encryption.key_fingerprint.is_some() && !target.supports_encryption() {
+        return Err(Error::InvalidArgument {
+            message: format!("the {:?} backend cannot be encrypted", target),
+        });
+    }
+
+    if config.encryption.rotation.is_some() {
+        return Err(Error::InvalidArgument {
+            message: "wait for the key rotation to finish".to_string(),
+        });
+    }
+
+    let size = open_source(config.backend)?
+        .seek(SeekFrom::End(0))
+        .map_err(io_error)?;
+
+    // the copy needs as much space as the database itself, unless it is rewritten in place
+    if !(stores_in_memories(config.backend) && stores_in_memories(target)) {
+        let used_bytes = DB
+            .with(|db| quota::used_bytes(db.borrow().as_ref().unwrap()))
+            .map_err(quota::map_write_error)?;
+        quota::check(config.storage_quota.as_ref(), used_bytes + size)?;
+    }
+
+    // leftovers of an earlier migration
+    remove_staging_file();
+
+    match target {
+        Backend::StableFsFile(chunk_type) => {
+            // the chunk type is fixed when the file is created
+            ic_wasi_polyfill::FS.with(|fs| {
+                fs.borrow_mut().storage.set_chunk_type(chunk_type.into());
+            });
+            std::fs::File::create(STAGING_FILE_NAME).map_err(io_error)?;
+        }
+        _ => {
+            ic_wasi_polyfill::mount_memory_file(
+                STAGING_FILE_NAME,
+                Box::new(memory(MOUNTED_MEMORY_ID)),
+            );
+        }
+    }
+
+    Ok(Migration {
+        target,
+        phase: Phase::Copy,
+        offset: 0,
+        size,
+    })
+}
+
+/// Copies and then compares the database in chunks until the instruction budget
+/// is used up or the whole copy has been verified.
+fn copy_and_verify(
+    migration: &mut Migration,
+    source: &mut dyn ReadSeek,
+    staging: &mut (impl Read + Write + Seek),
+    instruction_counter: impl Fn() -> u64,
+) -> Result<(), Error> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut copy = vec![0u8; CHUNK_SIZE];
+
+    while instruction_counter() < MIGRATION_INSTRUCTION_BUDGET {
+        if migration.offset == migration.size {
+            match migration.phase {
+                Phase::Copy => {
+                    staging.flush().map_err(io_error)?;
+                    migration.phase = Phase::Verify;
+                    migration.offset = 0;
+                    continue;
+                }
+                _ => return Ok(()),
+            }
+        }
+
+        let len = (migration.size - migration.offset).min(CHUNK_SIZE as u64) as usize;
+
+        source
+            .seek(SeekFrom::Start(migration.offset))
+            .map_err(io_error)?;
+        source.read_exact(&mut chunk[..len]).map_err(io_error)?;
+        staging
+            .seek(SeekFrom::Start(migration.offset))
+            .map_err(io_error)?;
+
+        if migration.phase == Phase::Copy {
+            staging.write_all(&chunk[..len]).map_err(io_error)?;
+        } else {
+            staging.read_exact(&mut copy[..len]).map_err(io_error)?;
+
+            if chunk[..len] != copy[..len] {
+                return Err(Error::CanisterError {
+                    message: format!(
+                        "migration: the copy differs from the database at offset {}",
+                        migration.offset
+                    ),
+                });
+            }
+        }
+
+        migration.offset += len as u64;
+    }
+
+    Ok(())
+}
+
+/// Replaces the database files with a verified copy and reopens the database.
+fn switch(staging_file: &str, target: Backend, size: u64) -> Result<(), Error> {
+    let source = config::get().backend;
+
+    replay::abort_snapshot();
+    DB.with(|db| *db.borrow_mut() = None);
+
+    if source == Backend::MountedFile {
+        ic_wasi_polyfill::unmount_memory_file(DB_FILE_NAME);
+        ic_wasi_polyfill::unmount_memory_file(DB_JOURNAL_FILE_NAME);
+    }
+    let _ = std::fs::remove_file(DB_FILE_NAME);
+    let _ = std::fs::remove_file(DB_JOURNAL_FILE_NAME);
+
+    match target {
+        Backend::MountedFile => {
+            // the staging file keeps its mount when it is renamed
+            std::fs::rename(staging_file, DB_FILE_NAME).map_err(io_error)?;
+            ic_wasi_polyfill::mount_memory_file(
+                DB_JOURNAL_FILE_NAME,
+                Box::new(memory(JOURNAL_MEMORY_ID)),
+            );
+        }
+        Backend::MemoryVfs => {
+            ic_wasi_polyfill::unmount_memory_file(staging_file);
+            std::fs::remove_file(staging_file).map_err(io_error)?;
+
+            let sizes = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(VFS_SIZES_MEMORY_ID)));
+            let sizes = std::rc::Rc::new(sizes);
+            memory_vfs::mount(
+                DB_FILE_NAME,
+                Box::new(memory(MOUNTED_MEMORY_ID)),
+                sizes.clone(),
+                0,
+            );
+            memory_vfs::mount(
+                DB_JOURNAL_FILE_NAME,
+                Box::new(memory(JOURNAL_MEMORY_ID)),
+                sizes,
+                1,
+            );
+            memory_vfs::set_file_size(DB_FILE_NAME, size);
+            memory_vfs::set_file_size(DB_JOURNAL_FILE_NAME, 0);
+        }
+        Backend::StableFsFile(chunk_type) => {
+            std::fs::rename(staging_file, DB_FILE_NAME).map_err(io_error)?;
+
+            // SQLite creates the journal again on the next write
+            ic_wasi_polyfill::FS.with(|fs| {
+                fs.borrow_mut().storage.set_chunk_type(chunk_type.into());
+            });
+        }
+        Backend::Heap => unreachable!(),
+    }
+
+    config::update(|c| {
+        c.backend = target;
+        c.migration = None;
+    });
+
+    crate::open_database();
+    crate::set_pragmas();
+
+    DB.with(|db| {
+        let db = db.borrow();
+        let check: String = db
+            .as_ref()
+            .unwrap()
+            .pragma_query_value(None, "quick_check", |row| row.get(0))
+            .unwrap_or_else(|err| format!("{:?}", err));
+
+        if check != "ok" {
+            // rolls back every change of this call, the old database stays in use
+            ic_cdk::trap(&format!("the new database is corrupt: {}", check));
+        }
+    });
+
+    Ok(())
+}
+
+/// Starts a migration to `target` or continues the running one, returns its progress.
+pub fn step(target: Backend, instruction_counter: impl Fn() -> u64) -> Result<Migration, Error> {
+    let mut migration = match config::get().migration {
+        Some(migration) if migration.target == target => migration,
+        Some(migration) => {
+            return Err(Error::MigrationInProgress {
+                target: migration.target,
+            })
+        }
+        None => start(target)?,
+    };
+
+    let mut source = open_source(config::get().backend)?;
+    let mut staging = open_staging()?;
+
+    let res = copy_and_verify(
+        &mut migration,
+        source.as_mut(),
+        &mut staging,
+        instruction_counter,
+    );
+    drop(staging);
+
+    if let Err(err) = res {
+        // a copy that does not match cannot be repaired, the next call starts over
+        stop();
+        return Err(err);
+    }
+
+    if migration.phase == Phase::Verify && migration.offset == migration.size {
+        // the old files are already gone when the switch fails, trapping restores them
+        if let Err(err) = switch(STAGING_FILE_NAME, migration.target, migration.size) {
+            ic_cdk::trap(&format!("{:?}", err));
+        }
+        migration.phase = Phase::Done;
+    } else {
+        config::update(|c| c.migration = Some(migration.clone()));
+    }
+
+    Ok(migration)
+}
+
+fn remove_staging_file() {
+    ic_wasi_polyfill::unmount_memory_file(STAGING_FILE_NAME);
+    let _ = std::fs::remove_file(STAGING_FILE_NAME);
+}
+
+fn stop() {
+    remove_staging_file();
+
+    // new files of the database's own backend get its chunk type again
+    if let Backend::StableFsFile(chunk_type) = config::get().backend {
+        ic_wasi_polyfill::FS.with(|fs| {
+            fs.borrow_mut().storage.set_chunk_type(chunk_type.into());
+        });
+    }
+
+    config::update(|c| c.migration = None);
+}
+
+/// Stops a running migration and removes the staging file.
+pub fn cancel() -> Result<(), Error> {
+    if config::get().migration.is_none() {
+        return Err(Error::InvalidArgument {
+            message: "no migration is running".to_string(),
+        });
+    }
+
+    stop();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    fn migration(size: u64) -> Migration {
+        Migration {
+            target: Backend::MountedFile,
+            phase: Phase::Copy,
+            offset: 0,
+            size,
+        }
+    }
+
+    fn database(size: usize) -> Cursor<Vec<u8>> {
+        Cursor::new((0..size).map(|i| (i % 251) as u8).collect())
+    }
+
+    // allows `steps` loop iterations per call
+    fn budget(steps: u64) -> impl Fn() -> u64 {
+        let calls = Cell::new(0);
+        move || {
+            calls.set(calls.get() + 1);
+            if calls.get() > steps {
+                MIGRATION_INSTRUCTION_BUDGET
+            } else {
+                0
+            }
+        }
+    }
+
+    #[test]
+    fn copies_and_verifies_across_calls() {
+        let size = 5 * CHUNK_SIZE + 100;
+        let mut source = database(size);
+        let mut staging = Cursor::new(Vec::new());
+        let mut m = migration(size as u64);
+
+        copy_and_verify(&mut m, &mut source, &mut staging, budget(2)).unwrap();
+        assert_eq!(m.phase, Phase::Copy);
+        assert_eq!(m.offset, 2 * CHUNK_SIZE as u64);
+
+        while !(m.phase == Phase::Verify && m.offset == m.size) {
+            copy_and_verify(&mut m, &mut source, &mut staging, budget(3)).unwrap();
+        }
+        assert_eq!(staging.get_ref(), source.get_ref());
+    }
+
+    #[test]
+    fn reports_a_changed_copy() {
+        let size = 3 * CHUNK_SIZE;
+        let mut source = database(size);
+        let mut staging = Cursor::new(Vec::new());
+        let mut m = migration(size as u64);
+
+        copy_and_verify(&mut m, &mut source, &mut staging, budget(3)).unwrap();
+        assert_eq!(m.offset, m.size);
+
+        staging.get_mut()[CHUNK_SIZE + 7] ^= 1;
+
+        let err = copy_and_verify(&mut m, &mut source, &mut staging, budget(10)).unwrap_err();
+        assert!(matches!(err, Error::CanisterError { .. }));
+        assert_eq!(m.phase, Phase::Verify);
+        assert_eq!(m.offset, CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn reads_files_stored_in_memories() {
+        let memory = ic_stable_structures::VectorMemory::default();
+        memory.grow(1);
+        memory.write(0, b"SQLite format 3\0");
+
+        let mut reader = MemoryReader {
+            memory,
+            size: 10,
+            position: 0,
+        };
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 10);
+
+        let mut bytes = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, b"SQLite for");
+    }
+}
@@ -0,0 +1,289 @@
+//! Query plans of statements and the full-scan policy of the `query` endpoint.
+//!
+//! The plan is the tree of `EXPLAIN QUERY PLAN`, its `USE TEMP B-TREE` and
+//! `AUTOMATIC ... INDEX` steps are listed separately. The plan names a scanned table by
+//! its alias, so the full scans are found in the bytecode of `EXPLAIN` instead. The row
+//! count of a scanned table comes from `sqlite_stat1` once `ANALYZE` has run, from the
+//! span of its rowids otherwise, so checking a plan stays cheap on large tables.
+
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::util::quote;
+use crate::Error;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PlanNode {
+    pub id: i64,
+    pub detail: String,
+    pub children: Vec<PlanNode>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FullScan {
+    pub table: String,
+    pub estimated_rows: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub nodes: Vec<PlanNode>,
+    /// Tables read from the first row on, without an index.
+    pub full_scans: Vec<FullScan>,
+    /// Steps sorting or deduplicating rows in a temporary B-tree.
+    pub temp_btrees: Vec<String>,
+    /// Steps building an index for this statement only.
+    pub automatic_indexes: Vec<String>,
+}
+
+/// Rejects the `query` calls of non-admins that scan a table of more than `max_rows`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FullScanPolicy {
+    pub max_rows: u64,
+}
+
+// the tables the bytecode of `sql` reads from the first row on; the plan names a table
+// by its alias, the bytecode by its root page
+fn scanned_tables(db: &Connection, sql: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare(&format!("EXPLAIN {}", sql))?;
+    // the parameters of `sql` stay unbound
+    let ops: Vec<(String, i64, i64, i64)> = stmt
+        .raw_query()
+        .mapped(|row| Ok((row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut root_pages = HashMap::new();
+    let mut tables = Vec::new();
+    for (opcode, p1, p2, p3) in ops {
+        match opcode.as_str() {
+            // cursor p1 on the b-tree at root page p2 of the main database
+            "OpenRead" | "OpenWrite" if p3 == 0 => {
+                root_pages.insert(p1, p2);
+            }
+            "Rewind" | "Last" | "Count" => {
+                let Some(root_page) = root_pages.get(&p1) else {
+                    continue;
+                };
+                let table: Option<String> = db
+                    .query_row(
+                        "SELECT name FROM sqlite_schema WHERE type = 'table' AND rootpage = ?1",
+                        [root_page],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(table) = table.filter(|t| !tables.contains(t)) {
+                    tables.push(table);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(tables)
+}
+
+fn estimated_rows(db: &Connection, table: &str) -> rusqlite::Result<u64> {
+    let analyzed = db
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_schema WHERE name = 'sqlite_stat1')",
+            [],
+            |row| row.get::<_, bool>(0),
+        )?
+        .then(|| {
+            db.query_row(
+                "SELECT stat FROM sqlite_stat1 WHERE tbl = ?1 ORDER BY idx IS NOT NULL LIMIT 1",
+                [table],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+        })
+        .transpose()?
+        .flatten();
+
+    if let Some(rows) = analyzed.and_then(|stat| stat.split(' ').next()?.parse().ok()) {
+        return Ok(rows);
+    }
+
+    // min and max of the rowid are found without reading the table
+    let span: Option<i64> = db
+        .query_row(
+            &format!("SELECT max(rowid) - min(rowid) + 1 FROM {}", quote(table)),
+            [],
+            |row| row.get(0),
+        )
+        .or_else(|_| Ok::<_, rusqlite::Error>(None))?;
+    Ok(span.unwrap_or(0).max(0) as u64)
+}
+
+/// Returns the plan of the first statement of `sql`, it is not run and its parameters
+/// may stay unbound.
+pub fn explain(db: &Connection, sql: &str) -> rusqlite::Result<QueryPlan> {
+    let mut stmt = db.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+    let rows: Vec<(i64, i64, String)> = stmt
+        .raw_query()
+        .mapped(|row| Ok((row.get(0)?, row.get(1)?, row.get(3)?)))
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut plan = QueryPlan {
+        nodes: Vec::new(),
+        full_scans: Vec::new(),
+        temp_btrees: Vec::new(),
+        automatic_indexes: Vec::new(),
+    };
+
+    for table in scanned_tables(db, sql)? {
+        plan.full_scans.push(FullScan {
+            estimated_rows: estimated_rows(db, &table)?,
+            table,
+        });
+    }
+    for (_, _, detail) in &rows {
+        if detail.starts_with("USE TEMP B-TREE") {
+            plan.temp_btrees.push(detail.clone());
+        }
+        if detail.contains(" AUTOMATIC ") {
+            plan.automatic_indexes.push(detail.clone());
+        }
+    }
+
+    // the rows come in depth-first order, a child after its parent
+    fn children(rows: &[(i64, i64, String)], parent: i64) -> Vec<PlanNode> {
+        rows.iter()
+            .filter(|(_, p, _)| *p == parent)
+            .map(|(id, _, detail)| PlanNode {
+                id: *id,
+                detail: detail.clone(),
+                children: children(rows, *id),
+            })
+            .collect()
+    }
+    plan.nodes = children(&rows, 0);
+
+    Ok(plan)
+}
+
+/// Refuses a statement that scans a table larger than the policy allows.
+pub fn check_policy(db: &Connection, sql: &str, policy: &FullScanPolicy) -> Result<(), Error> {
+    let plan = explain(db, sql).map_err(|err| Error::CanisterError {
+        message: format!("{:?}", err),
+    })?;
+
+    match plan
+        .full_scans
+        .into_iter()
+        .find(|scan| scan.estimated_rows > policy.max_rows)
+    {
+        Some(scan) => Err(Error::FullScanRejected {
+            table: scan.table,
+            estimated_rows: scan.estimated_rows,
+            max_rows: policy.max_rows,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let db = crate::util::database();
+        db.execute_batch(
+            "CREATE INDEX person_name ON person (name);
+            CREATE TABLE pet (id INTEGER PRIMARY KEY, owner INTEGER, kind TEXT);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+            INSERT INTO person (name, age) SELECT 'name ' || i, i % 50 FROM n;
+            INSERT INTO pet (owner, kind) SELECT id, 'cat' FROM person;",
+        )
+        .unwrap();
+        db
+    }
+
+    fn details(nodes: &[PlanNode]) -> Vec<String> {
+        nodes
+            .iter()
+            .flat_map(|n| std::iter::once(n.detail.clone()).chain(details(&n.children)))
+            .collect()
+    }
+
+    #[test]
+    fn flags_scans_sorts_and_automatic_indexes() {
+        let db = database();
+
+        let plan = explain(&db, "SELECT id FROM person WHERE name = 'amy'").unwrap();
+        assert_eq!(
+            details(&plan.nodes),
+            ["SEARCH person USING COVERING INDEX person_name (name=?)"]
+        );
+        assert!(plan.full_scans.is_empty() && plan.temp_btrees.is_empty());
+
+        let plan = explain(
+            &db,
+            "SELECT p.name, count(*) FROM person p JOIN pet ON pet.owner = p.id
+            WHERE p.age > 10 GROUP BY p.age ORDER BY 2",
+        )
+        .unwrap();
+        assert_eq!(
+            plan.full_scans,
+            [FullScan {
+                table: "pet".to_string(),
+                estimated_rows: 200
+            }]
+        );
+        assert!(!plan.temp_btrees.is_empty());
+
+        let plan = explain(
+            &db,
+            "SELECT * FROM (SELECT age FROM person) a JOIN (SELECT owner FROM pet) b
+            ON a.age = b.owner",
+        )
+        .unwrap();
+        assert!(!plan.automatic_indexes.is_empty(), "{plan:?}");
+
+        // a subquery is a child of the step that runs it
+        let plan = explain(
+            &db,
+            "SELECT name FROM person WHERE id IN (SELECT owner FROM pet WHERE kind = 'dog')",
+        )
+        .unwrap();
+        assert!(
+            plan.nodes.iter().any(|n| !n.children.is_empty()),
+            "{plan:?}"
+        );
+    }
+
+    #[test]
+    fn rejects_scans_of_large_tables() {
+        let db = database();
+        let scan = "SELECT * FROM pet AS p WHERE p.kind = 'cat'";
+
+        assert!(check_policy(&db, scan, &FullScanPolicy { max_rows: 200 }).is_ok());
+        assert!(matches!(
+            check_policy(&db, scan, &FullScanPolicy { max_rows: 100 }),
+            Err(Error::FullScanRejected {
+                estimated_rows: 200,
+                ..
+            })
+        ));
+        assert!(check_policy(
+            &db,
+            "SELECT * FROM pet WHERE id = 7",
+            &FullScanPolicy { max_rows: 0 }
+        )
+        .is_ok());
+
+        // counting reads every row as well
+        assert!(check_policy(
+            &db,
+            "SELECT count(*) FROM pet",
+            &FullScanPolicy { max_rows: 100 }
+        )
+        .is_err());
+
+        // the statistics of ANALYZE take precedence over the rowid span
+        db.execute_batch("DELETE FROM pet WHERE id > 50; ANALYZE pet;")
+            .unwrap();
+        assert_eq!(explain(&db, scan).unwrap().full_scans[0].estimated_rows, 50);
+    }
+}
@@ -0,0 +1,287 @@
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+
+use crate::Error;
+
+/// Pragmas reported by `get_pragmas`, in the order they are returned.
+const REPORTED: &[&str] = &[
+    "journal_mode",
+    "locking_mode",
+    "page_size",
+    "page_count",
+    "freelist_count",
+    "cache_size",
+    "cache_spill",
+    "temp_store",
+    "automatic_index",
+    "synchronous",
+    "foreign_keys",
+    "secure_delete",
+    "auto_vacuum",
+];
+
+// `cache_size` is given in pages when positive and in KiB when negative,
+// keep both forms below ~1 GiB so that a typo cannot exhaust the canister heap
+const MAX_CACHE_PAGES: i64 = 65536;
+const MAX_CACHE_KIB: i64 = 1024 * 1024;
+
+// all page sizes accepted by SQLite, the default is set in `set_pragmas`
+const MIN_PAGE_SIZE: i64 = 512;
+const MAX_PAGE_SIZE: i64 = 65536;
+
+/// A pragma that can be changed at runtime through `set_pragma`.
+struct Tunable {
+    name: &'static str,
+    parse: fn(&str) -> Option<i64>,
+}
+
+const TUNABLE: &[Tunable] = &[
+    // only takes effect for a new database or after the next VACUUM
+    Tunable {
+        name: "page_size",
+        parse: parse_page_size,
+    },
+    // switching between NONE and FULL or INCREMENTAL also needs a VACUUM
+    Tunable {
+        name: "auto_vacuum",
+        parse: parse_auto_vacuum,
+    },
+    Tunable {
+        name: "cache_size",
+        parse: parse_cache_size,
+    },
+    Tunable {
+        name: "cache_spill",
+        parse: parse_bool,
+    },
+    Tunable {
+        name: "temp_store",
+        parse: parse_temp_store,
+    },
+    Tunable {
+        name: "automatic_index",
+        parse: parse_bool,
+    },
+    Tunable {
+        name: "synchronous",
+        parse: parse_synchronous,
+    },
+    Tunable {
+        name: "foreign_keys",
+        parse: parse_bool,
+    },
+    Tunable {
+        name: "secure_delete",
+        parse: parse_bool,
+    },
+];
+
+fn parse_cache_size(value: &str) -> Option<i64> {
+    let v: i64 = value.parse().ok()?;
+    if (-MAX_CACHE_KIB..=MAX_CACHE_PAGES).contains(&v) {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+fn parse_page_size(value: &str) -> Option<i64> {
+    let v: i64 = value.parse().ok()?;
+    if (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&v) && (v as u64).is_power_of_two() {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+fn parse_bool(value: &str) -> Option<i64> {
+    match value.to_ascii_uppercase().as_str() {
+        "0" | "OFF" | "FALSE" | "NO" => Some(0),
+        "1" | "ON" | "TRUE" | "YES" => Some(1),
+        _ => None,
+    }
+}
+
+fn parse_temp_store(value: &str) -> Option<i64> {
+    match value.to_ascii_uppercase().as_str() {
+        "0" | "DEFAULT" => Some(0),
+        "1" | "FILE" => Some(1),
+        "2" | "MEMORY" => Some(2),
+        _ => None,
+    }
+}
+
+fn parse_auto_vacuum(value: &str) -> Option<i64> {
+    match value.to_ascii_uppercase().as_str() {
+        "0" | "NONE" => Some(0),
+        "1" | "FULL" => Some(1),
+        "2" | "INCREMENTAL" => Some(2),
+        _ => None,
+    }
+}
+
+fn parse_synchronous(value: &str) -> Option<i64> {
+    match value.to_ascii_uppercase().as_str() {
+        "0" | "OFF" => Some(0),
+        "1" | "NORMAL" => Some(1),
+        "2" | "FULL" => Some(2),
+        "3" | "EXTRA" => Some(3),
+        _ => None,
+    }
+}
+
+/// Checks that `name` is tunable and converts `value` into the integer form stored in the config.
+pub fn validate(name: &str, value: &str) -> Result<(String, i64), Error> {
+    let name = name.trim().to_ascii_lowercase();
+
+    let tunable =
+        TUNABLE
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| Error::InvalidArgument {
+                message: format!(
+                    "pragma '{}' cannot be changed at runtime, tunable pragmas: {}",
+                    name,
+                    TUNABLE
+                        .iter()
+                        .map(|t| t.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })?;
+
+    let value = (tunable.parse)(value.trim()).ok_or_else(|| Error::InvalidArgument {
+        message: format!("invalid value '{}' for pragma '{}'", value, name),
+    })?;
+
+    Ok((name, value))
+}
+
+/// Reads the current value of a single pragma as text.
+pub fn read(db: &Connection, name: &str) -> rusqlite::Result<String> {
+    db.pragma_query_value(None, name, |row| {
+        Ok(match row.get_ref(0)? {
+            ValueRef::Null => String::new(),
+            ValueRef::Integer(i) => i.to_string(),
+            ValueRef::Real(f) => f.to_string(),
+            ValueRef::Text(t) => String::from_utf8_lossy(t).into_owned(),
+            ValueRef::Blob(b) => hex::encode(b),
+        })
+    })
+}
+
+/// Reads the effective values of all reported pragmas.
+pub fn read_all(db: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    REPORTED
+        .iter()
+        .map(|name| Ok((name.to_string(), read(db, name)?)))
+        .collect()
+}
+
+/// Applies a validated pragma value and returns the value SQLite reports afterwards.
+pub fn apply(db: &Connection, name: &str, value: i64) -> rusqlite::Result<String> {
+    db.pragma_update(None, name, value)?;
+    read(db, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_all_legal_page_sizes() {
+        for size in [512, 1024, 4096, 16384, 32768, 65536] {
+            assert_eq!(validate("page_size", &size.to_string()).unwrap().1, size);
+        }
+        for size in ["0", "256", "3000", "131072", "-4096"] {
+            assert!(validate("page_size", size).is_err());
+        }
+    }
+
+    #[test]
+    fn page_size_changes_with_vacuum() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        db.execute_batch(
+            "CREATE TABLE person2 (id INTEGER PRIMARY KEY, data TEXT);
+            INSERT INTO person2 (data) VALUES (hex(randomblob(2000)));",
+        )
+        .unwrap();
+
+        // the page size is fixed once the database exists
+        assert_eq!(apply(&db, "page_size", 65536).unwrap(), "4096");
+
+        db.execute_batch("VACUUM").unwrap();
+        assert_eq!(read(&db, "page_size").unwrap(), "65536");
+    }
+
+    // writes 64 KiB pages the way SQLite does and reads the whole file back,
+    // stable-fs before 0.6.4 returned short reads and stale data for the unmounted file
+    #[test]
+    fn page_size_65536_round_trips_through_stable_fs() {
+        use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+        use ic_stable_structures::DefaultMemoryImpl;
+        use ic_wasi_polyfill::{FileSystem, StableStorage};
+        use stable_fs::fs::{FdStat, OpenFlags, Whence};
+
+        const PAGE: usize = 65536;
+
+        let m = MemoryManager::init(DefaultMemoryImpl::default());
+        ic_wasi_polyfill::FS.with(|fs| {
+            *fs.borrow_mut() = FileSystem::new(Box::new(StableStorage::new_with_memory_manager(
+                &m,
+                crate::WASI_MEMORY_ID..crate::WASI_MEMORY_ID + 10,
+            )))
+            .unwrap();
+        });
+        ic_wasi_polyfill::mount_memory_file(
+            crate::DB_FILE_NAME,
+            Box::new(m.get(MemoryId::new(crate::MOUNTED_MEMORY_ID))),
+        );
+        ic_wasi_polyfill::mount_memory_file(
+            crate::DB_JOURNAL_FILE_NAME,
+            Box::new(m.get(MemoryId::new(crate::JOURNAL_MEMORY_ID))),
+        );
+
+        // the mounted files of `Backend::MountedFile` and a plain file of `Backend::StableFsFile`
+        for name in [
+            crate::DB_FILE_NAME,
+            crate::DB_JOURNAL_FILE_NAME,
+            "stable.db3",
+        ] {
+            ic_wasi_polyfill::FS.with(|fs| {
+                let mut fs = fs.borrow_mut();
+                let root = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root, name, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
+
+                let mut expected = Vec::new();
+                let mut write = |offset: usize, len: usize, seed: u8| {
+                    let data: Vec<u8> = (0..len).map(|i| seed.wrapping_add(i as u8)).collect();
+                    fs.seek(fd, offset as i64, Whence::SET).unwrap();
+                    fs.write(fd, &data).unwrap();
+                    if expected.len() < offset + len {
+                        expected.resize(offset + len, 0);
+                    }
+                    expected[offset..offset + len].copy_from_slice(&data);
+                };
+
+                // a journal header followed by records of page number, page and checksum
+                write(0, 28, 1);
+                for record in 0..3 {
+                    write(512 + record * (PAGE + 8), PAGE + 8, 10 + record as u8);
+                }
+                // database pages past a hole and a partial page rewrite
+                write(8 * PAGE, PAGE, 50);
+                write(5 * PAGE + 100, 3000, 60);
+
+                let mut actual = vec![0xAA; expected.len()];
+                fs.seek(fd, 0, Whence::SET).unwrap();
+                assert_eq!(fs.read(fd, &mut actual).unwrap() as usize, expected.len());
+                assert!(actual == expected, "{} was read back with wrong data", name);
+                fs.close(fd).unwrap();
+            });
+        }
+    }
+}
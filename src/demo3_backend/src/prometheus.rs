@@ -0,0 +1,324 @@
+//! Metrics of the canister in the Prometheus text exposition format, served at
+//! `/metrics` by `http_request`.
+//!
+//! The calls, their instructions and their errors are counted on the heap per endpoint
+//! and start over after an upgrade, which Prometheus handles as a counter reset. A query
+//! call drops its state when it ends, so only the update calls are counted. The gauges
+//! are read when the metrics are rendered.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+
+use rusqlite::Connection;
+
+use crate::maintenance::Outcome;
+use crate::{config, storage};
+
+/// Upper bounds of the buckets of the instruction histograms, up to the limit of an
+/// update call.
+pub const INSTRUCTION_BUCKETS: [u64; 8] = [
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+    5_000_000_000,
+    20_000_000_000,
+    40_000_000_000,
+];
+
+#[derive(Default)]
+struct EndpointStats {
+    calls: u64,
+    // calls per bucket, not cumulative; the last one is +Inf
+    buckets: [u64; INSTRUCTION_BUCKETS.len() + 1],
+    instructions: u64,
+    // by error code
+    errors: BTreeMap<&'static str, u64>,
+}
+
+thread_local! {
+    static ENDPOINTS: RefCell<BTreeMap<&'static str, EndpointStats>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Counts a call of `endpoint` that used `instructions` and failed with `error` if any.
+pub fn record_call(endpoint: &'static str, instructions: u64, error: Option<&'static str>) {
+    ENDPOINTS.with(|endpoints| {
+        let mut endpoints = endpoints.borrow_mut();
+        let stats = endpoints.entry(endpoint).or_default();
+
+        stats.calls += 1;
+        stats.instructions += instructions;
+        let bucket = INSTRUCTION_BUCKETS
+            .iter()
+            .position(|bound| instructions <= *bound)
+            .unwrap_or(INSTRUCTION_BUCKETS.len());
+        stats.buckets[bucket] += 1;
+        if let Some(error) = error {
+            *stats.errors.entry(error).or_default() += 1;
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * crate::metrics::WASM_PAGE_SIZE
+}
+
+// only the wasm build has a heap memory to measure
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_bytes() -> u64 {
+    0
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+// label values are names chosen by this canister, they need no escaping
+fn sample(out: &mut String, name: &str, labels: &[(&str, &dyn Display)], value: impl Display) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, value))
+        .collect();
+    if labels.is_empty() {
+        writeln!(out, "{} {}", name, value).unwrap();
+    } else {
+        writeln!(out, "{}{{{}}} {}", name, labels.join(","), value).unwrap();
+    }
+}
+
+fn pragma_u64(db: &Connection, name: &str) -> rusqlite::Result<u64> {
+    db.pragma_query_value(None, name, |row| row.get(0))
+}
+
+/// Renders all metrics.
+pub fn render(db: &Connection) -> rusqlite::Result<String> {
+    let mut out = String::new();
+    let out = &mut out;
+
+    ENDPOINTS.with(|endpoints| {
+        let endpoints = endpoints.borrow();
+
+        header(
+            out,
+            "demo3_update_calls_total",
+            "counter",
+            "Update calls of an endpoint.",
+        );
+        for (endpoint, stats) in endpoints.iter() {
+            sample(
+                out,
+                "demo3_update_calls_total",
+                &[("endpoint", endpoint)],
+                stats.calls,
+            );
+        }
+
+        header(
+            out,
+            "demo3_update_call_instructions",
+            "histogram",
+            "Instructions used by the update calls of an endpoint.",
+        );
+        for (endpoint, stats) in endpoints.iter() {
+            let bounds = INSTRUCTION_BUCKETS
+                .iter()
+                .map(|bound| bound.to_string())
+                .chain(["+Inf".to_string()]);
+            let mut cumulative = 0;
+            for (bound, calls) in bounds.zip(stats.buckets) {
+                cumulative += calls;
+                sample(
+                    out,
+                    "demo3_update_call_instructions_bucket",
+                    &[("endpoint", endpoint), ("le", &bound)],
+                    cumulative,
+                );
+            }
+            let labels: &[(&str, &dyn Display)] = &[("endpoint", endpoint)];
+            sample(
+                out,
+                "demo3_update_call_instructions_sum",
+                labels,
+                stats.instructions,
+            );
+            sample(
+                out,
+                "demo3_update_call_instructions_count",
+                labels,
+                stats.calls,
+            );
+        }
+
+        header(
+            out,
+            "demo3_update_errors_total",
+            "counter",
+            "Update calls of an endpoint that returned an error, by error code.",
+        );
+        for (endpoint, stats) in endpoints.iter() {
+            for (code, count) in &stats.errors {
+                sample(
+                    out,
+                    "demo3_update_errors_total",
+                    &[("endpoint", endpoint), ("code", code)],
+                    count,
+                );
+            }
+        }
+    });
+
+    let gauges = [
+        (
+            "demo3_database_pages",
+            "Pages of the database file.",
+            "page_count",
+        ),
+        (
+            "demo3_database_freelist_pages",
+            "Unused pages of the database file.",
+            "freelist_count",
+        ),
+        (
+            "demo3_database_page_size_bytes",
+            "Size of a database page.",
+            "page_size",
+        ),
+    ];
+    for (name, help, pragma) in gauges {
+        header(out, name, "gauge", help);
+        sample(out, name, &[], pragma_u64(db, pragma)?);
+    }
+
+    header(
+        out,
+        "demo3_stable_memory_pages",
+        "gauge",
+        "Wasm pages of 64 KiB used by a memory of the memory manager.",
+    );
+    for memory in storage::memory_stats() {
+        sample(
+            out,
+            "demo3_stable_memory_pages",
+            &[("memory_id", &memory.memory_id), ("name", &memory.name)],
+            memory.pages,
+        );
+    }
+
+    header(out, "demo3_heap_bytes", "gauge", "Size of the heap memory.");
+    sample(out, "demo3_heap_bytes", &[], heap_bytes());
+
+    let runs = config::get().maintenance_runs;
+    header(
+        out,
+        "demo3_maintenance_last_run_timestamp_seconds",
+        "gauge",
+        "Start of the last run of a maintenance task.",
+    );
+    for run in &runs {
+        let task = format!("{:?}", run.task);
+        sample(
+            out,
+            "demo3_maintenance_last_run_timestamp_seconds",
+            &[("task", &task)],
+            run.started_at / 1_000_000_000,
+        );
+    }
+    header(
+        out,
+        "demo3_maintenance_last_run_instructions",
+        "gauge",
+        "Instructions used by the last run of a maintenance task.",
+    );
+    for run in &runs {
+        let task = format!("{:?}", run.task);
+        sample(
+            out,
+            "demo3_maintenance_last_run_instructions",
+            &[("task", &task)],
+            run.instructions,
+        );
+    }
+    header(
+        out,
+        "demo3_maintenance_last_run_outcome",
+        "gauge",
+        "Outcome of the last run of a maintenance task, always 1.",
+    );
+    for run in &runs {
+        let task = format!("{:?}", run.task);
+        let outcome = match run.outcome {
+            Outcome::Done { .. } => "Done",
+            Outcome::Interrupted => "Interrupted",
+            Outcome::Skipped { .. } => "Skipped",
+            Outcome::Failed { .. } => "Failed",
+        };
+        sample(
+            out,
+            "demo3_maintenance_last_run_outcome",
+            &[("task", &task), ("outcome", &outcome)],
+            1,
+        );
+    }
+
+    Ok(std::mem::take(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_calls_and_database_gauges() {
+        let db = crate::util::database();
+
+        record_call("add", 50_000, None);
+        record_call("add", 3_000_000, Some("QuotaExceeded"));
+        record_call("add", 50_000_000_000, Some("QuotaExceeded"));
+        record_call("vacuum", 2_000_000_000, Some("Unauthorized"));
+
+        let text = render(&db).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "# TYPE demo3_update_calls_total counter",
+            "demo3_update_calls_total{endpoint=\"add\"} 3",
+            "demo3_update_calls_total{endpoint=\"vacuum\"} 1",
+            "# TYPE demo3_update_call_instructions histogram",
+            "demo3_update_call_instructions_bucket{endpoint=\"add\",le=\"100000\"} 1",
+            "demo3_update_call_instructions_bucket{endpoint=\"add\",le=\"1000000\"} 1",
+            "demo3_update_call_instructions_bucket{endpoint=\"add\",le=\"10000000\"} 2",
+            "demo3_update_call_instructions_bucket{endpoint=\"add\",le=\"40000000000\"} 2",
+            "demo3_update_call_instructions_bucket{endpoint=\"add\",le=\"+Inf\"} 3",
+            "demo3_update_call_instructions_sum{endpoint=\"add\"} 50003050000",
+            "demo3_update_call_instructions_count{endpoint=\"add\"} 3",
+            "demo3_update_call_instructions_bucket{endpoint=\"vacuum\",le=\"1000000000\"} 0",
+            "demo3_update_call_instructions_bucket{endpoint=\"vacuum\",le=\"5000000000\"} 1",
+            "demo3_update_errors_total{endpoint=\"add\",code=\"QuotaExceeded\"} 2",
+            "demo3_update_errors_total{endpoint=\"vacuum\",code=\"Unauthorized\"} 1",
+            "demo3_database_pages 2",
+            "demo3_database_freelist_pages 0",
+            "# TYPE demo3_heap_bytes gauge",
+        ] {
+            assert!(lines.contains(&expected), "{expected} missing from\n{text}");
+        }
+
+        // every sample belongs to a declared metric
+        let declared: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .filter_map(|line| line.split(' ').next())
+            .collect();
+        for sample in lines.iter().filter(|line| !line.starts_with('#')) {
+            let name = sample.split(['{', ' ']).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .filter(|family| declared.contains(family))
+                .unwrap_or(name);
+            assert!(declared.contains(&family), "{sample}");
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Storage quota, refuses writes before the canister runs out of stable memory.
+//!
+//! The soft limit is checked before every write that may grow the database. The usage
+//! counts the pages of the database that hold data, so that deletes and vacuum lower it,
+//! and the stable memory handed out by the `MemoryManager` for everything else. Deletes
+//! and maintenance stay possible past the soft limit, see `crate::check_writable`. The
+//! hard limit is enforced by SQLite itself through `max_page_count`, which makes a
+//! growing write fail with `SQLITE_FULL` instead of trapping deep inside the file system.
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory;
+use rusqlite::{Connection, ErrorCode};
+
+use crate::{config, Error, MEMORY_MANAGER};
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// SQLite's own upper bound, used when no quota is set
+const DEFAULT_MAX_PAGE_COUNT: u64 = 0xfffffffe;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StorageQuota {
+    /// Stable memory the canister may use, also the upper bound for the database file.
+    pub max_bytes: u64,
+    /// Writes are refused once this share of `max_bytes` is in use.
+    pub soft_limit_percent: u8,
+}
+
+impl StorageQuota {
+    pub fn soft_limit_bytes(&self) -> u64 {
+        (self.max_bytes as u128 * self.soft_limit_percent as u128 / 100) as u64
+    }
+}
+
+pub fn validate(quota: &StorageQuota) -> Result<(), Error> {
+    if quota.soft_limit_percent == 0 || quota.soft_limit_percent > 100 {
+        return Err(Error::InvalidArgument {
+            message: format!(
+                "soft_limit_percent must be between 1 and 100, got {}",
+                quota.soft_limit_percent
+            ),
+        });
+    }
+
+    if quota.max_bytes < WASM_PAGE_SIZE {
+        return Err(Error::InvalidArgument {
+            message: format!("max_bytes must be at least {}", WASM_PAGE_SIZE),
+        });
+    }
+
+    Ok(())
+}
+
+// memories that hold the database and its journal, a memory never shrinks so the pages
+// of the database are counted instead
+fn holds_database(id: u8) -> bool {
+    id == crate::MOUNTED_MEMORY_ID
+        || id == crate::JOURNAL_MEMORY_ID
+        || (crate::WASI_MEMORY_ID..crate::WASI_MEMORY_ID + 10).contains(&id)
+}
+
+/// Returns the bytes in use: the pages of the database that are not on the freelist,
+/// and all other memories of the `MemoryManager`.
+pub fn used_bytes(db: &Connection) -> rusqlite::Result<u64> {
+    let pragma = |name| db.pragma_query_value(None, name, |row| row.get::<_, u64>(0));
+    let database = (pragma("page_count")? - pragma("freelist_count")?) * pragma("page_size")?;
+
+    let others = MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+        (0..u8::MAX)
+            .filter(|id| !holds_database(*id))
+            .map(|id| m.get(MemoryId::new(id)).size())
+            .sum::<u64>()
+            * WASM_PAGE_SIZE
+    });
+
+    Ok(database + others)
+}
+
+/// Checks `used_bytes` against the soft limit of the quota.
+pub fn check(quota: Option<&StorageQuota>, used_bytes: u64) -> Result<(), Error> {
+    match quota {
+        Some(quota) if used_bytes >= quota.soft_limit_bytes() => Err(Error::QuotaExceeded {
+            used_bytes,
+            soft_limit_bytes: quota.soft_limit_bytes(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Guard for writes that may grow the database, reads are never restricted.
+pub fn check_writable(db: &Connection) -> Result<(), Error> {
+    let Some(quota) = config::get().storage_quota else {
+        return Ok(());
+    };
+    check(Some(&quota), used_bytes(db).map_err(map_write_error)?)
+}
+
+/// Caps the database file at the quota and returns the effective `max_page_count`.
+///
+/// SQLite never lowers `max_page_count` below the current size of the database.
+pub fn apply_page_limit(db: &Connection, quota: Option<&StorageQuota>) -> rusqlite::Result<u64> {
+    let max_page_count = match quota {
+        Some(quota) => {
+            let page_size: u64 = db.pragma_query_value(None, "page_size", |row| row.get(0))?;
+            (quota.max_bytes / page_size).max(1)
+        }
+        None => DEFAULT_MAX_PAGE_COUNT,
+    };
+
+    db.pragma_update_and_check(None, "max_page_count", max_page_count, |row| row.get(0))
+}
+
+/// Converts a failed write into `StorageFull` when SQLite ran into `max_page_count`.
+pub fn map_write_error(err: rusqlite::Error) -> Error {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::DiskFull) => Error::StorageFull,
+        _ => Error::CanisterError {
+            message: format!("{:?}", err),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(max_bytes: u64, soft_limit_percent: u8) -> StorageQuota {
+        StorageQuota {
+            max_bytes,
+            soft_limit_percent,
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_quotas() {
+        assert!(validate(&quota(1 << 30, 90)).is_ok());
+        assert!(validate(&quota(1 << 30, 0)).is_err());
+        assert!(validate(&quota(1 << 30, 101)).is_err());
+        assert!(validate(&quota(1024, 90)).is_err());
+    }
+
+    #[test]
+    fn refuses_writes_past_the_soft_limit() {
+        let q = quota(1000 * WASM_PAGE_SIZE, 90);
+
+        assert!(check(None, u64::MAX).is_ok());
+        assert!(check(Some(&q), 899 * WASM_PAGE_SIZE).is_ok());
+        assert!(matches!(
+            check(Some(&q), 900 * WASM_PAGE_SIZE),
+            Err(Error::QuotaExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn counts_the_pages_in_use() {
+        let db = crate::util::database();
+        let page_size: u64 = db
+            .pragma_query_value(None, "page_size", |row| row.get(0))
+            .unwrap();
+        let empty = used_bytes(&db).unwrap();
+
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+            INSERT INTO person (name, data) SELECT 'x', hex(randomblob(1000)) FROM n;",
+        )
+        .unwrap();
+        assert!(used_bytes(&db).unwrap() > empty + 200 * 2000);
+
+        // the pages of the deleted rows go to the freelist, the file keeps its size
+        db.execute("DELETE FROM person", []).unwrap();
+        assert!(used_bytes(&db).unwrap() <= empty + page_size);
+    }
+
+    #[test]
+    fn sqlite_refuses_to_grow_past_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        db.execute_batch("PRAGMA page_size = 4096; CREATE TABLE person (data TEXT);")
+            .unwrap();
+
+        let q = quota(64 * 4096, 90);
+        assert_eq!(apply_page_limit(&db, Some(&q)).unwrap(), 64);
+
+        let err = (0..100)
+            .try_for_each(|_| {
+                db.execute(
+                    "INSERT INTO person (data) VALUES (hex(randomblob(2000)))",
+                    [],
+                )
+                .map(|_| ())
+            })
+            .unwrap_err();
+        assert!(matches!(map_write_error(err), Error::StorageFull));
+
+        // reads keep working on a full database
+        let count: i64 = db
+            .query_row("SELECT count(*) FROM person", [], |row| row.get(0))
+            .unwrap();
+        assert!(count > 0);
+
+        assert_eq!(apply_page_limit(&db, None).unwrap(), DEFAULT_MAX_PAGE_COUNT);
+    }
+}
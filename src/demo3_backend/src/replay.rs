@@ -0,0 +1,1172 @@
+//! Logical replay log, a second copy of the data to rebuild the database from.
+//!
+//! When enabled, every successful write is appended to a `StableLog` in memories of
+//! its own, with the SQL, the bound parameters, the caller and the time. The entry is
+//! written in the same message as the change, so the log is in commit order and a
+//! trap drops both. The log memories are encrypted like the database files.
+//!
+//! A snapshot is a copy of the database file at a log index, in memories of its own
+//! that are encrypted like the database files. `truncate` takes one with the online
+//! backup API of SQLite, a few pages at a time over as many calls as needed. Writes made
+//! meanwhile through the live connection reach the copy as well, so the snapshot matches
+//! the log index at which its last pages are copied. A new snapshot is taken in the other
+//! of two slots and only replaces the previous one once it is complete. The entries
+//! before the snapshot are then dropped: a `StableLog` only grows, so the kept entries
+//! are copied in chunks to a second log, which replaces the first one once it caught up.
+//! The indexes of the kept entries do not change.
+//!
+//! A rebuild replays the log into the snapshot from its index on, over as many calls as
+//! needed. The snapshot is brought up to date this way and can then replace the live
+//! database. The activation copies the snapshot over the live database with the backup
+//! API, a few pages at a time, on a timer; the connection is taken out of use until the
+//! copy is complete, and an upgrade in between rolls the copy back. The change feed and
+//! the changesets are reset afterwards, their consumers reload the tables and the
+//! followers resync. Without a snapshot the log is only replayed into a new, empty database if
+//! it was enabled while the live database still was one, as recorded at that time.
+//! Enabling the log again drops the snapshot, the writes made while it was disabled are
+//! not in the log; a rebuild is refused until `truncate` took a new one.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_int, CStr};
+use std::time::Duration;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableLog, Storable};
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::{ffi, Connection, ToSql};
+
+use crate::config::{self, Backend};
+use crate::encryption::EncryptedMemory;
+use crate::util::{invalid, quote, to_error};
+use crate::{changes, changesets, migrate, Error, DB, MEMORY_MANAGER};
+
+pub const REPLAY_LOG_INDEX_MEMORY_ID: u8 = 23;
+pub const REPLAY_LOG_DATA_MEMORY_ID: u8 = 24;
+pub const SECOND_LOG_INDEX_MEMORY_ID: u8 = 30;
+pub const SECOND_LOG_DATA_MEMORY_ID: u8 = 31;
+
+// index and data memories of the two logs, a truncation moves from one to the other
+const LOG_MEMORY_IDS: [(u8, u8); 2] = [
+    (REPLAY_LOG_INDEX_MEMORY_ID, REPLAY_LOG_DATA_MEMORY_ID),
+    (SECOND_LOG_INDEX_MEMORY_ID, SECOND_LOG_DATA_MEMORY_ID),
+];
+
+/// Memories of the two snapshot slots, mounted as `SNAPSHOT_FILE_NAMES`.
+pub const SNAPSHOT_MEMORY_IDS: [u8; 2] = [28, 29];
+pub const SNAPSHOT_FILE_NAMES: [&str; 2] = ["db.snapshot0.db3", "db.snapshot1.db3"];
+
+// instructions one `rebuild` call may spend on replaying entries
+const REPLAY_INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+// instructions one `truncate` call may spend on the snapshot or on copying entries
+const TRUNCATE_INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+// instructions one message may spend on copying the rebuilt database over the live one
+const ACTIVATION_INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+const ACTIVATION_INTERVAL: Duration = Duration::from_secs(1);
+
+// pages copied by the backup API between two checks of the instruction counter
+const SNAPSHOT_STEP_PAGES: c_int = 64;
+
+// entries returned by one `page` call at most
+const MAX_PAGE_SIZE: u64 = 1000;
+
+type LogMemory = EncryptedMemory<VirtualMemory<DefaultMemoryImpl>>;
+
+/// A value bound to a statement parameter.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl SqlValue {
+    /// Approximate size of the value in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            SqlValue::Text(v) => v.len(),
+            SqlValue::Blob(v) => v.len(),
+            _ => 8,
+        }
+    }
+}
+
+impl From<ValueRef<'_>> for SqlValue {
+    fn from(value: ValueRef) -> Self {
+        match value {
+            ValueRef::Null => SqlValue::Null,
+            ValueRef::Integer(v) => SqlValue::Integer(v),
+            ValueRef::Real(v) => SqlValue::Real(v),
+            ValueRef::Text(v) => SqlValue::Text(String::from_utf8_lossy(v).into_owned()),
+            ValueRef::Blob(v) => SqlValue::Blob(v.to_vec()),
+        }
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Owned(match self {
+            SqlValue::Null => Value::Null,
+            SqlValue::Integer(v) => Value::Integer(*v),
+            SqlValue::Real(v) => Value::Real(*v),
+            SqlValue::Text(v) => Value::Text(v.clone()),
+            SqlValue::Blob(v) => Value::Blob(v.clone()),
+        }))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub sql: String,
+    pub params: Vec<SqlValue>,
+    pub caller: Principal,
+    /// Time of the write in nanoseconds.
+    pub time: u64,
+}
+
+impl Storable for LogEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Replay log settings kept in the config.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ReplayLogConfig {
+    pub enabled: bool,
+    /// Index of the first entry still in the log, raised by `truncate`.
+    pub first_index: u64,
+    /// Next entry to replay while a rebuild is running.
+    pub rebuild_cursor: Option<u64>,
+    /// The log in use, 0 or 1.
+    #[serde(default)]
+    pub log: u8,
+    /// The last complete snapshot.
+    #[serde(default)]
+    pub snapshot: Option<Snapshot>,
+    /// A running truncation.
+    #[serde(default)]
+    pub truncation: Option<Truncation>,
+    /// The database was still empty when the log was enabled, so replaying the log from
+    /// `enabled_at` into a new database rebuilds it.
+    #[serde(default)]
+    pub covers_creation: bool,
+    /// Index of the first entry logged since the log was last enabled.
+    #[serde(default)]
+    pub enabled_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The slot holding it, 0 or 1.
+    pub slot: u8,
+    /// Index of the first entry whose write is not in the snapshot.
+    pub index: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Truncation {
+    pub up_to: u64,
+    /// Next entry to copy to the other log, none while the snapshot is taken.
+    pub next: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub struct TruncateProgress {
+    /// Index of the snapshot the entries are dropped after, none until it is complete.
+    pub snapshot_index: Option<u64>,
+    /// Entries copied to the new log so far.
+    pub copied: u64,
+    /// Entries left once the log is truncated, call again while this is none.
+    pub remaining: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LogPage {
+    pub first_index: u64,
+    /// Index the next entry will get.
+    pub end_index: u64,
+    pub entries: Vec<(u64, LogEntry)>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ActivationProgress {
+    /// Pages of the rebuilt database copied over the live one so far.
+    pub copied_pages: u64,
+    pub total_pages: u64,
+    /// The rebuilt database is in use, until then a timer continues the copy.
+    pub done: bool,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RebuildProgress {
+    pub replayed: u64,
+    pub end_index: u64,
+    /// Result of `PRAGMA quick_check` on the rebuilt file once every entry is replayed.
+    pub quick_check: Option<String>,
+}
+
+type Log = StableLog<LogEntry, LogMemory, LogMemory>;
+
+fn memory(id: u8) -> LogMemory {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+    EncryptedMemory::new(memory, id)
+}
+
+fn open_log(log: u8) -> Log {
+    let (index, data) = LOG_MEMORY_IDS[log as usize];
+    StableLog::init(memory(index), memory(data)).expect("failed to initialize the replay log")
+}
+
+// a snapshot being taken, it starts over after an upgrade
+struct SnapshotCopy {
+    // declared first, the backup is finished before its destination is closed
+    backup: *mut ffi::sqlite3_backup,
+    destination: Connection,
+}
+
+impl Drop for SnapshotCopy {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_backup_finish(self.backup) };
+    }
+}
+
+// the rebuilt database being copied over the live one, whose connection is out of `DB`
+// meanwhile
+struct Activation {
+    backup: *mut ffi::sqlite3_backup,
+    live: Option<Connection>,
+    // the source of the backup, closed once it is finished
+    _rebuilt: Connection,
+}
+
+impl Activation {
+    // finishes the backup, which rolls back a copy that is not complete, and returns the
+    // connection
+    fn finish(mut self) -> (c_int, Connection) {
+        let rc = unsafe { ffi::sqlite3_backup_finish(self.backup) };
+        // finishing a null backup does nothing
+        self.backup = std::ptr::null_mut();
+        (rc, self.live.take().unwrap())
+    }
+}
+
+impl Drop for Activation {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_backup_finish(self.backup) };
+    }
+}
+
+thread_local! {
+    static LOG: RefCell<Log> = RefCell::new(open_log(config::get().replay_log.log));
+
+    static SNAPSHOT_COPY: RefCell<Option<SnapshotCopy>> = const { RefCell::new(None) };
+
+    static ACTIVATION: RefCell<Option<Activation>> = const { RefCell::new(None) };
+
+    static ACTIVATION_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+fn end_index() -> u64 {
+    config::get().replay_log.first_index + LOG.with(|log| log.borrow().len())
+}
+
+fn error_message(db: &Connection) -> String {
+    unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(db.handle())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Mounts the memories of the snapshot slots as files.
+pub fn mount_snapshot_files() {
+    for (id, file_name) in SNAPSHOT_MEMORY_IDS.iter().zip(SNAPSHOT_FILE_NAMES) {
+        ic_wasi_polyfill::mount_memory_file(file_name, Box::new(memory(*id)));
+    }
+}
+
+// opens a snapshot, its journal stays on the heap so that no page is written in plaintext
+fn open_snapshot(file_name: &str) -> Result<Connection, Error> {
+    let db = Connection::open(file_name).map_err(to_error)?;
+    db.pragma_update_and_check(None, "journal_mode", "MEMORY", |row| {
+        row.get::<_, String>(0)
+    })
+    .map_err(to_error)?;
+    Ok(db)
+}
+
+/// Appends a successful write to the log if it is enabled.
+///
+/// Traps if the entry cannot be stored, which also rolls back the write itself.
+pub fn record(sql: &str, params: &[SqlValue], caller: Principal, time: u64) {
+    if !config::get().replay_log.enabled {
+        return;
+    }
+
+    let entry = LogEntry {
+        sql: sql.to_string(),
+        params: params.to_vec(),
+        caller,
+        time,
+    };
+
+    if let Err(err) = LOG.with(|log| log.borrow().append(&entry)) {
+        ic_cdk::trap(&format!("failed to append to the replay log: {:?}", err));
+    }
+}
+
+/// Enables or disables the log, records whether it covers the database from its creation.
+pub fn set_enabled(db: &Connection, enabled: bool) -> Result<(), Error> {
+    let settings = config::get().replay_log;
+    if settings.enabled == enabled {
+        return Ok(());
+    }
+    if settings.rebuild_cursor.is_some() {
+        return Err(invalid("a rebuild from the replay log is running"));
+    }
+    if settings.truncation.is_some() {
+        return Err(invalid("a truncation of the replay log is running"));
+    }
+
+    if !enabled {
+        abort_snapshot();
+        config::update(|c| {
+            c.replay_log.enabled = false;
+            c.replay_log.covers_creation = false;
+        });
+        return Ok(());
+    }
+
+    let covers_creation = as_created(db)?;
+    let enabled_at = end_index();
+    config::update(|c| {
+        c.replay_log.enabled = true;
+        c.replay_log.covers_creation = covers_creation;
+        c.replay_log.enabled_at = enabled_at;
+        // it misses the writes made while the log was disabled
+        c.replay_log.snapshot = None;
+    });
+
+    Ok(())
+}
+
+// whether the database has the schema of a new one and no rows
+fn as_created(db: &Connection) -> Result<bool, Error> {
+    let schema = |db: &Connection| -> rusqlite::Result<Vec<(String, String, Option<String>)>> {
+        db.prepare(
+            "SELECT type, name, sql FROM sqlite_schema
+            WHERE name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect()
+    };
+
+    let new = Connection::open_in_memory().map_err(to_error)?;
+    crate::create_tables_in(&new).map_err(to_error)?;
+    let live = schema(db).map_err(to_error)?;
+    if live != schema(&new).map_err(to_error)? {
+        return Ok(false);
+    }
+
+    for (kind, name, _) in live {
+        if kind != "table" {
+            continue;
+        }
+        let has_rows: bool = db
+            .query_row(
+                &format!("SELECT EXISTS (SELECT 1 FROM {})", quote(&name)),
+                [],
+                |row| row.get(0),
+            )
+            .map_err(to_error)?;
+        if has_rows {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns up to `limit` entries starting at `start`, for auditing.
+pub fn page(start: u64, limit: u64) -> LogPage {
+    let first_index = config::get().replay_log.first_index;
+    let start = start.max(first_index);
+
+    let entries = LOG.with(|log| {
+        let log = log.borrow();
+        (start - first_index..log.len())
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .filter_map(|idx| log.get(idx).map(|entry| (first_index + idx, entry)))
+            .collect()
+    });
+
+    LogPage {
+        first_index,
+        end_index: end_index(),
+        entries,
+    }
+}
+
+/// Stops taking a snapshot, before the live connection it copies is closed.
+pub fn abort_snapshot() {
+    SNAPSHOT_COPY.with(|copy| *copy.borrow_mut() = None);
+}
+
+// copies pages of the live database to the free snapshot slot until the instruction
+// budget is used up, returns the snapshot once it is complete
+fn take_snapshot(
+    db: &Connection,
+    instruction_counter: &impl Fn() -> u64,
+    snapshot_files: [&str; 2],
+) -> Result<Option<Snapshot>, Error> {
+    let slot = config::get()
+        .replay_log
+        .snapshot
+        .map_or(0, |snapshot| 1 - snapshot.slot);
+
+    SNAPSHOT_COPY.with(|copy| {
+        let mut copy = copy.borrow_mut();
+
+        if copy.is_none() {
+            let destination = open_snapshot(snapshot_files[slot as usize])?;
+            let main = c"main".as_ptr();
+            let backup =
+                unsafe { ffi::sqlite3_backup_init(destination.handle(), main, db.handle(), main) };
+            if backup.is_null() {
+                return Err(Error::CanisterError {
+                    message: format!("cannot take a snapshot: {}", error_message(&destination)),
+                });
+            }
+            *copy = Some(SnapshotCopy {
+                backup,
+                destination,
+            });
+        }
+
+        let backup = copy.as_ref().unwrap().backup;
+        loop {
+            match unsafe { ffi::sqlite3_backup_step(backup, SNAPSHOT_STEP_PAGES) } {
+                ffi::SQLITE_DONE => break,
+                ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => {
+                    if instruction_counter() >= TRUNCATE_INSTRUCTION_BUDGET {
+                        return Ok(None);
+                    }
+                }
+                _ => {
+                    let message = error_message(&copy.take().unwrap().destination);
+                    return Err(Error::CanisterError {
+                        message: format!("taking the snapshot failed: {}", message),
+                    });
+                }
+            }
+        }
+        *copy = None;
+
+        // the writes of the entries up to here are in the copy
+        let snapshot = Snapshot {
+            slot,
+            index: end_index(),
+        };
+        config::update(|c| c.replay_log.snapshot = Some(snapshot));
+        Ok(Some(snapshot))
+    })
+}
+
+/// Drops the entries before `up_to` over as many calls as needed.
+///
+/// The live database must pass `PRAGMA quick_check` when the truncation starts, a
+/// damaged database keeps its log. A snapshot at `up_to` or later is taken first unless
+/// there is one already, then the kept entries are copied to the other log.
+pub fn truncate(
+    db: &Connection,
+    up_to: u64,
+    instruction_counter: impl Fn() -> u64,
+) -> Result<TruncateProgress, Error> {
+    truncate_with(db, up_to, instruction_counter, SNAPSHOT_FILE_NAMES)
+}
+
+fn truncate_with(
+    db: &Connection,
+    up_to: u64,
+    instruction_counter: impl Fn() -> u64,
+    snapshot_files: [&str; 2],
+) -> Result<TruncateProgress, Error> {
+    let settings = config::get().replay_log;
+
+    if !settings.enabled {
+        return Err(invalid("the replay log is disabled"));
+    }
+    if settings.rebuild_cursor.is_some() {
+        return Err(invalid("a rebuild from the replay log is running"));
+    }
+    if config::get().backend == Backend::Heap {
+        return Err(invalid(
+            "the snapshot of a Heap database would not survive an upgrade",
+        ));
+    }
+
+    let mut truncation = match settings.truncation {
+        Some(truncation) if truncation.up_to == up_to => truncation,
+        Some(truncation) => {
+            return Err(Error::InvalidArgument {
+                message: format!(
+                    "a truncation up to {} is running, call again with it",
+                    truncation.up_to
+                ),
+            })
+        }
+        None => {
+            let end = end_index();
+            if up_to < settings.first_index || up_to > end {
+                return Err(Error::InvalidArgument {
+                    message: format!("up_to must be between {} and {}", settings.first_index, end),
+                });
+            }
+
+            let check: String = db
+                .query_row("PRAGMA quick_check(1)", [], |row| row.get(0))
+                .map_err(to_error)?;
+            if check != "ok" {
+                return Err(Error::CanisterError {
+                    message: format!("the database failed the check, the log is kept: {}", check),
+                });
+            }
+
+            Truncation { up_to, next: None }
+        }
+    };
+
+    let mut progress = TruncateProgress {
+        snapshot_index: None,
+        copied: 0,
+        remaining: None,
+    };
+
+    let next = match truncation.next {
+        Some(next) => next,
+        None => {
+            let snapshot = match settings.snapshot {
+                Some(snapshot) if snapshot.index >= up_to => Some(snapshot),
+                _ => take_snapshot(db, &instruction_counter, snapshot_files)?,
+            };
+            if snapshot.is_none() {
+                config::update(|c| c.replay_log.truncation = Some(truncation));
+                return Ok(progress);
+            }
+
+            // empties the other log
+            let (index, data) = LOG_MEMORY_IDS[1 - settings.log as usize];
+            Log::new(memory(index), memory(data));
+            up_to
+        }
+    };
+    progress.snapshot_index = config::get().replay_log.snapshot.map(|s| s.index);
+
+    // the entries appended meanwhile are copied as well, until the copy caught up
+    let other = 1 - settings.log;
+    let target = open_log(other);
+    let next = LOG.with(|log| {
+        let log = log.borrow();
+        let mut next = next;
+        // at least one entry per call, so that the copy always moves on
+        while next - settings.first_index < log.len() {
+            let entry = log.get(next - settings.first_index).unwrap();
+            if let Err(err) = target.append(&entry) {
+                ic_cdk::trap(&format!("failed to copy the replay log: {:?}", err));
+            }
+            next += 1;
+            if instruction_counter() >= TRUNCATE_INSTRUCTION_BUDGET {
+                break;
+            }
+        }
+        next
+    });
+    progress.copied = next - up_to;
+
+    if next < end_index() {
+        truncation.next = Some(next);
+        config::update(|c| c.replay_log.truncation = Some(truncation));
+        return Ok(progress);
+    }
+
+    LOG.with(|log| *log.borrow_mut() = target);
+    config::update(|c| {
+        c.replay_log.log = other;
+        c.replay_log.first_index = up_to;
+        c.replay_log.truncation = None;
+    });
+    progress.remaining = Some(next - up_to);
+
+    Ok(progress)
+}
+
+/// Replays entries from `cursor` into `db` within the instruction budget and
+/// returns the next entry to replay.
+fn replay(
+    db: &mut Connection,
+    cursor: u64,
+    instruction_counter: impl Fn() -> u64,
+) -> Result<u64, Error> {
+    let first_index = config::get().replay_log.first_index;
+    let tx = db.transaction().map_err(to_error)?;
+    let mut cursor = cursor;
+
+    LOG.with(|log| {
+        let log = log.borrow();
+
+        while cursor - first_index < log.len() && instruction_counter() < REPLAY_INSTRUCTION_BUDGET
+        {
+            let entry = log.get(cursor - first_index).unwrap();
+            tx.execute(&entry.sql, rusqlite::params_from_iter(&entry.params))
+                .map_err(|err| Error::CanisterError {
+                    message: format!("replaying entry {} failed: {:?}", cursor, err),
+                })?;
+            cursor += 1;
+        }
+
+        Ok(())
+    })?;
+
+    tx.commit().map_err(to_error)?;
+
+    Ok(cursor)
+}
+
+/// Replays the log into the snapshot from its index on, continues a running rebuild.
+pub fn rebuild(instruction_counter: impl Fn() -> u64) -> Result<RebuildProgress, Error> {
+    rebuild_with(instruction_counter, SNAPSHOT_FILE_NAMES)
+}
+
+fn rebuild_with(
+    instruction_counter: impl Fn() -> u64,
+    snapshot_files: [&str; 2],
+) -> Result<RebuildProgress, Error> {
+    let config = config::get();
+    let settings = config.replay_log;
+
+    if config.backend == Backend::Heap {
+        return Err(invalid(
+            "Heap databases cannot be rebuilt from the replay log",
+        ));
+    }
+    if !settings.enabled {
+        return Err(invalid("the replay log is disabled"));
+    }
+    if settings.truncation.is_some() {
+        return Err(invalid("a truncation of the replay log is running"));
+    }
+    check_idle()?;
+
+    let snapshot = match settings.snapshot {
+        Some(snapshot) => snapshot,
+        None if !settings.covers_creation || settings.first_index > settings.enabled_at => {
+            return Err(invalid(
+                "the replay log does not cover the whole database, \
+                take a snapshot with truncate_replay_log first",
+            ))
+        }
+        None => {
+            // the log starts with an empty database, its first snapshot is an empty one
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(snapshot_files[0])
+                .map_err(|err| Error::CanisterError {
+                    message: format!("{:?}", err),
+                })?;
+            let db = open_snapshot(snapshot_files[0])?;
+            db.pragma_update(None, "page_size", crate::PAGE_SIZE)
+                .map_err(to_error)?;
+            crate::create_tables_in(&db).map_err(to_error)?;
+            Snapshot {
+                slot: 0,
+                index: settings.enabled_at,
+            }
+        }
+    };
+
+    let mut db = open_snapshot(snapshot_files[snapshot.slot as usize])?;
+    let cursor = replay(&mut db, snapshot.index, instruction_counter)?;
+    config::update(|c| {
+        c.replay_log.snapshot = Some(Snapshot {
+            index: cursor,
+            ..snapshot
+        });
+        c.replay_log.rebuild_cursor = Some(cursor);
+    });
+
+    let end_index = end_index();
+    let quick_check = if cursor == end_index {
+        Some(
+            db.query_row("PRAGMA quick_check(1)", [], |row| row.get(0))
+                .map_err(to_error)?,
+        )
+    } else {
+        None
+    };
+
+    Ok(RebuildProgress {
+        replayed: cursor,
+        end_index,
+        quick_check,
+    })
+}
+
+/// Whether the rebuilt database is being copied over the live one, which has no
+/// connection meanwhile.
+pub fn activating() -> bool {
+    ACTIVATION.with(|activation| activation.borrow().is_some())
+}
+
+/// Refuses to use or change the database while the rebuilt one is copied over it.
+pub fn check_idle() -> Result<(), Error> {
+    if activating() {
+        return Err(invalid("the rebuilt database is being activated"));
+    }
+    Ok(())
+}
+
+/// Starts or continues copying the rebuilt snapshot over the live database, every
+/// entry must be replayed. A timer continues the copy until it is complete.
+pub fn activate_rebuild(
+    instruction_counter: impl Fn() -> u64,
+) -> Result<ActivationProgress, Error> {
+    let progress = activate_rebuild_with(instruction_counter, SNAPSHOT_FILE_NAMES);
+
+    match &progress {
+        Ok(progress) if !progress.done => {
+            if ACTIVATION_TIMER.get().is_none() {
+                ACTIVATION_TIMER.set(Some(ic_cdk_timers::set_timer_interval(
+                    ACTIVATION_INTERVAL,
+                    || {
+                        if let Err(err) = activate_rebuild(ic_cdk::api::instruction_counter) {
+                            ic_cdk::eprintln!("activating the rebuilt database failed: {:?}", err);
+                        }
+                    },
+                )));
+            }
+        }
+        _ => {
+            if let Some(timer) = ACTIVATION_TIMER.take() {
+                ic_cdk_timers::clear_timer(timer);
+            }
+        }
+    }
+
+    progress
+}
+
+/// Rolls back a running activation and gives the connection back, before an upgrade.
+pub fn abort_activation() {
+    if let Some(activation) = ACTIVATION.with(|activation| activation.borrow_mut().take()) {
+        let (_, live) = activation.finish();
+        DB.with(|db| *db.borrow_mut() = Some(live));
+    }
+}
+
+fn activate_rebuild_with(
+    instruction_counter: impl Fn() -> u64,
+    snapshot_files: [&str; 2],
+) -> Result<ActivationProgress, Error> {
+    if !activating() {
+        start_activation(snapshot_files)?;
+    }
+
+    let backup = ACTIVATION.with(|activation| activation.borrow().as_ref().unwrap().backup);
+    let progress = |done| unsafe {
+        let total_pages = ffi::sqlite3_backup_pagecount(backup) as u64;
+        ActivationProgress {
+            copied_pages: total_pages - ffi::sqlite3_backup_remaining(backup) as u64,
+            total_pages,
+            done,
+        }
+    };
+
+    loop {
+        match unsafe { ffi::sqlite3_backup_step(backup, SNAPSHOT_STEP_PAGES) } {
+            ffi::SQLITE_DONE => break,
+            ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => {
+                if instruction_counter() >= ACTIVATION_INSTRUCTION_BUDGET {
+                    return Ok(progress(false));
+                }
+            }
+            _ => {
+                let message = ACTIVATION.with(|activation| {
+                    error_message(activation.borrow().as_ref().unwrap().live.as_ref().unwrap())
+                });
+                abort_activation();
+                return Err(Error::CanisterError {
+                    message: format!("copying the rebuilt database failed: {}", message),
+                });
+            }
+        }
+    }
+    let progress = progress(true);
+
+    let activation = ACTIVATION.with(|activation| activation.borrow_mut().take().unwrap());
+    let (rc, live) = activation.finish();
+    let message = error_message(&live);
+    DB.with(|db| *db.borrow_mut() = Some(live));
+    if rc != ffi::SQLITE_OK {
+        return Err(Error::CanisterError {
+            message: format!("copying the rebuilt database failed: {}", message),
+        });
+    }
+
+    // the tables changed without changes or changesets, their consumers start over
+    changes::reset();
+    changesets::reset();
+    config::update(|c| c.replay_log.rebuild_cursor = None);
+    crate::lifecycle::rebuilt();
+
+    Ok(progress)
+}
+
+// takes the connection of the live database, which the backup writes to
+fn start_activation(snapshot_files: [&str; 2]) -> Result<(), Error> {
+    let settings = config::get().replay_log;
+
+    match settings.rebuild_cursor {
+        Some(cursor) if cursor == end_index() => {}
+        Some(_) => return Err(invalid("the rebuild has not replayed every entry yet")),
+        None => return Err(invalid("no rebuild is running")),
+    }
+    migrate::check_idle()?;
+
+    // the rebuild checked the snapshot once it replayed the last entry, and the backup
+    // copies its pages as they are
+    let snapshot = settings.snapshot.unwrap();
+    let rebuilt = open_snapshot(snapshot_files[snapshot.slot as usize])?;
+
+    abort_snapshot();
+    let live = DB.with(|db| db.borrow_mut().take()).unwrap();
+    let main = c"main".as_ptr();
+    let backup = unsafe { ffi::sqlite3_backup_init(live.handle(), main, rebuilt.handle(), main) };
+    if backup.is_null() {
+        let message = error_message(&live);
+        DB.with(|db| *db.borrow_mut() = Some(live));
+        return Err(Error::CanisterError {
+            message: format!("cannot copy the rebuilt database: {}", message),
+        });
+    }
+
+    ACTIVATION.with(|activation| {
+        *activation.borrow_mut() = Some(Activation {
+            backup,
+            live: Some(live),
+            _rebuilt: rebuilt,
+        })
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSERT: &str = "INSERT INTO person (name, data, age) VALUES (?1, ?2, ?3)";
+
+    fn log_inserts(count: u64) {
+        config::update(|c| c.replay_log.enabled = true);
+        for i in 0..count {
+            let params = [
+                SqlValue::Text(format!("person{}", i)),
+                SqlValue::Null,
+                SqlValue::Integer(i as i64),
+            ];
+            record(INSERT, &params, Principal::anonymous(), i);
+        }
+    }
+
+    fn database() -> (tempfile::TempDir, Connection) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        crate::create_tables_in(&db).unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn nothing_is_logged_while_disabled() {
+        record(INSERT, &[], Principal::anonymous(), 0);
+        assert_eq!(end_index(), 0);
+    }
+
+    #[test]
+    fn replays_in_commit_order_across_calls() {
+        log_inserts(10);
+        record(
+            "UPDATE person SET data = ?1 WHERE age < ?2",
+            &[SqlValue::Blob(vec![1, 2]), SqlValue::Integer(5)],
+            Principal::anonymous(),
+            10,
+        );
+
+        let (_dir, mut db) = database();
+        let calls = std::cell::Cell::new(0);
+        let budget = || {
+            calls.set(calls.get() + 1);
+            if calls.get() % 5 == 0 {
+                REPLAY_INSTRUCTION_BUDGET
+            } else {
+                0
+            }
+        };
+
+        let mut cursor = 0;
+        while cursor < end_index() {
+            cursor = replay(&mut db, cursor, budget).unwrap();
+        }
+
+        let (count, updated): (i64, i64) = db
+            .query_row(
+                "SELECT count(*), count(data) FROM person WHERE name LIKE 'person%'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((count, updated), (10, 5));
+    }
+
+    fn snapshot_files(dir: &tempfile::TempDir) -> [String; 2] {
+        SNAPSHOT_FILE_NAMES.map(|name| dir.path().join(name).to_str().unwrap().to_string())
+    }
+
+    fn files(names: &[String; 2]) -> [&str; 2] {
+        [&names[0], &names[1]]
+    }
+
+    fn names(db: &Connection) -> Vec<String> {
+        db.prepare("SELECT name FROM person ORDER BY age")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    // logs and runs the inserts like the endpoints do
+    fn insert(db: &Connection, from: u64, count: u64) {
+        set_enabled(db, true).unwrap();
+        for i in from..from + count {
+            let params = [
+                SqlValue::Text(format!("person{}", i)),
+                SqlValue::Null,
+                SqlValue::Integer(i as i64),
+            ];
+            db.execute(INSERT, rusqlite::params_from_iter(&params))
+                .unwrap();
+            record(INSERT, &params, Principal::anonymous(), i);
+        }
+    }
+
+    // the budget is used up on every `calls`-th check
+    fn every(calls: u64) -> impl Fn() -> u64 {
+        let count = std::cell::Cell::new(0u64);
+        move || {
+            count.set(count.get() + 1);
+            if count.get().is_multiple_of(calls) {
+                u64::MAX
+            } else {
+                0
+            }
+        }
+    }
+
+    // copies the rebuilt database over `db` a step at a time, like the timer does
+    fn activate(db: Connection, snapshot_files: [&str; 2]) -> (Connection, Result<(), Error>) {
+        DB.with(|live| *live.borrow_mut() = Some(db));
+        let activated = loop {
+            match activate_rebuild_with(every(1), snapshot_files) {
+                Ok(progress) if progress.done => break Ok(()),
+                // nothing may use the live database meanwhile
+                Ok(_) => assert!(DB.with(|live| live.borrow().is_none())),
+                Err(err) => break Err(err),
+            }
+        };
+        (DB.with(|live| live.borrow_mut().take().unwrap()), activated)
+    }
+
+    #[test]
+    fn truncate_keeps_the_indexes_of_newer_entries() {
+        let (dir, db) = database();
+        let snapshots = snapshot_files(&dir);
+        insert(&db, 0, 10);
+
+        let progress = loop {
+            let progress = truncate_with(&db, 6, every(3), files(&snapshots)).unwrap();
+            if progress.remaining.is_some() {
+                break progress;
+            }
+            // entries are still appended while the log is copied
+            if progress.snapshot_index.is_some() {
+                insert(&db, end_index(), 1);
+            }
+        };
+        let end = end_index();
+        assert_eq!(progress.remaining, Some(end - 6));
+        assert_eq!(progress.snapshot_index, Some(10));
+        assert!(end > 10);
+        assert!(truncate_with(&db, 3, || 0, files(&snapshots)).is_err());
+
+        let first = page(0, 2);
+        assert_eq!((first.first_index, first.end_index), (6, end));
+        assert_eq!(first.entries.len(), 2);
+        assert_eq!(first.entries[0].0, 6);
+        assert_eq!(
+            first.entries[0].1.params[0],
+            SqlValue::Text("person6".to_string())
+        );
+
+        // new entries continue after the old ones
+        insert(&db, end, 1);
+        assert_eq!(
+            page(end - 1, 10)
+                .entries
+                .iter()
+                .map(|e| e.0)
+                .collect::<Vec<_>>(),
+            [end - 1, end]
+        );
+    }
+
+    #[test]
+    fn snapshot_follows_the_writes_made_while_it_is_taken() {
+        let (dir, db) = database();
+        let snapshots = snapshot_files(&dir);
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+            INSERT INTO person (name, data, age) SELECT 'filler', randomblob(1000), 1000 + i FROM n;",
+        )
+        .unwrap();
+        insert(&db, 0, 4);
+
+        // a page per call
+        let progress = truncate_with(&db, 4, every(1), files(&snapshots)).unwrap();
+        assert_eq!(progress.snapshot_index, None);
+        insert(&db, 4, 2);
+        while truncate_with(&db, 4, every(1), files(&snapshots))
+            .unwrap()
+            .remaining
+            .is_none()
+        {}
+
+        let snapshot = config::get().replay_log.snapshot.unwrap();
+        assert_eq!(snapshot.index, 6);
+        let copy = open_snapshot(&snapshots[snapshot.slot as usize]).unwrap();
+        assert_eq!(names(&copy), names(&db));
+        assert_eq!(
+            copy.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0))
+                .unwrap(),
+            "ok"
+        );
+    }
+
+    #[test]
+    fn rebuilds_from_the_snapshot() {
+        let (dir, db) = database();
+        let snapshots = snapshot_files(&dir);
+        insert(&db, 0, 5);
+
+        // without a snapshot the log is replayed into an empty database
+        let progress = rebuild_with(every(3), files(&snapshots)).unwrap();
+        assert!(progress.replayed < 5 && progress.quick_check.is_none());
+        let (db, activated) = activate(db, files(&snapshots));
+        assert!(activated.is_err());
+        while rebuild_with(every(3), files(&snapshots))
+            .unwrap()
+            .quick_check
+            .is_none()
+        {}
+        let (db, activated) = activate(db, files(&snapshots));
+        activated.unwrap();
+        assert_eq!(config::get().replay_log.rebuild_cursor, None);
+        // the rebuilt database has the tables of a new one
+        assert!(crate::util::exists(&db, "_jobs").unwrap());
+
+        while truncate_with(&db, 5, || 0, files(&snapshots))
+            .unwrap()
+            .remaining
+            .is_none()
+        {}
+        insert(&db, 5, 3);
+        let expected = names(&db);
+
+        // the truncated entries are only in the snapshot
+        db.execute("DELETE FROM person", []).unwrap();
+        let progress = rebuild_with(|| 0, files(&snapshots)).unwrap();
+        assert_eq!(
+            (progress.replayed, progress.end_index, progress.quick_check),
+            (8, 8, Some("ok".to_string()))
+        );
+        assert!(truncate_with(&db, 8, || 0, files(&snapshots)).is_err());
+        let (mut db, activated) = activate(db, files(&snapshots));
+        activated.unwrap();
+        assert_eq!(names(&db), expected);
+
+        // the database stays usable
+        let tx = db.transaction().unwrap();
+        tx.execute("DELETE FROM person WHERE age = 7", []).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(names(&db).len(), 7);
+    }
+
+    #[test]
+    fn a_rebuild_needs_the_writes_from_before_the_log() {
+        let (dir, db) = database();
+        let snapshots = snapshot_files(&dir);
+        // more pages than the activation copies per step
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+            INSERT INTO person (name, data, age) SELECT 'filler', randomblob(1000), 1000 + i FROM n;",
+        )
+        .unwrap();
+        insert(&db, 0, 2);
+        assert!(!config::get().replay_log.covers_creation);
+
+        // the row written before the log was enabled would be lost
+        assert!(rebuild_with(|| 0, files(&snapshots)).is_err());
+
+        let end = end_index();
+        while truncate_with(&db, end, || 0, files(&snapshots))
+            .unwrap()
+            .remaining
+            .is_none()
+        {}
+        insert(&db, 2, 1);
+        let expected = names(&db);
+        assert_eq!(expected.len(), 503);
+        while rebuild_with(|| 0, files(&snapshots))
+            .unwrap()
+            .quick_check
+            .is_none()
+        {}
+        let (db, activated) = activate(db, files(&snapshots));
+        activated.unwrap();
+        assert_eq!(names(&db), expected);
+        // the consumers of the change feed and of the changesets start over
+        assert!(changes::changes_since(0, 10).gap);
+        assert!(changesets::changeset_since(0, false).is_err());
+
+        // the writes made while the log is disabled are not in it, nor in the snapshot
+        set_enabled(&db, false).unwrap();
+        db.execute(INSERT, ("unlogged", "", 200)).unwrap();
+        set_enabled(&db, true).unwrap();
+        assert_eq!(config::get().replay_log.snapshot, None);
+        assert!(rebuild_with(|| 0, files(&snapshots)).is_err());
+    }
+}
@@ -0,0 +1,774 @@
+//! Primary/follower replication between `demo3_backend` canisters.
+//!
+//! A primary captures its writes as changesets (see `changesets`) and a timer pushes
+//! them to every registered follower with a `replicate` call. A follower applies the
+//! batches to its own database, refuses writes from anyone else and serves the usual
+//! queries. Each follower acknowledges the last checkpoint it applied; one that is
+//! new, too far behind or behind the oldest kept changeset is resynced from a
+//! snapshot instead: its tables are emptied and copied page by page, after which the
+//! changesets from the checkpoint the snapshot started at are replayed with the
+//! `Replace` policy. That converges even though the snapshot is copied while the
+//! primary keeps writing.
+//!
+//! Changesets only carry rows, so every `Changes` and `SnapshotStart` batch also carries
+//! the tables, indexes and views of the primary. The follower creates the ones it lacks
+//! and drops the indexes and views the primary no longer has before it applies the rows,
+//! this covers the indexes of `CreateIndex` jobs and index suggestions as well. Triggers
+//! stay on the primary, the rows they write arrive in the changesets. A table is never
+//! dropped or altered on a follower: a changeset for a table the follower lacks, or with
+//! another number of columns, is refused instead of being skipped.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_timers::TimerId;
+use rusqlite::Connection;
+
+use crate::changesets::{self, ConflictPolicy};
+use crate::config;
+use crate::replay::{self, SqlValue};
+use crate::util::{quote, to_error};
+use crate::{Error, DB};
+
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Checkpoints a follower may lag behind before it is resynced from a snapshot.
+pub const DEFAULT_MAX_LAG: u64 = 10_000;
+
+// rows sent in one snapshot batch, well below the inter-canister message limit
+const MAX_SNAPSHOT_PAGE_BYTES: usize = 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Role {
+    #[default]
+    Standalone,
+    Primary,
+    Follower {
+        primary: Principal,
+    },
+}
+
+/// Where a primary is with one of its followers.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct FollowerState {
+    /// Last checkpoint the follower acknowledged, `None` until its first snapshot.
+    pub applied_checkpoint: Option<u64>,
+    pub snapshot: Option<SnapshotCursor>,
+    pub last_error: Option<String>,
+    /// Time of the last answer in nanoseconds.
+    pub last_contact: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotCursor {
+    /// Checkpoint of the primary when the snapshot started.
+    pub checkpoint: u64,
+    /// Table copied last, the tables are copied in name order.
+    pub table: String,
+    pub after_rowid: i64,
+}
+
+/// Replication settings kept in the config.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ReplicationConfig {
+    pub role: Role,
+    /// `DEFAULT_MAX_LAG` if not set.
+    pub max_lag: Option<u64>,
+    /// Followers of a primary.
+    pub followers: BTreeMap<Principal, FollowerState>,
+    /// Last checkpoint of the primary applied by a follower.
+    pub applied_checkpoint: Option<u64>,
+}
+
+/// A table, index or view of the primary.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SchemaObject {
+    /// `table`, `index` or `view`.
+    pub kind: String,
+    pub name: String,
+    pub sql: String,
+}
+
+/// What a primary sends to a follower in one call.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Batch {
+    Changes {
+        from: u64,
+        to: u64,
+        changeset: Vec<u8>,
+        schema: Vec<SchemaObject>,
+    },
+    SnapshotStart {
+        checkpoint: u64,
+        schema: Vec<SchemaObject>,
+    },
+    SnapshotRows {
+        table: String,
+        columns: Vec<String>,
+        rows: Vec<Vec<SqlValue>>,
+        last_rowid: i64,
+    },
+    SnapshotEnd {
+        checkpoint: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FollowerStatus {
+    pub applied_checkpoint: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ReplicationStatus {
+    pub role: Role,
+    /// Checkpoint the next write of a primary is captured under.
+    pub next_checkpoint: u64,
+    pub applied_checkpoint: Option<u64>,
+    pub followers: Vec<(Principal, FollowerState)>,
+}
+
+thread_local! {
+    static TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // followers with a `replicate` call in flight
+    static PUSHING: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Refuses writes on a follower, its data only changes through `replicate`.
+pub fn check_writable() -> Result<(), Error> {
+    match config::get().replication.role {
+        Role::Follower { primary } => Err(Error::ReadOnlyReplica { primary }),
+        _ => Ok(()),
+    }
+}
+
+pub fn set_role(role: Role, max_lag: Option<u64>) {
+    if role == Role::Primary {
+        changesets::set_enabled(true);
+    }
+
+    config::update(|c| {
+        if c.replication.role != role {
+            c.replication.applied_checkpoint = None;
+        }
+        c.replication.role = role;
+        c.replication.max_lag = max_lag;
+    });
+
+    start_timer();
+}
+
+pub fn add_follower(follower: Principal) {
+    config::update(|c| {
+        c.replication.followers.entry(follower).or_default();
+    });
+}
+
+pub fn remove_follower(follower: Principal) {
+    config::update(|c| {
+        c.replication.followers.remove(&follower);
+    });
+}
+
+pub fn status() -> ReplicationStatus {
+    let settings = config::get().replication;
+
+    ReplicationStatus {
+        role: settings.role,
+        next_checkpoint: changesets::next_checkpoint(),
+        applied_checkpoint: settings.applied_checkpoint,
+        followers: settings.followers.into_iter().collect(),
+    }
+}
+
+/// Starts or stops the push timer to match the role, also called after an upgrade.
+pub fn start_timer() {
+    TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some(id) = timer.take() {
+            ic_cdk_timers::clear_timer(id);
+        }
+
+        if config::get().replication.role == Role::Primary {
+            *timer = Some(ic_cdk_timers::set_timer_interval(
+                REPLICATION_INTERVAL,
+                push_all,
+            ));
+        }
+    });
+}
+
+fn push_all() {
+    // the live database has no connection while a rebuilt one is copied over it
+    if replay::activating() {
+        return;
+    }
+
+    for follower in config::get().replication.followers.into_keys() {
+        if PUSHING.with(|p| p.borrow_mut().insert(follower)) {
+            ic_cdk::spawn(async move {
+                push(follower).await;
+                PUSHING.with(|p| p.borrow_mut().remove(&follower));
+            });
+        }
+    }
+}
+
+async fn push(follower: Principal) {
+    let settings = config::get().replication;
+    let Some(state) = settings.followers.get(&follower) else {
+        return;
+    };
+    let max_lag = settings.max_lag.unwrap_or(DEFAULT_MAX_LAG);
+
+    let batch = DB.with(|db| next_batch(db.borrow().as_ref().unwrap(), state, max_lag));
+    let batch = match batch {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return,
+        Err(err) => {
+            ic_cdk::eprintln!("replication: no batch for {}: {:?}", follower, err);
+            return;
+        }
+    };
+
+    let result: Result<FollowerStatus, String> =
+        match ic_cdk::call::<_, (Result<FollowerStatus, Error>,)>(follower, "replicate", (&batch,))
+            .await
+        {
+            Ok((Ok(status),)) => Ok(status),
+            Ok((Err(err),)) => Err(format!("{:?}", err)),
+            Err((code, message)) => Err(format!("{:?}: {}", code, message)),
+        };
+
+    let time = ic_cdk::api::time();
+    config::update(|c| {
+        // the follower may have been removed while the call was in flight
+        if let Some(state) = c.replication.followers.get_mut(&follower) {
+            acknowledge(state, &batch, result, time);
+        }
+    });
+}
+
+/// Chooses what to send to a follower next, `None` if it is up to date.
+pub fn next_batch(
+    db: &Connection,
+    state: &FollowerState,
+    max_lag: u64,
+) -> Result<Option<Batch>, Error> {
+    if let Some(cursor) = &state.snapshot {
+        return snapshot_page(db, cursor).map(Some);
+    }
+
+    let next = changesets::next_checkpoint();
+    let first = changesets::first_checkpoint();
+
+    match state.applied_checkpoint {
+        Some(applied) if applied >= next => Ok(None),
+        Some(applied) if applied >= first && next - applied <= max_lag => {
+            let page = changesets::changeset_since(applied, false)?;
+            Ok(Some(Batch::Changes {
+                from: applied,
+                to: page.next_checkpoint,
+                changeset: page.changeset,
+                schema: schema(db)?,
+            }))
+        }
+        _ => Ok(Some(Batch::SnapshotStart {
+            checkpoint: next,
+            schema: schema(db)?,
+        })),
+    }
+}
+
+// the tables, indexes and views outside of the jobs, the tables first; automatic
+// indexes have no SQL and come with their table
+fn schema(db: &Connection) -> Result<Vec<SchemaObject>, Error> {
+    let mut stmt = db
+        .prepare(
+            "SELECT type, name, sql FROM sqlite_schema
+            WHERE type IN ('table', 'index', 'view') AND sql IS NOT NULL
+                AND name NOT LIKE 'sqlite_%' AND substr(name, 1, length(?1)) != ?1
+                AND substr(tbl_name, 1, length(?1)) != ?1
+            ORDER BY type != 'table', type != 'index', rowid",
+        )
+        .map_err(to_error)?;
+    let objects = stmt
+        .query_map([crate::jobs::INTERNAL_TABLE_PREFIX], |row| {
+            Ok(SchemaObject {
+                kind: row.get(0)?,
+                name: row.get(1)?,
+                sql: row.get(2)?,
+            })
+        })
+        .map_err(to_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(to_error)?;
+    Ok(objects)
+}
+
+// creates the objects of the primary the follower lacks and drops its indexes and views
+// the primary no longer has
+fn apply_schema(db: &Connection, primary: &[SchemaObject]) -> Result<(), Error> {
+    let local = schema(db)?;
+
+    let tx = db.unchecked_transaction().map_err(to_error)?;
+    for object in &local {
+        if object.kind != "table" && !primary.iter().any(|o| o.name == object.name) {
+            tx.execute(&format!("DROP {} {}", object.kind, quote(&object.name)), [])
+                .map_err(to_error)?;
+        }
+    }
+    for object in primary {
+        if !local.iter().any(|o| o.name == object.name) {
+            tx.execute(&object.sql, []).map_err(to_error)?;
+        }
+    }
+    tx.commit().map_err(to_error)
+}
+
+// refuses a changeset for tables the follower lacks or has with other columns, SQLite
+// would skip their changes
+fn check_tables(db: &Connection, changeset: &[u8]) -> Result<(), Error> {
+    for (table, columns) in crate::session::tables(changeset).map_err(to_error)? {
+        let local: usize = db
+            .query_row(
+                "SELECT count(*) FROM pragma_table_info(?1)",
+                [&table],
+                |row| row.get(0),
+            )
+            .map_err(to_error)?;
+        if local != columns {
+            return Err(Error::CanisterError {
+                message: format!(
+                    "table {} has {} columns on the primary and {} on the follower",
+                    table, columns, local
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// the tables of the jobs stay on the primary
+fn user_tables(db: &Connection) -> Result<Vec<String>, Error> {
+    let mut stmt = db
+        .prepare(
+            "SELECT name FROM sqlite_schema
+            WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+                AND substr(name, 1, length(?1)) != ?1
+            ORDER BY name",
+        )
+        .map_err(to_error)?;
+    let tables = stmt
+        .query_map([crate::jobs::INTERNAL_TABLE_PREFIX], |row| row.get(0))
+        .map_err(to_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(to_error)?;
+    Ok(tables)
+}
+
+fn snapshot_page(db: &Connection, cursor: &SnapshotCursor) -> Result<Batch, Error> {
+    for table in user_tables(db)? {
+        let after_rowid = match table.as_str().cmp(cursor.table.as_str()) {
+            std::cmp::Ordering::Less => continue,
+            std::cmp::Ordering::Equal => cursor.after_rowid,
+            std::cmp::Ordering::Greater => i64::MIN,
+        };
+
+        let mut stmt = db
+            .prepare(&format!(
+                "SELECT rowid, * FROM {} WHERE rowid > ?1 ORDER BY rowid",
+                quote(&table)
+            ))
+            .map_err(to_error)?;
+        let columns: Vec<String> = stmt
+            .column_names()
+            .into_iter()
+            .skip(1)
+            .map(String::from)
+            .collect();
+
+        let mut rows = Vec::new();
+        let mut last_rowid = after_rowid;
+        let mut size = 0;
+
+        let mut result = stmt.query([after_rowid]).map_err(to_error)?;
+        while let Some(row) = result.next().map_err(to_error)? {
+            last_rowid = row.get(0).map_err(to_error)?;
+
+            let values: Vec<SqlValue> = (1..=columns.len())
+                .map(|col| row.get_ref(col).map(SqlValue::from))
+                .collect::<rusqlite::Result<_>>()
+                .map_err(to_error)?;
+            size += values.iter().map(SqlValue::size).sum::<usize>();
+            rows.push(values);
+
+            if size >= MAX_SNAPSHOT_PAGE_BYTES {
+                break;
+            }
+        }
+
+        if !rows.is_empty() {
+            return Ok(Batch::SnapshotRows {
+                table,
+                columns,
+                rows,
+                last_rowid,
+            });
+        }
+    }
+
+    Ok(Batch::SnapshotEnd {
+        checkpoint: cursor.checkpoint,
+    })
+}
+
+/// Records the answer of a follower to a batch.
+pub fn acknowledge(
+    state: &mut FollowerState,
+    batch: &Batch,
+    result: Result<FollowerStatus, String>,
+    time: u64,
+) {
+    state.last_contact = Some(time);
+
+    let status = match result {
+        Ok(status) => status,
+        Err(err) => {
+            state.last_error = Some(err);
+            return;
+        }
+    };
+    state.last_error = None;
+
+    match batch {
+        Batch::SnapshotStart { checkpoint, .. } => {
+            state.snapshot = Some(SnapshotCursor {
+                checkpoint: *checkpoint,
+                table: String::new(),
+                after_rowid: i64::MIN,
+            });
+        }
+        Batch::SnapshotRows {
+            table, last_rowid, ..
+        } => {
+            if let Some(cursor) = state.snapshot.as_mut() {
+                cursor.table = table.clone();
+                cursor.after_rowid = *last_rowid;
+            }
+        }
+        Batch::SnapshotEnd { .. } => state.snapshot = None,
+        Batch::Changes { .. } => {}
+    }
+
+    state.applied_checkpoint = status.applied_checkpoint;
+}
+
+/// Applies a batch on a follower, `applied` is the last checkpoint applied before.
+///
+/// Returns the last checkpoint applied afterwards. Changes that do not continue at
+/// `applied` are ignored, the primary then sends what the follower really needs.
+pub fn apply_batch(
+    db: &Connection,
+    batch: &Batch,
+    applied: Option<u64>,
+) -> Result<Option<u64>, Error> {
+    match batch {
+        Batch::Changes {
+            from,
+            to,
+            changeset,
+            schema,
+        } => {
+            if applied != Some(*from) {
+                return Ok(applied);
+            }
+
+            apply_schema(db, schema)?;
+            check_tables(db, changeset)?;
+            let (done, _) = changesets::apply_uncaptured(db, changeset, ConflictPolicy::Replace)?;
+            if !done {
+                return Err(Error::CanisterError {
+                    message: format!("the changes up to checkpoint {} were not applied", to),
+                });
+            }
+            Ok(Some(*to))
+        }
+        Batch::SnapshotStart { schema, .. } => {
+            apply_schema(db, schema)?;
+            let tx = db.unchecked_transaction().map_err(to_error)?;
+            for table in user_tables(&tx)? {
+                tx.execute(&format!("DELETE FROM {}", quote(&table)), [])
+                    .map_err(to_error)?;
+            }
+            tx.commit().map_err(to_error)?;
+            Ok(None)
+        }
+        Batch::SnapshotRows {
+            table,
+            columns,
+            rows,
+            ..
+        } => {
+            if applied.is_some() {
+                return Err(Error::InvalidArgument {
+                    message: "no snapshot is being copied".to_string(),
+                });
+            }
+
+            // a page sent again after a lost answer overwrites the rows it inserted before
+            let sql = format!(
+                "INSERT OR REPLACE INTO {} ({}) VALUES ({})",
+                quote(table),
+                columns
+                    .iter()
+                    .map(|c| quote(c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                (1..=columns.len())
+                    .map(|i| format!("?{}", i))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+
+            let tx = db.unchecked_transaction().map_err(to_error)?;
+            {
+                let mut stmt = tx.prepare(&sql).map_err(to_error)?;
+                for row in rows {
+                    stmt.execute(rusqlite::params_from_iter(row))
+                        .map_err(crate::quota::map_write_error)?;
+                }
+            }
+            tx.commit().map_err(to_error)?;
+            Ok(None)
+        }
+        Batch::SnapshotEnd { checkpoint } => Ok(Some(*checkpoint)),
+    }
+}
+
+/// Handles a `replicate` call on a follower.
+pub fn replicate(
+    db: &Connection,
+    caller: Principal,
+    batch: &Batch,
+) -> Result<FollowerStatus, Error> {
+    let settings = config::get().replication;
+    match settings.role {
+        Role::Follower { primary } if primary == caller => {}
+        _ => return Err(Error::Unauthorized),
+    }
+
+    let applied = apply_batch(db, batch, settings.applied_checkpoint)?;
+    config::update(|c| c.replication.applied_checkpoint = applied);
+
+    Ok(FollowerStatus {
+        applied_checkpoint: applied,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::database;
+
+    fn write(db: &Connection, sql: &str) {
+        changesets::capture(db, || {
+            db.execute_batch(sql).unwrap();
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn rows(db: &Connection) -> Vec<(i64, String, Option<String>, i64)> {
+        let mut stmt = db
+            .prepare("SELECT id, name, data, age FROM person ORDER BY id")
+            .unwrap();
+        stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect()
+    }
+
+    /// Pushes batches until the follower is up to date, returns the batches sent.
+    fn sync(
+        primary: &Connection,
+        follower: &Connection,
+        state: &mut FollowerState,
+        applied: &mut Option<u64>,
+        max_lag: u64,
+    ) -> Vec<Batch> {
+        let mut sent = Vec::new();
+        while let Some(batch) = next_batch(primary, state, max_lag).unwrap() {
+            *applied = apply_batch(follower, &batch, *applied).unwrap();
+            let status = FollowerStatus {
+                applied_checkpoint: *applied,
+            };
+            acknowledge(state, &batch, Ok(status), 0);
+            sent.push(batch);
+        }
+        sent
+    }
+
+    fn is_snapshot(batches: &[Batch]) -> bool {
+        matches!(batches.first(), Some(Batch::SnapshotStart { .. }))
+    }
+
+    #[test]
+    fn follower_catches_up_with_a_snapshot_then_changes() {
+        let primary = database();
+        primary
+            .execute_batch("INSERT INTO person (name, data, age) VALUES ('before', 'capture', 1);")
+            .unwrap();
+        changesets::set_enabled(true);
+        write(
+            &primary,
+            "INSERT INTO person (name, data, age) VALUES ('a', NULL, 2), ('b', 'x', 3);",
+        );
+
+        // the follower has stale rows of its own
+        let follower = database();
+        follower
+            .execute_batch("INSERT INTO person (name, age) VALUES ('stale', 0);")
+            .unwrap();
+
+        let mut state = FollowerState::default();
+        let mut applied = None;
+        let sent = sync(&primary, &follower, &mut state, &mut applied, 100);
+        assert!(is_snapshot(&sent));
+        assert!(matches!(sent.last(), Some(Batch::SnapshotEnd { .. })));
+        assert_eq!(rows(&follower), rows(&primary));
+        assert_eq!(applied, Some(changesets::next_checkpoint()));
+
+        write(&primary, "UPDATE person SET age = 20 WHERE name = 'a';");
+        write(&primary, "DELETE FROM person WHERE name = 'b';");
+        write(&primary, "INSERT INTO person (name, age) VALUES ('c', 4);");
+
+        let sent = sync(&primary, &follower, &mut state, &mut applied, 100);
+        assert!(sent.iter().all(|b| matches!(b, Batch::Changes { .. })));
+        assert_eq!(rows(&follower), rows(&primary));
+        assert_eq!(
+            state.applied_checkpoint,
+            Some(changesets::next_checkpoint())
+        );
+    }
+
+    #[test]
+    fn writes_during_a_snapshot_converge() {
+        let primary = database();
+        changesets::set_enabled(true);
+        for i in 0..5 {
+            write(
+                &primary,
+                &format!("INSERT INTO person (name, age) VALUES ('p{}', {})", i, i),
+            );
+        }
+
+        let follower = database();
+        let mut state = FollowerState::default();
+        let mut applied = None;
+
+        // start the snapshot, then change rows before and after the copy position
+        let batch = next_batch(&primary, &state, 100).unwrap().unwrap();
+        applied = apply_batch(&follower, &batch, applied).unwrap();
+        acknowledge(
+            &mut state,
+            &batch,
+            Ok(FollowerStatus {
+                applied_checkpoint: applied,
+            }),
+            0,
+        );
+
+        write(&primary, "UPDATE person SET age = 100 WHERE id = 1;");
+        write(&primary, "DELETE FROM person WHERE id = 5;");
+        write(
+            &primary,
+            "INSERT INTO person (name, age) VALUES ('new', 6);",
+        );
+
+        sync(&primary, &follower, &mut state, &mut applied, 100);
+        assert_eq!(rows(&follower), rows(&primary));
+    }
+
+    #[test]
+    fn follower_receives_new_tables_and_indexes() {
+        let primary = database();
+        changesets::set_enabled(true);
+        let follower = database();
+        follower
+            .execute_batch("CREATE INDEX person_age ON person (age);")
+            .unwrap();
+        let mut state = FollowerState::default();
+        let mut applied = None;
+        sync(&primary, &follower, &mut state, &mut applied, 100);
+        assert_eq!(schema(&follower).unwrap(), schema(&primary).unwrap());
+
+        primary
+            .execute_batch(
+                "CREATE TABLE tag (id INTEGER PRIMARY KEY, label TEXT);
+                CREATE INDEX tag_label ON tag (label);
+                CREATE VIEW labels AS SELECT label FROM tag;
+                CREATE TABLE _job1_new_tag (id INTEGER PRIMARY KEY, label TEXT);",
+            )
+            .unwrap();
+        write(
+            &primary,
+            "INSERT INTO tag (label) VALUES ('x');
+            INSERT INTO _job1_new_tag (label) VALUES ('x');",
+        );
+        let sent = sync(&primary, &follower, &mut state, &mut applied, 100);
+        assert!(sent.iter().all(|b| matches!(b, Batch::Changes { .. })));
+        assert_eq!(schema(&follower).unwrap(), schema(&primary).unwrap());
+        let labels: String = follower
+            .query_row("SELECT group_concat(label) FROM labels", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(labels, "x");
+
+        // a table with other columns is refused rather than skipped
+        primary
+            .execute_batch("ALTER TABLE tag ADD COLUMN color TEXT;")
+            .unwrap();
+        write(
+            &primary,
+            "INSERT INTO tag (label, color) VALUES ('y', 'red');",
+        );
+        let batch = next_batch(&primary, &state, 100).unwrap().unwrap();
+        assert!(apply_batch(&follower, &batch, applied).is_err());
+    }
+
+    #[test]
+    fn a_lagging_follower_is_resynced() {
+        let primary = database();
+        changesets::set_enabled(true);
+        write(&primary, "INSERT INTO person (name, age) VALUES ('a', 1);");
+
+        let follower = database();
+        let mut state = FollowerState::default();
+        let mut applied = None;
+        sync(&primary, &follower, &mut state, &mut applied, 3);
+
+        for i in 0..4 {
+            write(
+                &primary,
+                &format!("INSERT INTO person (name, age) VALUES ('l{}', {})", i, i),
+            );
+        }
+        let sent = sync(&primary, &follower, &mut state, &mut applied, 3);
+        assert!(is_snapshot(&sent));
+        assert_eq!(rows(&follower), rows(&primary));
+
+        // a follower that lost its state is sent only what it can apply
+        let batch = Batch::Changes {
+            from: 0,
+            to: 1,
+            changeset: Vec::new(),
+            schema: Vec::new(),
+        };
+        assert_eq!(apply_batch(&follower, &batch, None).unwrap(), None);
+    }
+}
@@ -0,0 +1,277 @@
+//! Minimal bindings to the SQLite session extension.
+//!
+//! rusqlite only wraps the extension behind its `session` feature, which needs bindgen
+//! and libclang at build time. The bundled bindings of `libsqlite3-sys` declare the
+//! functions anyway, so the extension is compiled in with `LIBSQLITE3_FLAGS` (see
+//! `.cargo/config.toml`) and wrapped here.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ptr;
+
+use rusqlite::{ffi, Connection};
+
+use crate::replay::SqlValue;
+
+fn check(rc: c_int) -> rusqlite::Result<()> {
+    if rc == ffi::SQLITE_OK {
+        Ok(())
+    } else {
+        Err(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None))
+    }
+}
+
+// copies a buffer allocated by SQLite and frees it
+unsafe fn take_buffer(size: c_int, data: *mut c_void) -> Vec<u8> {
+    let bytes = if data.is_null() || size <= 0 {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(data as *const u8, size as usize).to_vec()
+    };
+    ffi::sqlite3_free(data);
+    bytes
+}
+
+/// Records the changes made to the tables of the main database while it lives, except
+/// the internal tables of the jobs.
+pub struct Session<'conn> {
+    session: *mut ffi::sqlite3_session,
+    _db: &'conn Connection,
+}
+
+impl<'conn> Session<'conn> {
+    pub fn new(db: &'conn Connection) -> rusqlite::Result<Self> {
+        let mut session = ptr::null_mut();
+        unsafe {
+            check(ffi::sqlite3session_create(
+                db.handle(),
+                c"main".as_ptr(),
+                &mut session,
+            ))?;
+        }
+        let session = Session { session, _db: db };
+
+        // a null table name attaches every table with a primary key
+        check(unsafe { ffi::sqlite3session_attach(session.session, ptr::null()) })?;
+        unsafe {
+            ffi::sqlite3session_table_filter(session.session, Some(user_table), ptr::null_mut())
+        };
+
+        Ok(session)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe { ffi::sqlite3session_isempty(self.session) != 0 }
+    }
+
+    pub fn changeset(&self) -> rusqlite::Result<Vec<u8>> {
+        let mut size = 0;
+        let mut data = ptr::null_mut();
+        unsafe {
+            check(ffi::sqlite3session_changeset(
+                self.session,
+                &mut size,
+                &mut data,
+            ))?;
+            Ok(take_buffer(size, data))
+        }
+    }
+
+    pub fn patchset(&self) -> rusqlite::Result<Vec<u8>> {
+        let mut size = 0;
+        let mut data = ptr::null_mut();
+        unsafe {
+            check(ffi::sqlite3session_patchset(
+                self.session,
+                &mut size,
+                &mut data,
+            ))?;
+            Ok(take_buffer(size, data))
+        }
+    }
+}
+
+unsafe extern "C" fn user_table(_ctx: *mut c_void, table: *const c_char) -> c_int {
+    let table = CStr::from_ptr(table).to_string_lossy();
+    !crate::jobs::is_internal_table(&table) as c_int
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3session_delete(self.session) }
+    }
+}
+
+/// Combines changesets, or patchsets, into one in the order given.
+pub fn combine<'a>(changesets: impl IntoIterator<Item = &'a [u8]>) -> rusqlite::Result<Vec<u8>> {
+    let mut group = ptr::null_mut();
+    check(unsafe { ffi::sqlite3changegroup_new(&mut group) })?;
+
+    let result = (|| {
+        for changeset in changesets {
+            check(unsafe {
+                ffi::sqlite3changegroup_add(
+                    group,
+                    changeset.len() as c_int,
+                    changeset.as_ptr() as *mut c_void,
+                )
+            })?;
+        }
+
+        let mut size = 0;
+        let mut data = ptr::null_mut();
+        unsafe {
+            check(ffi::sqlite3changegroup_output(group, &mut size, &mut data))?;
+            Ok(take_buffer(size, data))
+        }
+    })();
+
+    unsafe { ffi::sqlite3changegroup_delete(group) };
+    result
+}
+
+/// The tables a changeset, or patchset, changes and their number of columns, in the
+/// order they appear.
+pub fn tables(changeset: &[u8]) -> rusqlite::Result<Vec<(String, usize)>> {
+    let mut iter = ptr::null_mut();
+    check(unsafe {
+        ffi::sqlite3changeset_start(
+            &mut iter,
+            changeset.len() as c_int,
+            changeset.as_ptr() as *mut c_void,
+        )
+    })?;
+
+    let mut tables: Vec<(String, usize)> = Vec::new();
+    let rc = loop {
+        let rc = unsafe { ffi::sqlite3changeset_next(iter) };
+        if rc != ffi::SQLITE_ROW {
+            break rc;
+        }
+
+        let mut table = ptr::null();
+        let mut columns = 0;
+        let mut op = 0;
+        unsafe {
+            ffi::sqlite3changeset_op(iter, &mut table, &mut columns, &mut op, ptr::null_mut())
+        };
+        let table = unsafe { CStr::from_ptr(table) }.to_string_lossy();
+        if tables.last().map(|(last, _)| last.as_str()) != Some(&table) {
+            tables.push((table.into_owned(), columns as usize));
+        }
+    };
+    unsafe { ffi::sqlite3changeset_finalize(iter) };
+
+    if rc == ffi::SQLITE_DONE {
+        Ok(tables)
+    } else {
+        check(rc).map(|_| tables)
+    }
+}
+
+/// The change a conflict handler is called for.
+pub struct ConflictingChange {
+    iter: *mut ffi::sqlite3_changeset_iter,
+}
+
+impl ConflictingChange {
+    /// Returns the table name and the operation code, e.g. `ffi::SQLITE_INSERT`.
+    pub fn operation(&self) -> (String, c_int) {
+        let mut table = ptr::null();
+        let mut columns = 0;
+        let mut op = 0;
+        let mut indirect = 0;
+        unsafe {
+            ffi::sqlite3changeset_op(self.iter, &mut table, &mut columns, &mut op, &mut indirect);
+            let table = if table.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(table).to_string_lossy().into_owned()
+            };
+            (table, op)
+        }
+    }
+
+    /// Values of the primary key columns, the new ones of an insert, the old ones otherwise.
+    pub fn primary_key(&self) -> Vec<SqlValue> {
+        let (_, op) = self.operation();
+
+        let mut flags = ptr::null_mut();
+        let mut columns = 0;
+        unsafe {
+            if ffi::sqlite3changeset_pk(self.iter, &mut flags, &mut columns) != ffi::SQLITE_OK {
+                return Vec::new();
+            }
+            let flags = std::slice::from_raw_parts(flags, columns.max(0) as usize);
+
+            flags
+                .iter()
+                .enumerate()
+                .filter(|(_, &is_pk)| is_pk != 0)
+                .filter_map(|(col, _)| {
+                    let mut value = ptr::null_mut();
+                    let rc = if op == ffi::SQLITE_INSERT {
+                        ffi::sqlite3changeset_new(self.iter, col as c_int, &mut value)
+                    } else {
+                        ffi::sqlite3changeset_old(self.iter, col as c_int, &mut value)
+                    };
+                    (rc == ffi::SQLITE_OK && !value.is_null()).then(|| to_value(value))
+                })
+                .collect()
+        }
+    }
+}
+
+unsafe fn to_value(value: *mut ffi::sqlite3_value) -> SqlValue {
+    match ffi::sqlite3_value_type(value) {
+        ffi::SQLITE_INTEGER => SqlValue::Integer(ffi::sqlite3_value_int64(value)),
+        ffi::SQLITE_FLOAT => SqlValue::Real(ffi::sqlite3_value_double(value)),
+        ffi::SQLITE_TEXT => {
+            let text = ffi::sqlite3_value_text(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            let bytes = std::slice::from_raw_parts(text, len);
+            SqlValue::Text(String::from_utf8_lossy(bytes).into_owned())
+        }
+        ffi::SQLITE_BLOB => {
+            let blob = ffi::sqlite3_value_blob(value) as *const u8;
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if blob.is_null() {
+                SqlValue::Blob(Vec::new())
+            } else {
+                SqlValue::Blob(std::slice::from_raw_parts(blob, len).to_vec())
+            }
+        }
+        _ => SqlValue::Null,
+    }
+}
+
+unsafe extern "C" fn call_conflict<F>(
+    ctx: *mut c_void,
+    conflict: c_int,
+    iter: *mut ffi::sqlite3_changeset_iter,
+) -> c_int
+where
+    F: FnMut(c_int, &ConflictingChange) -> c_int,
+{
+    let on_conflict = &mut *(ctx as *mut F);
+    on_conflict(conflict, &ConflictingChange { iter })
+}
+
+/// Applies a changeset or patchset in one transaction.
+///
+/// `on_conflict` gets the `SQLITE_CHANGESET_*` conflict type and returns the action,
+/// e.g. `ffi::SQLITE_CHANGESET_OMIT`. An aborted apply returns `SQLITE_ABORT`.
+pub fn apply<F>(db: &Connection, changeset: &[u8], mut on_conflict: F) -> rusqlite::Result<()>
+where
+    F: FnMut(c_int, &ConflictingChange) -> c_int,
+{
+    check(unsafe {
+        ffi::sqlite3changeset_apply(
+            db.handle(),
+            changeset.len() as c_int,
+            changeset.as_ptr() as *mut c_void,
+            None,
+            Some(call_conflict::<F>),
+            &mut on_conflict as *mut F as *mut c_void,
+        )
+    })
+}
@@ -0,0 +1,252 @@
+//! Access to the `person` table for a router canister that shards it (see `demo3_router`).
+//!
+//! The router assigns the ids and places each row by its partition key, a hash of the id
+//! in `0..KEY_SPACE`. A shard holds the rows of the key ranges assigned to it; it can
+//! list, copy and delete rows by key range so the router can move ranges between
+//! shards.
+
+use candid::{CandidType, Deserialize};
+use rusqlite::{Connection, OptionalExtension, Row};
+
+use crate::replay::SqlValue;
+
+/// Partition keys are in `0..KEY_SPACE`.
+pub const KEY_SPACE: u64 = 1 << 32;
+
+/// The partition key of the `id` column, the router computes the same in Rust.
+///
+/// A multiplicative hash of the lower 31 bits of the id; the product stays below 2^63
+/// so SQLite keeps it an integer.
+pub const PARTITION_KEY_SQL: &str = "(((id & 2147483647) * 2654435761) & 4294967295)";
+
+// rows returned by one call at most
+const MAX_PAGE_SIZE: u32 = 1000;
+
+pub const PUT_SQL: &str =
+    "INSERT OR REPLACE INTO person (id, name, data, age) VALUES (?1, ?2, ?3, ?4)";
+pub const DELETE_SQL: &str = "DELETE FROM person WHERE id = ?1";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Person {
+    pub id: u64,
+    pub name: String,
+    pub data: String,
+    pub age: u32,
+}
+
+impl Person {
+    pub fn params(&self) -> [SqlValue; 4] {
+        [
+            SqlValue::Integer(self.id as i64),
+            SqlValue::Text(self.name.clone()),
+            SqlValue::Text(self.data.clone()),
+            SqlValue::Integer(self.age as i64),
+        ]
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Person {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            data: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            age: row.get::<_, Option<u32>>(3)?.unwrap_or_default(),
+        })
+    }
+}
+
+/// Sort order of a listing, ties are broken by the id.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PersonOrder {
+    Id,
+    Name,
+    Age,
+}
+
+pub fn get(db: &Connection, id: u64) -> rusqlite::Result<Option<Person>> {
+    db.query_row(
+        "SELECT id, name, data, age FROM person WHERE id = ?1",
+        [id],
+        Person::from_row,
+    )
+    .optional()
+}
+
+/// Returns up to `limit` rows in `order` that come after the row `after`.
+pub fn list(
+    db: &Connection,
+    order: PersonOrder,
+    after: Option<&Person>,
+    limit: u32,
+) -> rusqlite::Result<Vec<Person>> {
+    let column = match order {
+        PersonOrder::Id => "id",
+        PersonOrder::Name => "name",
+        PersonOrder::Age => "age",
+    };
+    let filter = match after {
+        None => String::new(),
+        Some(_) if order == PersonOrder::Id => "WHERE id > ?2".to_string(),
+        Some(_) => format!("WHERE ({column}, id) > (?1, ?2)"),
+    };
+    let sql = format!(
+        "SELECT id, name, data, age FROM person {filter} ORDER BY {column}, id LIMIT {}",
+        limit.min(MAX_PAGE_SIZE)
+    );
+
+    let mut stmt = db.prepare(&sql)?;
+    let rows = match after {
+        None => stmt.query_map([], Person::from_row)?,
+        Some(after) => {
+            let value = match order {
+                PersonOrder::Id => SqlValue::Null,
+                PersonOrder::Name => SqlValue::Text(after.name.clone()),
+                PersonOrder::Age => SqlValue::Integer(after.age as i64),
+            };
+            // an unused parameter is allowed as long as it is numbered
+            stmt.query_map((value, after.id), Person::from_row)?
+        }
+    };
+    rows.collect()
+}
+
+/// Returns up to `limit` rows with a partition key in `start..end`, ordered by id.
+pub fn key_range(
+    db: &Connection,
+    start: u64,
+    end: u64,
+    after_id: Option<u64>,
+    limit: u32,
+) -> rusqlite::Result<Vec<Person>> {
+    let sql = format!(
+        "SELECT id, name, data, age FROM person
+        WHERE {PARTITION_KEY_SQL} >= ?1 AND {PARTITION_KEY_SQL} < ?2 AND id > ?3
+        ORDER BY id LIMIT ?4"
+    );
+    let after_id = after_id.map_or(-1, |id| id as i64);
+
+    let mut stmt = db.prepare(&sql)?;
+    let rows = stmt.query_map(
+        (start, end, after_id, limit.min(MAX_PAGE_SIZE)),
+        Person::from_row,
+    )?;
+    rows.collect()
+}
+
+/// The statement and parameters deleting up to `limit` rows with a partition key in
+/// `start..end`.
+pub fn delete_key_range_sql(start: u64, end: u64, limit: u32) -> (String, [SqlValue; 3]) {
+    (
+        format!(
+            "DELETE FROM person WHERE id IN (
+                SELECT id FROM person
+                WHERE {PARTITION_KEY_SQL} >= ?1 AND {PARTITION_KEY_SQL} < ?2
+                ORDER BY id LIMIT ?3
+            )"
+        ),
+        [
+            SqlValue::Integer(start as i64),
+            SqlValue::Integer(end as i64),
+            SqlValue::Integer(limit.min(MAX_PAGE_SIZE) as i64),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // as computed by the router
+    fn partition_key(id: u64) -> u64 {
+        ((id & 0x7FFF_FFFF) * 2_654_435_761) & 0xFFFF_FFFF
+    }
+
+    fn database(count: u64) -> Connection {
+        let db = crate::util::database();
+        for id in 1..=count {
+            let person = Person {
+                id,
+                name: format!("name {}", id % 3),
+                data: String::new(),
+                age: (id % 5) as u32,
+            };
+            db.execute(PUT_SQL, rusqlite::params_from_iter(person.params()))
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn partition_key_matches_the_sql_expression() {
+        let db = Connection::open_in_memory().unwrap();
+        for id in [
+            0,
+            1,
+            2,
+            1000,
+            0x7FFF_FFFF,
+            0x8000_0000,
+            u32::MAX as u64,
+            1 << 40,
+        ] {
+            let key: i64 = db
+                .query_row(
+                    &format!("SELECT {}", PARTITION_KEY_SQL.replace("id", "?1")),
+                    [id as i64],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(key as u64, partition_key(id), "id {id}");
+            assert!(partition_key(id) < KEY_SPACE);
+        }
+    }
+
+    #[test]
+    fn lists_pages_in_order() {
+        let db = database(20);
+
+        for order in [PersonOrder::Id, PersonOrder::Name, PersonOrder::Age] {
+            let mut all = Vec::new();
+            let mut after = None;
+            loop {
+                let page = list(&db, order, after.as_ref(), 6).unwrap();
+                if page.is_empty() {
+                    break;
+                }
+                after = page.last().cloned();
+                all.extend(page);
+            }
+
+            let mut expected = list(&db, PersonOrder::Id, None, 100).unwrap();
+            match order {
+                PersonOrder::Id => {}
+                PersonOrder::Name => expected.sort_by(|a, b| (&a.name, a.id).cmp(&(&b.name, b.id))),
+                PersonOrder::Age => expected.sort_by_key(|p| (p.age, p.id)),
+            }
+            assert_eq!(all, expected, "{order:?}");
+        }
+    }
+
+    #[test]
+    fn copies_and_deletes_key_ranges() {
+        let db = database(50);
+        let half = KEY_SPACE / 2;
+
+        let lower = key_range(&db, 0, half, None, 1000).unwrap();
+        let upper = key_range(&db, half, KEY_SPACE, None, 1000).unwrap();
+        assert_eq!(lower.len() + upper.len(), 50);
+        assert!(lower.iter().all(|p| partition_key(p.id) < half));
+        assert!(upper.iter().all(|p| partition_key(p.id) >= half));
+
+        let page = key_range(&db, half, KEY_SPACE, Some(upper[1].id), 1000).unwrap();
+        assert_eq!(page, upper[2..]);
+
+        let (sql, params) = delete_key_range_sql(half, KEY_SPACE, 1000);
+        let deleted = db
+            .execute(&sql, rusqlite::params_from_iter(&params))
+            .unwrap();
+        assert_eq!(deleted, upper.len());
+        assert_eq!(list(&db, PersonOrder::Id, None, 1000).unwrap(), lower);
+        assert_eq!(get(&db, lower[0].id).unwrap().as_ref(), Some(&lower[0]));
+        assert_eq!(get(&db, upper[0].id).unwrap(), None);
+    }
+}
@@ -0,0 +1,301 @@
+//! Named statements registered by the admins and run by the clients with typed
+//! arguments.
+//!
+//! The registry is stored in the config, so it survives upgrades. The statements are
+//! compiled once per connection through `prepare_cached`. In allowlist mode the
+//! `query` endpoint refuses arbitrary SQL and only the registered statements run.
+
+use candid::{CandidType, Deserialize};
+use rusqlite::Connection;
+
+use crate::replay::SqlValue;
+use crate::util::invalid;
+use crate::{config, Error};
+
+/// Compiled statements kept by a connection, the rusqlite default is 16.
+pub const CACHE_CAPACITY: usize = 64;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    Integer,
+    /// Also accepts an integer.
+    Real,
+    Text,
+    Blob,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Param {
+    /// Must match the name of a `:name`, `@name` or `$name` parameter; `?NNN`
+    /// parameters are bound by position.
+    pub name: String,
+    pub param_type: ParamType,
+    pub nullable: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Runs in `query_statement`, the statement must not change the database.
+    Read,
+    /// Runs in `execute_statement`.
+    Write,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NamedStatement {
+    pub name: String,
+    pub sql: String,
+    /// In the order of the statement parameters.
+    pub params: Vec<Param>,
+    pub access: Access,
+}
+
+/// Checks a statement before it is registered.
+pub fn validate(db: &Connection, statement: &NamedStatement) -> Result<(), Error> {
+    let name = &statement.name;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(invalid(format!(
+            "a statement name is made of letters, digits and underscores, got {:?}",
+            name
+        )));
+    }
+
+    let mut batch = rusqlite::Batch::new(db, &statement.sql);
+    let stmt = batch
+        .next()
+        .map_err(|err| invalid(format!("{}: {}", name, err)))?
+        .ok_or_else(|| invalid(format!("{} has no statement", name)))?;
+    if !matches!(batch.next(), Ok(None)) {
+        return Err(invalid(format!("{} has more than one statement", name)));
+    }
+
+    if stmt.parameter_count() != statement.params.len() {
+        return Err(invalid(format!(
+            "{} has {} parameters, {} are declared",
+            name,
+            stmt.parameter_count(),
+            statement.params.len()
+        )));
+    }
+    for (index, param) in statement.params.iter().enumerate() {
+        if let Some(sql_name) = stmt.parameter_name(index + 1) {
+            if !sql_name.starts_with('?') && sql_name[1..] != param.name {
+                return Err(invalid(format!(
+                    "parameter {} of {} is {}, declared as {}",
+                    index + 1,
+                    name,
+                    sql_name,
+                    param.name
+                )));
+            }
+        }
+    }
+
+    if statement.access == Access::Read && !stmt.readonly() {
+        return Err(invalid(format!("{} writes to the database", name)));
+    }
+
+    Ok(())
+}
+
+/// Checks the arguments of a call against the declared parameters.
+pub fn check_args(statement: &NamedStatement, args: &[SqlValue]) -> Result<(), Error> {
+    if args.len() != statement.params.len() {
+        return Err(invalid(format!(
+            "{} takes {} arguments, got {}",
+            statement.name,
+            statement.params.len(),
+            args.len()
+        )));
+    }
+
+    for (param, arg) in statement.params.iter().zip(args) {
+        let matches = match (param.param_type, arg) {
+            (_, SqlValue::Null) => param.nullable,
+            (ParamType::Integer, SqlValue::Integer(_)) => true,
+            (ParamType::Real, SqlValue::Real(_) | SqlValue::Integer(_)) => true,
+            (ParamType::Text, SqlValue::Text(_)) => true,
+            (ParamType::Blob, SqlValue::Blob(_)) => true,
+            _ => false,
+        };
+        if !matches {
+            return Err(invalid(format!(
+                "{} of {} must be {}{:?}, got {:?}",
+                param.name,
+                statement.name,
+                if param.nullable { "null or " } else { "" },
+                param.param_type,
+                arg
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// The registered statement `name`, if its access is `access`.
+pub fn get(name: &str, access: Access) -> Result<NamedStatement, Error> {
+    let statement = config::get()
+        .statements
+        .remove(name)
+        .ok_or_else(|| invalid(format!("there is no statement {}", name)))?;
+
+    if statement.access != access {
+        return Err(invalid(format!(
+            "{} is a {:?} statement",
+            name, statement.access
+        )));
+    }
+    Ok(statement)
+}
+
+/// Returns the rows of a `Read` statement.
+pub fn query(
+    db: &Connection,
+    statement: &NamedStatement,
+    args: &[SqlValue],
+) -> rusqlite::Result<Vec<Vec<SqlValue>>> {
+    let mut stmt = db.prepare_cached(&statement.sql)?;
+    let columns = stmt.column_count();
+    let mut rows = stmt.query(rusqlite::params_from_iter(args))?;
+
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        result.push(
+            (0..columns)
+                .map(|i| SqlValue::from(row.get_ref_unwrap(i)))
+                .collect(),
+        );
+    }
+    Ok(result)
+}
+
+/// Runs a `Write` statement and returns the number of changed rows.
+pub fn execute(
+    db: &Connection,
+    statement: &NamedStatement,
+    args: &[SqlValue],
+) -> rusqlite::Result<usize> {
+    db.prepare_cached(&statement.sql)?
+        .execute(rusqlite::params_from_iter(args))
+}
+
+/// Refuses arbitrary SQL in allowlist mode.
+pub fn check_arbitrary_sql() -> Result<(), Error> {
+    if config::get().statements_only {
+        Err(Error::ArbitrarySqlDisabled)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::database;
+
+    fn param(name: &str, param_type: ParamType, nullable: bool) -> Param {
+        Param {
+            name: name.to_string(),
+            param_type,
+            nullable,
+        }
+    }
+
+    fn statement(name: &str, sql: &str, params: Vec<Param>, access: Access) -> NamedStatement {
+        NamedStatement {
+            name: name.to_string(),
+            sql: sql.to_string(),
+            params,
+            access,
+        }
+    }
+
+    #[test]
+    fn runs_statements_with_typed_arguments() {
+        let db = database();
+        let insert = statement(
+            "add_person",
+            "INSERT INTO person (name, data, age) VALUES (:name, :data, :age)",
+            vec![
+                param("name", ParamType::Text, false),
+                param("data", ParamType::Text, true),
+                param("age", ParamType::Integer, false),
+            ],
+            Access::Write,
+        );
+        let find = statement(
+            "find_by_name",
+            "SELECT id, data, age FROM person WHERE name = ?1 ORDER BY id",
+            vec![param("name", ParamType::Text, false)],
+            Access::Read,
+        );
+        validate(&db, &insert).unwrap();
+        validate(&db, &find).unwrap();
+
+        for data in [SqlValue::Text("x".to_string()), SqlValue::Null] {
+            let args = [
+                SqlValue::Text("amy".to_string()),
+                data,
+                SqlValue::Integer(7),
+            ];
+            check_args(&insert, &args).unwrap();
+            assert_eq!(execute(&db, &insert, &args).unwrap(), 1);
+        }
+        assert_eq!(
+            query(&db, &find, &[SqlValue::Text("amy".to_string())]).unwrap(),
+            [
+                [
+                    SqlValue::Integer(1),
+                    SqlValue::Text("x".to_string()),
+                    SqlValue::Integer(7)
+                ],
+                [SqlValue::Integer(2), SqlValue::Null, SqlValue::Integer(7)],
+            ]
+        );
+
+        let wrong_args = [
+            vec![],
+            vec![SqlValue::Blob(vec![1])],
+            vec![SqlValue::Integer(1)],
+            vec![SqlValue::Null],
+            vec![SqlValue::Text("amy".to_string()), SqlValue::Null],
+        ];
+        for args in wrong_args {
+            assert!(check_args(&find, &args).is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_statements() {
+        let db = database();
+        let text = |name| param(name, ParamType::Text, false);
+
+        let invalid = [
+            statement("", "SELECT 1", vec![], Access::Read),
+            statement("find-person", "SELECT 1", vec![], Access::Read),
+            statement("broken", "SELEC 1", vec![], Access::Read),
+            statement("two", "SELECT 1; SELECT 2", vec![], Access::Read),
+            statement("empty", "-- nothing", vec![], Access::Read),
+            statement("count", "SELECT ?1", vec![], Access::Read),
+            statement("renamed", "SELECT :name", vec![text("email")], Access::Read),
+            statement(
+                "sneaky",
+                "DELETE FROM person WHERE name = ?1",
+                vec![text("name")],
+                Access::Read,
+            ),
+        ];
+        for statement in invalid {
+            assert!(validate(&db, &statement).is_err(), "{}", statement.name);
+        }
+
+        let delete = statement(
+            "delete_by_name",
+            "DELETE FROM person WHERE name = ?1",
+            vec![text("name")],
+            Access::Write,
+        );
+        assert!(validate(&db, &delete).is_ok());
+    }
+}
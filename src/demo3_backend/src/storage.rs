@@ -0,0 +1,289 @@
+//! Storage usage statistics and space reclamation.
+//!
+//! Stable memory handed out by the `MemoryManager` is never returned, so a vacuum
+//! does not lower the page counts of the memories. The pages it frees are reused
+//! by the database before the memory has to grow again.
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory;
+use rusqlite::Connection;
+
+use crate::advisor::WORKLOAD_MEMORY_ID;
+use crate::changes::CHANGE_LOG_MEMORY_ID;
+use crate::changesets::CHANGESETS_MEMORY_ID;
+use crate::quota::StorageQuota;
+use crate::replay::{
+    REPLAY_LOG_DATA_MEMORY_ID, REPLAY_LOG_INDEX_MEMORY_ID, SECOND_LOG_DATA_MEMORY_ID,
+    SECOND_LOG_INDEX_MEMORY_ID, SNAPSHOT_MEMORY_IDS,
+};
+use crate::trace::SLOW_QUERY_LOG_MEMORY_ID;
+use crate::util::to_error;
+use crate::{
+    config, Error, JOURNAL_MEMORY_ID, MEMORY_MANAGER, MOUNTED_MEMORY_ID, VFS_SIZES_MEMORY_ID,
+    WASI_MEMORY_ID,
+};
+
+// instructions one `incremental_vacuum` call may spend before it returns,
+// well below the limit of an update call so that the last step always completes
+const VACUUM_INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+// pages released by a single `PRAGMA incremental_vacuum` statement
+const VACUUM_STEP_PAGES: u64 = 128;
+
+/// Stable memory used by one memory id of the `MemoryManager`.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct MemoryStats {
+    pub memory_id: u8,
+    pub name: String,
+    /// Size in wasm pages of 64 KiB.
+    pub pages: u64,
+}
+
+/// Space used by a table or an index, as reported by `dbstat`.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ObjectStats {
+    pub name: String,
+    pub kind: String,
+    pub pages: u64,
+    pub bytes: u64,
+    pub unused_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct StorageStats {
+    pub memories: Vec<MemoryStats>,
+    pub page_size: u64,
+    pub page_count: u64,
+    pub freelist_count: u64,
+    pub auto_vacuum: String,
+    pub max_page_count: u64,
+    pub quota: Option<StorageQuota>,
+    /// Empty when SQLite is built without the `dbstat` virtual table.
+    pub objects: Vec<ObjectStats>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct VacuumProgress {
+    pub freed_pages: u64,
+    /// Free pages left in the database, call again while this is not 0.
+    pub freelist_count: u64,
+}
+
+fn memory_name(id: u8) -> Option<String> {
+    match id {
+        config::CONFIG_MEMORY_ID => Some("config".to_string()),
+        MOUNTED_MEMORY_ID => Some("database".to_string()),
+        JOURNAL_MEMORY_ID => Some("journal".to_string()),
+        VFS_SIZES_MEMORY_ID => Some("file sizes".to_string()),
+        REPLAY_LOG_INDEX_MEMORY_ID => Some("replay log index".to_string()),
+        REPLAY_LOG_DATA_MEMORY_ID => Some("replay log".to_string()),
+        SECOND_LOG_INDEX_MEMORY_ID => Some("second replay log index".to_string()),
+        SECOND_LOG_DATA_MEMORY_ID => Some("second replay log".to_string()),
+        id if SNAPSHOT_MEMORY_IDS.contains(&id) => {
+            Some(format!("snapshot {}", id - SNAPSHOT_MEMORY_IDS[0]))
+        }
+        CHANGE_LOG_MEMORY_ID => Some("change log".to_string()),
+        CHANGESETS_MEMORY_ID => Some("changesets".to_string()),
+        SLOW_QUERY_LOG_MEMORY_ID => Some("slow-query log".to_string()),
+        WORKLOAD_MEMORY_ID => Some("workload".to_string()),
+        id if (WASI_MEMORY_ID..WASI_MEMORY_ID + 10).contains(&id) => {
+            Some(format!("file system {}", id - WASI_MEMORY_ID))
+        }
+        _ => None,
+    }
+}
+
+pub fn memory_stats() -> Vec<MemoryStats> {
+    MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+
+        (0..u8::MAX)
+            .filter_map(|id| {
+                let pages = m.get(MemoryId::new(id)).size();
+                if pages == 0 {
+                    return None;
+                }
+
+                Some(MemoryStats {
+                    memory_id: id,
+                    name: memory_name(id).unwrap_or_else(|| "unknown".to_string()),
+                    pages,
+                })
+            })
+            .collect()
+    })
+}
+
+fn pragma_u64(db: &Connection, name: &str) -> rusqlite::Result<u64> {
+    db.pragma_query_value(None, name, |row| row.get(0))
+}
+
+fn object_stats(db: &Connection) -> Vec<ObjectStats> {
+    let rows = db
+        .prepare(
+            "SELECT d.name, coalesce(s.type, 'table'), d.pageno, d.pgsize, d.unused
+            FROM dbstat AS d LEFT JOIN sqlite_schema AS s ON s.name = d.name
+            WHERE d.aggregate = TRUE
+            ORDER BY d.pgsize DESC",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(ObjectStats {
+                    name: row.get(0)?,
+                    kind: row.get(1)?,
+                    pages: row.get(2)?,
+                    bytes: row.get(3)?,
+                    unused_bytes: row.get(4)?,
+                })
+            })?
+            .collect()
+        });
+
+    // dbstat is an optional compile time feature of SQLite
+    rows.unwrap_or_default()
+}
+
+/// Collects the memory usage of the canister and the space usage of the database.
+pub fn stats(db: &Connection) -> rusqlite::Result<StorageStats> {
+    let auto_vacuum = match pragma_u64(db, "auto_vacuum")? {
+        0 => "NONE",
+        1 => "FULL",
+        _ => "INCREMENTAL",
+    };
+
+    Ok(StorageStats {
+        memories: memory_stats(),
+        page_size: pragma_u64(db, "page_size")?,
+        page_count: pragma_u64(db, "page_count")?,
+        freelist_count: pragma_u64(db, "freelist_count")?,
+        auto_vacuum: auto_vacuum.to_string(),
+        max_page_count: pragma_u64(db, "max_page_count")?,
+        quota: config::get().storage_quota,
+        objects: object_stats(db),
+    })
+}
+
+/// Rebuilds the database, this also applies a changed `page_size` or `auto_vacuum`.
+///
+/// The rebuild is a single statement and cannot be split across calls, large
+/// databases should use `auto_vacuum = INCREMENTAL` and `incremental_vacuum` instead.
+pub fn vacuum(db: &Connection) -> rusqlite::Result<VacuumProgress> {
+    let before = pragma_u64(db, "freelist_count")?;
+    db.execute_batch("VACUUM")?;
+
+    Ok(VacuumProgress {
+        freed_pages: before,
+        freelist_count: pragma_u64(db, "freelist_count")?,
+    })
+}
+
+/// Releases up to `max_pages` free pages in small steps, stopping early when
+/// `instruction_counter` reports that the instruction budget of the call is used up.
+pub fn incremental_vacuum(
+    db: &Connection,
+    max_pages: u64,
+    instruction_counter: impl Fn() -> u64,
+) -> Result<VacuumProgress, Error> {
+    if pragma_u64(db, "auto_vacuum").map_err(to_error)? != 2 {
+        return Err(Error::InvalidArgument {
+            message: "incremental_vacuum requires auto_vacuum = INCREMENTAL, \
+                set it with set_pragma and run vacuum once"
+                .to_string(),
+        });
+    }
+
+    let before = pragma_u64(db, "freelist_count").map_err(to_error)?;
+    let mut left = before;
+    let mut freed = 0;
+
+    while left > 0 && freed < max_pages && instruction_counter() < VACUUM_INSTRUCTION_BUDGET {
+        let step = VACUUM_STEP_PAGES.min(max_pages - freed);
+
+        // every step runs in its own transaction, so the work done so far is kept
+        db.execute_batch(&format!("PRAGMA incremental_vacuum({})", step))
+            .map_err(to_error)?;
+
+        left = pragma_u64(db, "freelist_count").map_err(to_error)?;
+        freed = before - left;
+    }
+
+    Ok(VacuumProgress {
+        freed_pages: freed,
+        freelist_count: left,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(auto_vacuum: &str) -> (tempfile::TempDir, Connection) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Connection::open(dir.path().join("db.db3")).unwrap();
+        db.pragma_update(None, "auto_vacuum", auto_vacuum).unwrap();
+        db.execute_batch(
+            "CREATE TABLE person (id INTEGER PRIMARY KEY, name TEXT, data TEXT);
+            CREATE INDEX person_name ON person(name);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+            INSERT INTO person (name, data) SELECT 'person' || i, hex(randomblob(500)) FROM n;
+            DELETE FROM person WHERE id > 100;",
+        )
+        .unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn reports_tables_and_indexes() {
+        let (_dir, db) = setup("NONE");
+
+        let objects = object_stats(&db);
+        let person = objects.iter().find(|o| o.name == "person").unwrap();
+        let index = objects.iter().find(|o| o.name == "person_name").unwrap();
+
+        assert_eq!(person.kind, "table");
+        assert_eq!(index.kind, "index");
+        assert!(person.pages > index.pages);
+        assert_eq!(
+            person.bytes,
+            person.pages * pragma_u64(&db, "page_size").unwrap()
+        );
+    }
+
+    #[test]
+    fn vacuum_releases_the_freelist() {
+        let (_dir, db) = setup("NONE");
+        assert!(pragma_u64(&db, "freelist_count").unwrap() > 0);
+
+        let progress = vacuum(&db).unwrap();
+        assert!(progress.freed_pages > 0);
+        assert_eq!(progress.freelist_count, 0);
+    }
+
+    #[test]
+    fn incremental_vacuum_continues_across_calls() {
+        let (_dir, db) = setup("INCREMENTAL");
+        let free = pragma_u64(&db, "freelist_count").unwrap();
+        assert!(free > 10);
+
+        let progress = incremental_vacuum(&db, 10, || 0).unwrap();
+        assert_eq!(progress.freed_pages, 10);
+        assert_eq!(progress.freelist_count, free - 10);
+
+        // an exhausted budget stops before the first step
+        let progress = incremental_vacuum(&db, u64::MAX, || u64::MAX).unwrap();
+        assert_eq!(progress.freed_pages, 0);
+
+        let progress = incremental_vacuum(&db, u64::MAX, || 0).unwrap();
+        assert_eq!(progress.freelist_count, 0);
+    }
+
+    #[test]
+    fn incremental_vacuum_requires_incremental_mode() {
+        let (_dir, db) = setup("NONE");
+        assert!(matches!(
+            incremental_vacuum(&db, 10, || 0),
+            Err(Error::InvalidArgument { .. })
+        ));
+    }
+}
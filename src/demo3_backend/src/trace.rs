@@ -0,0 +1,363 @@
+//! Statement tracing and the slow-query log.
+//!
+//! A `sqlite3_trace_v2` callback sees every statement run on the live connection. It
+//! notes the instruction counter when a statement starts (`SQLITE_TRACE_STMT`), counts
+//! the rows it returns (`SQLITE_TRACE_ROW`) and records it with its cost, caller and time
+//! once it ends (`SQLITE_TRACE_PROFILE`). The last `RECENT_CAPACITY` statements are kept
+//! on the heap, the ones above the threshold of the slow-query log are also appended to
+//! a bounded log in stable memory, encrypted like the database files since it holds the
+//! SQL text. Like any other state, the records of a query call are
+//! dropped with the call, only update calls and timers keep them.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
+use std::ptr;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use rusqlite::{ffi, Connection};
+
+use crate::encryption::EncryptedMemory;
+use crate::{config, metrics, Error, MEMORY_MANAGER};
+
+pub const SLOW_QUERY_LOG_MEMORY_ID: u8 = 27;
+
+type SlowQueryMemory = EncryptedMemory<VirtualMemory<DefaultMemoryImpl>>;
+
+/// Statements kept by the heap trace.
+pub const RECENT_CAPACITY: usize = 100;
+
+// entries returned by one `slow_queries` call at most
+const MAX_PAGE_SIZE: u64 = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TracedStatement {
+    /// The text of the statement, without the values of its parameters.
+    pub sql: String,
+    pub instructions: u64,
+    pub rows: u64,
+    /// The caller of the endpoint, none for a statement run by a timer or an upgrade.
+    pub caller: Option<Principal>,
+    /// Time the statement ended, in nanoseconds.
+    pub time: u64,
+}
+
+impl Storable for TracedStatement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Settings of the slow-query log kept in the config.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlowQueryLogConfig {
+    /// Statements using more instructions are logged, `u64::MAX` turns the log off.
+    pub threshold_instructions: u64,
+    /// Entries kept, the oldest ones are dropped first.
+    pub capacity: u64,
+}
+
+impl Default for SlowQueryLogConfig {
+    fn default() -> Self {
+        SlowQueryLogConfig {
+            threshold_instructions: 1_000_000_000,
+            capacity: 1000,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SlowQueryPage {
+    pub entries: Vec<(u64, TracedStatement)>,
+    /// Index to ask for in the next call.
+    pub next_index: u64,
+}
+
+struct Tracer {
+    counter: fn() -> u64,
+    time: fn() -> u64,
+    caller: Option<Principal>,
+    slow_query_log: SlowQueryLogConfig,
+    // instruction counter at the start and rows returned so far, by statement handle
+    running: HashMap<usize, (u64, u64)>,
+    recent: VecDeque<TracedStatement>,
+}
+
+thread_local! {
+    static TRACER: RefCell<Option<Tracer>> = const { RefCell::new(None) };
+
+    static SLOW_QUERIES: RefCell<StableBTreeMap<u64, TracedStatement, SlowQueryMemory>> =
+        RefCell::new(StableBTreeMap::init(memory()));
+}
+
+fn memory() -> SlowQueryMemory {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(SLOW_QUERY_LOG_MEMORY_ID)));
+    EncryptedMemory::new(memory, SLOW_QUERY_LOG_MEMORY_ID)
+}
+
+fn with_tracer(f: impl FnOnce(&mut Tracer)) {
+    TRACER.with(|t| {
+        if let Some(tracer) = t.borrow_mut().as_mut() {
+            f(tracer)
+        }
+    })
+}
+
+unsafe extern "C" fn trace_callback(
+    mask: c_uint,
+    _ctx: *mut c_void,
+    stmt: *mut c_void,
+    data: *mut c_void,
+) -> c_int {
+    match mask as c_int {
+        ffi::SQLITE_TRACE_STMT => {
+            // a trigger program starts with a comment and runs within its statement
+            if CStr::from_ptr(data as *const c_char)
+                .to_bytes()
+                .starts_with(b"--")
+            {
+                return 0;
+            }
+            with_tracer(|t| {
+                t.running.insert(stmt as usize, ((t.counter)(), 0));
+            });
+        }
+        ffi::SQLITE_TRACE_ROW => {
+            metrics::count_row();
+            with_tracer(|t| {
+                if let Some((_, rows)) = t.running.get_mut(&(stmt as usize)) {
+                    *rows += 1;
+                }
+            });
+        }
+        ffi::SQLITE_TRACE_PROFILE => {
+            let sql = ffi::sqlite3_sql(stmt as *mut ffi::sqlite3_stmt);
+            if sql.is_null() {
+                return 0;
+            }
+            let sql = CStr::from_ptr(sql).to_string_lossy().into_owned();
+            let mut slow = None;
+            with_tracer(|t| {
+                let Some((start, rows)) = t.running.remove(&(stmt as usize)) else {
+                    return;
+                };
+                let statement = TracedStatement {
+                    sql,
+                    instructions: (t.counter)().saturating_sub(start),
+                    rows,
+                    caller: t.caller,
+                    time: (t.time)(),
+                };
+                if statement.instructions > t.slow_query_log.threshold_instructions {
+                    slow = Some((statement.clone(), t.slow_query_log.capacity));
+                }
+                if t.recent.len() == RECENT_CAPACITY {
+                    t.recent.pop_front();
+                }
+                t.recent.push_back(statement);
+            });
+            if let Some((statement, capacity)) = slow {
+                append_slow_query(statement, capacity);
+            }
+        }
+        _ => {}
+    }
+    0
+}
+
+/// Installs the trace callback on a connection to the live database, `counter` and
+/// `time` give the instruction counter of the message and the current time.
+pub fn install(db: &Connection, counter: fn() -> u64, time: fn() -> u64) {
+    let slow_query_log = config::get().slow_query_log;
+    TRACER.with(|t| {
+        *t.borrow_mut() = Some(Tracer {
+            counter,
+            time,
+            caller: None,
+            slow_query_log,
+            running: HashMap::new(),
+            recent: VecDeque::new(),
+        })
+    });
+
+    let mask = ffi::SQLITE_TRACE_STMT | ffi::SQLITE_TRACE_ROW | ffi::SQLITE_TRACE_PROFILE;
+    unsafe {
+        ffi::sqlite3_trace_v2(
+            db.handle(),
+            mask as c_uint,
+            Some(trace_callback),
+            ptr::null_mut(),
+        );
+    }
+}
+
+/// Sets the caller the next statements are recorded with.
+pub fn set_caller(caller: Option<Principal>) {
+    with_tracer(|t| t.caller = caller);
+}
+
+/// The last statements, the oldest first.
+pub fn recent() -> Vec<TracedStatement> {
+    TRACER.with(|t| {
+        t.borrow()
+            .as_ref()
+            .map_or(Vec::new(), |t| t.recent.iter().cloned().collect())
+    })
+}
+
+fn trim(log: &mut StableBTreeMap<u64, TracedStatement, SlowQueryMemory>, capacity: u64) {
+    while log.len() > capacity {
+        let (index, _) = log.first_key_value().unwrap();
+        log.remove(&index);
+    }
+}
+
+fn append_slow_query(statement: TracedStatement, capacity: u64) {
+    SLOW_QUERIES.with(|log| {
+        let mut log = log.borrow_mut();
+        let index = log.last_key_value().map_or(0, |(index, _)| index + 1);
+        log.insert(index, statement);
+        trim(&mut log, capacity);
+    });
+}
+
+/// Changes the threshold and capacity of the slow-query log, the oldest entries beyond
+/// the new capacity are dropped at once. The statements of query calls are never logged.
+pub fn set_slow_query_log(slow_query_log: SlowQueryLogConfig) -> Result<(), Error> {
+    if slow_query_log.capacity == 0 {
+        return Err(Error::InvalidArgument {
+            message: "the slow-query log must keep at least one entry".to_string(),
+        });
+    }
+
+    config::update(|c| c.slow_query_log = slow_query_log);
+    with_tracer(|t| t.slow_query_log = slow_query_log);
+    SLOW_QUERIES.with(|log| trim(&mut log.borrow_mut(), slow_query_log.capacity));
+
+    Ok(())
+}
+
+/// Returns up to `limit` entries of the slow-query log starting at `start`.
+pub fn slow_queries(start: u64, limit: u64) -> SlowQueryPage {
+    SLOW_QUERIES.with(|log| {
+        let log = log.borrow();
+        let entries: Vec<(u64, TracedStatement)> = log
+            .range(start..)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .collect();
+
+        SlowQueryPage {
+            next_index: entries.last().map_or(start, |(index, _)| index + 1),
+            entries,
+        }
+    })
+}
+
+/// Empties the slow-query log and returns the number of entries removed, the next entry
+/// gets index 0 again.
+pub fn reset_slow_queries() -> u64 {
+    SLOW_QUERIES.with(|log| {
+        let mut log = log.borrow_mut();
+        let removed = log.len();
+        trim(&mut log, 0);
+        removed
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every row returned so far costs 1000 instructions
+    fn counter() -> u64 {
+        metrics::rows_read() * 1000
+    }
+
+    fn database(threshold_instructions: u64) -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        install(&db, counter, || 42);
+        set_slow_query_log(SlowQueryLogConfig {
+            threshold_instructions,
+            capacity: 2,
+        })
+        .unwrap();
+        crate::create_schema(&db).unwrap();
+        db.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50)
+            INSERT INTO person (name, age) SELECT 'name ' || i, i FROM n;",
+        )
+        .unwrap();
+        db
+    }
+
+    fn select(db: &Connection, sql: &str) -> usize {
+        let mut stmt = db.prepare(sql).unwrap();
+        let rows = stmt.query_map([], |_| Ok(())).unwrap().count();
+        rows
+    }
+
+    #[test]
+    fn records_statements_with_their_cost() {
+        let db = database(u64::MAX);
+        set_caller(Some(Principal::anonymous()));
+        assert_eq!(select(&db, "SELECT name FROM person WHERE age <= 30"), 30);
+        set_caller(None);
+        db.execute("DELETE FROM person WHERE age > 40", []).unwrap();
+
+        let recent = recent();
+        let [.., select, delete] = recent.as_slice() else {
+            panic!("{recent:?}");
+        };
+        assert_eq!(
+            select,
+            &TracedStatement {
+                sql: "SELECT name FROM person WHERE age <= 30".to_string(),
+                instructions: 30_000,
+                rows: 30,
+                caller: Some(Principal::anonymous()),
+                time: 42,
+            }
+        );
+        assert_eq!(delete.sql, "DELETE FROM person WHERE age > 40");
+        assert_eq!(
+            (delete.instructions, delete.rows, delete.caller),
+            (0, 0, None)
+        );
+        assert!(slow_queries(0, 10).entries.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_latest_slow_queries() {
+        let db = database(10_000);
+        for limit in [5, 20, 30, 40] {
+            select(&db, &format!("SELECT id FROM person LIMIT {}", limit));
+        }
+
+        let page = slow_queries(0, 10);
+        let logged: Vec<(u64, u64)> = page
+            .entries
+            .iter()
+            .map(|(index, s)| (*index, s.rows))
+            .collect();
+        assert_eq!(logged, [(1, 30), (2, 40)]);
+        assert_eq!(page.next_index, 3);
+
+        assert_eq!(reset_slow_queries(), 2);
+        assert!(slow_queries(0, 10).entries.is_empty());
+        assert!(set_slow_query_log(SlowQueryLogConfig {
+            threshold_instructions: 0,
+            capacity: 0
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,45 @@
+//! Helpers shared by the modules that build SQL and report its errors.
+
+use rusqlite::Connection;
+
+use crate::Error;
+
+pub fn to_error(err: rusqlite::Error) -> Error {
+    Error::CanisterError {
+        message: format!("{:?}", err),
+    }
+}
+
+pub fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidArgument {
+        message: message.into(),
+    }
+}
+
+/// Quotes a table, index or column name.
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quotes a string value.
+pub fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Whether the schema has a table, index, view or trigger called `name`.
+pub fn exists(db: &Connection, name: &str) -> Result<bool, Error> {
+    db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_schema WHERE name = ?1)",
+        [name],
+        |row| row.get(0),
+    )
+    .map_err(to_error)
+}
+
+/// An in-memory database with the schema of the canister, for the tests.
+#[cfg(test)]
+pub fn database() -> Connection {
+    let db = Connection::open_in_memory().unwrap();
+    crate::create_schema(&db).unwrap();
+    db
+}
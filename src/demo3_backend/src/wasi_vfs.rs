@@ -0,0 +1,434 @@
+//! Test-only SQLite VFS over the `ic_wasi_polyfill` file system.
+//!
+//! On the host SQLite uses the unix VFS, so the files mounted with
+//! `ic_wasi_polyfill::mount_memory_file` are never touched by the tests. This VFS
+//! ports the file I/O of `wasm32-wasi-vfs.c`, the VFS `libsqlite3-sys` builds into the
+//! canister, onto `ic_wasi_polyfill::FS`:
+//!
+//! - the writes to the main journal are buffered, up to 8 KiB of contiguous data,
+//!   until the next read, sync, size query or close;
+//! - truncating does nothing, the polyfill does not implement `ftruncate` either;
+//! - deleting unlinks the file from the file system.
+//!
+//! File controls are left to SQLite, so that the pragmas are not swallowed.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::sync::Once;
+
+use ic_wasi_polyfill::FS;
+use rusqlite::ffi;
+use stable_fs::error::Error as FsError;
+use stable_fs::fs::{FdStat, OpenFlags, Whence};
+
+/// Name to pass to `Connection::open_with_flags_and_vfs`.
+pub const VFS_NAME: &str = "wasi_files";
+
+const VFS_NAME_C: &CStr = c"wasi_files";
+
+// SQLITE_DEMOVFS_BUFFERSZ
+const BUFFER_SIZE: usize = 8192;
+
+const MAX_PATH_NAME: c_int = 512;
+
+/// Registers the VFS with SQLite, it is not made the default VFS.
+pub fn register() {
+    static REGISTER: Once = Once::new();
+
+    REGISTER.call_once(|| unsafe {
+        let default_vfs = ffi::sqlite3_vfs_find(std::ptr::null());
+        assert!(!default_vfs.is_null(), "SQLite has no default VFS");
+
+        let vfs = Box::leak(Box::new(ffi::sqlite3_vfs {
+            iVersion: 1,
+            szOsFile: std::mem::size_of::<OpenFile>() as c_int,
+            mxPathname: MAX_PATH_NAME,
+            pNext: std::ptr::null_mut(),
+            zName: VFS_NAME_C.as_ptr(),
+            pAppData: default_vfs as *mut c_void,
+            xOpen: Some(x_open),
+            xDelete: Some(x_delete),
+            xAccess: Some(x_access),
+            xFullPathname: Some(x_full_pathname),
+            xDlOpen: None,
+            xDlError: None,
+            xDlSym: None,
+            xDlClose: None,
+            xRandomness: Some(x_randomness),
+            xSleep: Some(x_sleep),
+            xCurrentTime: Some(x_current_time),
+            xGetLastError: Some(x_get_last_error),
+            xCurrentTimeInt64: None,
+            xSetSystemCall: None,
+            xGetSystemCall: None,
+            xNextSystemCall: None,
+        }));
+
+        let rc = ffi::sqlite3_vfs_register(vfs, 0);
+        assert_eq!(
+            rc,
+            ffi::SQLITE_OK,
+            "failed to register the {} VFS",
+            VFS_NAME
+        );
+    });
+}
+
+// pending writes of the main journal
+struct Buffer {
+    data: Vec<u8>,
+    offset: u64,
+}
+
+#[repr(C)]
+struct OpenFile {
+    base: ffi::sqlite3_file,
+    fd: u32,
+    buffer: Option<Buffer>,
+}
+
+impl OpenFile {
+    fn write_at(&self, offset: u64, data: &[u8]) -> c_int {
+        FS.with(|fs| {
+            let mut fs = fs.borrow_mut();
+            match fs.seek(self.fd, offset as i64, Whence::SET) {
+                Ok(position) if position == offset => {}
+                _ => return ffi::SQLITE_IOERR_WRITE,
+            }
+            match fs.write(self.fd, data) {
+                Ok(written) if written == data.len() as u64 => ffi::SQLITE_OK,
+                _ => ffi::SQLITE_IOERR_WRITE,
+            }
+        })
+    }
+
+    fn flush(&mut self) -> c_int {
+        let Some(buffer) = &mut self.buffer else {
+            return ffi::SQLITE_OK;
+        };
+        if buffer.data.is_empty() {
+            return ffi::SQLITE_OK;
+        }
+
+        let data = std::mem::take(&mut buffer.data);
+        let offset = buffer.offset;
+        let rc = self.write_at(offset, &data);
+
+        // keeps the allocation for the next writes
+        let buffer = self.buffer.as_mut().unwrap();
+        buffer.data = data;
+        buffer.data.clear();
+        rc
+    }
+}
+
+static IO_METHODS: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(x_close),
+    xRead: Some(x_read),
+    xWrite: Some(x_write),
+    xTruncate: Some(x_truncate),
+    xSync: Some(x_sync),
+    xFileSize: Some(x_file_size),
+    xLock: Some(x_lock),
+    xUnlock: Some(x_lock),
+    xCheckReservedLock: Some(x_check_reserved_lock),
+    xFileControl: Some(x_file_control),
+    xSectorSize: Some(x_sector_size),
+    xDeviceCharacteristics: Some(x_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe fn name_of<'a>(z_name: *const c_char) -> Option<&'a str> {
+    if z_name.is_null() {
+        None
+    } else {
+        CStr::from_ptr(z_name).to_str().ok()
+    }
+}
+
+unsafe fn open_file<'a>(file: *mut ffi::sqlite3_file) -> &'a mut OpenFile {
+    &mut *(file as *mut OpenFile)
+}
+
+unsafe fn default_vfs(vfs: *mut ffi::sqlite3_vfs) -> *mut ffi::sqlite3_vfs {
+    (*vfs).pAppData as *mut ffi::sqlite3_vfs
+}
+
+unsafe extern "C" fn x_open(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    // there are no anonymous temporary files
+    let Some(name) = name_of(z_name) else {
+        return ffi::SQLITE_IOERR;
+    };
+
+    let mut open_flags = OpenFlags::empty();
+    if flags & ffi::SQLITE_OPEN_CREATE != 0 {
+        open_flags |= OpenFlags::CREATE;
+    }
+    if flags & ffi::SQLITE_OPEN_EXCLUSIVE != 0 {
+        open_flags |= OpenFlags::EXCLUSIVE;
+    }
+
+    let fd = FS.with(|fs| {
+        let mut fs = fs.borrow_mut();
+        let root = fs.root_fd();
+        fs.open_or_create(root, name, FdStat::default(), open_flags, 0)
+    });
+    let Ok(fd) = fd else {
+        return ffi::SQLITE_CANTOPEN;
+    };
+
+    let buffer = (flags & ffi::SQLITE_OPEN_MAIN_JOURNAL != 0).then(|| Buffer {
+        data: Vec::with_capacity(BUFFER_SIZE),
+        offset: 0,
+    });
+
+    std::ptr::write(
+        file as *mut OpenFile,
+        OpenFile {
+            base: ffi::sqlite3_file {
+                pMethods: &IO_METHODS,
+            },
+            fd,
+            buffer,
+        },
+    );
+
+    if !out_flags.is_null() {
+        *out_flags = flags;
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_delete(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    _sync_dir: c_int,
+) -> c_int {
+    let Some(name) = name_of(z_name) else {
+        return ffi::SQLITE_IOERR_DELETE;
+    };
+
+    let result = FS.with(|fs| {
+        let mut fs = fs.borrow_mut();
+        let root = fs.root_fd();
+        fs.remove_file(root, name)
+    });
+
+    match result {
+        Ok(()) | Err(FsError::NotFound) => ffi::SQLITE_OK,
+        Err(_) => ffi::SQLITE_IOERR_DELETE,
+    }
+}
+
+unsafe extern "C" fn x_access(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    _flags: c_int,
+    res_out: *mut c_int,
+) -> c_int {
+    let exists = name_of(z_name).is_some_and(|name| {
+        FS.with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let root = fs.root_fd();
+            fs.open_metadata(root, name).is_ok()
+        })
+    });
+
+    *res_out = exists as c_int;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_full_pathname(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    n_out: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let name = CStr::from_ptr(z_name).to_bytes_with_nul();
+    if name.len() > n_out as usize {
+        return ffi::SQLITE_CANTOPEN;
+    }
+
+    std::ptr::copy_nonoverlapping(name.as_ptr() as *const c_char, z_out, name.len());
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_randomness(
+    vfs: *mut ffi::sqlite3_vfs,
+    n_byte: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xRandomness.unwrap()(default_vfs, n_byte, z_out)
+}
+
+unsafe extern "C" fn x_sleep(vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xSleep.unwrap()(default_vfs, microseconds)
+}
+
+unsafe extern "C" fn x_current_time(vfs: *mut ffi::sqlite3_vfs, out: *mut f64) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xCurrentTime.unwrap()(default_vfs, out)
+}
+
+unsafe extern "C" fn x_get_last_error(
+    vfs: *mut ffi::sqlite3_vfs,
+    n_byte: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xGetLastError.unwrap()(default_vfs, n_byte, z_out)
+}
+
+unsafe extern "C" fn x_close(file: *mut ffi::sqlite3_file) -> c_int {
+    let open = open_file(file);
+    let rc = open.flush();
+    FS.with(|fs| fs.borrow_mut().close(open.fd)).ok();
+    std::ptr::drop_in_place(file as *mut OpenFile);
+    rc
+}
+
+unsafe extern "C" fn x_read(
+    file: *mut ffi::sqlite3_file,
+    buf: *mut c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    let open = open_file(file);
+    let rc = open.flush();
+    if rc != ffi::SQLITE_OK {
+        return rc;
+    }
+
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, amount as usize);
+    let read = FS.with(|fs| {
+        let mut fs = fs.borrow_mut();
+        match fs.seek(open.fd, offset, Whence::SET) {
+            Ok(position) if position == offset as u64 => fs.read(open.fd, buf).ok(),
+            _ => None,
+        }
+    });
+
+    match read {
+        Some(read) if read == buf.len() as u64 => ffi::SQLITE_OK,
+        Some(read) => {
+            // SQLite expects the missing part to be zeroed
+            buf[read as usize..].fill(0);
+            ffi::SQLITE_IOERR_SHORT_READ
+        }
+        None => ffi::SQLITE_IOERR_READ,
+    }
+}
+
+unsafe extern "C" fn x_write(
+    file: *mut ffi::sqlite3_file,
+    buf: *const c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    let open = open_file(file);
+    let data = std::slice::from_raw_parts(buf as *const u8, amount as usize);
+    let mut offset = offset as u64;
+
+    if open.buffer.is_none() {
+        return open.write_at(offset, data);
+    }
+
+    let mut data = data;
+    while !data.is_empty() {
+        let buffer = open.buffer.as_ref().unwrap();
+        // only contiguous writes are collected
+        if buffer.data.len() == BUFFER_SIZE || buffer.offset + buffer.data.len() as u64 != offset {
+            let rc = open.flush();
+            if rc != ffi::SQLITE_OK {
+                return rc;
+            }
+        }
+
+        let buffer = open.buffer.as_mut().unwrap();
+        buffer.offset = offset - buffer.data.len() as u64;
+        let copied = (BUFFER_SIZE - buffer.data.len()).min(data.len());
+        buffer.data.extend_from_slice(&data[..copied]);
+        data = &data[copied..];
+        offset += copied as u64;
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_truncate(_file: *mut ffi::sqlite3_file, _size: ffi::sqlite3_int64) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_sync(file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    let open = open_file(file);
+    let rc = open.flush();
+    if rc != ffi::SQLITE_OK {
+        return rc;
+    }
+
+    match FS.with(|fs| fs.borrow_mut().flush(open.fd)) {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(_) => ffi::SQLITE_IOERR_FSYNC,
+    }
+}
+
+unsafe extern "C" fn x_file_size(
+    file: *mut ffi::sqlite3_file,
+    size: *mut ffi::sqlite3_int64,
+) -> c_int {
+    let open = open_file(file);
+    let rc = open.flush();
+    if rc != ffi::SQLITE_OK {
+        return rc;
+    }
+
+    match FS.with(|fs| fs.borrow().metadata(open.fd)) {
+        Ok(metadata) => {
+            *size = metadata.size as ffi::sqlite3_int64;
+            ffi::SQLITE_OK
+        }
+        Err(_) => ffi::SQLITE_IOERR_FSTAT,
+    }
+}
+
+unsafe extern "C" fn x_lock(_file: *mut ffi::sqlite3_file, _lock: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_check_reserved_lock(
+    _file: *mut ffi::sqlite3_file,
+    res_out: *mut c_int,
+) -> c_int {
+    *res_out = 0;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_control(
+    _file: *mut ffi::sqlite3_file,
+    _op: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    ffi::SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn x_sector_size(_file: *mut ffi::sqlite3_file) -> c_int {
+    0
+}
+
+unsafe extern "C" fn x_device_characteristics(_file: *mut ffi::sqlite3_file) -> c_int {
+    0
+}
@@ -0,0 +1,7 @@
+fn main() {
+    // the wasm builtins are only needed (and only available) for the canister target
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("wasm32") {
+        println!("cargo:rustc-link-search=/opt/wasi-sdk/lib/clang/18/lib/wasi/");
+        println!("cargo:rustc-link-arg=-lclang_rt.builtins-wasm32");
+    }
+}
@@ -0,0 +1,233 @@
+//! Router canister that shards the `person` table across `demo3_backend` canisters.
+//!
+//! Rows are placed by a hash of their id (see `metadata`), point reads and writes go to
+//! the shard owning the id and listings are gathered from all shards (see `scatter`).
+
+use std::cell::RefCell;
+
+mod metadata;
+mod rebalance;
+mod scatter;
+mod shard;
+
+use candid::{CandidType, Deserialize, Principal};
+use futures::future::join_all;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+use ic_stable_structures::DefaultMemoryImpl;
+use rusqlite::{Connection, ToSql};
+
+use metadata::{Range, ShardInfo};
+use scatter::PeoplePage;
+use shard::{Person, PersonOrder};
+
+thread_local! {
+    static DB: RefCell<Option<Connection>> = const { RefCell::new(None) };
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+const WASI_MEMORY_ID: u8 = 200;
+const DB_MEMORY_ID: u8 = 20;
+const JOURNAL_MEMORY_ID: u8 = DB_MEMORY_ID + 1;
+const DB_FILE_NAME: &str = "router.db3";
+const DB_JOURNAL_FILE_NAME: &str = "router.db3-journal";
+
+fn with_db<T>(f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+    DB.with(|db| f(db.borrow().as_ref().unwrap()))
+}
+
+fn check_admin() -> Result {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+// the shard that takes writes for an id
+fn writable_shard(id: u64) -> Result<Principal> {
+    let range = with_db(|db| metadata::route(db, id))?;
+    match range.moving_to {
+        Some(_) => Err(Error::RangeMoving { start: range.start }),
+        None => Ok(range.shard),
+    }
+}
+
+#[ic_cdk::update]
+async fn add(name: String, data: String, age: u32) -> Result<u64> {
+    let id = with_db(metadata::next_id)?;
+    let shard = writable_shard(id)?;
+
+    let person = Person {
+        id,
+        name,
+        data,
+        age,
+    };
+    shard::call::<_, ()>(shard, "put_people", (vec![person],)).await?;
+
+    Ok(id)
+}
+
+#[ic_cdk::update]
+async fn delete(id: u64) -> Result<bool> {
+    check_admin()?;
+
+    let shard = writable_shard(id)?;
+    shard::call(shard, "delete_person", (id,)).await
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get(id: u64) -> Result<Option<Person>> {
+    let range = with_db(|db| metadata::route(db, id))?;
+    shard::call(range.shard, "get_person", (id,)).await
+}
+
+/// Lists the rows of all shards in `order`, starting after the row `after`.
+#[ic_cdk::query(composite = true)]
+async fn list(order: PersonOrder, after: Option<Person>, limit: u32) -> Result<PeoplePage> {
+    let ranges = with_db(metadata::ranges)?;
+    let shards = with_db(metadata::shards)?;
+
+    let calls = shards.iter().map(|shard| {
+        shard::call::<_, Vec<Person>>(shard.canister, "list_people", (order, &after, limit))
+    });
+    let mut pages = Vec::new();
+    for (shard, page) in shards.iter().zip(join_all(calls).await) {
+        pages.push((shard.canister, page?));
+    }
+
+    Ok(scatter::merge(order, pages, &ranges, limit))
+}
+
+#[ic_cdk::query]
+fn get_shards() -> Result<Vec<ShardInfo>> {
+    with_db(metadata::shards)
+}
+
+#[ic_cdk::query]
+fn get_ranges() -> Result<Vec<Range>> {
+    with_db(metadata::ranges)
+}
+
+/// Registers an existing shard, the router has to be one of its controllers.
+#[ic_cdk::update]
+fn add_shard(canister: Principal) -> Result {
+    check_admin()?;
+
+    with_db(|db| metadata::add_shard(db, canister, ic_cdk::api::time()))
+}
+
+/// Sets the wasm module `create_shard` installs.
+#[ic_cdk::update]
+fn set_shard_wasm(module: Vec<u8>) -> Result {
+    check_admin()?;
+
+    with_db(|db| metadata::set_shard_wasm(db, &module))
+}
+
+#[ic_cdk::update]
+async fn create_shard(cycles: u128) -> Result<Principal> {
+    check_admin()?;
+
+    rebalance::create_shard(cycles, ic_cdk::caller()).await
+}
+
+/// Splits a range at `at`, in the middle if not given.
+#[ic_cdk::update]
+fn split_range(start: u64, at: Option<u64>) -> Result<Vec<Range>> {
+    check_admin()?;
+
+    with_db(|db| {
+        let at = match at {
+            Some(at) => at,
+            None => metadata::ranges(db)?
+                .iter()
+                .find(|range| range.start == start)
+                .map_or(start, |range| start + (range.end - start) / 2),
+        };
+        let (lower, upper) = metadata::split(db, start, at)?;
+        Ok(vec![lower, upper])
+    })
+}
+
+#[ic_cdk::update]
+async fn move_range(start: u64, target: Principal) -> Result<Range> {
+    check_admin()?;
+
+    rebalance::move_range(start, target).await
+}
+
+/// Deletes rows that moved ranges left on their previous shards.
+#[ic_cdk::update]
+async fn clean_up_moved_ranges() -> Result<u64> {
+    check_admin()?;
+
+    rebalance::clean_up().await
+}
+
+fn open_database() {
+    MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+        ic_wasi_polyfill::init_with_memory_manager(
+            &[0u8; 32],
+            &[],
+            &m,
+            WASI_MEMORY_ID..WASI_MEMORY_ID + 10,
+        );
+
+        ic_wasi_polyfill::mount_memory_file(
+            DB_FILE_NAME,
+            Box::new(m.get(MemoryId::new(DB_MEMORY_ID))),
+        );
+        ic_wasi_polyfill::mount_memory_file(
+            DB_JOURNAL_FILE_NAME,
+            Box::new(m.get(MemoryId::new(JOURNAL_MEMORY_ID))),
+        );
+    });
+
+    let db = Connection::open(DB_FILE_NAME).unwrap();
+
+    // the metadata is small and rarely written, the pragmas of demo3_backend are enough
+    db.pragma_update(None, "journal_mode", &"TRUNCATE" as &dyn ToSql)
+        .unwrap();
+    db.pragma_update(None, "synchronous", &0 as &dyn ToSql)
+        .unwrap();
+    db.pragma_update(None, "locking_mode", &"EXCLUSIVE" as &dyn ToSql)
+        .unwrap();
+    db.pragma_update(None, "temp_store", &2 as &dyn ToSql)
+        .unwrap();
+
+    metadata::create_schema(&db).unwrap();
+
+    DB.with(|cell| *cell.borrow_mut() = Some(db));
+}
+
+#[ic_cdk::init]
+fn init() {
+    open_database();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    DB.with(|db| db.borrow_mut().take());
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    open_database();
+}
+
+// variant names are part of the public candid interface
+#[allow(clippy::enum_variant_names)]
+#[derive(CandidType, Deserialize, Debug)]
+enum Error {
+    CanisterError { message: String },
+    Unauthorized,
+    InvalidArgument { message: String },
+    NoShards,
+    RangeMoving { start: u64 },
+    ShardError { shard: Principal, message: String },
+}
+
+type Result<T = (), E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,462 @@
+//! Shards and key ranges, kept in the router's own SQLite database.
+//!
+//! The partition key space `0..KEY_SPACE` is divided into contiguous ranges, each one
+//! owned by a shard. A range that is being moved keeps its owner, which serves its
+//! reads, until all its rows are copied to the target; writes to it are refused in the
+//! meantime. The rows left on the previous owner are deleted afterwards.
+
+use candid::{CandidType, Deserialize, Principal};
+use rusqlite::{Connection, OptionalExtension, Row};
+
+use crate::Error;
+
+/// Partition keys are in `0..KEY_SPACE`.
+pub const KEY_SPACE: u64 = 1 << 32;
+
+/// The partition key of a person id, it must match `PARTITION_KEY_SQL` of the shards.
+pub fn partition_key(id: u64) -> u64 {
+    ((id & 0x7FFF_FFFF) * 2_654_435_761) & 0xFFFF_FFFF
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+    pub shard: Principal,
+    /// Shard the range is being moved to.
+    pub moving_to: Option<Principal>,
+    /// Last id copied to the target of a move.
+    pub copied_up_to: Option<u64>,
+}
+
+impl Range {
+    pub fn contains(&self, key: u64) -> bool {
+        self.start <= key && key < self.end
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShardInfo {
+    pub canister: Principal,
+    /// Time the shard was added in nanoseconds.
+    pub added_at: u64,
+    /// Key ranges owned by the shard.
+    pub ranges: u64,
+}
+
+/// Rows a shard still holds for a range moved away from it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Cleanup {
+    pub shard: Principal,
+    pub start: u64,
+    pub end: u64,
+}
+
+fn to_error(err: rusqlite::Error) -> Error {
+    Error::CanisterError {
+        message: format!("{:?}", err),
+    }
+}
+
+pub fn create_schema(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS shards (
+            id        INTEGER PRIMARY KEY,
+            canister  BLOB NOT NULL UNIQUE,
+            added_at  INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS ranges (
+            start         INTEGER PRIMARY KEY,
+            end           INTEGER NOT NULL,
+            shard         INTEGER NOT NULL REFERENCES shards (id),
+            moving_to     INTEGER REFERENCES shards (id),
+            copied_up_to  INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS cleanups (
+            shard  INTEGER NOT NULL REFERENCES shards (id),
+            start  INTEGER NOT NULL,
+            end    INTEGER NOT NULL,
+            PRIMARY KEY (shard, start)
+        );
+        CREATE TABLE IF NOT EXISTS counters (
+            name   TEXT PRIMARY KEY,
+            value  INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS shard_wasm (
+            id      INTEGER PRIMARY KEY CHECK (id = 0),
+            module  BLOB NOT NULL
+        );",
+    )
+}
+
+fn shard_id(db: &Connection, canister: Principal) -> Result<i64, Error> {
+    db.query_row(
+        "SELECT id FROM shards WHERE canister = ?1",
+        [canister.as_slice()],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(to_error)?
+    .ok_or_else(|| Error::InvalidArgument {
+        message: format!("{} is not a shard", canister),
+    })
+}
+
+fn principal(row: &Row, idx: usize) -> rusqlite::Result<Principal> {
+    Ok(Principal::from_slice(&row.get::<_, Vec<u8>>(idx)?))
+}
+
+/// Registers a shard, the first one owns the whole key space.
+pub fn add_shard(db: &Connection, canister: Principal, time: u64) -> Result<(), Error> {
+    if shard_id(db, canister).is_ok() {
+        return Err(Error::InvalidArgument {
+            message: format!("{} is already a shard", canister),
+        });
+    }
+
+    let tx = db.unchecked_transaction().map_err(to_error)?;
+    tx.execute(
+        "INSERT INTO shards (canister, added_at) VALUES (?1, ?2)",
+        (canister.as_slice(), time as i64),
+    )
+    .map_err(to_error)?;
+    tx.execute(
+        "INSERT INTO ranges (start, end, shard)
+        SELECT 0, ?1, last_insert_rowid() WHERE NOT EXISTS (SELECT 1 FROM ranges)",
+        [KEY_SPACE as i64],
+    )
+    .map_err(to_error)?;
+    tx.commit().map_err(to_error)
+}
+
+pub fn shards(db: &Connection) -> Result<Vec<ShardInfo>, Error> {
+    let mut stmt = db
+        .prepare(
+            "SELECT canister, added_at, (SELECT COUNT(*) FROM ranges WHERE ranges.shard = shards.id)
+            FROM shards ORDER BY id",
+        )
+        .map_err(to_error)?;
+    let shards = stmt
+        .query_map([], |row| {
+            Ok(ShardInfo {
+                canister: principal(row, 0)?,
+                added_at: row.get(1)?,
+                ranges: row.get(2)?,
+            })
+        })
+        .map_err(to_error)?;
+    shards.collect::<rusqlite::Result<_>>().map_err(to_error)
+}
+
+const RANGE_COLUMNS: &str =
+    "ranges.start, ranges.end, owner.canister, target.canister, ranges.copied_up_to
+    FROM ranges
+    JOIN shards AS owner ON owner.id = ranges.shard
+    LEFT JOIN shards AS target ON target.id = ranges.moving_to";
+
+fn range_from_row(row: &Row) -> rusqlite::Result<Range> {
+    Ok(Range {
+        start: row.get(0)?,
+        end: row.get(1)?,
+        shard: principal(row, 2)?,
+        moving_to: row
+            .get::<_, Option<Vec<u8>>>(3)?
+            .map(|bytes| Principal::from_slice(&bytes)),
+        copied_up_to: row.get(4)?,
+    })
+}
+
+/// All ranges ordered by their start.
+pub fn ranges(db: &Connection) -> Result<Vec<Range>, Error> {
+    let mut stmt = db
+        .prepare(&format!("SELECT {RANGE_COLUMNS} ORDER BY ranges.start"))
+        .map_err(to_error)?;
+    let ranges = stmt.query_map([], range_from_row).map_err(to_error)?;
+    ranges.collect::<rusqlite::Result<_>>().map_err(to_error)
+}
+
+fn range(db: &Connection, start: u64) -> Result<Range, Error> {
+    db.query_row(
+        &format!("SELECT {RANGE_COLUMNS} WHERE ranges.start = ?1"),
+        [start as i64],
+        range_from_row,
+    )
+    .optional()
+    .map_err(to_error)?
+    .ok_or_else(|| Error::InvalidArgument {
+        message: format!("no range starts at {}", start),
+    })
+}
+
+/// The range of a person id.
+pub fn route(db: &Connection, id: u64) -> Result<Range, Error> {
+    db.query_row(
+        &format!(
+            "SELECT {RANGE_COLUMNS} WHERE ranges.start <= ?1 ORDER BY ranges.start DESC LIMIT 1"
+        ),
+        [partition_key(id) as i64],
+        range_from_row,
+    )
+    .optional()
+    .map_err(to_error)?
+    .ok_or(Error::NoShards)
+}
+
+/// Finds the range of a key in ranges ordered by their start.
+pub fn find(ranges: &[Range], key: u64) -> Option<&Range> {
+    let idx = ranges.partition_point(|range| range.start <= key);
+    ranges[..idx].last().filter(|range| range.contains(key))
+}
+
+/// Allocates the id of a new person, ids start at 1.
+pub fn next_id(db: &Connection) -> Result<u64, Error> {
+    db.query_row(
+        "INSERT INTO counters (name, value) VALUES ('person_id', 1)
+        ON CONFLICT (name) DO UPDATE SET value = value + 1
+        RETURNING value",
+        [],
+        |row| row.get(0),
+    )
+    .map_err(to_error)
+}
+
+/// Splits a range at `at`, both parts stay on the same shard.
+pub fn split(db: &Connection, start: u64, at: u64) -> Result<(Range, Range), Error> {
+    let range = range(db, start)?;
+    if range.moving_to.is_some() {
+        return Err(Error::RangeMoving { start });
+    }
+    if at <= range.start || at >= range.end {
+        return Err(Error::InvalidArgument {
+            message: format!(
+                "{} is not inside the range {}..{}",
+                at, range.start, range.end
+            ),
+        });
+    }
+
+    let tx = db.unchecked_transaction().map_err(to_error)?;
+    tx.execute(
+        "UPDATE ranges SET end = ?2 WHERE start = ?1",
+        (start as i64, at as i64),
+    )
+    .map_err(to_error)?;
+    tx.execute(
+        "INSERT INTO ranges (start, end, shard) SELECT ?1, ?2, shard FROM ranges WHERE start = ?3",
+        (at as i64, range.end as i64, start as i64),
+    )
+    .map_err(to_error)?;
+    tx.commit().map_err(to_error)?;
+
+    Ok((self::range(db, start)?, self::range(db, at)?))
+}
+
+/// Marks a range as moving to `target`, or returns the move in progress to it.
+pub fn start_move(db: &Connection, start: u64, target: Principal) -> Result<Range, Error> {
+    let range = range(db, start)?;
+    let target_id = shard_id(db, target)?;
+
+    match range.moving_to {
+        Some(moving_to) if moving_to == target => return Ok(range),
+        Some(_) => return Err(Error::RangeMoving { start }),
+        None => {}
+    }
+    let leftovers: bool = db
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM cleanups WHERE shard = ?1 AND start < ?3 AND end > ?2)",
+            (target_id, range.start as i64, range.end as i64),
+            |row| row.get(0),
+        )
+        .map_err(to_error)?;
+    if leftovers {
+        return Err(Error::InvalidArgument {
+            message: format!(
+                "{} still holds rows of a moved range, clean it up first",
+                target
+            ),
+        });
+    }
+    if range.shard == target {
+        return Err(Error::InvalidArgument {
+            message: format!(
+                "the range {}..{} is already on {}",
+                range.start, range.end, target
+            ),
+        });
+    }
+
+    db.execute(
+        "UPDATE ranges SET moving_to = ?2, copied_up_to = NULL WHERE start = ?1",
+        (start as i64, target_id),
+    )
+    .map_err(to_error)?;
+
+    self::range(db, start)
+}
+
+pub fn record_copied(db: &Connection, start: u64, id: u64) -> Result<(), Error> {
+    db.execute(
+        "UPDATE ranges SET copied_up_to = ?2 WHERE start = ?1",
+        (start as i64, id as i64),
+    )
+    .map_err(to_error)?;
+    Ok(())
+}
+
+/// Hands a copied range over to the target of its move.
+pub fn finish_move(db: &Connection, start: u64) -> Result<Range, Error> {
+    let tx = db.unchecked_transaction().map_err(to_error)?;
+    tx.execute(
+        "INSERT OR REPLACE INTO cleanups (shard, start, end)
+        SELECT shard, start, end FROM ranges WHERE start = ?1 AND moving_to IS NOT NULL",
+        [start as i64],
+    )
+    .map_err(to_error)?;
+    tx.execute(
+        "UPDATE ranges SET shard = moving_to, moving_to = NULL, copied_up_to = NULL
+        WHERE start = ?1 AND moving_to IS NOT NULL",
+        [start as i64],
+    )
+    .map_err(to_error)?;
+    tx.commit().map_err(to_error)?;
+
+    range(db, start)
+}
+
+pub fn cleanups(db: &Connection) -> Result<Vec<Cleanup>, Error> {
+    let mut stmt = db
+        .prepare(
+            "SELECT shards.canister, cleanups.start, cleanups.end
+            FROM cleanups JOIN shards ON shards.id = cleanups.shard
+            ORDER BY cleanups.shard, cleanups.start",
+        )
+        .map_err(to_error)?;
+    let cleanups = stmt
+        .query_map([], |row| {
+            Ok(Cleanup {
+                shard: principal(row, 0)?,
+                start: row.get(1)?,
+                end: row.get(2)?,
+            })
+        })
+        .map_err(to_error)?;
+    cleanups.collect::<rusqlite::Result<_>>().map_err(to_error)
+}
+
+pub fn remove_cleanup(db: &Connection, cleanup: &Cleanup) -> Result<(), Error> {
+    db.execute(
+        "DELETE FROM cleanups WHERE shard = ?1 AND start = ?2",
+        (shard_id(db, cleanup.shard)?, cleanup.start as i64),
+    )
+    .map_err(to_error)?;
+    Ok(())
+}
+
+pub fn set_shard_wasm(db: &Connection, module: &[u8]) -> Result<(), Error> {
+    db.execute(
+        "INSERT OR REPLACE INTO shard_wasm (id, module) VALUES (0, ?1)",
+        [module],
+    )
+    .map_err(to_error)?;
+    Ok(())
+}
+
+pub fn shard_wasm(db: &Connection) -> Result<Option<Vec<u8>>, Error> {
+    db.query_row("SELECT module FROM shard_wasm WHERE id = 0", [], |row| {
+        row.get(0)
+    })
+    .optional()
+    .map_err(to_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        create_schema(&db).unwrap();
+        db
+    }
+
+    fn canister(id: u8) -> Principal {
+        Principal::from_slice(&[id; 10])
+    }
+
+    #[test]
+    fn the_first_shard_owns_the_key_space() {
+        let db = database();
+        assert!(matches!(route(&db, 1), Err(Error::NoShards)));
+
+        add_shard(&db, canister(1), 10).unwrap();
+        add_shard(&db, canister(2), 20).unwrap();
+        assert!(add_shard(&db, canister(2), 30).is_err());
+
+        let ranges = ranges(&db).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].start, ranges[0].end), (0, KEY_SPACE));
+        assert_eq!(route(&db, 12345).unwrap(), ranges[0]);
+
+        let shards = shards(&db).unwrap();
+        assert_eq!(shards.len(), 2);
+        assert_eq!((shards[0].canister, shards[0].ranges), (canister(1), 1));
+        assert_eq!((shards[1].added_at, shards[1].ranges), (20, 0));
+
+        assert_eq!(next_id(&db).unwrap(), 1);
+        assert_eq!(next_id(&db).unwrap(), 2);
+    }
+
+    #[test]
+    fn splits_and_moves_ranges() {
+        let db = database();
+        add_shard(&db, canister(1), 0).unwrap();
+        add_shard(&db, canister(2), 0).unwrap();
+
+        let half = KEY_SPACE / 2;
+        let (lower, upper) = split(&db, 0, half).unwrap();
+        assert_eq!(
+            (lower.start, lower.end, upper.start, upper.end),
+            (0, half, half, KEY_SPACE)
+        );
+        assert!(split(&db, 0, half).is_err());
+        assert!(split(&db, 1, 2).is_err());
+
+        assert!(start_move(&db, half, canister(1)).is_err());
+        assert!(start_move(&db, half, canister(3)).is_err());
+        let moving = start_move(&db, half, canister(2)).unwrap();
+        assert_eq!(moving.moving_to, Some(canister(2)));
+        assert_eq!(start_move(&db, half, canister(2)).unwrap(), moving);
+        assert!(matches!(
+            split(&db, half, half + 1),
+            Err(Error::RangeMoving { .. })
+        ));
+
+        record_copied(&db, half, 42).unwrap();
+        assert_eq!(range(&db, half).unwrap().copied_up_to, Some(42));
+
+        let moved = finish_move(&db, half).unwrap();
+        assert_eq!(
+            (moved.shard, moved.moving_to, moved.copied_up_to),
+            (canister(2), None, None)
+        );
+
+        let cleanups = cleanups(&db).unwrap();
+        assert_eq!(
+            cleanups,
+            [Cleanup {
+                shard: canister(1),
+                start: half,
+                end: KEY_SPACE
+            }]
+        );
+        remove_cleanup(&db, &cleanups[0]).unwrap();
+        assert!(self::cleanups(&db).unwrap().is_empty());
+
+        let ranges = ranges(&db).unwrap();
+        for id in 1..100 {
+            let range = route(&db, id).unwrap();
+            assert!(range.contains(partition_key(id)));
+            assert_eq!(find(&ranges, partition_key(id)), Some(&range));
+        }
+    }
+}
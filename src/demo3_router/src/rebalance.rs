@@ -0,0 +1,150 @@
+//! Creating shards and moving key ranges between them.
+//!
+//! A move copies the rows of a range page by page from its owner to the target, hands
+//! the range over and then deletes the rows from the previous owner. Writes to the
+//! range are refused while it is copied; the writes routed to the owner before the
+//! move started reach it before the first page is read, as calls from one canister
+//! to another are delivered in order. An interrupted move is resumed by moving the
+//! range to the same target again.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use candid::Principal;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+
+use crate::metadata::{self, Range};
+use crate::shard::{self, Person};
+use crate::{with_db, Error};
+
+// rows copied or deleted per call
+const PAGE_SIZE: u32 = 500;
+
+thread_local! {
+    // ranges with a move in flight
+    static MOVING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+struct MoveGuard(u64);
+
+impl MoveGuard {
+    fn new(start: u64) -> Result<Self, Error> {
+        if MOVING.with(|m| m.borrow_mut().insert(start)) {
+            Ok(MoveGuard(start))
+        } else {
+            Err(Error::RangeMoving { start })
+        }
+    }
+}
+
+impl Drop for MoveGuard {
+    fn drop(&mut self) {
+        MOVING.with(|m| m.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Creates a canister with the uploaded shard wasm and registers it.
+pub async fn create_shard(cycles: u128, admin: Principal) -> Result<Principal, Error> {
+    let wasm_module = with_db(metadata::shard_wasm)?.ok_or_else(|| Error::InvalidArgument {
+        message: "no shard wasm, upload one with set_shard_wasm".to_string(),
+    })?;
+
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id(), admin]),
+        ..Default::default()
+    };
+    let (record,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(settings),
+        },
+        cycles,
+    )
+    .await
+    .map_err(|(code, message)| Error::CanisterError {
+        message: format!("cannot create a shard: {:?}: {}", code, message),
+    })?;
+    let canister = record.canister_id;
+
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: canister,
+        wasm_module,
+        arg: candid::encode_args(()).unwrap(),
+    })
+    .await
+    .map_err(|(code, message)| Error::CanisterError {
+        message: format!("cannot install {}: {:?}: {}", canister, code, message),
+    })?;
+
+    with_db(|db| metadata::add_shard(db, canister, ic_cdk::api::time()))?;
+
+    Ok(canister)
+}
+
+/// Moves a range to another shard.
+pub async fn move_range(start: u64, target: Principal) -> Result<Range, Error> {
+    let _guard = MoveGuard::new(start)?;
+
+    // rows left on the target by an earlier move must not mix with the copied ones
+    clean_up().await?;
+
+    let range = with_db(|db| metadata::start_move(db, start, target))?;
+    let mut copied_up_to = range.copied_up_to;
+
+    loop {
+        let page: Vec<Person> = shard::call(
+            range.shard,
+            "get_key_range",
+            (range.start, range.end, copied_up_to, PAGE_SIZE),
+        )
+        .await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        let last = last.id;
+
+        shard::call::<_, ()>(target, "put_people", (&page,)).await?;
+        with_db(|db| metadata::record_copied(db, start, last))?;
+        copied_up_to = Some(last);
+
+        if page.len() < PAGE_SIZE as usize {
+            break;
+        }
+    }
+
+    let range = with_db(|db| metadata::finish_move(db, start))?;
+
+    // the leftovers are skipped by listings, a failed cleanup is retried later
+    if let Err(err) = clean_up().await {
+        ic_cdk::eprintln!("cleanup after moving {}: {:?}", start, err);
+    }
+
+    Ok(range)
+}
+
+/// Deletes the rows of moved ranges from their previous owners, returns the count.
+pub async fn clean_up() -> Result<u64, Error> {
+    let mut deleted = 0;
+
+    for cleanup in with_db(metadata::cleanups)? {
+        loop {
+            let count: u64 = shard::call(
+                cleanup.shard,
+                "delete_key_range",
+                (cleanup.start, cleanup.end, PAGE_SIZE),
+            )
+            .await?;
+            deleted += count;
+
+            if count < PAGE_SIZE as u64 {
+                break;
+            }
+        }
+        with_db(|db| metadata::remove_cleanup(db, &cleanup))?;
+    }
+
+    Ok(deleted)
+}
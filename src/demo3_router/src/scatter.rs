@@ -0,0 +1,173 @@
+//! Scatter-gather listing of all shards.
+//!
+//! Every shard returns its first page after the cursor and the pages are merged. A
+//! shard may hold rows of a range it does not own, copied there by a move in progress
+//! or left behind by a finished one; those are skipped.
+
+use std::cmp::Ordering;
+
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::metadata::{self, Range};
+use crate::shard::{Person, PersonOrder};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeoplePage {
+    pub people: Vec<Person>,
+    /// Cursor of the next page, `None` after the last one.
+    pub next: Option<Person>,
+}
+
+pub fn compare(order: PersonOrder, a: &Person, b: &Person) -> Ordering {
+    let by_key = match order {
+        PersonOrder::Id => Ordering::Equal,
+        PersonOrder::Name => a.name.cmp(&b.name),
+        PersonOrder::Age => a.age.cmp(&b.age),
+    };
+    by_key.then(a.id.cmp(&b.id))
+}
+
+/// Merges the pages of `limit` rows returned by the shards into one page.
+pub fn merge(
+    order: PersonOrder,
+    pages: Vec<(Principal, Vec<Person>)>,
+    ranges: &[Range],
+    limit: u32,
+) -> PeoplePage {
+    let limit = limit as usize;
+
+    // the rows after the end of a full page are unknown, so nothing past the
+    // smallest such end can be returned yet
+    let bound = pages
+        .iter()
+        .filter(|(_, page)| limit > 0 && page.len() >= limit)
+        .filter_map(|(_, page)| page.last())
+        .min_by(|a, b| compare(order, a, b))
+        .cloned();
+
+    let mut people: Vec<Person> = pages
+        .into_iter()
+        .flat_map(|(shard, page)| {
+            page.into_iter().filter(move |person| {
+                metadata::find(ranges, metadata::partition_key(person.id))
+                    .is_some_and(|range| range.shard == shard)
+            })
+        })
+        .filter(|person| {
+            bound
+                .as_ref()
+                .is_none_or(|bound| compare(order, person, bound) != Ordering::Greater)
+        })
+        .collect();
+    people.sort_by(|a, b| compare(order, a, b));
+
+    let next = if people.len() >= limit && limit > 0 {
+        people.truncate(limit);
+        people.last().cloned()
+    } else {
+        bound
+    };
+
+    PeoplePage { people, next }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::KEY_SPACE;
+
+    fn canister(id: u8) -> Principal {
+        Principal::from_slice(&[id; 10])
+    }
+
+    fn person(id: u64) -> Person {
+        Person {
+            id,
+            name: format!("name {}", id % 4),
+            data: String::new(),
+            age: (id % 7) as u32,
+        }
+    }
+
+    fn ranges() -> Vec<Range> {
+        let half = KEY_SPACE / 2;
+        vec![
+            Range {
+                start: 0,
+                end: half,
+                shard: canister(1),
+                moving_to: None,
+                copied_up_to: None,
+            },
+            Range {
+                start: half,
+                end: KEY_SPACE,
+                shard: canister(2),
+                moving_to: None,
+                copied_up_to: None,
+            },
+        ]
+    }
+
+    fn owner(id: u64) -> Principal {
+        metadata::find(&ranges(), metadata::partition_key(id))
+            .unwrap()
+            .shard
+    }
+
+    // what a shard returns for a page
+    fn shard_page(
+        rows: &[Person],
+        order: PersonOrder,
+        after: Option<&Person>,
+        limit: u32,
+    ) -> Vec<Person> {
+        let mut rows: Vec<Person> = rows
+            .iter()
+            .filter(|p| after.is_none_or(|after| compare(order, p, after) == Ordering::Greater))
+            .cloned()
+            .collect();
+        rows.sort_by(|a, b| compare(order, a, b));
+        rows.truncate(limit as usize);
+        rows
+    }
+
+    #[test]
+    fn pages_through_all_shards_in_order() {
+        let all: Vec<Person> = (1..=40).map(person).collect();
+        let mut shard1: Vec<Person> = all
+            .iter()
+            .filter(|p| owner(p.id) == canister(1))
+            .cloned()
+            .collect();
+        let shard2: Vec<Person> = all
+            .iter()
+            .filter(|p| owner(p.id) == canister(2))
+            .cloned()
+            .collect();
+        // rows shard 1 still holds from before their range moved to shard 2
+        shard1.extend(shard2.iter().take(5).cloned());
+
+        for order in [PersonOrder::Id, PersonOrder::Name, PersonOrder::Age] {
+            let mut listed = Vec::new();
+            let mut after = None;
+            loop {
+                let pages = vec![
+                    (canister(1), shard_page(&shard1, order, after.as_ref(), 7)),
+                    (canister(2), shard_page(&shard2, order, after.as_ref(), 7)),
+                ];
+                let page = merge(order, pages, &ranges(), 7);
+                assert!(page.people.len() <= 7);
+                listed.extend(page.people);
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+
+            let mut expected = all.clone();
+            expected.sort_by(|a, b| compare(order, a, b));
+            assert_eq!(listed, expected, "{order:?}");
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Calls to the `demo3_backend` shards.
+
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Deserialize, Principal, Reserved};
+use serde::de::DeserializeOwned;
+
+use crate::Error;
+
+/// A row of the sharded `person` table.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Person {
+    pub id: u64,
+    pub name: String,
+    pub data: String,
+    pub age: u32,
+}
+
+/// Sort order of a listing, ties are broken by the id.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PersonOrder {
+    Id,
+    Name,
+    Age,
+}
+
+/// The error type of `demo3_backend`.
+#[allow(clippy::enum_variant_names, dead_code)]
+#[derive(CandidType, Deserialize, Debug)]
+enum ShardError {
+    InvalidCanister,
+    CanisterError {
+        message: String,
+    },
+    Unauthorized,
+    InvalidArgument {
+        message: String,
+    },
+    StorageFull,
+    QuotaExceeded {
+        used_bytes: u64,
+        soft_limit_bytes: u64,
+    },
+    MigrationInProgress {
+        target: Reserved,
+    },
+    ReadOnlyReplica {
+        primary: Principal,
+    },
+    DatabaseDamaged {
+        check: String,
+    },
+    BudgetExceeded {
+        budget: u64,
+        used: u64,
+        partial_rows: Reserved,
+    },
+    ArbitrarySqlDisabled,
+    FullScanRejected {
+        table: String,
+        estimated_rows: u64,
+        max_rows: u64,
+    },
+}
+
+/// Calls a shard method that returns a `Result`.
+pub async fn call<A, T>(shard: Principal, method: &str, args: A) -> Result<T, Error>
+where
+    A: ArgumentEncoder,
+    T: CandidType + DeserializeOwned,
+{
+    match ic_cdk::call::<A, (Result<T, ShardError>,)>(shard, method, args).await {
+        Ok((Ok(result),)) => Ok(result),
+        Ok((Err(err),)) => Err(Error::ShardError {
+            shard,
+            message: format!("{:?}", err),
+        }),
+        Err((code, message)) => Err(Error::ShardError {
+            shard,
+            message: format!("{:?}: {}", code, message),
+        }),
+    }
+}
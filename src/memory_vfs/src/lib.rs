@@ -0,0 +1,580 @@
+//! A SQLite VFS that stores files directly in `ic_stable_structures` memories.
+//!
+//! Every mounted file is backed by its own [`Memory`], file offsets map 1:1 to memory
+//! offsets, so the page layout is the same as the one of a memory mounted with
+//! `ic_wasi_polyfill::mount_memory_file`. The file sizes are kept in a separate
+//! memory, at 8 bytes per file.
+//!
+//! Files that are not mounted (e.g. statement journals) live in the heap and are
+//! dropped when they are deleted. Locking is a no-op, a canister runs a single thread.
+//! Randomness and time come from the default VFS.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::rc::Rc;
+use std::sync::Once;
+
+use ic_stable_structures::{Memory, VectorMemory};
+use rusqlite::ffi;
+
+/// Name to pass to `Connection::open_with_flags_and_vfs`.
+pub const VFS_NAME: &str = "stable_memory";
+
+const VFS_NAME_C: &CStr = c"stable_memory";
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+const SECTOR_SIZE: c_int = 4096;
+
+const MAX_PATH_NAME: c_int = 512;
+
+struct StoredFile {
+    memory: Box<dyn Memory>,
+    /// Memory and offset of the persisted file size, `None` for heap files.
+    size_slot: Option<(Rc<dyn Memory>, u64)>,
+    size: Cell<u64>,
+}
+
+impl StoredFile {
+    fn set_size(&self, size: u64) {
+        self.size.set(size);
+
+        if let Some((memory, offset)) = &self.size_slot {
+            let end = offset + 8;
+            if memory.size() * WASM_PAGE_SIZE < end {
+                memory.grow(end.div_ceil(WASM_PAGE_SIZE) - memory.size());
+            }
+            memory.write(*offset, &size.to_le_bytes());
+        }
+    }
+}
+
+thread_local! {
+    static FILES: RefCell<BTreeMap<String, Rc<StoredFile>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Makes `memory` available to SQLite as the file `name`.
+///
+/// `sizes` keeps the size of the file at `slot * 8`, several files can share it.
+/// Mounting a name again replaces the previous memory.
+pub fn mount(name: &str, memory: Box<dyn Memory>, sizes: Rc<dyn Memory>, slot: u64) {
+    register();
+
+    let offset = slot * 8;
+    let mut size = [0u8; 8];
+    if sizes.size() * WASM_PAGE_SIZE >= offset + 8 {
+        sizes.read(offset, &mut size);
+    }
+
+    let file = StoredFile {
+        memory,
+        size_slot: Some((sizes, offset)),
+        size: Cell::new(u64::from_le_bytes(size)),
+    };
+
+    FILES.with(|f| f.borrow_mut().insert(name.to_string(), Rc::new(file)));
+}
+
+/// Returns the size of a mounted or heap file, `None` if there is no such file.
+pub fn file_size(name: &str) -> Option<u64> {
+    FILES.with(|f| f.borrow().get(name).map(|file| file.size.get()))
+}
+
+/// Sets the size of a file whose memory was filled outside of SQLite, e.g. by a copy.
+///
+/// Returns `false` if there is no such file.
+pub fn set_file_size(name: &str, size: u64) -> bool {
+    FILES.with(|f| match f.borrow().get(name) {
+        Some(file) => {
+            file.set_size(size);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Registers the VFS with SQLite, it is not made the default VFS.
+pub fn register() {
+    static REGISTER: Once = Once::new();
+
+    REGISTER.call_once(|| unsafe {
+        let default_vfs = ffi::sqlite3_vfs_find(std::ptr::null());
+        assert!(!default_vfs.is_null(), "SQLite has no default VFS");
+
+        let vfs = Box::leak(Box::new(ffi::sqlite3_vfs {
+            iVersion: 1,
+            szOsFile: std::mem::size_of::<OpenFile>() as c_int,
+            mxPathname: MAX_PATH_NAME,
+            pNext: std::ptr::null_mut(),
+            zName: VFS_NAME_C.as_ptr(),
+            pAppData: default_vfs as *mut c_void,
+            xOpen: Some(x_open),
+            xDelete: Some(x_delete),
+            xAccess: Some(x_access),
+            xFullPathname: Some(x_full_pathname),
+            xDlOpen: None,
+            xDlError: None,
+            xDlSym: None,
+            xDlClose: None,
+            xRandomness: Some(x_randomness),
+            xSleep: Some(x_sleep),
+            xCurrentTime: Some(x_current_time),
+            xGetLastError: Some(x_get_last_error),
+            xCurrentTimeInt64: None,
+            xSetSystemCall: None,
+            xGetSystemCall: None,
+            xNextSystemCall: None,
+        }));
+
+        let rc = ffi::sqlite3_vfs_register(vfs, 0);
+        assert_eq!(
+            rc,
+            ffi::SQLITE_OK,
+            "failed to register the {} VFS",
+            VFS_NAME
+        );
+    });
+}
+
+#[repr(C)]
+struct OpenFile {
+    base: ffi::sqlite3_file,
+    file: Rc<StoredFile>,
+}
+
+static IO_METHODS: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(x_close),
+    xRead: Some(x_read),
+    xWrite: Some(x_write),
+    xTruncate: Some(x_truncate),
+    xSync: Some(x_sync),
+    xFileSize: Some(x_file_size),
+    xLock: Some(x_lock),
+    xUnlock: Some(x_lock),
+    xCheckReservedLock: Some(x_check_reserved_lock),
+    xFileControl: Some(x_file_control),
+    xSectorSize: Some(x_sector_size),
+    xDeviceCharacteristics: Some(x_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe fn name_of(z_name: *const c_char) -> Option<String> {
+    if z_name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(z_name).to_string_lossy().into_owned())
+    }
+}
+
+unsafe fn stored_file<'a>(file: *mut ffi::sqlite3_file) -> &'a StoredFile {
+    &(*(file as *mut OpenFile)).file
+}
+
+unsafe fn default_vfs(vfs: *mut ffi::sqlite3_vfs) -> *mut ffi::sqlite3_vfs {
+    (*vfs).pAppData as *mut ffi::sqlite3_vfs
+}
+
+fn heap_file() -> Rc<StoredFile> {
+    Rc::new(StoredFile {
+        memory: Box::new(VectorMemory::default()),
+        size_slot: None,
+        size: Cell::new(0),
+    })
+}
+
+unsafe extern "C" fn x_open(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    let stored = match name_of(z_name) {
+        // anonymous temporary files
+        None => heap_file(),
+        Some(name) => {
+            let existing = FILES.with(|f| f.borrow().get(&name).cloned());
+            match existing {
+                Some(stored) => stored,
+                None if flags & ffi::SQLITE_OPEN_CREATE != 0 => {
+                    let stored = heap_file();
+                    FILES.with(|f| f.borrow_mut().insert(name, stored.clone()));
+                    stored
+                }
+                None => return ffi::SQLITE_CANTOPEN,
+            }
+        }
+    };
+
+    std::ptr::write(
+        file as *mut OpenFile,
+        OpenFile {
+            base: ffi::sqlite3_file {
+                pMethods: &IO_METHODS,
+            },
+            file: stored,
+        },
+    );
+
+    if !out_flags.is_null() {
+        *out_flags = flags;
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_delete(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    _sync_dir: c_int,
+) -> c_int {
+    if let Some(name) = name_of(z_name) {
+        FILES.with(|f| {
+            let mut files = f.borrow_mut();
+            match files.get(&name) {
+                // mounted files cannot go away, they just become empty
+                Some(file) if file.size_slot.is_some() => file.set_size(0),
+                Some(_) => {
+                    files.remove(&name);
+                }
+                None => {}
+            }
+        });
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_access(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    _flags: c_int,
+    res_out: *mut c_int,
+) -> c_int {
+    // an empty mounted file is reported as missing, like a deleted journal
+    let exists = name_of(z_name)
+        .and_then(|name| file_size(&name))
+        .is_some_and(|size| size > 0);
+
+    *res_out = exists as c_int;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_full_pathname(
+    _vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    n_out: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let name = CStr::from_ptr(z_name).to_bytes_with_nul();
+    if name.len() > n_out as usize {
+        return ffi::SQLITE_CANTOPEN;
+    }
+
+    std::ptr::copy_nonoverlapping(name.as_ptr() as *const c_char, z_out, name.len());
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_randomness(
+    vfs: *mut ffi::sqlite3_vfs,
+    n_byte: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xRandomness.unwrap()(default_vfs, n_byte, z_out)
+}
+
+unsafe extern "C" fn x_sleep(vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xSleep.unwrap()(default_vfs, microseconds)
+}
+
+unsafe extern "C" fn x_current_time(vfs: *mut ffi::sqlite3_vfs, out: *mut f64) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xCurrentTime.unwrap()(default_vfs, out)
+}
+
+unsafe extern "C" fn x_get_last_error(
+    vfs: *mut ffi::sqlite3_vfs,
+    n_byte: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let default_vfs = default_vfs(vfs);
+    (*default_vfs).xGetLastError.unwrap()(default_vfs, n_byte, z_out)
+}
+
+unsafe extern "C" fn x_close(file: *mut ffi::sqlite3_file) -> c_int {
+    std::ptr::drop_in_place(file as *mut OpenFile);
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_read(
+    file: *mut ffi::sqlite3_file,
+    buf: *mut c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    let file = stored_file(file);
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, amount as usize);
+    let offset = offset as u64;
+
+    let available = file.size.get().saturating_sub(offset).min(buf.len() as u64) as usize;
+    if available > 0 {
+        file.memory.read(offset, &mut buf[..available]);
+    }
+
+    if available < buf.len() {
+        // SQLite expects the missing part to be zeroed
+        buf[available..].fill(0);
+        return ffi::SQLITE_IOERR_SHORT_READ;
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_write(
+    file: *mut ffi::sqlite3_file,
+    buf: *const c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    let file = stored_file(file);
+    let buf = std::slice::from_raw_parts(buf as *const u8, amount as usize);
+    let offset = offset as u64;
+    let end = offset + buf.len() as u64;
+
+    let pages = end.div_ceil(WASM_PAGE_SIZE);
+    let current = file.memory.size();
+    if pages > current && file.memory.grow(pages - current) < 0 {
+        return ffi::SQLITE_FULL;
+    }
+
+    file.memory.write(offset, buf);
+
+    if end > file.size.get() {
+        file.set_size(end);
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_truncate(file: *mut ffi::sqlite3_file, size: ffi::sqlite3_int64) -> c_int {
+    let file = stored_file(file);
+
+    // the memory itself cannot shrink, the pages are reused when the file grows again
+    if (size as u64) < file.size.get() {
+        file.set_size(size as u64);
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_sync(_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_size(
+    file: *mut ffi::sqlite3_file,
+    size: *mut ffi::sqlite3_int64,
+) -> c_int {
+    *size = stored_file(file).size.get() as ffi::sqlite3_int64;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_lock(_file: *mut ffi::sqlite3_file, _lock: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_check_reserved_lock(
+    _file: *mut ffi::sqlite3_file,
+    res_out: *mut c_int,
+) -> c_int {
+    *res_out = 0;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_control(
+    _file: *mut ffi::sqlite3_file,
+    _op: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    ffi::SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn x_sector_size(_file: *mut ffi::sqlite3_file) -> c_int {
+    SECTOR_SIZE
+}
+
+unsafe extern "C" fn x_device_characteristics(_file: *mut ffi::sqlite3_file) -> c_int {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{Connection, OpenFlags};
+
+    fn mount_files(db: &VectorMemory, journal: &VectorMemory, sizes: &VectorMemory) {
+        let sizes: Rc<dyn Memory> = Rc::new(sizes.clone());
+        mount("db.db3", Box::new(db.clone()), sizes.clone(), 0);
+        mount("db.db3-journal", Box::new(journal.clone()), sizes, 1);
+    }
+
+    fn open() -> Connection {
+        let db =
+            Connection::open_with_flags_and_vfs("db.db3", OpenFlags::default(), VFS_NAME).unwrap();
+        db.execute_batch(
+            "PRAGMA page_size = 16384;
+            PRAGMA cache_size = 2;
+            CREATE TABLE IF NOT EXISTS person (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                data TEXT
+            );",
+        )
+        .unwrap();
+        db
+    }
+
+    fn insert(db: &Connection, rows: i64) {
+        db.execute_batch(&format!(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {rows})
+            INSERT INTO person (name, data) SELECT 'person' || i, hex(randomblob(500)) FROM n;"
+        ))
+        .unwrap();
+    }
+
+    fn count(db: &Connection) -> i64 {
+        db.query_row("SELECT count(*) FROM person", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn data_survives_remounting() {
+        let (data, journal, sizes) = Default::default();
+        mount_files(&data, &journal, &sizes);
+
+        let db = open();
+        insert(&db, 1000);
+        drop(db);
+
+        let size = file_size("db.db3").unwrap();
+        assert!(size > 500 * 1000);
+        assert!(data.size() * WASM_PAGE_SIZE >= size);
+
+        // a new mount, like after an upgrade, picks up the stored file sizes
+        mount_files(&data, &journal, &sizes);
+        assert_eq!(file_size("db.db3"), Some(size));
+
+        let db = open();
+        assert_eq!(count(&db), 1000);
+        let integrity: String = db
+            .pragma_query_value(None, "integrity_check", |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+    }
+
+    #[test]
+    fn files_copied_into_memory_can_be_opened() {
+        let (data, journal, sizes) = Default::default();
+        mount_files(&data, &journal, &sizes);
+        let db = open();
+        insert(&db, 100);
+        drop(db);
+
+        // copy the bytes of the database, like a migration from another backend does
+        let size = file_size("db.db3").unwrap();
+        let mut bytes = vec![0; size as usize];
+        data.read(0, &mut bytes);
+        let copy = VectorMemory::default();
+        copy.grow(size.div_ceil(WASM_PAGE_SIZE));
+        copy.write(0, &bytes);
+
+        let (journal, sizes) = Default::default();
+        mount_files(&copy, &journal, &sizes);
+        assert!(set_file_size("db.db3", size));
+        assert!(!set_file_size("missing.db3", size));
+
+        let db = open();
+        assert_eq!(count(&db), 100);
+    }
+
+    #[test]
+    fn rollback_from_the_journal() {
+        let (data, journal, sizes) = Default::default();
+        mount_files(&data, &journal, &sizes);
+
+        let mut db = open();
+        insert(&db, 500);
+
+        {
+            let tx = db.transaction().unwrap();
+            tx.execute("DELETE FROM person WHERE id % 2 = 0", [])
+                .unwrap();
+            insert(&tx, 500);
+            // dropped without a commit
+        }
+
+        assert_eq!(count(&db), 500);
+        assert!(journal.size() > 0);
+    }
+
+    #[test]
+    fn journal_modes() {
+        for mode in ["DELETE", "TRUNCATE", "PERSIST", "MEMORY"] {
+            let (data, journal, sizes) = Default::default();
+            mount_files(&data, &journal, &sizes);
+
+            let db = open();
+            let effective: String = db
+                .pragma_update_and_check(None, "journal_mode", mode, |row| row.get(0))
+                .unwrap();
+            assert_eq!(effective.to_uppercase(), mode);
+
+            insert(&db, 200);
+            db.execute("UPDATE person SET data = 'x' WHERE id < 100", [])
+                .unwrap();
+            assert_eq!(count(&db), 200);
+
+            // the journal is not hot after a commit
+            drop(db);
+            let db = open();
+            assert_eq!(count(&db), 200);
+        }
+    }
+
+    #[test]
+    fn truncate_and_vacuum() {
+        let (data, journal, sizes) = Default::default();
+        mount_files(&data, &journal, &sizes);
+
+        let db = open();
+        insert(&db, 1000);
+        let before = file_size("db.db3").unwrap();
+
+        db.execute_batch("DELETE FROM person WHERE id > 10; VACUUM;")
+            .unwrap();
+
+        assert!(file_size("db.db3").unwrap() < before);
+        assert_eq!(count(&db), 10);
+    }
+
+    #[test]
+    fn missing_files_cannot_be_opened_read_only() {
+        register();
+        let res = Connection::open_with_flags_and_vfs(
+            "missing.db3",
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            VFS_NAME,
+        )
+        .and_then(|db| db.query_row("SELECT count(*) FROM sqlite_schema", [], |_| Ok(())));
+        assert!(res.is_err());
+    }
+}